@@ -0,0 +1,103 @@
+use bytes::Bytes;
+use kcp2k_rust::kcp2k::Kcp2K;
+use kcp2k_rust::kcp2k_auth::Kcp2KAuth;
+use kcp2k_rust::kcp2k_callback::{Callback, CallbackType};
+use kcp2k_rust::kcp2k_config::Kcp2KConfig;
+use kcp2k_rust::kcp2k_connection::Kcp2KConnection;
+use kcp2k_rust::kcp2k_protocol::{Kcp2KReject, Kcp2KRejectReason};
+use std::sync::Mutex;
+use std::thread::sleep;
+use std::time::Duration;
+
+// 应用定义的拒绝原因
+const INVALID_TOKEN: u8 = 1;
+
+// 等待异步认证的连接和令牌，由主循环模拟的登录服务处理
+static PENDING: Mutex<Vec<(u64, Bytes)>> = Mutex::new(Vec::new());
+
+// 认证钩子："token:" 开头的令牌立即接受，"slow:" 开头的交给登录服务异步检查，其他的拒绝
+fn authenticate(conn: &Kcp2KConnection, payload: Bytes) -> Kcp2KAuth {
+    println!(
+        "S - authenticate {} from {:?}: {:?}",
+        conn.get_connection_id(),
+        conn.get_sock_addr().as_socket(),
+        payload
+    );
+    if let Some(user) = payload.strip_prefix(b"token:") {
+        Kcp2KAuth::Accept(Bytes::copy_from_slice(user))
+    } else if payload.starts_with(b"slow:") {
+        PENDING
+            .lock()
+            .unwrap()
+            .push((conn.get_connection_id(), payload));
+        Kcp2KAuth::Pending
+    } else {
+        Kcp2KAuth::Reject(Kcp2KReject::new(
+            Kcp2KRejectReason::application(INVALID_TOKEN).unwrap(),
+            "invalid token".to_string(),
+        ))
+    }
+}
+
+fn s_call_back(conn: &Kcp2KConnection, cb: Callback) {
+    match cb.r#type {
+        // 认证钩子返回的用户数据在 OnConnected 时已经可以读取
+        CallbackType::OnConnected => println!(
+            "S - connected: {} user {:?}",
+            cb.conn_id,
+            conn.get_user_data()
+        ),
+        _ => println!("S - {:?}", cb),
+    }
+}
+
+fn c_call_back(conn: &Kcp2KConnection, cb: Callback) {
+    match cb.r#type {
+        CallbackType::OnError => println!(
+            "C - error: {} {:?} rejection: {:?}",
+            cb.conn_id,
+            cb.error_code,
+            conn.get_rejection()
+        ),
+        _ => println!("C - {:?}", cb),
+    }
+}
+
+fn main() {
+    // 创建 KCP 配置
+    let config = Kcp2KConfig::default();
+
+    // 创建 KCP 服务器并设置认证钩子
+    let mut server = Kcp2K::new_server(config, "0.0.0.0:3100".to_string(), s_call_back).unwrap();
+    server.set_authenticator(authenticate);
+
+    // 三个客户端在 Hello 中附带不同的令牌
+    let clients: Vec<Kcp2K> = ["token:alice", "slow:bob", "guest"]
+        .into_iter()
+        .map(|token| {
+            Kcp2K::new_client_with_hello(
+                config,
+                "127.0.0.1:3100".to_string(),
+                Bytes::from(token),
+                c_call_back,
+            )
+            .unwrap()
+        })
+        .collect();
+
+    for tick in 0..100 {
+        // 登录服务在几个 tick 后完成异步认证
+        if tick % 20 == 19 {
+            for (connection_id, payload) in PENDING.lock().unwrap().drain(..) {
+                println!("S - login service accepted {}", connection_id);
+                let user = payload.slice(b"slow:".len()..);
+                let _ = server.accept(connection_id, user);
+            }
+        }
+        server.tick();
+        for client in &clients {
+            client.tick();
+        }
+        sleep(Duration::from_millis(10));
+    }
+}
@@ -0,0 +1,78 @@
+use bytes::Bytes;
+use kcp2k_rust::kcp2k::Kcp2K;
+use kcp2k_rust::kcp2k_callback::{Callback, CallbackType};
+use kcp2k_rust::kcp2k_channel::Kcp2KChannel;
+use kcp2k_rust::kcp2k_config::Kcp2KConfig;
+use kcp2k_rust::kcp2k_connection::Kcp2KConnection;
+use std::sync::Mutex;
+use std::thread::sleep;
+use std::time::Duration;
+
+// 新连接的 ID，由主循环分配分组
+static CONNECTED: Mutex<Vec<u64>> = Mutex::new(Vec::new());
+
+fn s_call_back(_: &Kcp2KConnection, cb: Callback) {
+    if let CallbackType::OnConnected = cb.r#type {
+        println!("S - OnConnected {}", cb.conn_id);
+        CONNECTED.lock().unwrap().push(cb.conn_id);
+    }
+}
+
+fn c_call_back(_: &Kcp2KConnection, cb: Callback) {
+    if let CallbackType::OnData = cb.r#type {
+        println!("C - {} received {:?}", cb.conn_id, cb.data);
+    }
+}
+
+fn main() {
+    // 创建 KCP 配置
+    let config = Kcp2KConfig::default();
+
+    // 创建 KCP 服务器和三个客户端
+    let server = Kcp2K::new_server(config, "0.0.0.0:3100".to_string(), s_call_back).unwrap();
+    let clients: Vec<Kcp2K> = (0..3)
+        .map(|_| Kcp2K::new_client(config, "127.0.0.1:3100".to_string(), c_call_back).unwrap())
+        .collect();
+
+    let mut players = Vec::new();
+    for tick in 0..300 {
+        // 前两个连接加入 red 房间，其余加入 blue 房间
+        for connection_id in CONNECTED.lock().unwrap().drain(..) {
+            let group = if players.len() < 2 { "red" } else { "blue" };
+            server.join_group(group, connection_id).unwrap();
+            players.push(connection_id);
+        }
+
+        if tick % 100 == 99 && players.len() == 3 {
+            // 发给 red 房间
+            let sent = server
+                .broadcast(
+                    Some("red"),
+                    Bytes::from("red update"),
+                    Kcp2KChannel::Reliable,
+                    &[],
+                )
+                .unwrap();
+            println!("S - red update sent to {} players", sent);
+            // 发给所有人，第一个玩家除外
+            let sent = server
+                .broadcast(
+                    None,
+                    Bytes::from("world update"),
+                    Kcp2KChannel::Unreliable,
+                    &players[..1],
+                )
+                .unwrap();
+            println!("S - world update sent to {} players", sent);
+        }
+
+        server.tick();
+        for client in clients.iter() {
+            client.tick();
+        }
+        sleep(Duration::from_millis(10));
+    }
+    for group in server.get_group_names() {
+        println!("group {}: {:?}", group, server.get_group_members(&group));
+    }
+}
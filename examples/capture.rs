@@ -0,0 +1,58 @@
+use bytes::Bytes;
+use kcp2k_rust::kcp2k::Kcp2K;
+use kcp2k_rust::kcp2k_callback::{Callback, CallbackType};
+use kcp2k_rust::kcp2k_capture::Kcp2KCaptureConfig;
+use kcp2k_rust::kcp2k_channel::Kcp2KChannel;
+use kcp2k_rust::kcp2k_config::Kcp2KConfig;
+use kcp2k_rust::kcp2k_connection::Kcp2KConnection;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::sleep;
+use std::time::Duration;
+
+// 握手完成后客户端才开始发送
+static CONNECTED: AtomicBool = AtomicBool::new(false);
+
+fn s_call_back(conn: &Kcp2KConnection, cb: Callback) {
+    println!("S - {:?}", cb);
+    // 回显
+    if let CallbackType::OnData = cb.r#type {
+        let _ = conn.send_data(cb.data, cb.channel);
+    }
+}
+
+fn c_call_back(_: &Kcp2KConnection, cb: Callback) {
+    println!("C - {:?}", cb);
+    if let CallbackType::OnConnected = cb.r#type {
+        CONNECTED.store(true, Ordering::SeqCst);
+    }
+}
+
+fn main() {
+    // 创建 KCP 配置
+    let config = Kcp2KConfig::default();
+
+    // 创建 KCP 服务器和客户端
+    let server = Kcp2K::new_server(config, "0.0.0.0:3100".to_string(), s_call_back).unwrap();
+    let client = Kcp2K::new_client(config, "127.0.0.1:3100".to_string(), c_call_back).unwrap();
+
+    // 服务器抓包：每个文件最多 4 KiB，保留最新的 3 个文件
+    let mut capture = Kcp2KCaptureConfig::new("kcp2k-server.pcapng");
+    capture.max_file_size = 4096;
+    capture.max_files = 3;
+    server.start_capture(capture).unwrap();
+
+    for tick in 0..200 {
+        if tick % 20 == 0 && CONNECTED.load(Ordering::SeqCst) {
+            let _ = client.c_send(
+                Bytes::from(format!("tick {}", tick)),
+                Kcp2KChannel::Reliable,
+            );
+            let _ = client.c_send(Bytes::from("ping"), Kcp2KChannel::Unreliable);
+        }
+        server.tick();
+        client.tick();
+        sleep(Duration::from_millis(10));
+    }
+    server.stop_capture().unwrap();
+    println!("capture written to kcp2k-server*.pcapng, open it with Wireshark");
+}
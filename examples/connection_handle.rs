@@ -0,0 +1,60 @@
+use bytes::Bytes;
+use kcp2k_rust::kcp2k::Kcp2K;
+use kcp2k_rust::kcp2k_callback::{Callback, CallbackType};
+use kcp2k_rust::kcp2k_channel::Kcp2KChannel;
+use kcp2k_rust::kcp2k_config::Kcp2KConfig;
+use kcp2k_rust::kcp2k_connection::Kcp2KConnection;
+use kcp2k_rust::kcp2k_connection_handle::ConnectionHandle;
+use std::sync::Mutex;
+use std::thread::sleep;
+use std::time::Duration;
+
+// 游戏系统持有的连接句柄
+static PLAYERS: Mutex<Vec<ConnectionHandle>> = Mutex::new(Vec::new());
+
+fn s_call_back(conn: &Kcp2KConnection, cb: Callback) {
+    if let CallbackType::OnConnected = cb.r#type {
+        println!("S - OnConnected {}", cb.conn_id);
+        PLAYERS.lock().unwrap().push(conn.get_handle());
+    }
+}
+
+fn c_call_back(_: &Kcp2KConnection, cb: Callback) {
+    println!("C - {:?}", cb);
+}
+
+fn main() {
+    // 创建 KCP 配置
+    let config = Kcp2KConfig::default();
+
+    // 创建 KCP 服务器和客户端
+    let server = Kcp2K::new_server(config, "0.0.0.0:3100".to_string(), s_call_back).unwrap();
+    let client = Kcp2K::new_client(config, "127.0.0.1:3100".to_string(), c_call_back).unwrap();
+
+    // 游戏线程：通过句柄发送消息，不需要 &Kcp2K
+    std::thread::spawn(|| {
+        for tick in 0u8.. {
+            for player in PLAYERS.lock().unwrap().iter() {
+                if player.is_alive() {
+                    let _ = player.send(Bytes::from(vec![tick]), Kcp2KChannel::Reliable);
+                }
+            }
+            sleep(Duration::from_millis(500));
+        }
+    });
+
+    for _ in 0..300 {
+        // 网络线程：tick 时发送发件箱中的消息
+        server.tick();
+        client.tick();
+        sleep(Duration::from_millis(10));
+    }
+    for player in PLAYERS.lock().unwrap().iter() {
+        println!(
+            "player {} alive={} addr={:?}",
+            player.get_connection_id(),
+            player.is_alive(),
+            player.get_remote_addr()
+        );
+    }
+}
@@ -0,0 +1,111 @@
+use bytes::Bytes;
+use kcp2k_rust::kcp2k::Kcp2K;
+use kcp2k_rust::kcp2k_callback::{Callback, CallbackType};
+use kcp2k_rust::kcp2k_channel::Kcp2KChannel;
+use kcp2k_rust::kcp2k_config::Kcp2KConfig;
+use kcp2k_rust::kcp2k_connection::Kcp2KConnection;
+use kcp2k_rust::kcp2k_message::{send_message, Kcp2KMessage, Kcp2KMessageRegistry};
+use serde::{Deserialize, Serialize};
+use std::sync::LazyLock;
+use std::thread::sleep;
+use std::time::Duration;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Login {
+    name: String,
+}
+impl Kcp2KMessage for Login {
+    const ID: u16 = 1;
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Welcome {
+    player_id: u64,
+    motd: String,
+}
+impl Kcp2KMessage for Welcome {
+    const ID: u16 = 2;
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Move {
+    x: f32,
+    y: f32,
+}
+impl Kcp2KMessage for Move {
+    const ID: u16 = 3;
+}
+
+// 服务器处理的消息
+static SERVER_MESSAGES: LazyLock<Kcp2KMessageRegistry> = LazyLock::new(|| {
+    let mut registry = Kcp2KMessageRegistry::new();
+    registry.register(on_login).unwrap();
+    registry.register(on_move).unwrap();
+    registry
+});
+
+// 客户端处理的消息
+static CLIENT_MESSAGES: LazyLock<Kcp2KMessageRegistry> = LazyLock::new(|| {
+    let mut registry = Kcp2KMessageRegistry::new();
+    registry.register(on_welcome).unwrap();
+    registry
+});
+
+fn on_login(conn: &Kcp2KConnection, login: Login) {
+    println!("S - {:?}", login);
+    let welcome = Welcome {
+        player_id: conn.get_connection_id(),
+        motd: format!("hello {}", login.name),
+    };
+    let _ = send_message(conn, &welcome, Kcp2KChannel::Reliable);
+}
+
+fn on_move(_: &Kcp2KConnection, movement: Move) {
+    println!("S - {:?}", movement);
+}
+
+fn on_welcome(conn: &Kcp2KConnection, welcome: Welcome) {
+    println!("C - {:?}", welcome);
+    let _ = send_message(conn, &Move { x: 1.0, y: 2.5 }, Kcp2KChannel::Unreliable);
+    // 服务器没有注册的消息 ID，会在服务器触发 OnError
+    let _ = conn.send_data(Bytes::from_static(&[42, 0, 1]), Kcp2KChannel::Reliable);
+}
+
+fn s_call_back(conn: &Kcp2KConnection, cb: Callback) {
+    match cb.r#type {
+        CallbackType::OnData => {
+            let _ = SERVER_MESSAGES.dispatch(conn, &cb.data);
+        }
+        _ => println!("S - {:?}", cb),
+    }
+}
+
+fn c_call_back(conn: &Kcp2KConnection, cb: Callback) {
+    match cb.r#type {
+        CallbackType::OnConnected => {
+            let login = Login {
+                name: "rust".to_string(),
+            };
+            let _ = send_message(conn, &login, Kcp2KChannel::Reliable);
+        }
+        CallbackType::OnData => {
+            let _ = CLIENT_MESSAGES.dispatch(conn, &cb.data);
+        }
+        _ => println!("C - {:?}", cb),
+    }
+}
+
+fn main() {
+    // 创建 KCP 配置
+    let config = Kcp2KConfig::default();
+
+    // 创建 KCP 服务器和客户端
+    let server = Kcp2K::new_server(config, "0.0.0.0:3100".to_string(), s_call_back).unwrap();
+    let client = Kcp2K::new_client(config, "127.0.0.1:3100".to_string(), c_call_back).unwrap();
+
+    for _ in 0..200 {
+        server.tick();
+        client.tick();
+        sleep(Duration::from_millis(10));
+    }
+}
@@ -0,0 +1,73 @@
+use bytes::Bytes;
+use kcp2k_rust::kcp2k::Kcp2K;
+use kcp2k_rust::kcp2k_callback::{Callback, CallbackType};
+use kcp2k_rust::kcp2k_channel::Kcp2KChannel;
+use kcp2k_rust::kcp2k_config::Kcp2KConfig;
+use kcp2k_rust::kcp2k_connection::Kcp2KConnection;
+use std::thread::sleep;
+
+fn s_call_back(conn: &Kcp2KConnection, cb: Callback) {
+    if let CallbackType::OnData = cb.r#type {
+        // 原样返回
+        let _ = conn.send_data(cb.data, cb.channel);
+    }
+}
+
+fn c_call_back(conn: &Kcp2KConnection, cb: Callback) {
+    match cb.r#type {
+        CallbackType::OnConnected => {
+            println!(
+                "C - OnConnected {} {:?}",
+                cb.conn_id,
+                conn.get_sock_addr().as_socket()
+            );
+            let _ = conn.send_data(Bytes::from(vec![0]), Kcp2KChannel::Reliable);
+        }
+        CallbackType::OnData => {
+            // conn_id 和地址标识了数据来自哪个服务器
+            println!(
+                "C - received {:?} from {} {:?}",
+                cb.data.as_ref(),
+                cb.conn_id,
+                conn.get_sock_addr().as_socket()
+            );
+        }
+        _ => println!("C - {:?}", cb),
+    }
+}
+
+fn main() {
+    // 创建 KCP 配置
+    let config = Kcp2KConfig::default();
+
+    // 创建两个 KCP 服务器
+    let server_a = Kcp2K::new_server(config, "0.0.0.0:3100".to_string(), s_call_back).unwrap();
+    let server_b = Kcp2K::new_server(config, "0.0.0.0:3101".to_string(), s_call_back).unwrap();
+
+    // 创建多连接客户端，并同时连接两个服务器
+    let client = Kcp2K::new_multi_client(config, c_call_back).unwrap();
+    let conn_a = client.connect("127.0.0.1:3100".to_string()).unwrap();
+    let conn_b = client.connect("127.0.0.1:3101".to_string()).unwrap();
+
+    loop {
+        server_a.tick();
+        server_b.tick();
+        client.tick();
+        // 握手完成后按连接 ID 发送
+        if client
+            .get_connections()
+            .get(&conn_a)
+            .is_some_and(|c| c.is_authenticated())
+        {
+            let _ = client.send(conn_a, Bytes::from(vec![0xA]), Kcp2KChannel::Reliable);
+        }
+        if client
+            .get_connections()
+            .get(&conn_b)
+            .is_some_and(|c| c.is_authenticated())
+        {
+            let _ = client.send(conn_b, Bytes::from(vec![0xB]), Kcp2KChannel::Reliable);
+        }
+        sleep(std::time::Duration::from_millis(100));
+    }
+}
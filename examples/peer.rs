@@ -0,0 +1,53 @@
+use bytes::Bytes;
+use kcp2k_rust::kcp2k::Kcp2K;
+use kcp2k_rust::kcp2k_callback::{Callback, CallbackType};
+use kcp2k_rust::kcp2k_channel::Kcp2KChannel;
+use kcp2k_rust::kcp2k_config::Kcp2KConfig;
+use kcp2k_rust::kcp2k_connection::Kcp2KConnection;
+use std::thread::sleep;
+
+// 同一个回调同时处理主动发起和被动接受的连接
+fn call_back(conn: &Kcp2KConnection, cb: Callback) {
+    match cb.r#type {
+        CallbackType::OnConnected => {
+            println!(
+                "OnConnected {} {:?} {:?}",
+                cb.conn_id,
+                conn.get_mode(),
+                conn.get_sock_addr().as_socket()
+            );
+            let _ = conn.send_data(Bytes::from(vec![1]), Kcp2KChannel::Reliable);
+        }
+        CallbackType::OnData => {
+            println!(
+                "received {:?} from {} {:?}",
+                cb.data.as_ref(),
+                cb.conn_id,
+                conn.get_mode()
+            );
+        }
+        _ => println!("{:?}", cb),
+    }
+}
+
+fn main() {
+    // 创建 KCP 配置
+    let config = Kcp2KConfig::default();
+
+    // 创建三个 P2P 节点，每个节点绑定一个端口
+    let peer_a = Kcp2K::new_peer(config, "127.0.0.1:3100".to_string(), call_back).unwrap();
+    let peer_b = Kcp2K::new_peer(config, "127.0.0.1:3101".to_string(), call_back).unwrap();
+    let peer_c = Kcp2K::new_peer(config, "127.0.0.1:3102".to_string(), call_back).unwrap();
+
+    // B 连接 A，C 连接 A 和 B
+    peer_b.connect("127.0.0.1:3100".to_string()).unwrap();
+    peer_c.connect("127.0.0.1:3100".to_string()).unwrap();
+    peer_c.connect("127.0.0.1:3101".to_string()).unwrap();
+
+    loop {
+        peer_a.tick();
+        peer_b.tick();
+        peer_c.tick();
+        sleep(std::time::Duration::from_millis(10));
+    }
+}
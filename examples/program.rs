@@ -24,14 +24,22 @@ fn s_call_back(conn: &Kcp2KConnection, cb: Callback) {
         CallbackType::OnDisconnected => {
             println!("OnDisconnected {}", cb.conn_id);
         }
+        CallbackType::OnAddressChanged => {
+            println!(
+                "OnAddressChanged {} {:?}",
+                cb.conn_id,
+                conn.get_sock_addr().as_socket()
+            );
+        }
         CallbackType::OnError => {
             println!("OnError {:?} {}", cb.conn_id, cb.error_message);
         }
+        CallbackType::OnRpc => {}
     };
 }
 
 fn c_call_back(conn: &Kcp2KConnection, cb: Callback) {
-     match cb.r#type {
+    match cb.r#type {
         CallbackType::OnConnected => {
             println!("C - OnConnected {}", cb.conn_id);
             let _ = conn.send_data(Bytes::from(vec![0]), Kcp2KChannel::Reliable);
@@ -48,9 +56,17 @@ fn c_call_back(conn: &Kcp2KConnection, cb: Callback) {
             println!("OnDisconnected {}", cb.conn_id);
             exit(0);
         }
+        CallbackType::OnAddressChanged => {
+            println!(
+                "OnAddressChanged {} {:?}",
+                cb.conn_id,
+                conn.get_sock_addr().as_socket()
+            );
+        }
         CallbackType::OnError => {
             println!("OnError {:?} {}", cb.conn_id, cb.error_message);
         }
+        CallbackType::OnRpc => {}
     };
 }
 
@@ -0,0 +1,81 @@
+use bytes::Bytes;
+use kcp2k_rust::kcp2k::Kcp2K;
+use kcp2k_rust::kcp2k_callback::{Callback, CallbackType};
+use kcp2k_rust::kcp2k_channel::Kcp2KChannel;
+use kcp2k_rust::kcp2k_config::Kcp2KConfig;
+use kcp2k_rust::kcp2k_connection::Kcp2KConnection;
+use kcp2k_rust::kcp2k_replay::Kcp2KReplay;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread::sleep;
+use std::time::Duration;
+
+// 握手完成后客户端才开始发送
+static CONNECTED: AtomicBool = AtomicBool::new(false);
+// 服务器端的连接 ID
+static SERVER_CONN_ID: AtomicU64 = AtomicU64::new(0);
+
+// 录制和回放使用同一个服务器回调，回调中的回显在回放时由回调重现
+fn s_call_back(conn: &Kcp2KConnection, cb: Callback) {
+    println!("S - {:?}", cb);
+    match cb.r#type {
+        CallbackType::OnConnected => SERVER_CONN_ID.store(cb.conn_id, Ordering::SeqCst),
+        CallbackType::OnData => {
+            let _ = conn.send_data(cb.data, cb.channel);
+        }
+        _ => {}
+    }
+}
+
+fn c_call_back(_: &Kcp2KConnection, cb: Callback) {
+    if let CallbackType::OnConnected = cb.r#type {
+        CONNECTED.store(true, Ordering::SeqCst);
+    }
+}
+
+fn main() {
+    // 创建 KCP 配置
+    let config = Kcp2KConfig::default();
+
+    // 创建 KCP 服务器和客户端
+    let server = Kcp2K::new_server(config, "0.0.0.0:3100".to_string(), s_call_back).unwrap();
+    let client = Kcp2K::new_client(config, "127.0.0.1:3100".to_string(), c_call_back).unwrap();
+
+    // 录制服务器会话
+    server.start_recording("kcp2k-server.kcp2krec").unwrap();
+    for tick in 0..300 {
+        if tick < 200 && tick % 20 == 0 && CONNECTED.load(Ordering::SeqCst) {
+            let _ = client.c_send(
+                Bytes::from(format!("tick {}", tick)),
+                Kcp2KChannel::Reliable,
+            );
+            let _ = client.c_send(Bytes::from("ping"), Kcp2KChannel::Unreliable);
+        }
+        // 回调之外的发送和关闭也会被录制
+        if tick == 150 {
+            let _ = server.send(
+                SERVER_CONN_ID.load(Ordering::SeqCst),
+                Bytes::from("hello from server"),
+                Kcp2KChannel::Reliable,
+            );
+        }
+        if tick == 250 {
+            server.close_connection(SERVER_CONN_ID.load(Ordering::SeqCst));
+        }
+        server.tick();
+        client.tick();
+        sleep(Duration::from_millis(10));
+    }
+    server.stop_recording().unwrap();
+
+    // 回放：不需要网络，也不需要客户端
+    println!("---- replay ----");
+    let replay = Kcp2KReplay::open("kcp2k-server.kcp2krec").unwrap();
+    println!(
+        "recording: {:?}, {} events",
+        replay.get_mode(),
+        replay.get_event_count()
+    );
+    let report = replay.run(config, s_call_back).unwrap();
+    println!("{:?}", report);
+    println!("exact replay: {}", report.is_exact());
+}
@@ -0,0 +1,124 @@
+use bytes::Bytes;
+use kcp2k_rust::kcp2k::Kcp2K;
+use kcp2k_rust::kcp2k_callback::{Callback, CallbackType};
+use kcp2k_rust::kcp2k_config::Kcp2KConfig;
+use kcp2k_rust::kcp2k_connection::Kcp2KConnection;
+use kcp2k_rust::kcp2k_connection_handle::ConnectionHandle;
+use kcp2k_rust::kcp2k_protocol::Kcp2KCapabilities;
+use kcp2k_rust::kcp2k_rpc::Kcp2KRpc;
+use std::future::Future;
+use std::sync::{LazyLock, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread::sleep;
+use std::time::Duration;
+
+const LOGIN: u16 = 1;
+const BUY_ITEM: u16 = 2;
+const LOAD_PROFILE: u16 = 3;
+
+// 服务器注册的方法
+static SERVER_RPC: LazyLock<Kcp2KRpc> = LazyLock::new(|| {
+    let mut rpc = Kcp2KRpc::new();
+    rpc.register(LOGIN, login).unwrap();
+    rpc.register(BUY_ITEM, buy_item).unwrap();
+    rpc
+});
+
+// 客户端只发起调用
+static CLIENT_RPC: LazyLock<Kcp2KRpc> = LazyLock::new(Kcp2KRpc::new);
+static SERVER: Mutex<Option<ConnectionHandle>> = Mutex::new(None);
+
+fn login(_: &Kcp2KConnection, name: Bytes) -> Result<Bytes, Bytes> {
+    Ok(Bytes::from(format!(
+        "token-{}",
+        String::from_utf8_lossy(&name)
+    )))
+}
+
+fn buy_item(_: &Kcp2KConnection, item: Bytes) -> Result<Bytes, Bytes> {
+    Err(Bytes::from(format!(
+        "not enough gold for {}",
+        String::from_utf8_lossy(&item)
+    )))
+}
+
+fn s_call_back(conn: &Kcp2KConnection, cb: Callback) {
+    if !SERVER_RPC.handle(conn, &cb) {
+        println!("S - {:?}", cb);
+    }
+}
+
+fn c_call_back(conn: &Kcp2KConnection, cb: Callback) {
+    if CLIENT_RPC.handle(conn, &cb) {
+        return;
+    }
+    match cb.r#type {
+        CallbackType::OnConnected => {
+            let server = conn.get_handle();
+            let timeout = Duration::from_secs(1);
+            CLIENT_RPC.call(&server, LOGIN, Bytes::from("rust"), timeout, |result| {
+                println!("C - login: {:?}", result)
+            });
+            CLIENT_RPC.call(&server, BUY_ITEM, Bytes::from("sword"), timeout, |result| {
+                println!("C - buy item: {:?}", result)
+            });
+            CLIENT_RPC.call(&server, LOAD_PROFILE, Bytes::new(), timeout, |result| {
+                println!("C - load profile: {:?}", result)
+            });
+            *SERVER.lock().unwrap() = Some(server);
+        }
+        CallbackType::OnDisconnected => CLIENT_RPC.cancel_connection(cb.conn_id),
+        _ => println!("C - {:?}", cb),
+    }
+}
+
+fn main() {
+    // 创建 KCP 配置，双方都需要开启 RPC
+    let config = Kcp2KConfig {
+        capabilities: Kcp2KCapabilities::ALL.bits(),
+        ..Default::default()
+    };
+
+    // 创建 KCP 服务器和客户端
+    let server = Kcp2K::new_server(config, "0.0.0.0:3100".to_string(), s_call_back).unwrap();
+    let client = Kcp2K::new_client(config, "127.0.0.1:3100".to_string(), c_call_back).unwrap();
+
+    let mut future = None;
+    let mut context = Context::from_waker(Waker::noop());
+    for tick in 0..300 {
+        server.tick();
+        client.tick();
+        CLIENT_RPC.tick();
+
+        // 通过 future 等待结果
+        if tick == 150 {
+            let server = SERVER.lock().unwrap().clone().unwrap();
+            future = Some(Box::pin(CLIENT_RPC.call_future(
+                &server,
+                LOGIN,
+                Bytes::from("future"),
+                Duration::from_secs(1),
+            )));
+        }
+        if let Some(pending) = future.as_mut() {
+            if let Poll::Ready(result) = pending.as_mut().poll(&mut context) {
+                println!("C - login future: {:?}", result);
+                future = None;
+            }
+        }
+
+        // 断开连接后，未完成的调用被取消
+        if tick == 200 {
+            let server = SERVER.lock().unwrap().clone().unwrap();
+            CLIENT_RPC.call(
+                &server,
+                LOGIN,
+                Bytes::from("late"),
+                Duration::from_secs(5),
+                |result| println!("C - login after disconnect: {:?}", result),
+            );
+            client.close_connection(server.get_connection_id());
+        }
+        sleep(Duration::from_millis(10));
+    }
+}
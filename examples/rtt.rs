@@ -0,0 +1,56 @@
+use kcp2k_rust::kcp2k::Kcp2K;
+use kcp2k_rust::kcp2k_callback::Callback;
+use kcp2k_rust::kcp2k_config::Kcp2KConfig;
+use kcp2k_rust::kcp2k_connection::Kcp2KConnection;
+use std::thread::sleep;
+use std::time::Duration;
+
+fn s_call_back(_: &Kcp2KConnection, cb: Callback) {
+    println!("S - {:?}", cb);
+}
+
+fn c_call_back(_: &Kcp2KConnection, cb: Callback) {
+    println!("C - {:?}", cb);
+}
+
+// 打印每个连接的 RTT、抖动和丢包率
+fn print_rtt(name: &str, kcp2k: &Kcp2K) {
+    for conn in kcp2k.get_connections().iter() {
+        let rtt = conn.get_rtt();
+        println!(
+            "{} - {} rtt {:?} min {:?} jitter {:?} loss {:.1}% ({} pings, {} pongs)",
+            name,
+            conn.get_connection_id(),
+            rtt.rtt,
+            rtt.min_rtt,
+            rtt.jitter,
+            rtt.loss * 100.0,
+            rtt.pings,
+            rtt.pongs
+        );
+    }
+}
+
+fn main() {
+    // 使用不可靠 ping，每 200ms 测量一次。回应 pong 也算发送过消息，所以另一方靠 rtt_interval 测量
+    let config = Kcp2KConfig {
+        is_reliable_ping: false,
+        ping_interval: 200,
+        rtt_interval: 200,
+        ..Default::default()
+    };
+
+    // 创建 KCP 服务器和客户端，双方都会测量 RTT
+    let server = Kcp2K::new_server(config, "0.0.0.0:3100".to_string(), s_call_back).unwrap();
+    let client = Kcp2K::new_client(config, "127.0.0.1:3100".to_string(), c_call_back).unwrap();
+
+    for tick in 1..=300 {
+        server.tick();
+        client.tick();
+        if tick % 100 == 0 {
+            print_rtt("S", &server);
+            print_rtt("C", &client);
+        }
+        sleep(Duration::from_millis(10));
+    }
+}
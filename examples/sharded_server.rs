@@ -0,0 +1,62 @@
+use bytes::Bytes;
+use kcp2k_rust::kcp2k::Kcp2K;
+use kcp2k_rust::kcp2k_callback::{Callback, CallbackType};
+use kcp2k_rust::kcp2k_channel::Kcp2KChannel;
+use kcp2k_rust::kcp2k_config::Kcp2KConfig;
+use kcp2k_rust::kcp2k_connection::Kcp2KConnection;
+use kcp2k_rust::kcp2k_sharded::Kcp2KShardedServer;
+use std::thread::sleep;
+use std::time::Duration;
+
+fn s_call_back(conn: &Kcp2KConnection, cb: Callback) {
+    // 回调在连接所在的分片线程中执行
+    match cb.r#type {
+        CallbackType::OnConnected => println!(
+            "S - OnConnected {} on {:?}",
+            cb.conn_id,
+            std::thread::current().name()
+        ),
+        CallbackType::OnData => {
+            let _ = conn.send_data(cb.data, cb.channel);
+        }
+        _ => println!("S - {:?}", cb),
+    }
+}
+
+fn c_call_back(_: &Kcp2KConnection, cb: Callback) {
+    if let CallbackType::OnData = cb.r#type {
+        println!("C - received {:?} on {}", cb.data.as_ref(), cb.conn_id);
+    }
+}
+
+fn main() {
+    // 创建 KCP 配置
+    let config = Kcp2KConfig::default();
+
+    // 创建 4 个分片的服务器，每个分片一个线程
+    let server =
+        Kcp2KShardedServer::new(config, "0.0.0.0:3100".to_string(), 4, s_call_back).unwrap();
+    let handle = server.get_handle();
+
+    // 创建多个客户端
+    let clients: Vec<Kcp2K> = (0..8)
+        .map(|_| Kcp2K::new_client(config, "127.0.0.1:3100".to_string(), c_call_back).unwrap())
+        .collect();
+
+    for i in 0..300 {
+        for client in clients.iter() {
+            client.tick();
+        }
+        // 从其他线程广播给所有分片的连接
+        if i % 100 == 99 {
+            let _ = handle.broadcast(Bytes::from(vec![i as u8]), Kcp2KChannel::Reliable);
+            println!(
+                "stats {:?} per shard {:?}",
+                handle.get_stats(),
+                handle.get_shard_stats()
+            );
+        }
+        sleep(Duration::from_millis(10));
+    }
+    server.stop();
+}
@@ -0,0 +1,46 @@
+use bytes::Bytes;
+use kcp2k_rust::kcp2k::Kcp2K;
+use kcp2k_rust::kcp2k_callback::CallbackType;
+use kcp2k_rust::kcp2k_channel::Kcp2KChannel;
+use kcp2k_rust::kcp2k_config::Kcp2KConfig;
+use std::time::Duration;
+
+fn main() {
+    // 创建 KCP 配置
+    let config = Kcp2KConfig::default();
+
+    // 服务器和客户端各自运行在后台网络线程中，不需要手动 tick
+    let server = Kcp2K::spawn(config, "0.0.0.0:3100".to_string()).unwrap();
+    let client = Kcp2K::spawn_client(config, "127.0.0.1:3100".to_string()).unwrap();
+
+    // 在其他线程中处理服务器事件：原样返回收到的数据
+    let server_handle = server.clone();
+    std::thread::spawn(move || {
+        while let Some(cb) = server_handle.recv() {
+            println!("S - {:?}", cb);
+            if let CallbackType::OnData = cb.r#type {
+                let _ = server_handle.send(cb.conn_id, cb.data, cb.channel);
+            }
+        }
+    });
+
+    // 主线程处理客户端事件
+    let mut count = 0;
+    while let Some(cb) = client.recv_timeout(Duration::from_secs(5)) {
+        println!("C - {:?}", cb);
+        match cb.r#type {
+            CallbackType::OnConnected => {
+                let _ = client.send(cb.conn_id, Bytes::from(vec![count]), Kcp2KChannel::Reliable);
+            }
+            CallbackType::OnData => {
+                count += 1;
+                if count == 10 {
+                    break;
+                }
+                let _ = client.send(cb.conn_id, Bytes::from(vec![count]), Kcp2KChannel::Reliable);
+            }
+            _ => {}
+        }
+    }
+    // 句柄释放后网络线程自动退出
+}
@@ -0,0 +1,26 @@
+use kcp2k_rust::kcp2k_compat;
+
+// 打印线上格式的回归向量（手写，不是 C# kcp2k 的抓包），并用本库的编码器和解码器校验
+fn main() {
+    for vector in kcp2k_compat::WIRE_VECTORS.iter() {
+        let hex: Vec<String> = vector.bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        println!(
+            "{:<28} {:<6} {}\n{:<35} {}",
+            vector.name,
+            if vector.csharp { "c#" } else { "rust" },
+            hex.join(" "),
+            "",
+            vector.source
+        );
+    }
+    match kcp2k_compat::verify_wire_vectors() {
+        Ok(count) => println!(
+            "{} wire vectors match the encoder and decoder (not checked against a C# peer)",
+            count
+        ),
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,50 @@
+#![no_main]
+
+// 把任意数据报注入客户端模式的 Kcp2K，不能 panic
+//
+// 输入按记录拆分：控制字节 + 长度(u16 LE) + 数据报，长度可以覆盖超过 MTU 的数据报。
+// 控制字节的最低位选择发送方地址（第一个是客户端连接的服务器），其余位把虚拟时间推进相应的 10 毫秒数
+use bytes::Bytes;
+use kcp2k_rust::common::Kcp2KMode;
+use kcp2k_rust::kcp2k::Kcp2K;
+use kcp2k_rust::kcp2k_callback::{Callback, CallbackType};
+use kcp2k_rust::kcp2k_config::Kcp2KConfig;
+use kcp2k_rust::kcp2k_connection::Kcp2KConnection;
+use libfuzzer_sys::fuzz_target;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+// 收到的消息原样发回，覆盖发送路径
+fn call_back(conn: &Kcp2KConnection, cb: Callback) {
+    if matches!(cb.r#type, CallbackType::OnData) {
+        let _ = conn.send_data(cb.data, cb.channel);
+    }
+}
+
+fuzz_target!(|input: &[u8]| {
+    let client = match Kcp2K::new_in_memory(Kcp2KConfig::default(), Kcp2KMode::Client, call_back) {
+        Ok(client) => client,
+        Err(_) => return,
+    };
+    let addrs: [SocketAddr; 2] = [
+        "10.0.0.1:7777".parse().unwrap(),
+        "10.0.0.2:7777".parse().unwrap(),
+    ];
+    if client.connect(addrs[0].to_string()).is_err() {
+        return;
+    }
+    let mut now = Duration::ZERO;
+    let mut rest = input;
+    while let [control, len_low, len_high, tail @ ..] = rest {
+        let len = (u16::from_le_bytes([*len_low, *len_high]) as usize).min(tail.len());
+        let (datagram, next) = tail.split_at(len);
+        rest = next;
+        client.push_datagram(
+            addrs[(control & 1) as usize],
+            Bytes::copy_from_slice(datagram),
+        );
+        now += Duration::from_millis((control >> 1) as u64 * 10);
+        client.set_time(now);
+        client.tick();
+    }
+});
@@ -0,0 +1,908 @@
+// kcp2k-bench: 在本机回环上运行一个服务器和 N 个客户端，测量往返延迟分位数、可靠/不可靠通道的吞吐量和重传开销，
+// 可以通过丢包/延迟模拟器转发，用于选择 Kcp2KConfig 的参数
+//
+// 用法：kcp2k-bench [选项]
+//   --clients N            客户端数量，默认 4
+//   --duration SECS        每个场景的发送时长，默认 5
+//   --sizes LIST           消息大小列表（字节，逗号分隔），默认 32,256,1024
+//   --rate N               每个客户端每秒发送的消息数，默认 100
+//   --channel CHANNEL      reliable、unreliable 或 both，默认 both
+//   --loss PCT             模拟器每个方向的丢包率（百分比），默认 0
+//   --latency MS           模拟器每个方向的单向延迟，默认 0
+//   --jitter MS            模拟器单向延迟的抖动（±），默认 0
+//   --json                 输出 JSON
+// Kcp2KConfig 参数：每个字段对应一个选项，下划线换成连字符，例如 --interval 5 --send-window-size 256 --no-delay false
+//
+// 客户端发送的消息以 8 字节发送时间开头，服务器原样回显。
+// 吞吐量按回到客户端的载荷计算；开销为所有端发送的 UDP 字节数（含 KCP 头、ACK、ping 和重传）与应用载荷字节数之比。
+// 经过模拟器时，模拟器按 KCP 段的 sn 单独统计重传的 PUSH 段；不经过模拟器时不统计重传
+use bytes::{BufMut, Bytes, BytesMut};
+use kcp::KCP_OVERHEAD;
+use kcp2k_rust::kcp2k::Kcp2K;
+use kcp2k_rust::kcp2k_callback::{Callback, CallbackType};
+use kcp2k_rust::kcp2k_channel::Kcp2KChannel;
+use kcp2k_rust::kcp2k_config::Kcp2KConfig;
+use kcp2k_rust::kcp2k_connection::Kcp2KConnection;
+use kcp2k_rust::kcp2k_packet::{Kcp2KPacket, KCP_CMD_PUSH};
+use kcp2k_rust::kcp2k_peer::Kcp2KPeer;
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::io::ErrorKind;
+use std::net::{SocketAddr, UdpSocket};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock};
+use std::thread::{sleep, JoinHandle};
+use std::time::{Duration, Instant};
+use tklog::LEVEL;
+
+// 消息头：发送时间（微秒，小端序）
+const TIMESTAMP_SIZE: usize = 8;
+// 等待所有客户端完成握手的最长时间
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+// 发送结束后继续 tick，让在途的消息到达。可靠消息全部回显后提前结束
+const DRAIN_TIME: Duration = Duration::from_secs(1);
+const RELIABLE_DRAIN_TIME: Duration = Duration::from_secs(5);
+// tick 循环的间隔
+const TICK_SLEEP: Duration = Duration::from_millis(1);
+
+// 所有时间都相对于进程启动
+static START: LazyLock<Instant> = LazyLock::new(Instant::now);
+
+#[derive(Debug, Clone)]
+struct Options {
+    clients: usize,
+    duration: Duration,
+    sizes: Vec<usize>,
+    rate: u32,
+    channels: Vec<Kcp2KChannel>,
+    loss: f64,
+    latency: Duration,
+    jitter: Duration,
+    json: bool,
+    config: Kcp2KConfig,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            clients: 4,
+            duration: Duration::from_secs(5),
+            sizes: vec![32, 256, 1024],
+            rate: 100,
+            channels: vec![Kcp2KChannel::Reliable, Kcp2KChannel::Unreliable],
+            loss: 0.0,
+            latency: Duration::ZERO,
+            jitter: Duration::ZERO,
+            json: false,
+            config: Kcp2KConfig::default(),
+        }
+    }
+}
+
+impl Options {
+    fn simulated(&self) -> bool {
+        self.loss > 0.0 || !self.latency.is_zero() || !self.jitter.is_zero()
+    }
+}
+
+// 一个场景（通道 + 消息大小）的测量状态，由回调更新
+#[derive(Debug, Default)]
+struct Measurement {
+    connected: usize,
+    disconnected: usize,
+    errors: usize,
+    received_messages: u64,
+    received_bytes: u64,
+    rtts: Vec<u64>, // 微秒
+}
+
+thread_local! {
+    static MEASUREMENT: RefCell<Measurement> = RefCell::new(Measurement::default());
+}
+
+#[derive(Debug)]
+struct Report {
+    channel: Kcp2KChannel,
+    connected: usize,
+    size: usize,
+    sent_messages: u64,
+    received_messages: u64,
+    seconds: f64,
+    received_bytes: u64,
+    rtt: Latency,
+    wire_packets: u64,
+    wire_bytes: u64,
+    payload_bytes: u64,
+    retransmits: Option<Retransmits>, // 只有经过模拟器时才统计
+    disconnected: usize,
+    errors: usize,
+}
+
+fn main() -> ExitCode {
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("kcp2k-bench: {}", err);
+            eprintln!("usage: kcp2k-bench [--clients N] [--duration SECS] [--sizes LIST] [--rate N] [--channel reliable|unreliable|both] [--loss PCT] [--latency MS] [--jitter MS] [--json] [config options]");
+            return ExitCode::from(2);
+        }
+    };
+    // 日志会打乱报告，只保留错误；JSON 输出时关闭
+    let log = tklog::LOG;
+    log.set_level(if options.json {
+        LEVEL::Off
+    } else {
+        LEVEL::Error
+    });
+    LazyLock::force(&START);
+
+    let mut reports = Vec::new();
+    for &channel in &options.channels {
+        for &size in &options.sizes {
+            if channel == Kcp2KChannel::Unreliable
+                && size > Kcp2KPeer::unreliable_max_message_size(options.config.mtu as u32)
+            {
+                eprintln!(
+                    "kcp2k-bench: skipping unreliable size {}: larger than the unreliable limit for mtu {}",
+                    size, options.config.mtu
+                );
+                continue;
+            }
+            if !options.json {
+                eprintln!("kcp2k-bench: running {:?} {} bytes", channel, size);
+            }
+            match run_scenario(&options, channel, size) {
+                Ok(report) => reports.push(report),
+                Err(err) => {
+                    eprintln!("kcp2k-bench: {:?} {} bytes: {}", channel, size, err);
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+    }
+    if options.json {
+        print_json(&options, &reports);
+    } else {
+        print_table(&options, &reports);
+    }
+    ExitCode::SUCCESS
+}
+
+fn parse_args(args: impl Iterator<Item = String>) -> Result<Options, String> {
+    fn value<T: std::str::FromStr>(name: &str, value: Option<String>) -> Result<T, String> {
+        let value = value.ok_or_else(|| format!("{} requires a value", name))?;
+        value
+            .parse()
+            .map_err(|_| format!("invalid value for {}: {}", name, value))
+    }
+    // 时长参数，unit 为一个单位对应的秒数；负数、NaN 和溢出都是无效值
+    fn duration(name: &str, arg: Option<String>, unit: f64) -> Result<Duration, String> {
+        let text: String = value(name, arg)?;
+        let amount: f64 = value(name, Some(text.clone()))?;
+        Duration::try_from_secs_f64(amount * unit)
+            .map_err(|_| format!("invalid value for {}: {}", name, text))
+    }
+    let mut options = Options::default();
+    let mut args = args.peekable();
+    while let Some(arg) = args.next() {
+        let name = arg.as_str();
+        match name {
+            "--clients" => options.clients = value(name, args.next())?,
+            "--duration" => options.duration = duration(name, args.next(), 1.0)?,
+            "--sizes" => {
+                let list: String = value(name, args.next())?;
+                options.sizes = list
+                    .split(',')
+                    .map(|size| value(name, Some(size.trim().to_string())))
+                    .collect::<Result<_, _>>()?;
+            }
+            "--rate" => options.rate = value(name, args.next())?,
+            "--channel" => {
+                options.channels = match value::<String>(name, args.next())?.as_str() {
+                    "reliable" => vec![Kcp2KChannel::Reliable],
+                    "unreliable" => vec![Kcp2KChannel::Unreliable],
+                    "both" => vec![Kcp2KChannel::Reliable, Kcp2KChannel::Unreliable],
+                    other => return Err(format!("invalid channel: {}", other)),
+                }
+            }
+            "--loss" => options.loss = value::<f64>(name, args.next())? / 100.0,
+            "--latency" => options.latency = duration(name, args.next(), 0.001)?,
+            "--jitter" => options.jitter = duration(name, args.next(), 0.001)?,
+            "--json" => options.json = true,
+            "-h" | "--help" => return Err("benchmark kcp2k over loopback".to_string()),
+            // Kcp2KConfig 字段：--send-window-size 对应 send_window_size
+            _ if Kcp2KConfig::FIELDS.contains(&config_field(name).as_str()) => {
+                let value: String = value(name, args.next())?;
+                options
+                    .config
+                    .set(&config_field(name), &value)
+                    .map_err(|err| err.to_string())?;
+            }
+            _ => return Err(format!("unknown argument: {}", arg)),
+        }
+    }
+    if options.clients == 0 || options.rate == 0 || options.sizes.is_empty() {
+        return Err("--clients, --rate and --sizes must be positive".to_string());
+    }
+    if !(0.0..1.0).contains(&options.loss) {
+        return Err("--loss must be in [0, 100)".to_string());
+    }
+    Ok(options)
+}
+
+fn config_field(arg: &str) -> String {
+    arg.trim_start_matches("--").replace('-', "_")
+}
+
+fn now_micros() -> u64 {
+    START.elapsed().as_micros() as u64
+}
+
+// 服务器原样回显
+fn s_call_back(conn: &Kcp2KConnection, cb: Callback) {
+    if let CallbackType::OnData = cb.r#type {
+        let _ = conn.send_data(cb.data, cb.channel);
+    }
+}
+
+fn c_call_back(_: &Kcp2KConnection, cb: Callback) {
+    MEASUREMENT.with(|measurement| {
+        let mut measurement = measurement.borrow_mut();
+        match cb.r#type {
+            CallbackType::OnConnected => measurement.connected += 1,
+            CallbackType::OnData => {
+                if let Some(timestamp) = cb.data.get(..TIMESTAMP_SIZE) {
+                    let sent = u64::from_le_bytes(timestamp.try_into().unwrap_or_default());
+                    measurement.rtts.push(now_micros().saturating_sub(sent));
+                    measurement.received_messages += 1;
+                    measurement.received_bytes += cb.data.len() as u64;
+                }
+            }
+            CallbackType::OnDisconnected => measurement.disconnected += 1,
+            CallbackType::OnError => measurement.errors += 1,
+            CallbackType::OnAddressChanged | CallbackType::OnRpc => {}
+        }
+    });
+}
+
+fn run_scenario(options: &Options, channel: Kcp2KChannel, size: usize) -> Result<Report, String> {
+    MEASUREMENT.with(|measurement| *measurement.borrow_mut() = Measurement::default());
+    let size = size.max(TIMESTAMP_SIZE);
+    let config = options.config;
+    let server = Kcp2K::new_server(config, "127.0.0.1:0".to_string(), s_call_back)
+        .map_err(|err| format!("failed to start server: {}", err))?;
+    let server_addr = server
+        .get_local_addr()
+        .map_err(|err| format!("failed to read server address: {}", err))?;
+    let simulator = match options.simulated() {
+        true => Some(
+            Simulator::start(server_addr, options)
+                .map_err(|err| format!("failed to start simulator: {}", err))?,
+        ),
+        false => None,
+    };
+    let target = simulator
+        .as_ref()
+        .map_or(server_addr, |simulator| simulator.addr);
+    let clients = (0..options.clients)
+        .map(|_| Kcp2K::new_client(config, target.to_string(), c_call_back))
+        .collect::<Result<Vec<Kcp2K>, _>>()
+        .map_err(|err| format!("failed to start client: {}", err))?;
+    let tick_all = || {
+        server.tick();
+        for client in &clients {
+            client.tick();
+        }
+        sleep(TICK_SLEEP);
+    };
+
+    // 等待握手完成，丢包严重时部分客户端可能连接失败，只用已连接的客户端继续测量
+    let deadline = Instant::now() + CONNECT_TIMEOUT;
+    let connected = || MEASUREMENT.with(|measurement| measurement.borrow().connected);
+    while connected() < clients.len() && Instant::now() < deadline {
+        tick_all();
+    }
+    let connected = connected();
+    if connected == 0 {
+        return Err("timed out waiting for clients to connect".to_string());
+    }
+    if connected < clients.len() {
+        eprintln!(
+            "kcp2k-bench: only {}/{} clients connected",
+            connected,
+            clients.len()
+        );
+    }
+    let before: Vec<_> = std::iter::once(&server)
+        .chain(&clients)
+        .map(|kcp2k| kcp2k.get_stats())
+        .collect();
+    let retransmits_before = simulator.as_ref().map(Simulator::retransmits);
+
+    // 按速率发送
+    let padding = vec![0u8; size - TIMESTAMP_SIZE];
+    let start = Instant::now();
+    let mut sent_messages = 0u64;
+    let mut sent_per_client = 0u64;
+    while start.elapsed() < options.duration {
+        let due = (start.elapsed().as_secs_f64() * options.rate as f64) as u64;
+        while sent_per_client < due {
+            for client in &clients {
+                let mut message = BytesMut::with_capacity(size);
+                message.put_u64_le(now_micros());
+                message.put_slice(&padding);
+                if client.c_send(message.freeze(), channel).is_ok() {
+                    sent_messages += 1;
+                }
+            }
+            sent_per_client += 1;
+        }
+        tick_all();
+    }
+    let seconds = start.elapsed().as_secs_f64();
+    let drain = match channel {
+        Kcp2KChannel::Reliable => RELIABLE_DRAIN_TIME,
+        _ => DRAIN_TIME,
+    } + 2 * (options.latency + options.jitter);
+    let drain_start = Instant::now();
+    while drain_start.elapsed() < drain
+        && MEASUREMENT.with(|measurement| measurement.borrow().received_messages) < sent_messages
+    {
+        tick_all();
+    }
+
+    let after: Vec<_> = std::iter::once(&server)
+        .chain(&clients)
+        .map(|kcp2k| kcp2k.get_stats())
+        .collect();
+    let (wire_packets, wire_bytes) =
+        before
+            .iter()
+            .zip(&after)
+            .fold((0, 0), |(packets, bytes), (before, after)| {
+                (
+                    packets + after.sent_packets - before.sent_packets,
+                    bytes + after.sent_bytes - before.sent_bytes,
+                )
+            });
+    let retransmits = simulator
+        .as_ref()
+        .map(Simulator::retransmits)
+        .zip(retransmits_before)
+        .map(|(after, before)| after - before);
+    drop(simulator);
+
+    let mut measurement =
+        MEASUREMENT.with(|measurement| std::mem::take(&mut *measurement.borrow_mut()));
+    Ok(Report {
+        channel,
+        connected,
+        size,
+        sent_messages,
+        received_messages: measurement.received_messages,
+        seconds,
+        received_bytes: measurement.received_bytes,
+        rtt: Latency::from_rtts(&mut measurement.rtts),
+        wire_packets,
+        wire_bytes,
+        // 客户端发送的载荷 + 回显到达客户端的载荷
+        payload_bytes: sent_messages * size as u64 + measurement.received_bytes,
+        retransmits,
+        disconnected: measurement.disconnected,
+        errors: measurement.errors,
+    })
+}
+
+fn print_table(options: &Options, reports: &[Report]) {
+    println!(
+        "clients={} duration={:.1}s rate={}/s per client loss={:.1}% latency={}ms jitter={}ms mtu={} interval={}ms no_delay={} fast_resend={} congestion_window={} windows={}/{}",
+        options.clients,
+        options.duration.as_secs_f64(),
+        options.rate,
+        options.loss * 100.0,
+        options.latency.as_millis(),
+        options.jitter.as_millis(),
+        options.config.mtu,
+        options.config.interval,
+        options.config.no_delay,
+        options.config.fast_resend,
+        options.config.congestion_window,
+        options.config.send_window_size,
+        options.config.receive_window_size,
+    );
+    println!(
+        "{:<10} {:>6} {:>9} {:>9} {:>8} {:>10} {:>10} {:>8} {:>8} {:>8} {:>8} {:>9} {:>8} {:>8} {:>8}",
+        "channel",
+        "size",
+        "sent",
+        "echoed",
+        "deliv%",
+        "msg/s",
+        "KiB/s",
+        "p50ms",
+        "p90ms",
+        "p99ms",
+        "maxms",
+        "overhead",
+        "pkt/msg",
+        "resent",
+        "resent%"
+    );
+    for report in reports {
+        println!(
+            "{:<10} {:>6} {:>9} {:>9} {:>8.2} {:>10.1} {:>10.1} {:>8.2} {:>8.2} {:>8.2} {:>8.2} {:>9.2} {:>8.2} {:>8} {:>8}{}",
+            format!("{:?}", report.channel).to_lowercase(),
+            report.size,
+            report.sent_messages,
+            report.received_messages,
+            report.delivery() * 100.0,
+            report.received_messages as f64 / report.seconds,
+            report.received_bytes as f64 / report.seconds / 1024.0,
+            report.rtt.p50,
+            report.rtt.p90,
+            report.rtt.p99,
+            report.rtt.max,
+            report.overhead(),
+            report.packets_per_message(),
+            report
+                .retransmits
+                .map_or("-".to_string(), |retransmits| retransmits.segments.to_string()),
+            report
+                .retransmits
+                .map_or("-".to_string(), |retransmits| format!(
+                    "{:.2}",
+                    retransmits.rate() * 100.0
+                )),
+            match (report.connected, report.disconnected, report.errors) {
+                (connected, 0, 0) if connected == options.clients => String::new(),
+                (connected, disconnected, errors) => format!(
+                    "  ({} connected, {} disconnected, {} errors)",
+                    connected, disconnected, errors
+                ),
+            }
+        );
+    }
+}
+
+fn print_json(options: &Options, reports: &[Report]) {
+    let results: Vec<String> = reports
+        .iter()
+        .map(|report| {
+            format!(
+                "{{\"channel\":\"{}\",\"size\":{},\"connected\":{},\"sent_messages\":{},\"received_messages\":{},\"delivery\":{:.4},\"messages_per_second\":{:.1},\"bytes_per_second\":{:.1},\"rtt_ms\":{{\"p50\":{:.3},\"p90\":{:.3},\"p99\":{:.3},\"max\":{:.3}}},\"wire_packets\":{},\"wire_bytes\":{},\"payload_bytes\":{},\"overhead\":{:.3},\"packets_per_message\":{:.3},\"retransmits\":{},\"disconnected\":{},\"errors\":{}}}",
+                format!("{:?}", report.channel).to_lowercase(),
+                report.size,
+                report.connected,
+                report.sent_messages,
+                report.received_messages,
+                report.delivery(),
+                report.received_messages as f64 / report.seconds,
+                report.received_bytes as f64 / report.seconds,
+                report.rtt.p50,
+                report.rtt.p90,
+                report.rtt.p99,
+                report.rtt.max,
+                report.wire_packets,
+                report.wire_bytes,
+                report.payload_bytes,
+                report.overhead(),
+                report.packets_per_message(),
+                report
+                    .retransmits
+                    .map_or("null".to_string(), |retransmits| format!(
+                        "{{\"pushed_segments\":{},\"segments\":{},\"bytes\":{},\"rate\":{:.4}}}",
+                        retransmits.pushed_segments,
+                        retransmits.segments,
+                        retransmits.bytes,
+                        retransmits.rate()
+                    )),
+                report.disconnected,
+                report.errors,
+            )
+        })
+        .collect();
+    let config = options.config;
+    println!(
+        "{{\"clients\":{},\"duration\":{:.3},\"rate\":{},\"loss\":{},\"latency_ms\":{},\"jitter_ms\":{},\"config\":{{\"mtu\":{},\"interval\":{},\"no_delay\":{},\"fast_resend\":{},\"congestion_window\":{},\"send_window_size\":{},\"receive_window_size\":{},\"idle_timeout\":{},\"ping_interval\":{},\"rtt_interval\":{},\"max_retransmits\":{}}},\"results\":[{}]}}",
+        options.clients,
+        options.duration.as_secs_f64(),
+        options.rate,
+        options.loss,
+        options.latency.as_secs_f64() * 1000.0,
+        options.jitter.as_secs_f64() * 1000.0,
+        config.mtu,
+        config.interval,
+        config.no_delay,
+        config.fast_resend,
+        config.congestion_window,
+        config.send_window_size,
+        config.receive_window_size,
+        config.idle_timeout,
+        config.ping_interval,
+        config.rtt_interval,
+        config.max_retransmits,
+        results.join(","),
+    );
+}
+
+impl Report {
+    // 回到客户端的消息比例
+    fn delivery(&self) -> f64 {
+        match self.sent_messages {
+            0 => 0.0,
+            sent => self.received_messages as f64 / sent as f64,
+        }
+    }
+    // 线上字节数 / 应用载荷字节数（客户端发送 + 服务器回显），包括 KCP 头、ACK、ping 和重传
+    fn overhead(&self) -> f64 {
+        match self.payload_bytes {
+            0 => 0.0,
+            payload => self.wire_bytes as f64 / payload as f64,
+        }
+    }
+    // 每条消息（单向）平均的 UDP 数据包数
+    fn packets_per_message(&self) -> f64 {
+        match self.sent_messages + self.received_messages {
+            0 => 0.0,
+            messages => self.wire_packets as f64 / messages as f64,
+        }
+    }
+}
+
+// RTT 的分位数，单位为毫秒
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct Latency {
+    p50: f64,
+    p90: f64,
+    p99: f64,
+    max: f64,
+}
+
+impl Latency {
+    // rtts 单位为微秒，会被排序；没有样本时全部为 0
+    fn from_rtts(rtts: &mut [u64]) -> Self {
+        rtts.sort_unstable();
+        let millis = |percent| percentile(rtts, percent).map_or(0.0, |rtt| rtt as f64 / 1000.0);
+        Self {
+            p50: millis(50),
+            p90: millis(90),
+            p99: millis(99),
+            max: millis(100),
+        }
+    }
+}
+
+// 最近秩法：已排序样本中的第 ceil(n * percent / 100) 个，没有样本时为 None
+fn percentile(sorted: &[u64], percent: usize) -> Option<u64> {
+    let rank = (sorted.len() * percent).div_ceil(100).max(1);
+    sorted.get(rank - 1).copied()
+}
+
+// 模拟器看到的 KCP PUSH 段，在丢包之前统计，即各端实际发送的段
+#[derive(Debug, Default, Clone, Copy)]
+struct Retransmits {
+    pushed_segments: u64, // 所有 PUSH 段
+    segments: u64,        // 同一方向、同一 conv 的 sn 已经出现过的 PUSH 段
+    bytes: u64,           // 重传段的字节数（KCP 头 + 数据）
+}
+
+impl std::ops::Sub for Retransmits {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        Self {
+            pushed_segments: self.pushed_segments - other.pushed_segments,
+            segments: self.segments - other.segments,
+            bytes: self.bytes - other.bytes,
+        }
+    }
+}
+
+impl Retransmits {
+    // 重传段占所有 PUSH 段的比例
+    fn rate(&self) -> f64 {
+        match self.pushed_segments {
+            0 => 0.0,
+            pushed => self.segments as f64 / pushed as f64,
+        }
+    }
+}
+
+// 模拟器线程和主线程共享的重传计数
+#[derive(Debug, Default)]
+struct RetransmitCounter {
+    pushed_segments: AtomicU64,
+    segments: AtomicU64,
+    bytes: AtomicU64,
+}
+
+// 模拟器已经转发过的 PUSH 段：(客户端, 是否发往服务器, conv, sn)
+type SeenSegments = HashSet<(SocketAddr, bool, u32, u32)>;
+
+impl RetransmitCounter {
+    fn count(&self, seen: &mut SeenSegments, client: SocketAddr, to_server: bool, data: &[u8]) {
+        let Ok(packet) = Kcp2KPacket::decode(&Bytes::copy_from_slice(data)) else {
+            return;
+        };
+        for segment in packet.segments().map_while(Result::ok) {
+            if segment.cmd != KCP_CMD_PUSH {
+                continue;
+            }
+            self.pushed_segments.fetch_add(1, Ordering::Relaxed);
+            if !seen.insert((client, to_server, segment.conv, segment.sn)) {
+                self.segments.fetch_add(1, Ordering::Relaxed);
+                self.bytes.fetch_add(
+                    (KCP_OVERHEAD + segment.data.len()) as u64,
+                    Ordering::Relaxed,
+                );
+            }
+        }
+    }
+
+    fn snapshot(&self) -> Retransmits {
+        Retransmits {
+            pushed_segments: self.pushed_segments.load(Ordering::Relaxed),
+            segments: self.segments.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// 丢包/延迟模拟器：在客户端和服务器之间转发 UDP 数据报
+struct Simulator {
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    retransmits: Arc<RetransmitCounter>,
+    thread: Option<JoinHandle<()>>,
+}
+
+// 待转发的数据报，按到期时间排序
+struct Delayed {
+    due: Instant,
+    sequence: u64,
+    to_server: Option<usize>, // Some(上游 socket 序号) 发往服务器，None 发往客户端
+    client: SocketAddr,
+    data: Vec<u8>,
+}
+
+impl PartialEq for Delayed {
+    fn eq(&self, other: &Self) -> bool {
+        (self.due, self.sequence) == (other.due, other.sequence)
+    }
+}
+
+impl Eq for Delayed {}
+
+impl PartialOrd for Delayed {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Delayed {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (self.due, self.sequence).cmp(&(other.due, other.sequence))
+    }
+}
+
+impl Simulator {
+    fn start(server: SocketAddr, options: &Options) -> std::io::Result<Self> {
+        let listen = UdpSocket::bind("127.0.0.1:0")?;
+        listen.set_nonblocking(true)?;
+        let addr = listen.local_addr()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let (loss, latency, jitter) = (options.loss, options.latency, options.jitter);
+        let retransmits = Arc::new(RetransmitCounter::default());
+        let thread_stop = Arc::clone(&stop);
+        let thread_retransmits = Arc::clone(&retransmits);
+        let thread = std::thread::spawn(move || {
+            if let Err(err) = Self::run(
+                listen,
+                server,
+                loss,
+                latency,
+                jitter,
+                &thread_retransmits,
+                &thread_stop,
+            ) {
+                eprintln!("kcp2k-bench: simulator stopped: {}", err);
+            }
+        });
+        Ok(Self {
+            addr,
+            stop,
+            retransmits,
+            thread: Some(thread),
+        })
+    }
+
+    fn retransmits(&self) -> Retransmits {
+        self.retransmits.snapshot()
+    }
+
+    fn run(
+        listen: UdpSocket,
+        server: SocketAddr,
+        loss: f64,
+        latency: Duration,
+        jitter: Duration,
+        retransmits: &RetransmitCounter,
+        stop: &AtomicBool,
+    ) -> std::io::Result<()> {
+        // 每个客户端一个上游 socket，服务器据此区分客户端
+        let mut upstreams: Vec<(SocketAddr, UdpSocket)> = Vec::new();
+        let mut client_index: HashMap<SocketAddr, usize> = HashMap::new();
+        let mut queue: BinaryHeap<Reverse<Delayed>> = BinaryHeap::new();
+        let mut sequence = 0u64;
+        let mut seen = SeenSegments::new();
+        let mut buffer = vec![0u8; 65536];
+        let mut schedule = |queue: &mut BinaryHeap<Reverse<Delayed>>,
+                            to_server: Option<usize>,
+                            client: SocketAddr,
+                            data: &[u8]| {
+            if rand::random::<f64>() < loss {
+                return;
+            }
+            let offset = jitter.as_secs_f64() * (rand::random::<f64>() * 2.0 - 1.0);
+            let delay = Duration::from_secs_f64((latency.as_secs_f64() + offset).max(0.0));
+            sequence += 1;
+            queue.push(Reverse(Delayed {
+                due: Instant::now() + delay,
+                sequence,
+                to_server,
+                client,
+                data: data.to_vec(),
+            }));
+        };
+        while !stop.load(Ordering::Relaxed) {
+            let mut idle = true;
+            loop {
+                match listen.recv_from(&mut buffer) {
+                    Ok((size, client)) => {
+                        idle = false;
+                        let index = match client_index.get(&client) {
+                            Some(&index) => index,
+                            None => {
+                                let upstream = UdpSocket::bind("127.0.0.1:0")?;
+                                upstream.set_nonblocking(true)?;
+                                upstream.connect(server)?;
+                                upstreams.push((client, upstream));
+                                client_index.insert(client, upstreams.len() - 1);
+                                upstreams.len() - 1
+                            }
+                        };
+                        retransmits.count(&mut seen, client, true, &buffer[..size]);
+                        schedule(&mut queue, Some(index), client, &buffer[..size]);
+                    }
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                    // Linux 上 ICMP 端口不可达会让下一次接收失败，忽略
+                    Err(_) => break,
+                }
+            }
+            for (client, upstream) in &upstreams {
+                while let Ok(size) = upstream.recv(&mut buffer) {
+                    idle = false;
+                    retransmits.count(&mut seen, *client, false, &buffer[..size]);
+                    schedule(&mut queue, None, *client, &buffer[..size]);
+                }
+            }
+            let now = Instant::now();
+            while queue
+                .peek()
+                .is_some_and(|Reverse(delayed)| delayed.due <= now)
+            {
+                let Some(Reverse(delayed)) = queue.pop() else {
+                    break;
+                };
+                let _ = match delayed.to_server {
+                    Some(index) => upstreams[index].1.send(&delayed.data),
+                    None => listen.send_to(&delayed.data, delayed.client),
+                };
+            }
+            if idle {
+                sleep(Duration::from_micros(100));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Simulator {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentile_of_no_samples() {
+        assert_eq!(percentile(&[], 50), None);
+        assert_eq!(percentile(&[], 100), None);
+        assert_eq!(Latency::from_rtts(&mut []), Latency::default());
+    }
+
+    #[test]
+    fn percentile_of_one_sample() {
+        for percent in [0, 1, 50, 99, 100] {
+            assert_eq!(percentile(&[1500], percent), Some(1500));
+        }
+        let latency = Latency::from_rtts(&mut [1500]);
+        assert_eq!(
+            latency,
+            Latency {
+                p50: 1.5,
+                p90: 1.5,
+                p99: 1.5,
+                max: 1.5
+            }
+        );
+    }
+
+    #[test]
+    fn p99_boundaries() {
+        // 样本 1..=n，第 k 个样本的值为 k
+        let samples = |n: u64| (1..=n).collect::<Vec<u64>>();
+        // 100 个样本时 p99 是第 99 个，不是最大值
+        assert_eq!(percentile(&samples(100), 99), Some(99));
+        assert_eq!(percentile(&samples(100), 100), Some(100));
+        // 不足 100 个样本时 p99 就是最大值
+        assert_eq!(percentile(&samples(99), 99), Some(99));
+        assert_eq!(percentile(&samples(2), 99), Some(2));
+        // 超过 100 个样本时向上取整到下一个秩
+        assert_eq!(percentile(&samples(101), 99), Some(100));
+        assert_eq!(percentile(&samples(200), 99), Some(198));
+        assert_eq!(percentile(&samples(201), 99), Some(199));
+        assert_eq!(percentile(&samples(100), 50), Some(50));
+    }
+
+    #[test]
+    fn latency_sorts_and_converts_to_millis() {
+        let mut rtts: Vec<u64> = (1..=100).rev().map(|rtt| rtt * 1000).collect();
+        let latency = Latency::from_rtts(&mut rtts);
+        assert_eq!(
+            latency,
+            Latency {
+                p50: 50.0,
+                p90: 90.0,
+                p99: 99.0,
+                max: 100.0
+            }
+        );
+    }
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn durations_are_parsed() {
+        let options = parse(&["--duration", "1.5", "--latency", "20", "--jitter", "0.5"]).unwrap();
+        assert_eq!(options.duration, Duration::from_millis(1500));
+        assert_eq!(options.latency, Duration::from_millis(20));
+        assert_eq!(options.jitter, Duration::from_micros(500));
+    }
+
+    #[test]
+    fn invalid_durations_are_rejected() {
+        for name in ["--duration", "--latency", "--jitter"] {
+            for value in ["-1", "-5", "NaN", "inf", "-inf", "1e300", "abc"] {
+                assert_eq!(
+                    parse(&[name, value]).err().unwrap(),
+                    format!("invalid value for {}: {}", name, value)
+                );
+            }
+            assert_eq!(
+                parse(&[name]).err().unwrap(),
+                format!("{} requires a value", name)
+            );
+        }
+    }
+}
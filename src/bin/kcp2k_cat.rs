@@ -0,0 +1,329 @@
+// kcp2k-cat: netcat 风格的命令行工具，用于手动测试
+//
+// 用法：
+//   kcp2k-cat [选项] -l [HOST:]PORT   运行服务器
+//   kcp2k-cat [选项] HOST:PORT        运行客户端
+// 选项：
+//   --unreliable   通过不可靠通道发送，默认使用可靠通道
+//   --verbose      输出 kcp2k 的日志
+//   --hello TEXT   客户端在 Hello 中附带的应用载荷（如登录令牌），见 kcp2k_auth
+// Kcp2KConfig 参数：每个字段对应一个选项，下划线换成连字符，例如 --interval 5 --send-window-size 256 --dual-mode true
+//
+// 标准输入的每一行作为一条消息发送（不含换行符，kcp2k 不能发送空消息，空行被跳过），收到的消息逐条写到标准输出并追加换行符；
+// 连接、断开和错误事件写到标准错误。
+// 服务器把输入发给所有已连接的客户端；客户端在标准输入结束并发送完后断开连接并退出，连接断开时也会退出
+use bytes::Bytes;
+use kcp2k_rust::kcp2k::Kcp2K;
+use kcp2k_rust::kcp2k_callback::{Callback, CallbackType};
+use kcp2k_rust::kcp2k_channel::Kcp2KChannel;
+use kcp2k_rust::kcp2k_config::Kcp2KConfig;
+use kcp2k_rust::kcp2k_connection::Kcp2KConnection;
+use std::collections::VecDeque;
+use std::io::{BufRead, Write};
+use std::net::ToSocketAddrs;
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use tklog::LEVEL;
+
+// 标准输入结束后客户端继续 tick 的时间，让可靠消息送达后再断开
+const LINGER: Duration = Duration::from_millis(500);
+
+static CONNECTED: AtomicBool = AtomicBool::new(false);
+static DISCONNECTED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug)]
+enum Mode {
+    Listen(String),
+    Connect(String),
+}
+
+#[derive(Debug)]
+struct Options {
+    mode: Mode,
+    channel: Kcp2KChannel,
+    verbose: bool,
+    hello: Bytes,
+    config: Kcp2KConfig,
+}
+
+fn main() -> ExitCode {
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("kcp2k-cat: {}", err);
+            eprintln!("usage: kcp2k-cat [--unreliable] [--verbose] [--hello TEXT] [config options] -l [HOST:]PORT | HOST:PORT");
+            eprintln!(
+                "config options: {}",
+                Kcp2KConfig::FIELDS
+                    .iter()
+                    .map(|field| format!("--{} VALUE", field.replace('_', "-")))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            );
+            return ExitCode::from(2);
+        }
+    };
+    // 日志和消息都会写到标准输出，默认关闭日志
+    let log = tklog::LOG;
+    log.set_level(if options.verbose {
+        LEVEL::Info
+    } else {
+        LEVEL::Off
+    });
+    let result = match &options.mode {
+        Mode::Listen(addr) => run_server(&options, addr),
+        Mode::Connect(addr) => run_client(&options, addr),
+    };
+    match result {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("kcp2k-cat: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn parse_args(args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut mode = None;
+    let mut channel = Kcp2KChannel::Reliable;
+    let mut verbose = false;
+    let mut hello = Bytes::new();
+    let mut config = Kcp2KConfig::default();
+    let mut args = args.peekable();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-l" | "--listen" => {
+                mode = Some(Mode::Listen(args.next().ok_or("-l requires a port")?));
+            }
+            "--unreliable" => channel = Kcp2KChannel::Unreliable,
+            "--verbose" => verbose = true,
+            "--hello" => hello = Bytes::from(args.next().ok_or("--hello requires a value")?),
+            "-h" | "--help" => return Err("netcat for kcp2k".to_string()),
+            name if name.starts_with("--") => {
+                let field = name.trim_start_matches("--").replace('-', "_");
+                let value = args
+                    .next()
+                    .ok_or_else(|| format!("{} requires a value", name))?;
+                config.set(&field, &value).map_err(|err| err.to_string())?;
+            }
+            _ if mode.is_none() => mode = Some(Mode::Connect(arg)),
+            _ => return Err(format!("unexpected argument: {}", arg)),
+        }
+    }
+    Ok(Options {
+        mode: mode.ok_or("missing -l PORT or HOST:PORT")?,
+        channel,
+        verbose,
+        hello,
+        config,
+    })
+}
+
+// 在后台线程中逐行读取标准输入，None 表示输入结束
+fn read_stdin() -> Receiver<Option<String>> {
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            match line {
+                Ok(line) => {
+                    if sender.send(Some(line)).is_err() {
+                        return;
+                    }
+                }
+                Err(err) => {
+                    eprintln!("kcp2k-cat: failed to read stdin: {}", err);
+                    break;
+                }
+            }
+        }
+        let _ = sender.send(None);
+    });
+    receiver
+}
+
+// 取出已读取的行，返回标准输入是否已结束
+fn drain_stdin(stdin: &Receiver<Option<String>>, pending: &mut VecDeque<Bytes>) -> bool {
+    loop {
+        match stdin.try_recv() {
+            Ok(Some(line)) if line.is_empty() => {}
+            Ok(Some(line)) => pending.push_back(Bytes::from(line)),
+            Ok(None) | Err(TryRecvError::Disconnected) => return true,
+            Err(TryRecvError::Empty) => return false,
+        }
+    }
+}
+
+fn write_data(data: &[u8]) {
+    let mut stdout = std::io::stdout().lock();
+    let _ = stdout.write_all(data);
+    let _ = stdout.write_all(b"\n");
+    let _ = stdout.flush();
+}
+
+fn s_call_back(conn: &Kcp2KConnection, cb: Callback) {
+    let addr = conn
+        .get_sock_addr()
+        .as_socket()
+        .map(|addr| addr.to_string())
+        .unwrap_or_default();
+    match cb.r#type {
+        CallbackType::OnConnected => {
+            eprintln!("connected: {} from {}{}", cb.conn_id, addr, protocol(conn))
+        }
+        CallbackType::OnData => write_data(&cb.data),
+        CallbackType::OnDisconnected => eprintln!(
+            "disconnected: {} ({}){}",
+            cb.conn_id,
+            addr,
+            disconnect_reason(&cb)
+        ),
+        CallbackType::OnAddressChanged => {
+            eprintln!("address changed: {} now at {}", cb.conn_id, addr)
+        }
+        // 没有开启 RPC，不会收到 RPC 帧
+        CallbackType::OnRpc => {}
+        CallbackType::OnError => eprintln!(
+            "error: {} {:?} {}",
+            cb.conn_id, cb.error_code, cb.error_message
+        ),
+    }
+}
+
+fn c_call_back(conn: &Kcp2KConnection, cb: Callback) {
+    match cb.r#type {
+        CallbackType::OnConnected => {
+            CONNECTED.store(true, Ordering::SeqCst);
+            eprintln!(
+                "connected to {}{}",
+                conn.get_sock_addr()
+                    .as_socket()
+                    .map(|addr| addr.to_string())
+                    .unwrap_or_default(),
+                protocol(conn)
+            );
+        }
+        CallbackType::OnData => write_data(&cb.data),
+        CallbackType::OnDisconnected => {
+            DISCONNECTED.store(true, Ordering::SeqCst);
+            eprintln!("disconnected{}", disconnect_reason(&cb));
+        }
+        CallbackType::OnAddressChanged => eprintln!("address changed"),
+        CallbackType::OnRpc => {}
+        CallbackType::OnError => eprintln!("error: {:?} {}", cb.error_code, cb.error_message),
+    }
+}
+
+// 断开的原因和说明，例如 ": Kicked server full"
+fn disconnect_reason(cb: &Callback) -> String {
+    match cb.disconnect_message.is_empty() {
+        true => format!(": {:?}", cb.disconnect_reason),
+        false => format!(": {:?} {}", cb.disconnect_reason, cb.disconnect_message),
+    }
+}
+
+// 协商的协议版本和功能
+fn protocol(conn: &Kcp2KConnection) -> String {
+    match conn.get_protocol() {
+        Some(protocol) => format!(
+            " (protocol v{}, capabilities {:#x})",
+            protocol.version,
+            protocol.capabilities.bits()
+        ),
+        None => String::new(),
+    }
+}
+
+fn tick_sleep(config: &Kcp2KConfig) -> Duration {
+    Duration::from_millis(config.interval.max(1) as u64)
+}
+
+fn run_server(options: &Options, addr: &str) -> Result<ExitCode, String> {
+    // 只给出端口时监听所有地址
+    let addr = match (addr.parse::<u16>(), options.config.dual_mode) {
+        (Ok(port), false) => format!("0.0.0.0:{}", port),
+        (Ok(port), true) => format!("[::]:{}", port),
+        (Err(_), _) => addr.to_string(),
+    };
+    let server = Kcp2K::new_server(options.config, resolve(&addr)?, s_call_back)
+        .map_err(|err| format!("failed to listen on {}: {}", addr, err))?;
+    if let Ok(local_addr) = server.get_local_addr() {
+        eprintln!("listening on {}", local_addr);
+    }
+    let stdin = read_stdin();
+    let mut pending = VecDeque::new();
+    loop {
+        drain_stdin(&stdin, &mut pending);
+        // 没有已连接的客户端时保留输入，发送失败的行不会再成功，丢弃
+        while let Some(data) = pending.front() {
+            match server.broadcast(None, data.clone(), options.channel, &[]) {
+                Ok(0) => break,
+                Ok(_) => {
+                    pending.pop_front();
+                }
+                Err(err) => {
+                    eprintln!("kcp2k-cat: send failed: {:?}", err);
+                    pending.pop_front();
+                }
+            }
+        }
+        server.tick();
+        sleep(tick_sleep(&options.config));
+    }
+}
+
+fn run_client(options: &Options, addr: &str) -> Result<ExitCode, String> {
+    let client = Kcp2K::new_client_with_hello(
+        options.config,
+        resolve(addr)?,
+        options.hello.clone(),
+        c_call_back,
+    )
+    .map_err(|err| format!("failed to connect to {}: {}", addr, err))?;
+    let stdin = read_stdin();
+    let mut pending = VecDeque::new();
+    let mut eof_at: Option<Instant> = None;
+    loop {
+        if eof_at.is_none() && drain_stdin(&stdin, &mut pending) && pending.is_empty() {
+            eof_at = Some(Instant::now());
+        }
+        if eof_at.is_none() && CONNECTED.load(Ordering::SeqCst) {
+            while let Some(data) = pending.pop_front() {
+                if let Err(err) = client.c_send(data, options.channel) {
+                    eprintln!("kcp2k-cat: send failed: {:?}", err);
+                }
+            }
+        }
+        client.tick();
+        if DISCONNECTED.load(Ordering::SeqCst) {
+            return Ok(ExitCode::SUCCESS);
+        }
+        // 输入已结束且已发送完：等待送达后断开
+        if eof_at.is_some_and(|eof_at| eof_at.elapsed() > LINGER)
+            && CONNECTED.load(Ordering::SeqCst)
+        {
+            let ids: Vec<u64> = client
+                .get_connections()
+                .iter()
+                .map(|conn| *conn.key())
+                .collect();
+            for id in ids {
+                client.close_connection(id);
+            }
+            client.tick();
+            return Ok(ExitCode::SUCCESS);
+        }
+        sleep(tick_sleep(&options.config));
+    }
+}
+
+// Kcp2K 只接受 IP:PORT，先解析主机名
+fn resolve(addr: &str) -> Result<String, String> {
+    addr.to_socket_addrs()
+        .map_err(|err| format!("failed to resolve {}: {}", addr, err))?
+        .next()
+        .map(|addr| addr.to_string())
+        .ok_or_else(|| format!("failed to resolve {}", addr))
+}
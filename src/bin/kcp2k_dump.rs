@@ -0,0 +1,832 @@
+// kcp2k-dump: 把抓包文件（pcap/pcapng）或十六进制文本中的数据报解码为 kcp2k 协议，按连接输出时间线
+//
+// 用法：kcp2k-dump [--hex] [--port PORT] [--full] [FILE|-]
+//   FILE        pcap、pcapng 或十六进制文本文件，省略或为 - 时读取标准输入
+//   --hex       强制按十六进制文本解析（默认根据文件头自动识别）
+//   --port      只解码源端口或目的端口为 PORT 的 UDP 数据报
+//   --full      输出完整的载荷，默认只输出前 16 字节
+//
+// 十六进制文本：每行一个数据报，字节之间可以有空格、冒号或 0x 前缀，# 开头的行被忽略
+use bytes::Bytes;
+use kcp2k_rust::kcp2k_disconnect_reason::DisconnectReason;
+use kcp2k_rust::kcp2k_header::{Kcp2KHeaderReliable, Kcp2KHeaderUnreliable};
+use kcp2k_rust::kcp2k_packet::{
+    Kcp2KDecodeError, Kcp2KPacket, KcpSegment, KCP_CMD_ACK, KCP_CMD_PUSH, KCP_CMD_WASK,
+    KCP_CMD_WINS,
+};
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::{BufWriter, ErrorKind, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::process::ExitCode;
+
+// 默认输出的载荷字节数
+const PAYLOAD_PREVIEW: usize = 16;
+
+const LINKTYPE_NULL: u32 = 0;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LOOP: u32 = 108;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_IPV4: u32 = 228;
+const LINKTYPE_IPV6: u32 = 229;
+
+#[derive(Debug, Default)]
+struct Options {
+    path: Option<String>,
+    hex: bool,
+    port: Option<u16>,
+    full: bool,
+}
+
+// 一个待解码的数据报，十六进制输入没有时间和地址
+#[derive(Debug)]
+struct Datagram {
+    time: Option<f64>,
+    endpoints: Option<(SocketAddr, SocketAddr)>,
+    data: Vec<u8>,
+}
+
+// 一个连接（地址对）的时间线
+#[derive(Debug, Default)]
+struct Timeline {
+    datagrams: Vec<Datagram>,
+    cookies: Vec<[u8; 4]>,
+}
+
+fn main() -> ExitCode {
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("kcp2k-dump: {}", err);
+            eprintln!("usage: kcp2k-dump [--hex] [--port PORT] [--full] [FILE|-]");
+            return ExitCode::from(2);
+        }
+    };
+    let input = match read_input(options.path.as_deref()) {
+        Ok(input) => input,
+        Err(err) => {
+            eprintln!("kcp2k-dump: {}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+    let datagrams = if options.hex {
+        parse_hex(&input)
+    } else {
+        match read_u32_le(&input, 0) {
+            Some(0xa1b2_c3d4 | 0xd4c3_b2a1 | 0xa1b2_3c4d | 0x4d3c_b2a1) => parse_pcap(&input),
+            Some(0x0a0d_0d0a) => parse_pcapng(&input),
+            _ => parse_hex(&input),
+        }
+    };
+    let datagrams = match datagrams {
+        Ok(datagrams) => datagrams,
+        Err(err) => {
+            eprintln!("kcp2k-dump: {}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+    let datagrams =
+        datagrams
+            .into_iter()
+            .filter(|datagram| match (options.port, datagram.endpoints) {
+                (Some(port), Some((src, dst))) => src.port() == port || dst.port() == port,
+                _ => true,
+            });
+    let mut out = BufWriter::new(std::io::stdout().lock());
+    match print_timelines(&mut out, datagrams, &options).and_then(|_| out.flush()) {
+        // 输出被关闭（例如通过管道交给 head）时正常退出
+        Ok(_) => ExitCode::SUCCESS,
+        Err(err) if err.kind() == ErrorKind::BrokenPipe => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("kcp2k-dump: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn parse_args(args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options::default();
+    let mut args = args.peekable();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--hex" => options.hex = true,
+            "--full" => options.full = true,
+            "--port" => {
+                let port = args.next().ok_or("--port requires a value")?;
+                options.port = Some(
+                    port.parse()
+                        .map_err(|_| format!("invalid port: {}", port))?,
+                );
+            }
+            "-h" | "--help" => return Err("decode kcp2k datagrams".to_string()),
+            _ if arg.starts_with("--") => return Err(format!("unknown option: {}", arg)),
+            _ if options.path.is_none() => options.path = Some(arg),
+            _ => return Err(format!("unexpected argument: {}", arg)),
+        }
+    }
+    Ok(options)
+}
+
+fn read_input(path: Option<&str>) -> std::io::Result<Vec<u8>> {
+    match path {
+        None | Some("-") => {
+            let mut input = Vec::new();
+            std::io::stdin().read_to_end(&mut input)?;
+            Ok(input)
+        }
+        Some(path) => std::fs::read(path),
+    }
+}
+
+fn read_u16_be(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(
+        data.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn read_u16_le(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        data.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn read_u32_le(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn read_u32(data: &[u8], offset: usize, big_endian: bool) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?.try_into().ok()?;
+    Some(match big_endian {
+        true => u32::from_be_bytes(bytes),
+        false => u32::from_le_bytes(bytes),
+    })
+}
+
+fn parse_hex(input: &[u8]) -> Result<Vec<Datagram>, String> {
+    let text =
+        std::str::from_utf8(input).map_err(|_| "input is neither pcap nor hex text".to_string())?;
+    let mut datagrams = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut digits = String::new();
+        for token in line.split(|c: char| c.is_whitespace() || c == ':' || c == ',') {
+            digits.push_str(token.strip_prefix("0x").unwrap_or(token));
+        }
+        // 先检查全部是 ASCII 十六进制数字，之后才能按两个字节一组解析
+        if !digits.bytes().all(|digit| digit.is_ascii_hexdigit()) {
+            return Err(format!("line {}: invalid hex", number + 1));
+        }
+        if !digits.len().is_multiple_of(2) {
+            return Err(format!("line {}: odd number of hex digits", number + 1));
+        }
+        let data = digits
+            .as_bytes()
+            .chunks(2)
+            .map(|pair| (hex_value(pair[0]) << 4) | hex_value(pair[1]))
+            .collect();
+        datagrams.push(Datagram {
+            time: None,
+            endpoints: None,
+            data,
+        });
+    }
+    Ok(datagrams)
+}
+
+// 一个 ASCII 十六进制数字的值，调用前已检查
+fn hex_value(digit: u8) -> u8 {
+    match digit {
+        b'0'..=b'9' => digit - b'0',
+        b'a'..=b'f' => digit - b'a' + 10,
+        _ => digit - b'A' + 10,
+    }
+}
+
+fn parse_pcap(input: &[u8]) -> Result<Vec<Datagram>, String> {
+    let magic = read_u32_le(input, 0).ok_or("truncated pcap header")?;
+    let big_endian = matches!(magic, 0xd4c3_b2a1 | 0x4d3c_b2a1);
+    let nanos = matches!(magic, 0xa1b2_3c4d | 0x4d3c_b2a1);
+    let link_type = read_u32(input, 20, big_endian).ok_or("truncated pcap header")? & 0x0fff_ffff;
+    let mut datagrams = Vec::new();
+    let mut offset = 24;
+    while offset < input.len() {
+        let (Some(seconds), Some(fraction), Some(captured)) = (
+            read_u32(input, offset, big_endian),
+            read_u32(input, offset + 4, big_endian),
+            read_u32(input, offset + 8, big_endian),
+        ) else {
+            return Err(format!("truncated pcap record at offset {}", offset));
+        };
+        let start = offset + 16;
+        let frame = input
+            .get(start..start + captured as usize)
+            .ok_or_else(|| format!("truncated pcap record at offset {}", offset))?;
+        let time = seconds as f64 + fraction as f64 / if nanos { 1e9 } else { 1e6 };
+        if let Some(datagram) = parse_frame(link_type, frame, time) {
+            datagrams.push(datagram);
+        }
+        offset = start + captured as usize;
+    }
+    Ok(datagrams)
+}
+
+fn parse_pcapng(input: &[u8]) -> Result<Vec<Datagram>, String> {
+    // 每个接口的链路类型和时间精度（每秒的单位数）
+    let mut interfaces: Vec<(u32, f64)> = Vec::new();
+    let mut big_endian = false;
+    let mut datagrams = Vec::new();
+    let mut offset = 0;
+    while offset < input.len() {
+        let block_type = read_u32_le(input, offset).ok_or("truncated pcapng block")?;
+        if block_type == 0x0a0d_0d0a {
+            // SHB：字节序由 byte-order magic 决定
+            big_endian = read_u32_le(input, offset + 8) == Some(0x4d3c_2b1a);
+            interfaces.clear();
+        }
+        let length =
+            read_u32(input, offset + 4, big_endian).ok_or("truncated pcapng block")? as usize;
+        if length < 12 || !length.is_multiple_of(4) {
+            return Err(format!(
+                "invalid pcapng block length {} at offset {}",
+                length, offset
+            ));
+        }
+        let block = input
+            .get(offset..offset + length)
+            .ok_or_else(|| format!("truncated pcapng block at offset {}", offset))?;
+        let body = &block[8..length - 4];
+        match read_u32(block, 0, big_endian) {
+            // IDB
+            Some(1) => {
+                let link_type = read_u16(body, 0, big_endian).unwrap_or(0) as u32;
+                let resolution = parse_tsresol(body.get(8..).unwrap_or_default(), big_endian);
+                interfaces.push((link_type, resolution));
+            }
+            // EPB
+            Some(6) => {
+                let (Some(interface), Some(high), Some(low), Some(captured)) = (
+                    read_u32(body, 0, big_endian),
+                    read_u32(body, 4, big_endian),
+                    read_u32(body, 8, big_endian),
+                    read_u32(body, 12, big_endian),
+                ) else {
+                    return Err(format!(
+                        "truncated enhanced packet block at offset {}",
+                        offset
+                    ));
+                };
+                let (link_type, resolution) = interfaces
+                    .get(interface as usize)
+                    .copied()
+                    .ok_or_else(|| format!("packet for unknown interface {}", interface))?;
+                let frame = body.get(20..20 + captured as usize).ok_or_else(|| {
+                    format!("truncated enhanced packet block at offset {}", offset)
+                })?;
+                let time = (((high as u64) << 32) | low as u64) as f64 / resolution;
+                if let Some(datagram) = parse_frame(link_type, frame, time) {
+                    datagrams.push(datagram);
+                }
+            }
+            _ => {}
+        }
+        offset += length;
+    }
+    Ok(datagrams)
+}
+
+fn read_u16(data: &[u8], offset: usize, big_endian: bool) -> Option<u16> {
+    match big_endian {
+        true => read_u16_be(data, offset),
+        false => read_u16_le(data, offset),
+    }
+}
+
+// IDB 的 if_tsresol 选项，默认是微秒
+fn parse_tsresol(mut options: &[u8], big_endian: bool) -> f64 {
+    while let (Some(code), Some(length)) = (
+        read_u16(options, 0, big_endian),
+        read_u16(options, 2, big_endian),
+    ) {
+        let length = length as usize;
+        if code == 0 {
+            break;
+        }
+        if code == 9 && length == 1 {
+            if let Some(&value) = options.get(4) {
+                return match value & 0x80 {
+                    0 => 10f64.powi((value & 0x7f) as i32),
+                    _ => 2f64.powi((value & 0x7f) as i32),
+                };
+            }
+        }
+        let padded = 4 + length.div_ceil(4) * 4;
+        options = options.get(padded..).unwrap_or_default();
+    }
+    1e6
+}
+
+// 从链路层帧中取出 UDP 数据报，不是 UDP 时返回 None
+fn parse_frame(link_type: u32, frame: &[u8], time: f64) -> Option<Datagram> {
+    let packet = match link_type {
+        LINKTYPE_RAW | LINKTYPE_IPV4 | LINKTYPE_IPV6 => frame,
+        LINKTYPE_NULL | LINKTYPE_LOOP => frame.get(4..)?,
+        LINKTYPE_LINUX_SLL => frame.get(16..)?,
+        LINKTYPE_ETHERNET => {
+            let mut offset = 12;
+            let mut ether_type = read_u16_be(frame, offset)?;
+            // 跳过 VLAN 标签
+            while ether_type == 0x8100 || ether_type == 0x88a8 {
+                offset += 4;
+                ether_type = read_u16_be(frame, offset)?;
+            }
+            frame.get(offset + 2..)?
+        }
+        _ => return None,
+    };
+    let (src, dst, protocol, udp) = match packet.first()? >> 4 {
+        4 => {
+            let header_length = ((packet[0] & 0x0f) as usize) * 4;
+            let src = Ipv4Addr::from(<[u8; 4]>::try_from(packet.get(12..16)?).ok()?);
+            let dst = Ipv4Addr::from(<[u8; 4]>::try_from(packet.get(16..20)?).ok()?);
+            (
+                IpAddr::V4(src),
+                IpAddr::V4(dst),
+                *packet.get(9)?,
+                packet.get(header_length..)?,
+            )
+        }
+        6 => {
+            let src = Ipv6Addr::from(<[u8; 16]>::try_from(packet.get(8..24)?).ok()?);
+            let dst = Ipv6Addr::from(<[u8; 16]>::try_from(packet.get(24..40)?).ok()?);
+            (
+                IpAddr::V6(src),
+                IpAddr::V6(dst),
+                *packet.get(6)?,
+                packet.get(40..)?,
+            )
+        }
+        _ => return None,
+    };
+    // 只处理 UDP，不处理 IPv6 扩展头
+    if protocol != 17 {
+        return None;
+    }
+    let src_port = read_u16_be(udp, 0)?;
+    let dst_port = read_u16_be(udp, 2)?;
+    // 被截断的数据报（UDP 头不完整或长度字段超过实际长度）只取实际捕获的部分
+    let length = (read_u16_be(udp, 4)? as usize).min(udp.len());
+    Some(Datagram {
+        time: Some(time),
+        endpoints: Some((
+            SocketAddr::new(src, src_port),
+            SocketAddr::new(dst, dst_port),
+        )),
+        data: udp.get(8..length)?.to_vec(),
+    })
+}
+
+// 按地址对分组并输出每个连接的时间线
+fn print_timelines(
+    out: &mut impl Write,
+    datagrams: impl Iterator<Item = Datagram>,
+    options: &Options,
+) -> std::io::Result<()> {
+    let mut order: Vec<Option<(SocketAddr, SocketAddr)>> = Vec::new();
+    let mut timelines: HashMap<Option<(SocketAddr, SocketAddr)>, Timeline> = HashMap::new();
+    for datagram in datagrams {
+        // 两个方向属于同一个连接
+        let key = datagram.endpoints.map(|(src, dst)| match src <= dst {
+            true => (src, dst),
+            false => (dst, src),
+        });
+        let timeline = timelines.entry(key).or_insert_with(|| {
+            order.push(key);
+            Timeline::default()
+        });
+        if let Some(cookie) = datagram
+            .data
+            .get(1..5)
+            .and_then(|cookie| <[u8; 4]>::try_from(cookie).ok())
+        {
+            if !timeline.cookies.contains(&cookie) {
+                timeline.cookies.push(cookie);
+            }
+        }
+        timeline.datagrams.push(datagram);
+    }
+    for (index, key) in order.iter().enumerate() {
+        let Some(timeline) = timelines.get(key) else {
+            continue;
+        };
+        let cookies: Vec<String> = timeline.cookies.iter().map(|cookie| hex(cookie)).collect();
+        match key {
+            Some((a, b)) => writeln!(
+                out,
+                "connection {}: {} <-> {}, {} datagrams, cookies [{}]",
+                index + 1,
+                a,
+                b,
+                timeline.datagrams.len(),
+                cookies.join(", ")
+            ),
+            None => writeln!(
+                out,
+                "hex input: {} datagrams, cookies [{}]",
+                timeline.datagrams.len(),
+                cookies.join(", ")
+            ),
+        }?;
+        let start = timeline.datagrams.iter().find_map(|datagram| datagram.time);
+        // 每个方向上各序号的 frg，用于判断 PUSH 段是否是消息的第一个分片
+        let mut fragments: HashMap<Option<SocketAddr>, HashMap<u32, u8>> = HashMap::new();
+        for (number, datagram) in timeline.datagrams.iter().enumerate() {
+            let mut line = String::new();
+            match (datagram.time, start) {
+                (Some(time), Some(start)) => {
+                    let _ = write!(line, "  +{:>10.6}", time - start);
+                }
+                _ => {
+                    let _ = write!(line, "  #{:<5}", number + 1);
+                }
+            }
+            if let Some((src, dst)) = datagram.endpoints {
+                let _ = write!(line, "  {} -> {}", src, dst);
+            }
+            let direction = fragments
+                .entry(datagram.endpoints.map(|(src, _)| src))
+                .or_default();
+            writeln!(
+                out,
+                "{}  {}",
+                line,
+                decode(&datagram.data, direction, options.full)
+            )?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+// 解码一个 kcp2k 数据报
+fn decode(data: &[u8], fragments: &mut HashMap<u32, u8>, full: bool) -> String {
+    let packet = match Kcp2KPacket::decode(&Bytes::copy_from_slice(data)) {
+        Ok(packet) => packet,
+        // 未知的不可靠头部仍然输出 cookie 和载荷
+        Err(Kcp2KDecodeError::InvalidHeader(header)) => {
+            return format!(
+                "unreliable cookie={} unknown header {}{}",
+                hex(&data[1..5]),
+                header,
+                payload(&data[6..], full)
+            );
+        }
+        Err(Kcp2KDecodeError::InvalidChannel(channel)) => {
+            return format!("unknown channel {} ({} bytes)", channel, data.len());
+        }
+        Err(err) => return format!("malformed: {}", err),
+    };
+    let mut out = String::new();
+    match &packet {
+        Kcp2KPacket::Reliable { cookie, .. } => {
+            let _ = write!(out, "reliable cookie={}", hex(cookie));
+            for segment in packet.segments() {
+                match segment {
+                    Ok(segment) => {
+                        let _ =
+                            write!(out, "\n      {}", decode_segment(&segment, fragments, full));
+                    }
+                    Err(err) => {
+                        let _ = write!(out, "\n      malformed: {}", err);
+                    }
+                }
+            }
+        }
+        Kcp2KPacket::Unreliable {
+            cookie,
+            header,
+            payload: data,
+        } => {
+            let _ = write!(out, "unreliable cookie={} {:?}", hex(cookie), header);
+            match header {
+                // 断开消息的载荷是原因和说明
+                Kcp2KHeaderUnreliable::Disconnect if !data.is_empty() => {
+                    let (reason, message) = DisconnectReason::decode(data);
+                    let _ = write!(out, " reason={:?} message={:?}", reason, message);
+                }
+                _ => out.push_str(&payload(data, full)),
+            }
+        }
+    }
+    out
+}
+
+// 描述一个 KCP 段
+fn decode_segment(segment: &KcpSegment, fragments: &mut HashMap<u32, u8>, full: bool) -> String {
+    let cmd_name = match segment.cmd {
+        KCP_CMD_PUSH => "PUSH",
+        KCP_CMD_ACK => "ACK",
+        KCP_CMD_WASK => "WASK",
+        KCP_CMD_WINS => "WINS",
+        _ => "UNKNOWN",
+    };
+    let mut out = format!(
+        "conv={} cmd={}({}) frg={} wnd={} ts={} sn={} una={} len={}",
+        segment.conv,
+        cmd_name,
+        segment.cmd,
+        segment.frg,
+        segment.wnd,
+        segment.ts,
+        segment.sn,
+        segment.una,
+        segment.data.len()
+    );
+    if segment.cmd == KCP_CMD_PUSH {
+        // 只有消息的第一个分片以 Kcp2KHeaderReliable 开头；前一个序号未知时按第一个分片处理
+        let first = segment.sn == 0
+            || fragments
+                .get(&segment.sn.wrapping_sub(1))
+                .is_none_or(|&frg| frg == 0);
+        fragments.insert(segment.sn, segment.frg);
+        match (first, segment.data.first()) {
+            (true, Some(&header)) => {
+                match Kcp2KHeaderReliable::parse(header) {
+                    Some(header) => {
+                        let _ = write!(out, " {:?}", header);
+                    }
+                    None => {
+                        let _ = write!(out, " unknown header {}", header);
+                    }
+                }
+                out.push_str(&payload(&segment.data[1..], full));
+            }
+            (false, Some(_)) => {
+                out.push_str(" (fragment)");
+                out.push_str(&payload(segment.data, full));
+            }
+            (_, None) => out.push_str(" malformed: empty push segment"),
+        }
+    }
+    out
+}
+
+fn payload(data: &[u8], full: bool) -> String {
+    if data.is_empty() {
+        return String::new();
+    }
+    let shown = if full {
+        data.len()
+    } else {
+        data.len().min(PAYLOAD_PREVIEW)
+    };
+    let ellipsis = if shown < data.len() { " .." } else { "" };
+    format!(
+        " payload[{}]={}{}",
+        data.len(),
+        hex(&data[..shown]),
+        ellipsis
+    )
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter()
+        .fold(String::with_capacity(data.len() * 2), |mut out, byte| {
+            let _ = write!(out, "{:02x}", byte);
+            out
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+    use kcp2k_rust::kcp2k_channel::Kcp2KChannel;
+
+    const SRC: &str = "10.0.0.1:7777";
+    const DST: &str = "10.0.0.2:50000";
+
+    // 一个 IPv4/UDP 帧（LINKTYPE_RAW）
+    fn ipv4_udp(payload: &[u8]) -> Vec<u8> {
+        let (src, dst): (SocketAddr, SocketAddr) = (SRC.parse().unwrap(), DST.parse().unwrap());
+        let mut frame = vec![0x45, 0, 0, 0, 0, 0, 0, 0, 64, 17, 0, 0];
+        for addr in [src, dst] {
+            match addr.ip() {
+                IpAddr::V4(ip) => frame.extend_from_slice(&ip.octets()),
+                IpAddr::V6(_) => unreachable!(),
+            }
+        }
+        frame.extend_from_slice(&src.port().to_be_bytes());
+        frame.extend_from_slice(&dst.port().to_be_bytes());
+        frame.extend_from_slice(&(8 + payload.len() as u16).to_be_bytes());
+        frame.extend_from_slice(&[0, 0]);
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    fn pcap(frames: &[&[u8]]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for value in [0xa1b2_c3d4u32, 0x0004_0002, 0, 0, 65535, LINKTYPE_RAW] {
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+        for (i, frame) in frames.iter().enumerate() {
+            for value in [i as u32, 500_000, frame.len() as u32, frame.len() as u32] {
+                out.extend_from_slice(&value.to_le_bytes());
+            }
+            out.extend_from_slice(frame);
+        }
+        out
+    }
+
+    fn pcapng_block(block_type: u32, body: &[u8]) -> Vec<u8> {
+        let length = 12 + body.len().div_ceil(4) * 4;
+        let mut block = Vec::new();
+        block.extend_from_slice(&block_type.to_le_bytes());
+        block.extend_from_slice(&(length as u32).to_le_bytes());
+        block.extend_from_slice(body);
+        block.resize(length - 4, 0);
+        block.extend_from_slice(&(length as u32).to_le_bytes());
+        block
+    }
+
+    fn pcapng(frames: &[&[u8]]) -> Vec<u8> {
+        let mut shb = Vec::new();
+        shb.extend_from_slice(&0x1a2b_3c4du32.to_le_bytes());
+        shb.extend_from_slice(&[1, 0, 0, 0]);
+        shb.extend_from_slice(&u64::MAX.to_le_bytes());
+        let mut out = pcapng_block(0x0a0d_0d0a, &shb);
+        let mut idb = Vec::new();
+        idb.extend_from_slice(&(LINKTYPE_RAW as u16).to_le_bytes());
+        idb.extend_from_slice(&[0, 0]);
+        idb.extend_from_slice(&65535u32.to_le_bytes());
+        out.extend(pcapng_block(1, &idb));
+        for (i, frame) in frames.iter().enumerate() {
+            // 时间戳单位默认是微秒
+            let time = (i as u64 + 1) * 1_500_000;
+            let mut epb = Vec::new();
+            for value in [
+                0,
+                (time >> 32) as u32,
+                time as u32,
+                frame.len() as u32,
+                frame.len() as u32,
+            ] {
+                epb.extend_from_slice(&value.to_le_bytes());
+            }
+            epb.extend_from_slice(frame);
+            out.extend(pcapng_block(6, &epb));
+        }
+        out
+    }
+
+    fn endpoints() -> Option<(SocketAddr, SocketAddr)> {
+        Some((SRC.parse().unwrap(), DST.parse().unwrap()))
+    }
+
+    #[test]
+    fn pcap_records_are_decoded() {
+        let input = pcap(&[&ipv4_udp(b"first"), &ipv4_udp(b"second")]);
+        let datagrams = parse_pcap(&input).unwrap();
+        assert_eq!(datagrams.len(), 2);
+        assert_eq!(datagrams[0].data, b"first");
+        assert_eq!(datagrams[1].data, b"second");
+        assert_eq!(datagrams[1].time, Some(1.5));
+        assert_eq!(datagrams[0].endpoints, endpoints());
+    }
+
+    #[test]
+    fn pcap_truncated_record_is_an_error() {
+        let input = pcap(&[&ipv4_udp(b"first")]);
+        assert!(parse_pcap(&input[..input.len() - 1]).is_err());
+        assert!(parse_pcap(&input[..30]).is_err());
+        assert!(parse_pcap(&input[..10]).is_err());
+    }
+
+    #[test]
+    fn truncated_udp_header_is_skipped() {
+        // 截断到只剩 6 或 7 字节 UDP 头的帧不是错误，只是没有数据报
+        let frame = ipv4_udp(b"payload");
+        for udp_len in 0..8 {
+            let input = pcap(&[&frame[..20 + udp_len]]);
+            assert!(
+                parse_pcap(&input).unwrap().is_empty(),
+                "udp_len {}",
+                udp_len
+            );
+        }
+        // 长度字段超过捕获长度（snaplen 截断）时取实际捕获的部分
+        let input = pcap(&[&frame[..frame.len() - 3]]);
+        assert_eq!(parse_pcap(&input).unwrap()[0].data, b"payl");
+    }
+
+    #[test]
+    fn pcapng_blocks_are_decoded() {
+        let input = pcapng(&[&ipv4_udp(b"first"), &ipv4_udp(b"second!")]);
+        let datagrams = parse_pcapng(&input).unwrap();
+        assert_eq!(datagrams.len(), 2);
+        assert_eq!(datagrams[0].data, b"first");
+        assert_eq!(datagrams[0].time, Some(1.5));
+        assert_eq!(datagrams[1].data, b"second!");
+        assert_eq!(datagrams[1].endpoints, endpoints());
+    }
+
+    #[test]
+    fn pcapng_truncated_block_is_an_error() {
+        let input = pcapng(&[&ipv4_udp(b"first")]);
+        assert!(parse_pcapng(&input[..input.len() - 4]).is_err());
+        // 块长度不是 4 的倍数
+        let mut invalid = input.clone();
+        invalid[4] = 13;
+        assert!(parse_pcapng(&invalid).is_err());
+        // 截断的 UDP 头
+        let frame = ipv4_udp(b"payload");
+        let input = pcapng(&[&frame[..26]]);
+        assert!(parse_pcapng(&input).unwrap().is_empty());
+    }
+
+    #[test]
+    fn hex_lines_are_parsed() {
+        let input = b"# comment\n01 02:0x03,04\n\n  0a0B  \n";
+        let datagrams = parse_hex(input).unwrap();
+        assert_eq!(datagrams.len(), 2);
+        assert_eq!(datagrams[0].data, vec![1, 2, 3, 4]);
+        assert_eq!(datagrams[1].data, vec![0x0a, 0x0b]);
+        assert!(datagrams[0].time.is_none() && datagrams[0].endpoints.is_none());
+        assert_eq!(
+            parse_hex(b"01\n012\n").unwrap_err(),
+            "line 2: odd number of hex digits"
+        );
+        assert_eq!(parse_hex(b"zz\n").unwrap_err(), "line 1: invalid hex");
+        // 非 ASCII 字符不会在按字节切分时 panic
+        assert_eq!(
+            parse_hex("a€\n".as_bytes()).unwrap_err(),
+            "line 1: invalid hex"
+        );
+        assert_eq!(
+            parse_hex("01\n€€\n".as_bytes()).unwrap_err(),
+            "line 2: invalid hex"
+        );
+        // from_str_radix 接受的符号也不是十六进制数字
+        assert_eq!(parse_hex(b"+f\n").unwrap_err(), "line 1: invalid hex");
+        assert!(parse_hex(&[0xff, 0xfe]).is_err());
+    }
+
+    #[test]
+    fn reliable_datagram_is_decoded() {
+        let mut segments = BytesMut::new();
+        KcpSegment {
+            conv: 0,
+            cmd: KCP_CMD_PUSH,
+            frg: 0,
+            wnd: 128,
+            ts: 10,
+            sn: 0,
+            una: 0,
+            data: &[Kcp2KHeaderReliable::Data.to_u8(), 0xAB],
+        }
+        .encode(&mut segments);
+        let packet = Kcp2KPacket::Reliable {
+            cookie: [1, 2, 3, 4],
+            segments: segments.freeze(),
+        };
+        let out = decode(&packet.encode(), &mut HashMap::new(), false);
+        assert!(out.starts_with("reliable cookie=01020304"), "{}", out);
+        assert!(out.contains("cmd=PUSH(81)"), "{}", out);
+        assert!(out.contains("Data payload[1]=ab"), "{}", out);
+    }
+
+    #[test]
+    fn unreliable_and_malformed_datagrams_are_decoded() {
+        let packet = Kcp2KPacket::Unreliable {
+            cookie: [1, 2, 3, 4],
+            header: Kcp2KHeaderUnreliable::Disconnect,
+            payload: DisconnectReason::Timeout.encode("bye"),
+        };
+        let out = decode(&packet.encode(), &mut HashMap::new(), false);
+        assert!(
+            out.contains("Disconnect reason=Timeout message=\"bye\""),
+            "{}",
+            out
+        );
+
+        let mut fragments = HashMap::new();
+        assert_eq!(
+            decode(&[7, 1, 2, 3, 4, 0], &mut fragments, false),
+            "unknown channel 7 (6 bytes)"
+        );
+        assert!(decode(&[1, 1, 2], &mut fragments, false).starts_with("malformed"));
+        let unknown_header = [Kcp2KChannel::Unreliable as u8, 1, 2, 3, 4, 200, 0xEE];
+        assert_eq!(
+            decode(&unknown_header, &mut fragments, false),
+            "unreliable cookie=01020304 unknown header 200 payload[1]=ee"
+        );
+        // 截断的 KCP 段
+        let truncated = [Kcp2KChannel::Reliable as u8, 1, 2, 3, 4, 0, 0, 0];
+        assert!(decode(&truncated, &mut fragments, false).contains("malformed"));
+    }
+}
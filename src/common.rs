@@ -12,13 +12,15 @@ use tklog::info;
 pub enum Kcp2KMode {
     Client,
     Server,
+    Peer, // P2P：同一个 socket 既接受连接也主动连接
 }
 
 // sock_addr hash
 pub fn connection_hash(sock_addr: &SockAddr) -> u64 {
     // cookie 与 sock_addr 一起生成一个唯一的连接 ID
+    // 使用解析后的地址计算 hash，recv_from 返回的 SockAddr 与 SocketAddr 转换来的 SockAddr 底层存储可能不同
     let mut hasher = DefaultHasher::new();
-    sock_addr.hash(&mut hasher);
+    sock_addr.as_socket().hash(&mut hasher);
     hasher.finish()
 }
 
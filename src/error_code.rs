@@ -12,4 +12,8 @@ pub enum ErrorCode {
     SendError,          // 发送数据失败
     ConnectionNotFound, // 未找到连接
     ConnectionLocked,   // 连接被锁定
+    UnknownMessage,     // 收到未注册的消息 ID
+    InvalidMessage,     // 消息编码或解码失败
+    Rejected,           // 握手被拒绝，见 Kcp2KRejectReason
+    NotAuthenticating,  // 连接不在等待异步认证
 }
@@ -1,41 +1,168 @@
 use crate::common;
 use crate::error_code::ErrorCode;
+use crate::kcp2k_auth::{Kcp2KAuth, Kcp2KAuthenticator};
+use crate::kcp2k_batch;
+use crate::kcp2k_batch::SendQueue;
+use crate::kcp2k_budget::Kcp2KBudget;
 use crate::kcp2k_callback::Callback;
+use crate::kcp2k_capture::{Direction, Kcp2KCapture, Kcp2KCaptureConfig, Kcp2KCaptureTap};
 use crate::kcp2k_channel::Kcp2KChannel;
 use crate::kcp2k_config::Kcp2KConfig;
 use crate::kcp2k_connection::Kcp2KConnection;
-use crate::kcp2k_header::Kcp2KHeaderReliable;
+use crate::kcp2k_connection_handle::ConnectionHandle;
+use crate::kcp2k_context::Kcp2KContext;
+use crate::kcp2k_disconnect_reason::DisconnectReason;
+use crate::kcp2k_group::Kcp2KGroups;
+use crate::kcp2k_handle::Kcp2KHandle;
+use crate::kcp2k_header::{Kcp2KHeaderReliable, Kcp2KHeaderUnreliable};
+#[cfg(feature = "message")]
+use crate::kcp2k_message::{encode_message, Kcp2KMessage};
+use crate::kcp2k_packet;
+use crate::kcp2k_packet::Kcp2KDatagram;
 use crate::kcp2k_peer::Kcp2KPeer;
+use crate::kcp2k_pool::Kcp2KBufferPool;
+use crate::kcp2k_protocol::Kcp2KReject;
+use crate::kcp2k_rtt::Kcp2KRtt;
+use crate::kcp2k_socket::Kcp2KSocket;
+use crate::kcp2k_stats::Kcp2KStats;
 use bytes::Bytes;
 use common::Kcp2KMode;
+use crossbeam_queue::SegQueue;
 use dashmap::try_result::TryResult;
 use dashmap::DashMap;
 use socket2::{Domain, Protocol, SockAddr, Socket, Type};
 use std::collections::VecDeque;
-use std::io::Error;
-use std::mem::MaybeUninit;
+use std::hash::{BuildHasherDefault, DefaultHasher};
+use std::io::{Error, ErrorKind};
 use std::net::SocketAddr;
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::{Arc, Mutex};
-use tklog::{debug, error, info};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use tklog::{debug, error, info, warn};
+
+// 连接表使用固定的 hash 和分片数，遍历顺序只取决于连接的插入和删除，录制的会话可以按相同顺序回放
+pub type Kcp2KConnections = DashMap<u64, Kcp2KConnection, BuildHasherDefault<DefaultHasher>>;
+const CONNECTIONS_SHARD_AMOUNT: usize = 64;
 
 pub struct Kcp2K {
     mode: Kcp2KMode,
-    config: Arc<Kcp2KConfig>, // 配置
-    socket: Arc<Socket>,      // socket
-    connections: DashMap<u64, Kcp2KConnection>,
+    config: Arc<Kcp2KConfig>,     // 配置
+    socket: Arc<Kcp2KSocket>,     // socket
+    send_queue: SendQueue,        // 批量发送队列
+    buffer_pool: Kcp2KBufferPool, // 接收缓冲区内存池
+    connections: Kcp2KConnections,
+    addr_conn_ids: DashMap<u64, u64>, // 地址 hash -> 连接 ID
+    session_conn_ids: Arc<DashMap<Bytes, u64>>, // 会话 cookie -> 连接 ID，分片服务器的所有分片共用
+    pending_conn_ids: DashMap<u64, u64>, // 客户端：等待握手的服务器地址 hash -> 连接 ID
+    groups: Kcp2KGroups,              // 命名分组，用于广播
+    capture: Arc<Kcp2KCapture>,       // pcapng 抓包
+    context: Arc<Kcp2KContext>,       // 时钟、随机数和会话录制
     callback: fn(&Kcp2KConnection, Callback),
+    authenticator: Option<Kcp2KAuthenticator>, // 服务器：握手认证钩子
     rm_conn_ids: Arc<Mutex<VecDeque<u64>>>,
     _default_conn_id: AtomicU64,
+    shard: Option<(u64, u64)>, // 分片服务器：(分片序号, 分片数)，连接 ID % 分片数 == 分片序号
+    shard_inbound: Arc<Vec<SegQueue<(SockAddr, Bytes)>>>, // 分片服务器：其他分片转发来的数据报，按分片序号
+    received_packets: AtomicU64,
+    received_bytes: AtomicU64,
+    oversized_packets: AtomicU64,
+    dropped_packets: AtomicU64, // 批量发送丢弃的数据包数
+    tick_cursor: AtomicUsize,   // 每次 tick 轮换处理连接的起点，使全局预算公平分配
 }
 
 impl Kcp2K {
     pub fn new_server(
         config: Kcp2KConfig,
         addr: String,
-        callback: fn(&Kcp2KConnection,Callback),
+        callback: fn(&Kcp2KConnection, Callback),
     ) -> Result<Self, Error> {
         let socket_addr: SocketAddr = addr.parse().unwrap();
+        let socket = Self::bind_socket(&config, Kcp2KMode::Server, socket_addr, false)?;
+        let server = Self::new(config, Kcp2KMode::Server, socket, callback);
+        info!(format!(
+            "[KCP2K] Server bind on: {:?}",
+            server.socket.local_addr()?.as_socket().unwrap()
+        ));
+        Ok(server)
+    }
+    // 在后台网络线程中运行服务器，按 config.interval tick，返回可克隆的句柄
+    pub fn spawn(config: Kcp2KConfig, addr: String) -> Result<Kcp2KHandle, Error> {
+        Kcp2KHandle::spawn(config, addr, Kcp2KMode::Server)
+    }
+    // 在后台网络线程中运行客户端
+    pub fn spawn_client(config: Kcp2KConfig, addr: String) -> Result<Kcp2KHandle, Error> {
+        Kcp2KHandle::spawn(config, addr, Kcp2KMode::Client)
+    }
+    // 在后台网络线程中运行 P2P 节点
+    pub fn spawn_peer(config: Kcp2KConfig, addr: String) -> Result<Kcp2KHandle, Error> {
+        Kcp2KHandle::spawn(config, addr, Kcp2KMode::Peer)
+    }
+    // 分片服务器的一个分片：多个 socket 通过 SO_REUSEPORT 绑定同一地址，由内核按地址分配数据包
+    pub(crate) fn new_server_shard(
+        config: Kcp2KConfig,
+        socket_addr: SocketAddr,
+        callback: fn(&Kcp2KConnection, Callback),
+        shard_index: u64,
+        shard_count: u64,
+    ) -> Result<Self, Error> {
+        let socket = Self::bind_socket(&config, Kcp2KMode::Server, socket_addr, true)?;
+        let mut server = Self::new(config, Kcp2KMode::Server, socket, callback);
+        server.shard = Some((shard_index, shard_count));
+        info!(format!(
+            "[KCP2K] Server shard {}/{} bind on: {:?}",
+            shard_index,
+            shard_count,
+            server.socket.local_addr()?.as_socket().unwrap()
+        ));
+        Ok(server)
+    }
+    // 连接分片服务器的所有分片：共用会话 cookie 表和转发队列。
+    // 客户端地址变化后，内核通常把新地址的数据报交给另一个分片，该分片按 cookie 转发给连接所在的分片
+    pub(crate) fn link_shards(shards: &mut [Kcp2K]) {
+        let session_conn_ids = Arc::new(DashMap::new());
+        let shard_inbound: Vec<_> = (0..shards.len()).map(|_| SegQueue::new()).collect();
+        let shard_inbound = Arc::new(shard_inbound);
+        let shard_count = shards.len() as u64;
+        for (shard_index, shard) in shards.iter_mut().enumerate() {
+            shard.shard = Some((shard_index as u64, shard_count));
+            shard.session_conn_ids = Arc::clone(&session_conn_ids);
+            shard.shard_inbound = Arc::clone(&shard_inbound);
+        }
+    }
+    pub fn new_client(
+        config: Kcp2KConfig,
+        addr: String,
+        callback: fn(&Kcp2KConnection, Callback),
+    ) -> Result<Self, Error> {
+        Self::new_client_with_hello(config, addr, Bytes::new(), callback)
+    }
+    // 客户端，Hello 中附带应用载荷（如登录令牌），由服务器的认证钩子检查
+    pub fn new_client_with_hello(
+        config: Kcp2KConfig,
+        addr: String,
+        hello_payload: Bytes,
+        callback: fn(&Kcp2KConnection, Callback),
+    ) -> Result<Self, Error> {
+        Self::new_client_inner(config, addr, hello_payload, None, callback)
+    }
+    // 客户端，在发起连接之前开始录制会话，见 start_recording
+    pub fn new_client_recording(
+        config: Kcp2KConfig,
+        addr: String,
+        hello_payload: Bytes,
+        path: impl AsRef<Path>,
+        callback: fn(&Kcp2KConnection, Callback),
+    ) -> Result<Self, Error> {
+        Self::new_client_inner(config, addr, hello_payload, Some(path.as_ref()), callback)
+    }
+    fn new_client_inner(
+        config: Kcp2KConfig,
+        addr: String,
+        hello_payload: Bytes,
+        recording: Option<&Path>,
+        callback: fn(&Kcp2KConnection, Callback),
+    ) -> Result<Self, Error> {
+        let address: SocketAddr = addr.parse().unwrap();
         let socket = Socket::new(
             if config.dual_mode {
                 Domain::IPV6
@@ -49,23 +176,76 @@ impl Kcp2K {
             &socket,
             config.recv_buffer_size,
             config.send_buffer_size,
-            Arc::new(Kcp2KMode::Server),
+            Arc::new(Kcp2KMode::Client),
         )?;
         socket.set_nonblocking(true)?;
-        socket.bind(&socket_addr.into())?;
-        let server = Self::new(config, Kcp2KMode::Server, socket, callback);
+        socket.connect(&address.into())?;
+        let client = Self::new(config, Kcp2KMode::Client, socket, callback);
+        client.connect_default(address.into(), hello_payload, recording)?;
         info!(format!(
-            "[KCP2K] Server bind on: {:?}",
-            server.socket.local_addr()?.as_socket().unwrap()
+            "[KCP2K] Client connecting to: {:?}",
+            client.socket.peer_addr()?.as_socket().unwrap()
         ));
-        Ok(server)
+        Ok(client)
     }
-    pub fn new_client(
+    // 多连接客户端：socket 不 connect 到固定地址，通过 connect 向多个服务器发起连接
+    pub fn new_multi_client(
+        config: Kcp2KConfig,
+        callback: fn(&Kcp2KConnection, Callback),
+    ) -> Result<Self, Error> {
+        let local_addr: SocketAddr = if config.dual_mode {
+            "[::]:0".parse().unwrap()
+        } else {
+            "0.0.0.0:0".parse().unwrap()
+        };
+        let socket = Self::bind_socket(&config, Kcp2KMode::Client, local_addr, false)?;
+        let client = Self::new(config, Kcp2KMode::Client, socket, callback);
+        info!(format!(
+            "[KCP2K] Multi client bind on: {:?}",
+            client.socket.local_addr()?.as_socket().unwrap()
+        ));
+        Ok(client)
+    }
+    // 客户端构造时连接服务器，recording 不为 None 时在发起连接之前开始录制
+    pub(crate) fn connect_default(
+        &self,
+        sock_addr: SockAddr,
+        hello_payload: Bytes,
+        recording: Option<&Path>,
+    ) -> Result<(), Error> {
+        if let Some(path) = recording {
+            self.start_recording(path)?;
+        }
+        // 与 connect 一样录制并生成连接 ID，回放时由 Connect 事件重现
+        self.context.recorder.connect(&sock_addr, &hello_payload);
+        let connection_id = self.generate_connection_id();
+        self._default_conn_id.store(connection_id, Ordering::SeqCst);
+        self.dial(connection_id, sock_addr, hello_payload);
+        Ok(())
+    }
+    // P2P 节点：监听 addr 接受其他节点的连接，同时可以通过 connect 主动连接其他节点
+    pub fn new_peer(
         config: Kcp2KConfig,
         addr: String,
-        callback: fn(&Kcp2KConnection,Callback),
+        callback: fn(&Kcp2KConnection, Callback),
     ) -> Result<Self, Error> {
-        let address: SocketAddr = addr.parse().unwrap();
+        let socket_addr: SocketAddr = addr
+            .parse()
+            .map_err(|err| Error::new(ErrorKind::InvalidInput, err))?;
+        let socket = Self::bind_socket(&config, Kcp2KMode::Peer, socket_addr, false)?;
+        let peer = Self::new(config, Kcp2KMode::Peer, socket, callback);
+        info!(format!(
+            "[KCP2K] Peer bind on: {:?}",
+            peer.socket.local_addr()?.as_socket().unwrap()
+        ));
+        Ok(peer)
+    }
+    fn bind_socket(
+        config: &Kcp2KConfig,
+        mode: Kcp2KMode,
+        socket_addr: SocketAddr,
+        reuse_port: bool,
+    ) -> Result<Socket, Error> {
         let socket = Socket::new(
             if config.dual_mode {
                 Domain::IPV6
@@ -79,36 +259,158 @@ impl Kcp2K {
             &socket,
             config.recv_buffer_size,
             config.send_buffer_size,
-            Arc::new(Kcp2KMode::Client),
+            Arc::new(mode),
         )?;
         socket.set_nonblocking(true)?;
-        socket.connect(&address.into())?;
-        let client = Self::new(config, Kcp2KMode::Client, socket, callback);
-        client.create_connection(
-            client._default_conn_id.load(Ordering::SeqCst),
-            address.into(),
-        );
-        info!(format!(
-            "[KCP2K] Client connecting to: {:?}",
-            client.socket.peer_addr()?.as_socket().unwrap()
-        ));
-        Ok(client)
+        if reuse_port {
+            #[cfg(unix)]
+            socket.set_reuse_port(true)?;
+            #[cfg(not(unix))]
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "SO_REUSEPORT is not supported on this platform",
+            ));
+        }
+        socket.bind(&socket_addr.into())?;
+        Ok(socket)
+    }
+    // 向服务器（或其他节点）发起一个新连接，返回连接 ID。回调中的 conn_id 即为该 ID
+    pub fn connect(&self, addr: String) -> Result<u64, Error> {
+        self.connect_with_hello(addr, Bytes::new())
+    }
+    // 发起连接，Hello 中附带应用载荷
+    pub fn connect_with_hello(&self, addr: String, hello_payload: Bytes) -> Result<u64, Error> {
+        if self.mode == Kcp2KMode::Server || self.socket.peer_addr().is_ok() {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "connect requires a client created with new_multi_client or a peer",
+            ));
+        }
+        let address: SocketAddr = addr
+            .parse()
+            .map_err(|err| Error::new(ErrorKind::InvalidInput, err))?;
+        let sock_addr: SockAddr = address.into();
+        let addr_hash = common::connection_hash(&sock_addr);
+        if self.addr_conn_ids.contains_key(&addr_hash)
+            || self.pending_conn_ids.contains_key(&addr_hash)
+        {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!("already connected to {}", address),
+            ));
+        }
+        if !Kcp2KContext::is_in_callback() {
+            self.context.recorder.connect(&sock_addr, &hello_payload);
+        }
+        let connection_id = self.generate_connection_id();
+        self.dial(connection_id, sock_addr, hello_payload);
+        info!(format!("[KCP2K] Client connecting to: {:?}", address));
+        Ok(connection_id)
     }
-    fn new(config: Kcp2KConfig, mode: Kcp2KMode, socket: Socket, callback: fn(&Kcp2KConnection,Callback)) -> Self {
+    // 创建客户端连接，并等待服务器的握手
+    fn dial(&self, connection_id: u64, sock_addr: SockAddr, hello_payload: Bytes) {
+        self.pending_conn_ids
+            .insert(common::connection_hash(&sock_addr), connection_id);
+        self.create_connection(connection_id, sock_addr, Kcp2KMode::Client, hello_payload);
+    }
+    fn new(
+        config: Kcp2KConfig,
+        mode: Kcp2KMode,
+        socket: Socket,
+        callback: fn(&Kcp2KConnection, Callback),
+    ) -> Self {
+        Self::with_context(
+            config,
+            mode,
+            Kcp2KSocket::new(socket),
+            Kcp2KContext::new(),
+            callback,
+        )
+    }
+    // 回放录制的会话：离线 socket、虚拟时钟和录制时的随机数种子
+    pub(crate) fn new_replay(
+        mut config: Kcp2KConfig,
+        mode: Kcp2KMode,
+        seed: u64,
+        callback: fn(&Kcp2KConnection, Callback),
+    ) -> Result<Self, Error> {
+        config.batch_io = false;
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&SocketAddr::from(([127, 0, 0, 1], 0)).into())?;
+        Ok(Self::with_context(
+            config,
+            mode,
+            Kcp2KSocket::offline(socket),
+            Kcp2KContext::new_virtual(seed),
+            callback,
+        ))
+    }
+    fn with_context(
+        config: Kcp2KConfig,
+        mode: Kcp2KMode,
+        socket: Kcp2KSocket,
+        context: Kcp2KContext,
+        callback: fn(&Kcp2KConnection, Callback),
+    ) -> Self {
         Self {
             mode,
             config: Arc::new(config),
             socket: Arc::new(socket),
-            connections: DashMap::new(),
+            send_queue: Arc::new(Mutex::new(Vec::new())),
+            buffer_pool: Kcp2KBufferPool::new(Self::buffer_pool_capacity(&config)),
+            connections: DashMap::with_hasher_and_shard_amount(
+                BuildHasherDefault::default(),
+                CONNECTIONS_SHARD_AMOUNT,
+            ),
+            addr_conn_ids: DashMap::new(),
+            session_conn_ids: Arc::new(DashMap::new()),
+            pending_conn_ids: DashMap::new(),
+            groups: Kcp2KGroups::default(),
+            capture: Arc::new(Kcp2KCapture::default()),
+            context: Arc::new(context),
             callback,
+            authenticator: None,
             rm_conn_ids: Arc::new(Mutex::new(VecDeque::new())),
-            _default_conn_id: AtomicU64::new(rand::random()),
+            _default_conn_id: AtomicU64::new(0), // 客户端构造时由 context 的随机数生成
+            shard: None,
+            shard_inbound: Arc::new(Vec::new()),
+            received_packets: AtomicU64::new(0),
+            received_bytes: AtomicU64::new(0),
+            oversized_packets: AtomicU64::new(0),
+            dropped_packets: AtomicU64::new(0),
+            tick_cursor: AtomicUsize::new(0),
+        }
+    }
+    // 设置认证钩子：客户端的 Hello 到达后、连接进入 Authenticated 之前调用，只对之后建立的连接生效
+    pub fn set_authenticator(&mut self, authenticator: Kcp2KAuthenticator) {
+        self.authenticator = Some(authenticator);
+    }
+    // 接受等待异步认证的连接（认证钩子返回了 Kcp2KAuth::Pending）
+    pub fn accept(&self, connection_id: u64, user_data: Bytes) -> Result<(), ErrorCode> {
+        self.resolve_authentication(connection_id, Kcp2KAuth::Accept(user_data))
+    }
+    // 拒绝等待异步认证的连接，原因发送给客户端
+    pub fn reject(&self, connection_id: u64, reject: Kcp2KReject) -> Result<(), ErrorCode> {
+        self.resolve_authentication(connection_id, Kcp2KAuth::Reject(reject))
+    }
+    fn resolve_authentication(&self, connection_id: u64, auth: Kcp2KAuth) -> Result<(), ErrorCode> {
+        if !Kcp2KContext::is_in_callback() {
+            self.context
+                .recorder
+                .resolve_authentication(connection_id, &auth);
+        }
+        match self.connections.try_get(&connection_id) {
+            TryResult::Present(conn) => conn.resolve_authentication(auth),
+            TryResult::Absent => Err(ErrorCode::ConnectionNotFound),
+            TryResult::Locked => Err(ErrorCode::ConnectionLocked),
         }
     }
     pub fn stop(&self) -> Result<(), Error> {
         self.socket.shutdown(std::net::Shutdown::Both)
     }
-    pub fn s_send(
+    // 按连接 ID 发送，服务器和多连接客户端通用
+    pub fn send(
         &self,
         connection_id: u64,
         data: Bytes,
@@ -120,79 +422,372 @@ impl Kcp2K {
             TryResult::Locked => Err(ErrorCode::ConnectionLocked),
         }
     }
-    pub fn c_send(&self, data: Bytes, channel: Kcp2KChannel) -> Result<(), ErrorCode> {
-        match self
-            .connections
-            .try_get_mut(&self._default_conn_id.load(Ordering::SeqCst))
-        {
-            TryResult::Present(conn) => conn.send_data(data, channel),
-            TryResult::Absent => Err(ErrorCode::ConnectionNotFound),
-            TryResult::Locked => Err(ErrorCode::ConnectionLocked),
+    // 发送类型化消息，可以在回调之外使用
+    #[cfg(feature = "message")]
+    pub fn send_message<T: Kcp2KMessage>(
+        &self,
+        connection_id: u64,
+        message: &T,
+        channel: Kcp2KChannel,
+    ) -> Result<(), ErrorCode> {
+        self.send(connection_id, encode_message(message)?, channel)
+    }
+    // 广播：group 为 None 时发给所有连接，except 中的连接除外。
+    // 消息只构建一次，所有接收者共享；只发给已通过验证的连接，返回成功发送的连接数
+    pub fn broadcast(
+        &self,
+        group: Option<&str>,
+        data: Bytes,
+        channel: Kcp2KChannel,
+        except: &[u64],
+    ) -> Result<usize, ErrorCode> {
+        if data.is_empty() {
+            return Err(ErrorCode::InvalidSend);
+        }
+        let header = match channel {
+            Kcp2KChannel::Reliable => Kcp2KHeaderReliable::Data.to_u8(),
+            Kcp2KChannel::Unreliable => Kcp2KHeaderUnreliable::Data.to_u8(),
+            Kcp2KChannel::None => return Err(ErrorCode::InvalidSend),
+        };
+        let message = Kcp2KConnection::build_message(header, &data);
+        let send = |conn: &Kcp2KConnection| {
+            !except.contains(&conn.get_connection_id())
+                && conn.is_authenticated()
+                && conn.send_message(&message, channel).is_ok()
+        };
+        let sent = match group {
+            None => self.connections.iter().filter(|conn| send(conn)).count(),
+            Some(group) => self
+                .groups
+                .get_members(group)
+                .into_iter()
+                .filter(
+                    |connection_id| match self.connections.try_get(connection_id) {
+                        TryResult::Present(conn) => send(&conn),
+                        TryResult::Absent => false,
+                        TryResult::Locked => {
+                            error!(format!("[KCP2K] Connection {} is locked", connection_id));
+                            false
+                        }
+                    },
+                )
+                .count(),
+        };
+        Ok(sent)
+    }
+    // 把连接加入分组，连接断开后会自动离开所有分组
+    pub fn join_group(&self, group: &str, connection_id: u64) -> Result<(), ErrorCode> {
+        if !self.connections.contains_key(&connection_id) {
+            return Err(ErrorCode::ConnectionNotFound);
         }
+        self.groups.join(group, connection_id);
+        Ok(())
+    }
+    // 把连接移出分组，连接不在分组中时返回 false
+    pub fn leave_group(&self, group: &str, connection_id: u64) -> bool {
+        self.groups.leave(group, connection_id)
+    }
+    pub fn remove_group(&self, group: &str) {
+        self.groups.remove_group(group);
+    }
+    pub fn is_in_group(&self, group: &str, connection_id: u64) -> bool {
+        self.groups.contains(group, connection_id)
+    }
+    pub fn get_group_members(&self, group: &str) -> Vec<u64> {
+        self.groups.get_members(group)
+    }
+    pub fn get_group_names(&self) -> Vec<String> {
+        self.groups.get_names()
+    }
+    pub fn s_send(
+        &self,
+        connection_id: u64,
+        data: Bytes,
+        channel: Kcp2KChannel,
+    ) -> Result<(), ErrorCode> {
+        self.send(connection_id, data, channel)
+    }
+    pub fn c_send(&self, data: Bytes, channel: Kcp2KChannel) -> Result<(), ErrorCode> {
+        self.send(self._default_conn_id.load(Ordering::SeqCst), data, channel)
+    }
+    // 接收缓冲区比 MTU 多一个字节，用于检测超过 MTU 的数据包
+    fn receive_buffer_size(&self) -> usize {
+        self.config.mtu + 1
+    }
+    // 内存池每次分配的大小：批量接收时容纳一批数据包，逐包接收时只容纳一个，
+    // 这样回调持有的一个数据包只会占住它自己的内存，而不是整批的内存
+    fn buffer_pool_capacity(config: &Kcp2KConfig) -> usize {
+        let packets = match config.batch_io && kcp2k_batch::SUPPORTED {
+            true => kcp2k_batch::BATCH_SIZE,
+            false => 1,
+        };
+        (config.mtu + 1) * packets
     }
     fn raw_receive_from(&self) -> Option<(SockAddr, Bytes)> {
-        let mut buf: [MaybeUninit<u8>; 1024] = unsafe { MaybeUninit::uninit().assume_init() };
-        match self.socket.recv_from(&mut buf) {
-            Ok((size, sock_addr)) => {
-                let buf = unsafe {
-                    std::slice::from_raw_parts_mut(buf.as_mut_ptr() as *mut u8, buf.len())
-                };
-                Some((sock_addr, Bytes::copy_from_slice(&buf[..size])))
+        let size = self.receive_buffer_size();
+        let mut buf = self.buffer_pool.take(size);
+        match self.socket.recv_from(&mut buf.spare_capacity_mut()[..size]) {
+            Ok((received, sock_addr)) => {
+                // recv_from 已写入前 received 个字节
+                unsafe { buf.set_len(received) };
+                Some((sock_addr, buf.freeze()))
+            }
+            Err(_) => {
+                self.buffer_pool.give_back(buf);
+                None
             }
-            Err(_) => None,
         }
     }
-    fn handle_data(&self, sock_addr: &SockAddr, data: Bytes) {
-        // 生成连接 ID
-        let connection_id = common::connection_hash(sock_addr);
-        // 如果连接存在，则处理数据
-        if let Some(mut connection) = self.connections.get_mut(&connection_id) {
-            let _ = connection.raw_input(data);
-        } else if self.mode == Kcp2KMode::Server {
-            // 如果连接不存在，则创建连接
-            self.create_connection(connection_id, sock_addr.clone());
-        } else if self.mode == Kcp2KMode::Client
-            && data.len() > 28
-            && data[29] == Kcp2KHeaderReliable::Hello.to_u8()
-        {
-            // 如果是客户端模式
-            let cookie = Bytes::copy_from_slice(&data[1..5]);
+    fn receive_packet(&self, sock_addr: &SockAddr, data: Bytes) {
+        self.received_packets.fetch_add(1, Ordering::Relaxed);
+        self.received_bytes
+            .fetch_add(data.len() as u64, Ordering::Relaxed);
+        self.context.recorder.receive(sock_addr, &data);
+        if self.capture.is_active() {
+            let addr_hash = common::connection_hash(sock_addr);
+            let connection_id = match self.addr_conn_ids.get(&addr_hash) {
+                Some(connection_id) => Some(*connection_id),
+                None => self
+                    .pending_conn_ids
+                    .get(&addr_hash)
+                    .map(|connection_id| *connection_id),
+            };
+            self.capture
+                .record(Direction::Inbound, connection_id, sock_addr, &[&data]);
+        }
+        // 超过 MTU 的数据包已被截断，直接丢弃
+        if data.len() > self.config.mtu {
+            self.oversized_packets.fetch_add(1, Ordering::Relaxed);
             debug!(format!(
-                "[KCP2K] Client received handshake with cookie={:?}",
-                cookie.to_vec()
+                "[KCP2K] Dropped datagram larger than mtu={} from {:?}",
+                self.config.mtu,
+                sock_addr.as_socket()
             ));
-            match self
-                .connections
-                .remove(&self._default_conn_id.load(Ordering::SeqCst))
-            {
-                Some((_, mut conn)) => {
-                    self._default_conn_id.store(connection_id, Ordering::SeqCst);
-                    conn.set_connection_id(self._default_conn_id.load(Ordering::SeqCst));
-                    conn.set_kcp_peer(Kcp2KPeer::new(
-                        Arc::new(self.mode),
-                        Arc::clone(&self.config),
-                        Arc::new(cookie),
-                        Arc::clone(&self.socket),
-                        Arc::new(sock_addr.clone()),
-                    ));
-                    self.connections
-                        .insert(self._default_conn_id.load(Ordering::SeqCst), conn);
+            return;
+        }
+        self.handle_data(sock_addr, data);
+    }
+    fn handle_data(&self, sock_addr: &SockAddr, data: Bytes) {
+        // 地址 hash
+        let addr_hash = common::connection_hash(sock_addr);
+        // 如果地址对应的连接存在，则处理数据
+        if let Some(connection_id) = self.addr_conn_ids.get(&addr_hash).map(|id| *id) {
+            if let Some(mut connection) = self.connections.get_mut(&connection_id) {
+                let _ = connection.raw_input(data);
+            }
+            return;
+        }
+        // 未知地址的数据报先解码，无效的直接丢弃，不创建连接
+        let datagram = match kcp2k_packet::decode_datagram(&data) {
+            Ok(datagram) => datagram,
+            Err(err) => {
+                debug!(format!(
+                    "[KCP2K] Dropped datagram from {:?}: {}",
+                    sock_addr.as_socket(),
+                    err
+                ));
+                return;
+            }
+        };
+        if self.pending_conn_ids.contains_key(&addr_hash) {
+            // 主动发起的连接，等待对方的握手
+            self.handle_handshake(addr_hash, sock_addr, &datagram, data);
+        } else if self.mode != Kcp2KMode::Client {
+            // 服务器或 P2P 节点接受连接
+            // 如果 cookie 属于已有会话，则说明客户端地址发生了变化
+            // C# kcp2k 不支持连接迁移，兼容模式下新地址总是新连接
+            let session_conn_id = match self.config.csharp_compat {
+                true => None,
+                false => self.session_conn_ids.get(&datagram.cookie).map(|id| *id),
+            };
+            match session_conn_id {
+                Some(connection_id) => match self.shard {
+                    // 会话属于另一个分片，转发给它处理
+                    Some((index, count)) if connection_id % count != index => {
+                        let owner = (connection_id % count) as usize;
+                        debug!(format!(
+                            "[KCP2K] Forwarding datagram from {:?} to shard {}",
+                            sock_addr.as_socket(),
+                            owner
+                        ));
+                        self.shard_inbound[owner].push((sock_addr.clone(), data));
+                    }
+                    _ => self.migrate_connection(connection_id, sock_addr, data),
+                },
+                // 如果连接不存在并且对方发来了 Hello，则创建连接并处理这个数据报。
+                // 被拒绝或已关闭的客户端随后发来的断开消息不会再创建连接
+                None if kcp2k_packet::is_handshake(&datagram) => {
+                    let connection_id = self.generate_connection_id();
+                    self.create_connection(
+                        connection_id,
+                        sock_addr.clone(),
+                        Kcp2KMode::Server,
+                        Bytes::new(),
+                    );
+                    if let Some(mut connection) = self.connections.get_mut(&connection_id) {
+                        let _ = connection.raw_input(data);
+                    }
                 }
                 None => {}
             }
         }
     }
-    fn create_connection(&self, connection_id: u64, sock_addr: SockAddr) {
-        let cookie = common::generate_cookie();
+    // 处理主动发起连接时收到的握手
+    fn handle_handshake(
+        &self,
+        addr_hash: u64,
+        sock_addr: &SockAddr,
+        datagram: &Kcp2KDatagram,
+        data: Bytes,
+    ) {
+        if !kcp2k_packet::is_handshake(datagram) {
+            // 握手前只接受对方的拒绝和断开
+            let rejected = datagram.channel == Kcp2KChannel::Unreliable
+                && matches!(
+                    kcp2k_packet::decode_unreliable(&datagram.payload),
+                    Ok((
+                        Kcp2KHeaderUnreliable::Reject | Kcp2KHeaderUnreliable::Disconnect,
+                        _
+                    ))
+                );
+            if rejected {
+                if let Some(connection_id) = self.pending_conn_ids.get(&addr_hash).map(|id| *id) {
+                    if let Some(mut connection) = self.connections.get_mut(&connection_id) {
+                        let _ = connection.raw_input(data);
+                    }
+                }
+            }
+            return;
+        }
+        // 双方节点同时连接对方时，收到的是对方发起连接的 Hello 而不是回复
+        if self.mode == Kcp2KMode::Peer && !kcp2k_packet::is_handshake_reply(datagram) {
+            self.handle_simultaneous_open(addr_hash, sock_addr, datagram, data);
+            return;
+        }
+        // cookie 会被长期保存，拷贝出来，避免占用接收内存池
+        let cookie = Bytes::copy_from_slice(&datagram.cookie);
+        debug!(format!(
+            "[KCP2K] Client received handshake with cookie={:?}",
+            cookie.to_vec()
+        ));
+        let connection_id = match self.pending_conn_ids.remove(&addr_hash) {
+            Some((_, connection_id)) => connection_id,
+            None => return,
+        };
+        if let Some(mut conn) = self.connections.get_mut(&connection_id) {
+            conn.set_kcp_peer(Kcp2KPeer::new(
+                Arc::new(Kcp2KMode::Client),
+                Arc::clone(&self.config),
+                Arc::new(cookie),
+                Arc::clone(&self.socket),
+                Arc::clone(&self.send_queue),
+                Arc::new(RwLock::new(sock_addr.clone())),
+                Kcp2KCaptureTap::new(Arc::clone(&self.capture), connection_id),
+                &self.context,
+            ));
+            self.addr_conn_ids.insert(addr_hash, connection_id);
+            // 握手数据报中的 Hello 交给新的 KCP，不必等待对方重传
+            let _ = conn.raw_input(data);
+        }
+    }
+    // 同时连接：cookie 较小的一方发起的连接胜出。
+    // 胜出的一方忽略对方的 Hello，继续等待回复；另一方放弃自己发起的连接，
+    // 用同一个连接 ID 作为服务器接受对方的连接，自己 Hello 中的应用载荷不再发送。
+    // cookie 相同时双方都忽略对方的 Hello，直到握手超时
+    fn handle_simultaneous_open(
+        &self,
+        addr_hash: u64,
+        sock_addr: &SockAddr,
+        datagram: &Kcp2KDatagram,
+        data: Bytes,
+    ) {
+        let connection_id = match self.pending_conn_ids.get(&addr_hash).map(|id| *id) {
+            Some(connection_id) => connection_id,
+            None => return,
+        };
+        let cookie = match self.connections.get(&connection_id) {
+            Some(connection) => connection.get_cookie(),
+            None => return,
+        };
+        if cookie <= datagram.cookie {
+            return;
+        }
+        debug!(format!(
+            "[KCP2K] Peer {:?} dialed us at the same time, accepting its connection as {}",
+            sock_addr.as_socket(),
+            connection_id
+        ));
+        self.pending_conn_ids.remove(&addr_hash);
+        self.create_connection(
+            connection_id,
+            sock_addr.clone(),
+            Kcp2KMode::Server,
+            Bytes::new(),
+        );
+        if let Some(mut connection) = self.connections.get_mut(&connection_id) {
+            let _ = connection.raw_input(data);
+        }
+    }
+    // 把会话迁移到新地址，需要新地址先通过路径验证
+    fn migrate_connection(&self, connection_id: u64, sock_addr: &SockAddr, data: Bytes) {
+        if let Some(mut connection) = self.connections.get_mut(&connection_id) {
+            let old_addr_hash = common::connection_hash(&connection.get_sock_addr());
+            if connection.raw_input_migration(sock_addr, data) {
+                self.addr_conn_ids
+                    .remove_if(&old_addr_hash, |_, id| *id == connection_id);
+                self.addr_conn_ids
+                    .insert(common::connection_hash(sock_addr), connection_id);
+            }
+        }
+    }
+    // 生成一个未被占用的连接 ID
+    fn generate_connection_id(&self) -> u64 {
+        loop {
+            let connection_id = match self.shard {
+                // 分片服务器的连接 ID 对分片数取模即为分片序号
+                Some((shard_index, shard_count)) => {
+                    (self.context.random_u64() >> 16) * shard_count + shard_index
+                }
+                None => self.context.random_u64(),
+            };
+            if !self.connections.contains_key(&connection_id) {
+                return connection_id;
+            }
+        }
+    }
+    fn create_connection(
+        &self,
+        connection_id: u64,
+        sock_addr: SockAddr,
+        mode: Kcp2KMode,
+        hello_payload: Bytes,
+    ) {
+        // 服务器为每个会话生成唯一的 cookie，客户端地址变化时用它找回会话
+        let cookie = loop {
+            let cookie = Bytes::copy_from_slice(&self.context.random_bytes::<4>());
+            if !self.session_conn_ids.contains_key(&cookie) {
+                break cookie;
+            }
+        };
+        if mode == Kcp2KMode::Server {
+            self.addr_conn_ids
+                .insert(common::connection_hash(&sock_addr), connection_id);
+            self.session_conn_ids.insert(cookie.clone(), connection_id);
+        }
         let kcp_server_connection = Kcp2KConnection::new(
             Arc::clone(&self.config),
             Arc::new(cookie),
             Arc::clone(&self.socket),
+            Arc::clone(&self.send_queue),
             connection_id,
             Arc::new(sock_addr),
-            Arc::new(self.mode),
+            Arc::new(mode),
             self.callback,
             Arc::clone(&self.rm_conn_ids),
+            Kcp2KCaptureTap::new(Arc::clone(&self.capture), connection_id),
+            Arc::clone(&self.context),
+            hello_payload,
+            self.authenticator,
         );
 
         self.connections
@@ -203,10 +798,24 @@ impl Kcp2K {
         self.tick_outgoing();
     }
     pub fn tick_incoming(&self) {
+        let now = self.context.latch();
+        self.context.recorder.tick_incoming(now);
         match self.rm_conn_ids.try_lock() {
             Ok(mut rm_conn_ids) => {
                 while let Some(connection_id) = rm_conn_ids.pop_front() {
-                    self.connections.remove(&connection_id);
+                    self.groups.remove_connection(connection_id);
+                    if let Some((_, conn)) = self.connections.remove(&connection_id) {
+                        self.addr_conn_ids
+                            .remove_if(&common::connection_hash(&conn.get_sock_addr()), |_, id| {
+                                *id == connection_id
+                            });
+                        self.session_conn_ids
+                            .remove_if(&conn.get_cookie(), |_, id| *id == connection_id);
+                        self.pending_conn_ids
+                            .remove_if(&common::connection_hash(&conn.get_sock_addr()), |_, id| {
+                                *id == connection_id
+                            });
+                    }
                 }
             }
             Err(err) => {
@@ -214,18 +823,108 @@ impl Kcp2K {
             }
         }
 
-        while let Some((sock_addr, data)) = self.raw_receive_from() {
-            self.handle_data(&sock_addr, data);
+        if self.socket.is_offline() {
+            // 离线（回放）：接收内存队列中的数据报
+            while let Some((sock_addr, data)) = self.socket.pop_inbound() {
+                self.receive_packet(&sock_addr, data);
+            }
+        } else if self.config.batch_io && kcp2k_batch::SUPPORTED {
+            // 批量接收，直到 socket 中没有数据
+            while let Ok(packets) =
+                kcp2k_batch::recv_batch(&self.socket, &self.buffer_pool, self.receive_buffer_size())
+            {
+                let drained = packets.len() < kcp2k_batch::BATCH_SIZE;
+                for (sock_addr, data) in packets {
+                    self.receive_packet(&sock_addr, data);
+                }
+                if drained {
+                    break;
+                }
+            }
+        } else {
+            while let Some((sock_addr, data)) = self.raw_receive_from() {
+                self.receive_packet(&sock_addr, data);
+            }
+        }
+        // 其他分片转发来的数据报已经在接收的分片中统计过
+        if let Some((shard_index, _)) = self.shard {
+            while let Some((sock_addr, data)) = self.shard_inbound[shard_index as usize].pop() {
+                self.handle_data(&sock_addr, data);
+            }
         }
 
-        for connection in self.connections.iter() {
-            connection.tick_incoming();
+        // 全局预算用完后，剩余连接仍然处理 ping 和超时，但不再接收消息
+        let mut tick_budget = Kcp2KBudget::new(
+            self.config.tick_message_budget,
+            self.config.tick_byte_budget,
+        );
+        let start = match self.connections.len() {
+            0 => 0,
+            len => self.tick_cursor.fetch_add(1, Ordering::Relaxed) % len,
+        };
+        for connection in self
+            .connections
+            .iter()
+            .skip(start)
+            .chain(self.connections.iter().take(start))
+        {
+            connection.tick_incoming_budget(&mut tick_budget);
         }
     }
     pub fn tick_outgoing(&self) {
+        let now = self.context.latch();
+        self.context.recorder.tick_outgoing(now);
         for connection in self.connections.iter() {
             connection.tick_outgoing();
         }
+        self.flush_send_queue();
+        self.capture.flush();
+        self.context.recorder.flush();
+    }
+    // 通过 sendmmsg 发送 tick_outgoing 期间排队的 KCP 数据包，没有发送的数据包留到下一次 tick
+    fn flush_send_queue(&self) {
+        let mut packets = match self.send_queue.lock() {
+            Ok(mut send_queue) => std::mem::take(&mut *send_queue),
+            Err(err) => {
+                error!(format!("[KCP2K] Failed to lock send_queue: {:?}", err));
+                return;
+            }
+        };
+        if packets.is_empty() || self.socket.is_offline() {
+            return;
+        }
+        let (sent, error) = match kcp2k_batch::send_batch(&self.socket, &packets) {
+            Ok(sent) => (sent, None),
+            Err(err) => (0, Some(err)),
+        };
+        let bytes = packets[..sent]
+            .iter()
+            .map(|(_, data)| data.len() as u64)
+            .sum();
+        self.socket.count_sent(sent as u64, bytes);
+        if sent == packets.len() {
+            return;
+        }
+        let unsent = packets.split_off(sent);
+        let pending = unsent.len();
+        let dropped = match self.send_queue.lock() {
+            Ok(mut send_queue) => kcp2k_batch::requeue(&mut send_queue, unsent, error.as_ref()),
+            Err(_) => pending,
+        };
+        debug!(format!(
+            "[KCP2K] sendmmsg sent {}/{} packets: {:?}",
+            sent,
+            sent + pending,
+            error
+        ));
+        if dropped > 0 {
+            self.dropped_packets
+                .fetch_add(dropped as u64, Ordering::Relaxed);
+            warn!(format!(
+                "[KCP2K] sendmmsg dropped {} of {} unsent packets: {:?}",
+                dropped, pending, error
+            ));
+        }
     }
     pub fn get_connection_address(&self, connection_id: u64) -> String {
         match self.connections.try_get(&connection_id) {
@@ -243,13 +942,126 @@ impl Kcp2K {
         }
         "".to_string()
     }
-    pub fn get_connections(&self) -> &DashMap<u64, Kcp2KConnection> {
+    pub fn get_connections(&self) -> &Kcp2KConnections {
         &self.connections
     }
+    // 获取连接句柄，可以克隆到其他线程发送消息
+    pub fn get_connection_handle(&self, connection_id: u64) -> Option<ConnectionHandle> {
+        match self.connections.try_get(&connection_id) {
+            TryResult::Present(conn) => Some(conn.get_handle()),
+            TryResult::Absent => None,
+            TryResult::Locked => {
+                error!(format!("[KCP2K] Connection {} is locked", connection_id));
+                None
+            }
+        }
+    }
+    // 连接的 RTT、抖动和丢包率，见 kcp2k_rtt
+    pub fn get_rtt(&self, connection_id: u64) -> Option<Kcp2KRtt> {
+        match self.connections.try_get(&connection_id) {
+            TryResult::Present(conn) => Some(conn.get_rtt()),
+            TryResult::Absent => None,
+            TryResult::Locked => {
+                error!(format!("[KCP2K] Connection {} is locked", connection_id));
+                None
+            }
+        }
+    }
+    pub fn get_local_addr(&self) -> Result<SocketAddr, Error> {
+        match self.socket.local_addr()?.as_socket() {
+            Some(socket_addr) => Ok(socket_addr),
+            None => Err(Error::new(
+                ErrorKind::AddrNotAvailable,
+                "local address is not an IP address",
+            )),
+        }
+    }
+    // 开始把收发的数据报记录到 pcapng 文件，已在抓包时会切换到新文件
+    pub fn start_capture(&self, config: Kcp2KCaptureConfig) -> Result<(), Error> {
+        let _ = self.capture.stop();
+        self.capture.start(config, self.get_local_addr()?)
+    }
+    // 停止抓包并把缓冲区写入文件
+    pub fn stop_capture(&self) -> Result<(), Error> {
+        self.capture.stop()
+    }
+    pub fn is_capturing(&self) -> bool {
+        self.capture.is_active()
+    }
+    // 开始录制会话，录制文件可以用 Kcp2KReplay 回放。必须在第一个连接建立之前开始，
+    // new_client 在构造时就发起连接，需要录制时使用 new_client_recording
+    pub fn start_recording(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        if !self.connections.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "recording must start before the first connection",
+            ));
+        }
+        // 重新设置随机数种子并写入录制文件，回放时生成相同的连接 ID、cookie 和随机数
+        let seed: u64 = rand::random();
+        self.context.reseed(seed);
+        self.context.recorder.start(path.as_ref(), self.mode, seed)
+    }
+    pub fn stop_recording(&self) -> Result<(), Error> {
+        self.context.recorder.stop()
+    }
+    pub fn is_recording(&self) -> bool {
+        self.context.recorder.is_active()
+    }
+    pub(crate) fn get_context(&self) -> &Kcp2KContext {
+        &self.context
+    }
+    // 内存中的实例，供模糊测试使用：不收发网络数据，数据报由 push_datagram 注入，时间由 set_time 推进
+    #[cfg(feature = "fuzzing")]
+    pub fn new_in_memory(
+        config: Kcp2KConfig,
+        mode: Kcp2KMode,
+        callback: fn(&Kcp2KConnection, Callback),
+    ) -> Result<Self, Error> {
+        Self::new_replay(config, mode, 0, callback)
+    }
+    #[cfg(feature = "fuzzing")]
+    pub fn push_datagram(&self, addr: SocketAddr, data: Bytes) {
+        self.push_inbound(addr.into(), data);
+    }
+    #[cfg(feature = "fuzzing")]
+    pub fn set_time(&self, now: std::time::Duration) {
+        self.context.set_time(now);
+    }
+    // 测试：离线模式下发送的数据报
+    #[cfg(test)]
+    pub(crate) fn take_outbound(&self) -> Vec<(SockAddr, Bytes)> {
+        self.socket.take_outbound()
+    }
+    // 离线（回放）模式：放入一个待接收的数据报，在下一次 tick_incoming 时处理
+    pub(crate) fn push_inbound(&self, sock_addr: SockAddr, data: Bytes) {
+        self.socket.push_inbound(sock_addr, data);
+    }
+    pub fn get_stats(&self) -> Kcp2KStats {
+        let (sent_packets, sent_bytes) = self.socket.get_sent();
+        Kcp2KStats {
+            connections: self.connections.len(),
+            received_packets: self.received_packets.load(Ordering::Relaxed),
+            received_bytes: self.received_bytes.load(Ordering::Relaxed),
+            oversized_packets: self.oversized_packets.load(Ordering::Relaxed),
+            dropped_packets: self.dropped_packets.load(Ordering::Relaxed),
+            sent_packets,
+            sent_bytes,
+        }
+    }
     pub fn close_connection(&self, connection_id: u64) {
+        self.disconnect(connection_id, DisconnectReason::Closed, "");
+    }
+    // 断开连接并把原因告诉对方，例如 disconnect(id, DisconnectReason::Kicked, "server full")
+    pub fn disconnect(&self, connection_id: u64, reason: DisconnectReason, message: &str) {
+        if !Kcp2KContext::is_in_callback() {
+            self.context
+                .recorder
+                .disconnect(connection_id, reason, message);
+        }
         match self.connections.try_get(&connection_id) {
             TryResult::Present(conn) => {
-                conn.send_disconnect();
+                conn.disconnect(reason, message);
             }
             TryResult::Absent => {
                 debug!(format!("[KCP2K] Connection {} not found", connection_id));
@@ -260,3 +1072,253 @@ impl Kcp2K {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kcp2k_callback::CallbackType;
+    use crate::kcp2k_testing::{addr, ignore, run_network, Kcp2KTestLink};
+    use std::cell::RefCell;
+    use std::time::Duration;
+
+    thread_local! {
+        // 收到的事件：连接 ID 和 OnConnected 或 OnData 的数据
+        static EVENTS: RefCell<Vec<(u64, Option<Bytes>)>> = const { RefCell::new(Vec::new()) };
+    }
+
+    fn record(_: &Kcp2KConnection, cb: Callback) {
+        match cb.r#type {
+            CallbackType::OnConnected => {
+                EVENTS.with(|events| events.borrow_mut().push((cb.conn_id, None)))
+            }
+            CallbackType::OnData => {
+                EVENTS.with(|events| events.borrow_mut().push((cb.conn_id, Some(cb.data))))
+            }
+            _ => {}
+        }
+    }
+
+    fn take_events() -> Vec<(u64, Option<Bytes>)> {
+        EVENTS.with(|events| events.take())
+    }
+
+    fn link(server_mode: Kcp2KMode, client_mode: Kcp2KMode) -> Kcp2KTestLink {
+        take_events();
+        Kcp2KTestLink::unconnected(
+            Kcp2KConfig::default(),
+            server_mode,
+            client_mode,
+            record,
+            record,
+        )
+    }
+
+    // 唯一的连接已通过验证，返回它的连接 ID 和 cookie
+    fn only_connection(kcp2k: &Kcp2K) -> (u64, Bytes) {
+        let connections = kcp2k.get_connections();
+        assert_eq!(connections.len(), 1);
+        let connection = connections.iter().next().unwrap();
+        assert!(connection.is_authenticated());
+        (connection.get_connection_id(), connection.get_cookie())
+    }
+
+    // 双方互相发送数据，每个连接 ID 都应该收到对方的数据
+    fn assert_exchange(link: &mut Kcp2KTestLink, server_id: u64, client_id: u64) {
+        take_events();
+        link.server
+            .send(server_id, Bytes::from_static(b"s"), Kcp2KChannel::Reliable)
+            .unwrap();
+        link.client
+            .send(client_id, Bytes::from_static(b"c"), Kcp2KChannel::Reliable)
+            .unwrap();
+        link.run(Duration::from_millis(100));
+        let mut events = take_events();
+        events.sort_by_key(|(_, data)| data.clone());
+        assert_eq!(
+            events,
+            vec![
+                (server_id, Some(Bytes::from_static(b"c"))),
+                (client_id, Some(Bytes::from_static(b"s"))),
+            ]
+        );
+    }
+
+    #[test]
+    fn buffer_pool_holds_one_datagram_without_batch_io() {
+        let config = Kcp2KConfig::default();
+        assert_eq!(Kcp2K::buffer_pool_capacity(&config), config.mtu + 1);
+        let batch = Kcp2KConfig {
+            batch_io: true,
+            ..config
+        };
+        let expected = match kcp2k_batch::SUPPORTED {
+            true => (config.mtu + 1) * kcp2k_batch::BATCH_SIZE,
+            false => config.mtu + 1,
+        };
+        assert_eq!(Kcp2K::buffer_pool_capacity(&batch), expected);
+    }
+
+    #[test]
+    fn peer_accepts_a_client() {
+        let mut link = link(Kcp2KMode::Peer, Kcp2KMode::Client);
+        let client_id = link.client.connect("10.0.0.1:7777".to_string()).unwrap();
+        link.run(Duration::from_millis(200));
+        let (server_id, server_cookie) = only_connection(&link.server);
+        assert_eq!(only_connection(&link.client), (client_id, server_cookie));
+        assert_exchange(&mut link, server_id, client_id);
+    }
+
+    #[test]
+    fn peer_dials_a_server() {
+        let mut link = link(Kcp2KMode::Server, Kcp2KMode::Peer);
+        let client_id = link.client.connect("10.0.0.1:7777".to_string()).unwrap();
+        link.run(Duration::from_millis(200));
+        let (server_id, server_cookie) = only_connection(&link.server);
+        assert_eq!(only_connection(&link.client), (client_id, server_cookie));
+        assert_exchange(&mut link, server_id, client_id);
+    }
+
+    #[test]
+    fn peers_dialing_each_other_share_one_connection() {
+        let mut link = link(Kcp2KMode::Peer, Kcp2KMode::Peer);
+        let server_id = link.server.connect("10.0.0.2:50000".to_string()).unwrap();
+        let client_id = link.client.connect("10.0.0.1:7777".to_string()).unwrap();
+        link.run(Duration::from_millis(200));
+        // 每一方保留 connect 返回的连接 ID，并使用同一个 cookie
+        let (id, server_cookie) = only_connection(&link.server);
+        assert_eq!(id, server_id);
+        let (id, client_cookie) = only_connection(&link.client);
+        assert_eq!(id, client_id);
+        assert_eq!(server_cookie, client_cookie);
+        let mut connected: Vec<u64> = take_events().into_iter().map(|(id, _)| id).collect();
+        connected.sort();
+        let mut expected = vec![server_id, client_id];
+        expected.sort();
+        assert_eq!(connected, expected);
+        assert_exchange(&mut link, server_id, client_id);
+        // 握手后连接仍然保持
+        link.run(Duration::from_secs(5));
+        assert_eq!(only_connection(&link.server).0, server_id);
+        assert_eq!(only_connection(&link.client).0, client_id);
+    }
+
+    // 一个多连接客户端和两个服务器，数据报按目的地址转发
+    struct MultiLink {
+        client: Kcp2K,
+        servers: Vec<(Kcp2K, SockAddr)>,
+        now: Duration,
+    }
+
+    impl MultiLink {
+        fn new() -> Self {
+            take_events();
+            let config = Kcp2KConfig::default();
+            let servers = (1..=2)
+                .map(|i| {
+                    let server = Kcp2K::new_replay(config, Kcp2KMode::Server, i, record).unwrap();
+                    (server, addr(&format!("10.0.0.{}:7777", i)))
+                })
+                .collect();
+            Self {
+                client: Kcp2K::new_replay(config, Kcp2KMode::Client, 3, record).unwrap(),
+                servers,
+                now: Duration::ZERO,
+            }
+        }
+
+        fn run(&mut self, duration: Duration) {
+            let client_addr = addr("10.0.0.3:50000");
+            let nodes: Vec<(&Kcp2K, &SockAddr)> = std::iter::once((&self.client, &client_addr))
+                .chain(
+                    self.servers
+                        .iter()
+                        .map(|(server, server_addr)| (server, server_addr)),
+                )
+                .collect();
+            run_network(&nodes, &[], &mut self.now, duration);
+        }
+    }
+
+    #[test]
+    fn multi_client_talks_to_two_servers() {
+        let mut link = MultiLink::new();
+        let a = link.client.connect("10.0.0.1:7777".to_string()).unwrap();
+        let b = link.client.connect("10.0.0.2:7777".to_string()).unwrap();
+        assert_ne!(a, b);
+        link.run(Duration::from_millis(200));
+        assert_eq!(link.client.get_connections().len(), 2);
+        let (server_a, cookie_a) = only_connection(&link.servers[0].0);
+        let (server_b, cookie_b) = only_connection(&link.servers[1].0);
+        assert_eq!(
+            link.client.get_connections().get(&a).unwrap().get_cookie(),
+            cookie_a
+        );
+        assert_eq!(
+            link.client.get_connections().get(&b).unwrap().get_cookie(),
+            cookie_b
+        );
+
+        // 每个连接 ID 的数据只发给对应的服务器
+        take_events();
+        link.client
+            .send(a, Bytes::from_static(b"to a"), Kcp2KChannel::Reliable)
+            .unwrap();
+        link.client
+            .send(b, Bytes::from_static(b"to b"), Kcp2KChannel::Unreliable)
+            .unwrap();
+        link.run(Duration::from_millis(100));
+        let mut events = take_events();
+        events.sort_by_key(|(_, data)| data.clone());
+        assert_eq!(
+            events,
+            vec![
+                (server_a, Some(Bytes::from_static(b"to a"))),
+                (server_b, Some(Bytes::from_static(b"to b"))),
+            ]
+        );
+    }
+
+    #[test]
+    fn multi_client_rejects_a_second_connection_to_the_same_address() {
+        let mut link = MultiLink::new();
+        link.client.connect("10.0.0.1:7777".to_string()).unwrap();
+        // 握手进行中和完成后都不能重复连接
+        let err = link
+            .client
+            .connect("10.0.0.1:7777".to_string())
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        link.run(Duration::from_millis(200));
+        let err = link
+            .client
+            .connect("10.0.0.1:7777".to_string())
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        assert_eq!(link.client.get_connections().len(), 1);
+    }
+
+    #[test]
+    fn connect_requires_a_multi_client_or_peer() {
+        let config = Kcp2KConfig::default();
+        let client = Kcp2K::new_client(config, "127.0.0.1:7777".to_string(), ignore).unwrap();
+        let err = client.connect("127.0.0.1:7778".to_string()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Unsupported);
+        let server = Kcp2K::new_replay(config, Kcp2KMode::Server, 1, ignore).unwrap();
+        let err = server.connect("10.0.0.2:7777".to_string()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Unsupported);
+        let multi_client = Kcp2K::new_multi_client(config, ignore).unwrap();
+        let err = multi_client
+            .connect("not an address".to_string())
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn new_peer_rejects_an_invalid_address() {
+        let config = Kcp2KConfig::default();
+        let err = Kcp2K::new_peer(config, "not an address".to_string(), ignore)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+}
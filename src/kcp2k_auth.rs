@@ -0,0 +1,218 @@
+use crate::kcp2k_connection::Kcp2KConnection;
+use crate::kcp2k_protocol::Kcp2KReject;
+use bytes::Bytes;
+
+// 握手认证：客户端在 Hello 中附带应用载荷（如登录令牌），服务器在连接进入 Authenticated 之前调用认证钩子。
+// 钩子可以立即接受或拒绝，也可以返回 Pending，稍后通过 Kcp2K::accept / Kcp2K::reject 完成
+
+// 认证结果
+#[derive(Debug, Clone, PartialEq)]
+pub enum Kcp2KAuth {
+    Accept(Bytes),       // 接受，附带的用户数据可以通过 Kcp2KConnection::get_user_data 读取
+    Reject(Kcp2KReject), // 拒绝，原因随 Reject 消息发送给客户端
+    Pending,             // 异步认证，连接保持在 Connected 状态，不处理消息
+}
+
+// 认证钩子：连接（地址、连接 ID、协商的协议）和客户端 Hello 中的应用载荷。
+// C# kcp2k 客户端和兼容模式下的客户端不发送载荷
+pub type Kcp2KAuthenticator = fn(&Kcp2KConnection, Bytes) -> Kcp2KAuth;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::Kcp2KMode;
+    use crate::error_code::ErrorCode;
+    use crate::kcp2k_callback::{Callback, CallbackType};
+    use crate::kcp2k_config::Kcp2KConfig;
+    use crate::kcp2k_disconnect_reason::DisconnectReason;
+    use crate::kcp2k_protocol::Kcp2KRejectReason;
+    use crate::kcp2k_testing::Kcp2KTestLink;
+    use std::cell::RefCell;
+    use std::time::Duration;
+
+    // 回调中观察到的事件：(角色, 连接建立时的用户数据, 断开原因和说明, 拒绝原因)
+    #[derive(Debug, PartialEq)]
+    enum Event {
+        Connected(Kcp2KMode, Option<Bytes>),
+        Disconnected(Kcp2KMode, DisconnectReason, String, Option<Kcp2KReject>),
+    }
+
+    thread_local! {
+        static EVENTS: RefCell<Vec<Event>> = const { RefCell::new(Vec::new()) };
+    }
+
+    fn record(conn: &Kcp2KConnection, cb: Callback) {
+        let event = match cb.r#type {
+            CallbackType::OnConnected => Event::Connected(conn.get_mode(), conn.get_user_data()),
+            CallbackType::OnDisconnected => Event::Disconnected(
+                conn.get_mode(),
+                cb.disconnect_reason,
+                cb.disconnect_message,
+                conn.get_rejection(),
+            ),
+            _ => return,
+        };
+        EVENTS.with(|events| events.borrow_mut().push(event));
+    }
+
+    fn take_events() -> Vec<Event> {
+        EVENTS.with(|events| events.take())
+    }
+
+    fn invalid_token() -> Kcp2KReject {
+        Kcp2KReject::new(
+            Kcp2KRejectReason::application(1).unwrap(),
+            "invalid token".to_string(),
+        )
+    }
+
+    // "token:" 立即接受，"slow:" 异步认证，其他的拒绝
+    fn authenticate(_: &Kcp2KConnection, payload: Bytes) -> Kcp2KAuth {
+        if let Some(user) = payload.strip_prefix(b"token:") {
+            Kcp2KAuth::Accept(Bytes::copy_from_slice(user))
+        } else if payload.starts_with(b"slow:") {
+            Kcp2KAuth::Pending
+        } else {
+            Kcp2KAuth::Reject(invalid_token())
+        }
+    }
+
+    fn link(config: Kcp2KConfig, hello: &'static [u8]) -> Kcp2KTestLink {
+        let mut link = Kcp2KTestLink::with_hello(config, record, record, Bytes::from_static(hello));
+        link.server.set_authenticator(authenticate);
+        link.run(Duration::from_millis(200));
+        link
+    }
+
+    fn rejected(mode: Kcp2KMode) -> Event {
+        Event::Disconnected(
+            mode,
+            DisconnectReason::Rejected,
+            invalid_token().to_string(),
+            Some(invalid_token()),
+        )
+    }
+
+    #[test]
+    fn accept_delivers_user_data() {
+        let link = link(Kcp2KConfig::default(), b"token:alice");
+        assert!(link.server_conn().is_authenticated());
+        assert_eq!(
+            link.server_conn().get_user_data(),
+            Some(Bytes::from_static(b"alice"))
+        );
+        let events = take_events();
+        assert!(events.contains(&Event::Connected(
+            Kcp2KMode::Server,
+            Some(Bytes::from_static(b"alice"))
+        )));
+        assert!(events
+            .iter()
+            .any(|event| matches!(event, Event::Connected(Kcp2KMode::Client, _))));
+    }
+
+    #[test]
+    fn reject_reaches_client_with_reason() {
+        let link = link(Kcp2KConfig::default(), b"guest");
+        assert!(link.server.get_connections().is_empty());
+        assert!(link.client.get_connections().is_empty());
+        let events = take_events();
+        assert!(
+            events.contains(&rejected(Kcp2KMode::Client)),
+            "{:?}",
+            events
+        );
+        assert!(
+            events.contains(&rejected(Kcp2KMode::Server)),
+            "{:?}",
+            events
+        );
+        assert!(!events
+            .iter()
+            .any(|event| matches!(event, Event::Connected(..))));
+    }
+
+    #[test]
+    fn pending_connection_is_accepted_later() {
+        let mut link = link(Kcp2KConfig::default(), b"slow:bob");
+        let id = link.server_id();
+        assert!(link.server_conn().is_authenticating());
+        assert!(!link.server_conn().is_authenticated());
+        assert!(take_events().is_empty());
+
+        link.server.accept(id, Bytes::from_static(b"bob")).unwrap();
+        link.run(Duration::from_millis(200));
+        assert!(link.server_conn().is_authenticated());
+        assert_eq!(
+            link.server_conn().get_user_data(),
+            Some(Bytes::from_static(b"bob"))
+        );
+        let events = take_events();
+        assert!(events.contains(&Event::Connected(
+            Kcp2KMode::Server,
+            Some(Bytes::from_static(b"bob"))
+        )));
+        assert!(events
+            .iter()
+            .any(|event| matches!(event, Event::Connected(Kcp2KMode::Client, _))));
+        // 已经完成认证的连接不能再次完成
+        assert!(matches!(
+            link.server.accept(id, Bytes::new()),
+            Err(ErrorCode::NotAuthenticating)
+        ));
+    }
+
+    #[test]
+    fn pending_connection_is_rejected_later() {
+        let mut link = link(Kcp2KConfig::default(), b"slow:mallory");
+        let id = link.server_id();
+        link.server.reject(id, invalid_token()).unwrap();
+        link.run(Duration::from_millis(200));
+        assert!(link.server.get_connections().is_empty());
+        assert!(link.client.get_connections().is_empty());
+        let events = take_events();
+        assert!(
+            events.contains(&rejected(Kcp2KMode::Client)),
+            "{:?}",
+            events
+        );
+        assert!(
+            events.contains(&rejected(Kcp2KMode::Server)),
+            "{:?}",
+            events
+        );
+    }
+
+    #[test]
+    fn pending_connection_hits_handshake_timeout() {
+        let config = Kcp2KConfig {
+            handshake_timeout: 500,
+            ..Default::default()
+        };
+        let mut link = link(config, b"slow:carol");
+        let id = link.server_id();
+        // 认证等待期间客户端仍在发送 ping，不会触发空闲超时，只有握手超时
+        link.run(Duration::from_millis(250));
+        assert!(link.server_conn().is_authenticating());
+        link.run(Duration::from_millis(100));
+        assert!(link.server.get_connections().is_empty());
+        let handshake_timeout = |mode| {
+            Event::Disconnected(
+                mode,
+                DisconnectReason::Timeout,
+                "handshake timeout".to_string(),
+                None,
+            )
+        };
+        let events = take_events();
+        assert!(
+            events.contains(&handshake_timeout(Kcp2KMode::Server)),
+            "{:?}",
+            events
+        );
+        assert!(matches!(
+            link.server.accept(id, Bytes::new()),
+            Err(ErrorCode::ConnectionNotFound)
+        ));
+    }
+}
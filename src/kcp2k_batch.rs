@@ -0,0 +1,251 @@
+use crate::kcp2k_pool::Kcp2KBufferPool;
+use bytes::Bytes;
+use socket2::{SockAddr, Socket};
+use std::io;
+use std::sync::{Arc, Mutex};
+
+// 批量收发：Linux 上使用 recvmmsg/sendmmsg，一次系统调用处理多个数据包。
+// 其他平台不支持，调用方回退到逐包收发。
+pub const SUPPORTED: bool = cfg!(target_os = "linux");
+
+// 每次系统调用最多处理的数据包数
+pub const BATCH_SIZE: usize = 64;
+
+// tick_outgoing 期间排队等待 sendmmsg 的 KCP 数据包；地址为 None 表示 socket 已 connect
+pub type SendQueue = Arc<Mutex<Vec<(Option<SockAddr>, Bytes)>>>;
+
+// 发送缓冲区已满时最多保留多少个未发送的数据包等待下一次 tick
+pub const MAX_PENDING: usize = BATCH_SIZE * 16;
+
+// 批量接收，最多 BATCH_SIZE 个数据包，每个数据包最多 buffer_size 字节。
+// 数据包是从内存池中切出的 Bytes，不做拷贝，长度为实际收到的字节数；没有用到的缓冲区归还内存池
+#[cfg(target_os = "linux")]
+pub fn recv_batch(
+    socket: &Socket,
+    buffer_pool: &Kcp2KBufferPool,
+    buffer_size: usize,
+) -> io::Result<Vec<(SockAddr, Bytes)>> {
+    use std::mem::{size_of, zeroed};
+    use std::os::fd::AsRawFd;
+
+    let mut buffers = buffer_pool.take(BATCH_SIZE * buffer_size);
+    let mut storages: Vec<libc::sockaddr_storage> = vec![unsafe { zeroed() }; BATCH_SIZE];
+    let mut iovecs: Vec<libc::iovec> = buffers.spare_capacity_mut()[..BATCH_SIZE * buffer_size]
+        .chunks_mut(buffer_size)
+        .map(|buffer| libc::iovec {
+            iov_base: buffer.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buffer.len(),
+        })
+        .collect();
+    let mut messages: Vec<libc::mmsghdr> = iovecs
+        .iter_mut()
+        .zip(storages.iter_mut())
+        .map(|(iovec, storage)| {
+            let mut message: libc::mmsghdr = unsafe { zeroed() };
+            message.msg_hdr.msg_name = storage as *mut libc::sockaddr_storage as *mut libc::c_void;
+            message.msg_hdr.msg_namelen = size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            message.msg_hdr.msg_iov = iovec as *mut libc::iovec;
+            message.msg_hdr.msg_iovlen = 1;
+            message
+        })
+        .collect();
+
+    let received = unsafe {
+        libc::recvmmsg(
+            socket.as_raw_fd(),
+            messages.as_mut_ptr(),
+            BATCH_SIZE as libc::c_uint,
+            libc::MSG_DONTWAIT,
+            std::ptr::null_mut(),
+        )
+    };
+    if received < 0 {
+        let err = io::Error::last_os_error();
+        buffer_pool.give_back(buffers);
+        return Err(err);
+    }
+
+    let mut packets = Vec::with_capacity(received as usize);
+    for (i, message) in messages.iter().take(received as usize).enumerate() {
+        let size = (message.msg_len as usize).min(buffer_size);
+        let sock_addr = unsafe { SockAddr::new(storages[i], message.msg_hdr.msg_namelen) };
+        let rest = buffers.split_off(buffer_size);
+        let mut packet = std::mem::replace(&mut buffers, rest);
+        // recvmmsg 已写入前 size 个字节
+        unsafe { packet.set_len(size) };
+        packets.push((sock_addr, packet.freeze()));
+    }
+    buffer_pool.give_back(buffers);
+    Ok(packets)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn recv_batch(_: &Socket, _: &Kcp2KBufferPool, _: usize) -> io::Result<Vec<(SockAddr, Bytes)>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "recvmmsg is not supported on this platform",
+    ))
+}
+
+// 批量发送，返回发送成功的数据包数
+#[cfg(target_os = "linux")]
+pub fn send_batch(socket: &Socket, packets: &[(Option<SockAddr>, Bytes)]) -> io::Result<usize> {
+    use std::mem::zeroed;
+    use std::os::fd::AsRawFd;
+
+    let mut iovecs: Vec<libc::iovec> = packets
+        .iter()
+        .map(|(_, data)| libc::iovec {
+            iov_base: data.as_ptr() as *mut libc::c_void,
+            iov_len: data.len(),
+        })
+        .collect();
+    let mut messages: Vec<libc::mmsghdr> = iovecs
+        .iter_mut()
+        .zip(packets.iter())
+        .map(|(iovec, (sock_addr, _))| {
+            let mut message: libc::mmsghdr = unsafe { zeroed() };
+            if let Some(sock_addr) = sock_addr {
+                message.msg_hdr.msg_name = sock_addr.as_ptr() as *mut libc::c_void;
+                message.msg_hdr.msg_namelen = sock_addr.len();
+            }
+            message.msg_hdr.msg_iov = iovec as *mut libc::iovec;
+            message.msg_hdr.msg_iovlen = 1;
+            message
+        })
+        .collect();
+
+    // sendmmsg 可能只发送一部分，继续发送剩余的数据包
+    let mut sent = 0;
+    while sent < messages.len() {
+        let remaining = (messages.len() - sent).min(libc::UIO_MAXIOV as usize);
+        let result = unsafe {
+            libc::sendmmsg(
+                socket.as_raw_fd(),
+                messages[sent..].as_mut_ptr(),
+                remaining as libc::c_uint,
+                libc::MSG_DONTWAIT,
+            )
+        };
+        if result < 0 {
+            return match sent {
+                0 => Err(io::Error::last_os_error()),
+                _ => Ok(sent),
+            };
+        }
+        sent += result as usize;
+    }
+    Ok(sent)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn send_batch(_: &Socket, _: &[(Option<SockAddr>, Bytes)]) -> io::Result<usize> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "sendmmsg is not supported on this platform",
+    ))
+}
+
+// 把没有发送的数据包放回发送队列的最前面，下一次 tick 时按原来的顺序重试。
+// error 为 send_batch 的错误：发送缓冲区已满时全部重试；其他错误说明第一个数据包无法发送（例如超过路径 MTU），
+// 丢弃它以免阻塞之后的数据包。超过 MAX_PENDING 的部分也被丢弃，返回丢弃的数据包数
+pub(crate) fn requeue(
+    send_queue: &mut Vec<(Option<SockAddr>, Bytes)>,
+    mut unsent: Vec<(Option<SockAddr>, Bytes)>,
+    error: Option<&io::Error>,
+) -> usize {
+    let mut dropped = 0;
+    if error.is_some_and(|err| err.kind() != io::ErrorKind::WouldBlock) && !unsent.is_empty() {
+        unsent.remove(0);
+        dropped += 1;
+    }
+    let room = MAX_PENDING.saturating_sub(send_queue.len());
+    if unsent.len() > room {
+        dropped += unsent.len() - room;
+        unsent.truncate(room);
+    }
+    send_queue.splice(0..0, unsent);
+    dropped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packets(range: std::ops::Range<u8>) -> Vec<(Option<SockAddr>, Bytes)> {
+        range.map(|i| (None, Bytes::from(vec![i]))).collect()
+    }
+
+    fn first_bytes(queue: &[(Option<SockAddr>, Bytes)]) -> Vec<u8> {
+        queue.iter().map(|(_, data)| data[0]).collect()
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn recv_batch_slices_each_packet() {
+        use socket2::{Domain, Type};
+        use std::net::SocketAddr;
+
+        let local: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let receiver = Socket::new(Domain::IPV4, Type::DGRAM, None).unwrap();
+        receiver.bind(&local.into()).unwrap();
+        let sender = Socket::new(Domain::IPV4, Type::DGRAM, None).unwrap();
+        sender.bind(&local.into()).unwrap();
+        let target = receiver.local_addr().unwrap();
+        for size in [1, 300, 16] {
+            sender.send_to(&vec![size as u8; size], &target).unwrap();
+        }
+
+        let pool = Kcp2KBufferPool::new(BATCH_SIZE * 301);
+        let packets = recv_batch(&receiver, &pool, 301).unwrap();
+        let sizes: Vec<usize> = packets.iter().map(|(_, data)| data.len()).collect();
+        assert_eq!(sizes, vec![1, 300, 16]);
+        for (sock_addr, data) in &packets {
+            assert_eq!(
+                sock_addr.as_socket(),
+                sender.local_addr().unwrap().as_socket()
+            );
+            assert!(data.iter().all(|byte| *byte == data.len() as u8));
+        }
+        // 没有数据时返回 WouldBlock
+        let err = recv_batch(&receiver, &pool, 301).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn unsent_packets_go_back_to_the_front() {
+        // 部分发送之后，tick_outgoing 中又有新的数据包排队
+        let mut send_queue = packets(10..12);
+        let would_block = io::Error::from(io::ErrorKind::WouldBlock);
+        assert_eq!(requeue(&mut send_queue, packets(3..5), None), 0);
+        assert_eq!(first_bytes(&send_queue), vec![3, 4, 10, 11]);
+        assert_eq!(
+            requeue(&mut send_queue, packets(1..3), Some(&would_block)),
+            0
+        );
+        assert_eq!(first_bytes(&send_queue), vec![1, 2, 3, 4, 10, 11]);
+    }
+
+    #[test]
+    fn packet_that_cannot_be_sent_is_dropped() {
+        let mut send_queue = Vec::new();
+        let too_big = io::Error::from(io::ErrorKind::InvalidInput);
+        assert_eq!(requeue(&mut send_queue, packets(0..3), Some(&too_big)), 1);
+        assert_eq!(first_bytes(&send_queue), vec![1, 2]);
+    }
+
+    #[test]
+    fn pending_packets_are_bounded() {
+        let mut send_queue = packets(0..4);
+        let unsent = (0..MAX_PENDING)
+            .map(|_| (None, Bytes::from_static(b"x")))
+            .collect();
+        assert_eq!(requeue(&mut send_queue, unsent, None), 4);
+        assert_eq!(send_queue.len(), MAX_PENDING);
+        // 放回的数据包在前，已在队列中的数据包保持不变
+        assert_eq!(
+            first_bytes(&send_queue[MAX_PENDING - 4..]),
+            vec![0, 1, 2, 3]
+        );
+    }
+}
@@ -0,0 +1,186 @@
+// Kcp2KBudget: 每次 tick 接收可靠消息的预算
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Kcp2KBudget {
+    pub messages: usize, // 剩余消息数
+    pub bytes: usize,    // 剩余字节数
+}
+
+impl Kcp2KBudget {
+    pub fn new(messages: usize, bytes: usize) -> Self {
+        Self { messages, bytes }
+    }
+
+    // 预算用完。字节预算不足一条消息时仍允许接收这一条，保证大消息也能被处理
+    pub fn is_exhausted(&self) -> bool {
+        self.messages == 0 || self.bytes == 0
+    }
+
+    // 消耗一条消息
+    pub fn consume(&mut self, size: usize) {
+        self.messages = self.messages.saturating_sub(1);
+        self.bytes = self.bytes.saturating_sub(size);
+    }
+
+    // 取两个预算中较小的部分
+    pub fn min(self, other: Kcp2KBudget) -> Kcp2KBudget {
+        Kcp2KBudget {
+            messages: self.messages.min(other.messages),
+            bytes: self.bytes.min(other.bytes),
+        }
+    }
+
+    // 扣除另一个预算从 before 到 after 之间消耗的部分
+    pub fn spend(&mut self, before: Kcp2KBudget, after: Kcp2KBudget) {
+        self.messages = self
+            .messages
+            .saturating_sub(before.messages.saturating_sub(after.messages));
+        self.bytes = self
+            .bytes
+            .saturating_sub(before.bytes.saturating_sub(after.bytes));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kcp2k_callback::{Callback, CallbackType};
+    use crate::kcp2k_channel::Kcp2KChannel;
+    use crate::kcp2k_config::Kcp2KConfig;
+    use crate::kcp2k_connection::Kcp2KConnection;
+    use crate::kcp2k_testing::{ignore, Kcp2KTestClients, STEP};
+    use bytes::Bytes;
+    use std::cell::RefCell;
+    use std::time::Duration;
+
+    thread_local! {
+        // 服务器收到的数据：连接 ID 和数据
+        static SERVER_DATA: RefCell<Vec<(u64, Bytes)>> = const { RefCell::new(Vec::new()) };
+    }
+
+    fn record_data(conn: &Kcp2KConnection, cb: Callback) {
+        if matches!(cb.r#type, CallbackType::OnData) {
+            SERVER_DATA.with(|data| data.borrow_mut().push((conn.get_connection_id(), cb.data)));
+        }
+    }
+
+    fn take_data() -> Vec<(u64, Bytes)> {
+        SERVER_DATA.with(|data| data.take())
+    }
+
+    // 每个客户端一次发送 count 条 size 字节的可靠消息，返回每次 tick 服务器处理的消息
+    fn per_tick(
+        config: Kcp2KConfig,
+        clients: usize,
+        count: usize,
+        size: usize,
+    ) -> Vec<Vec<(u64, Bytes)>> {
+        let mut link = Kcp2KTestClients::new(config, clients, record_data, ignore);
+        link.run(Duration::from_millis(200));
+        assert_eq!(link.server.get_connections().len(), clients);
+        take_data();
+        for (i, (client, _)) in link.clients.iter().enumerate() {
+            for n in 0..count {
+                let mut message = vec![n as u8; size];
+                message[0] = i as u8;
+                client
+                    .send(
+                        link.client_id(i),
+                        Bytes::from(message),
+                        Kcp2KChannel::Reliable,
+                    )
+                    .unwrap();
+            }
+        }
+        let mut ticks = Vec::new();
+        for _ in 0..50 {
+            link.run(STEP);
+            ticks.push(take_data());
+        }
+        // 所有消息最终都按顺序送达
+        for i in 0..clients {
+            let id = link.server_id(i);
+            let received: Vec<Bytes> = ticks
+                .iter()
+                .flatten()
+                .filter(|(connection_id, _)| *connection_id == id)
+                .map(|(_, data)| data.clone())
+                .collect();
+            assert_eq!(received.len(), count);
+            for (n, data) in received.iter().enumerate() {
+                assert_eq!(data[0], i as u8);
+                assert!(data[1..].iter().all(|byte| *byte == n as u8));
+            }
+        }
+        ticks.retain(|tick| !tick.is_empty());
+        ticks
+    }
+
+    fn counts(ticks: &[Vec<(u64, Bytes)>]) -> Vec<usize> {
+        ticks.iter().map(|tick| tick.len()).collect()
+    }
+
+    #[test]
+    fn budget_is_consumed_per_message() {
+        let mut budget = Kcp2KBudget::new(2, 100);
+        assert!(!budget.is_exhausted());
+        budget.consume(60);
+        assert_eq!(budget, Kcp2KBudget::new(1, 40));
+        // 字节预算不足一条消息时仍允许这一条
+        assert!(!budget.is_exhausted());
+        budget.consume(60);
+        assert_eq!(budget, Kcp2KBudget::new(0, 0));
+        assert!(budget.is_exhausted());
+    }
+
+    #[test]
+    fn spend_deducts_what_a_connection_used() {
+        let mut tick_budget = Kcp2KBudget::new(10, 1000);
+        let connection_budget = Kcp2KBudget::new(4, 2000).min(tick_budget);
+        assert_eq!(connection_budget, Kcp2KBudget::new(4, 1000));
+        let mut after = connection_budget;
+        after.consume(300);
+        after.consume(300);
+        tick_budget.spend(connection_budget, after);
+        assert_eq!(tick_budget, Kcp2KBudget::new(8, 400));
+    }
+
+    #[test]
+    fn connection_message_budget_caps_each_tick() {
+        let config = Kcp2KConfig {
+            connection_message_budget: 5,
+            ..Kcp2KConfig::default()
+        };
+        // 剩下的消息在之后的 tick 中处理
+        assert_eq!(counts(&per_tick(config, 1, 20, 8)), vec![5, 5, 5, 5]);
+    }
+
+    #[test]
+    fn connection_byte_budget_caps_each_tick() {
+        let config = Kcp2KConfig {
+            connection_byte_budget: 100,
+            ..Kcp2KConfig::default()
+        };
+        // 每条消息加上头部 41 字节，第三条超出预算但仍被处理
+        assert_eq!(counts(&per_tick(config, 1, 9, 40)), vec![3, 3, 3]);
+    }
+
+    #[test]
+    fn tick_message_budget_is_shared_by_connections() {
+        let config = Kcp2KConfig {
+            tick_message_budget: 6,
+            ..Kcp2KConfig::default()
+        };
+        let ticks = per_tick(config, 2, 9, 8);
+        assert_eq!(counts(&ticks), vec![6, 6, 6]);
+    }
+
+    #[test]
+    fn tick_byte_budget_is_shared_by_connections() {
+        let config = Kcp2KConfig {
+            tick_byte_budget: 100,
+            ..Kcp2KConfig::default()
+        };
+        let ticks = per_tick(config, 2, 6, 40);
+        assert_eq!(counts(&ticks), vec![3, 3, 3, 3]);
+    }
+}
@@ -1,5 +1,6 @@
 use crate::error_code::ErrorCode;
 use crate::kcp2k_channel::Kcp2KChannel;
+use crate::kcp2k_disconnect_reason::DisconnectReason;
 use bytes::Bytes;
 use std::fmt::{Debug, Formatter};
 
@@ -9,6 +10,8 @@ pub enum CallbackType {
     OnData,
     OnDisconnected,
     OnError,
+    OnAddressChanged,
+    OnRpc, // RPC 帧，交给 Kcp2KRpc::handle 处理
 }
 
 // Callback: 服务器回调
@@ -19,6 +22,8 @@ pub struct Callback {
     pub channel: Kcp2KChannel,
     pub error_code: ErrorCode,
     pub error_message: String,
+    pub disconnect_reason: DisconnectReason, // OnDisconnected：断开的原因
+    pub disconnect_message: String,          // OnDisconnected：对方或本地附带的说明
 }
 impl Debug for Callback {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
@@ -34,7 +39,17 @@ impl Debug for Callback {
                 )
             }
             CallbackType::OnDisconnected => {
-                write!(f, "OnDisconnected: id {}", self.conn_id)
+                write!(
+                    f,
+                    "OnDisconnected: id {} {:?} {}",
+                    self.conn_id, self.disconnect_reason, self.disconnect_message
+                )
+            }
+            CallbackType::OnRpc => {
+                write!(f, "OnRpc: id {} {:?}", self.conn_id, self.data)
+            }
+            CallbackType::OnAddressChanged => {
+                write!(f, "OnAddressChanged: id {}", self.conn_id)
             }
             CallbackType::OnError => {
                 write!(
@@ -56,6 +71,8 @@ impl Default for Callback {
             channel: Kcp2KChannel::None,
             error_code: ErrorCode::None,
             error_message: "None".to_string(),
+            disconnect_reason: DisconnectReason::None,
+            disconnect_message: String::new(),
         }
     }
 }
@@ -0,0 +1,574 @@
+use socket2::SockAddr;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufWriter, Error, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tklog::error;
+
+// pcapng 块类型
+const BLOCK_SECTION_HEADER: u32 = 0x0A0D0D0A;
+const BLOCK_INTERFACE_DESCRIPTION: u32 = 0x00000001;
+const BLOCK_ENHANCED_PACKET: u32 = 0x00000006;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B3C4D;
+// 链路类型 RAW：数据包直接以 IPv4/IPv6 头部开始
+const LINKTYPE_RAW: u16 = 101;
+const SNAP_LEN: u32 = 65535;
+// 选项
+const OPT_END: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+const OPT_EPB_FLAGS: u16 = 2;
+// 合成头部的长度
+const IPV4_HEADER_SIZE: usize = 20;
+const IPV6_HEADER_SIZE: usize = 40;
+const UDP_HEADER_SIZE: usize = 8;
+const IP_PROTOCOL_UDP: u8 = 17;
+
+// Kcp2KCaptureConfig: 抓包配置
+#[derive(Debug, Clone)]
+pub struct Kcp2KCaptureConfig {
+    pub path: PathBuf, // pcapng 文件路径，轮转后的文件名为 name.1.pcapng、name.2.pcapng ...
+    pub connections: Option<Vec<u64>>, // 只记录这些连接的数据包，None 表示记录全部
+    pub max_file_size: u64, // 单个文件的最大字节数，超过后轮转，0 表示不轮转
+    pub max_files: usize, // 最多保留的文件数，超出时删除最旧的文件，0 表示全部保留
+}
+
+impl Kcp2KCaptureConfig {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            connections: None,
+            max_file_size: 0,
+            max_files: 0,
+        }
+    }
+}
+
+// 数据包方向，对应 pcapng 的 epb_flags
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Direction {
+    Inbound = 1,
+    Outbound = 2,
+}
+
+// Kcp2KCapture: 由 Kcp2K 和它的所有连接共享，未启动时只有一次原子读取的开销
+#[derive(Debug, Default)]
+pub(crate) struct Kcp2KCapture {
+    active: AtomicBool,
+    writer: Mutex<Option<CaptureWriter>>,
+}
+
+impl Kcp2KCapture {
+    pub(crate) fn start(
+        &self,
+        config: Kcp2KCaptureConfig,
+        local_addr: SocketAddr,
+    ) -> Result<(), Error> {
+        let writer = CaptureWriter::open(config, local_addr)?;
+        *self.lock_writer() = Some(writer);
+        self.active.store(true, Ordering::Release);
+        Ok(())
+    }
+    pub(crate) fn stop(&self) -> Result<(), Error> {
+        self.active.store(false, Ordering::Release);
+        match self.lock_writer().take() {
+            Some(mut writer) => writer.file.flush(),
+            None => Ok(()),
+        }
+    }
+    pub(crate) fn is_active(&self) -> bool {
+        self.active.load(Ordering::Acquire)
+    }
+    // 记录一个数据报，parts 依次拼接为 UDP 载荷
+    pub(crate) fn record(
+        &self,
+        direction: Direction,
+        connection_id: Option<u64>,
+        remote_addr: &SockAddr,
+        parts: &[&[u8]],
+    ) {
+        if !self.is_active() {
+            return;
+        }
+        let remote_addr = match remote_addr.as_socket() {
+            Some(remote_addr) => remote_addr,
+            None => return,
+        };
+        if let Some(writer) = self.lock_writer().as_mut() {
+            if let Err(err) = writer.write_packet(direction, connection_id, remote_addr, parts) {
+                error!(format!("[KCP2K] Capture write failed: {:?}", err));
+            }
+        }
+    }
+    // 把缓冲区写入文件，在每次 tick 后调用
+    pub(crate) fn flush(&self) {
+        if !self.is_active() {
+            return;
+        }
+        if let Some(writer) = self.lock_writer().as_mut() {
+            let _ = writer.file.flush();
+        }
+    }
+    fn lock_writer(&self) -> MutexGuard<'_, Option<CaptureWriter>> {
+        match self.writer.lock() {
+            Ok(writer) => writer,
+            Err(err) => err.into_inner(),
+        }
+    }
+}
+
+// Kcp2KCaptureTap: 连接持有的抓包入口，带上连接 ID 以便过滤，由 Kcp2K 创建
+#[derive(Debug, Clone)]
+pub struct Kcp2KCaptureTap {
+    capture: Arc<Kcp2KCapture>,
+    connection_id: u64,
+}
+
+impl Kcp2KCaptureTap {
+    pub(crate) fn new(capture: Arc<Kcp2KCapture>, connection_id: u64) -> Self {
+        Self {
+            capture,
+            connection_id,
+        }
+    }
+    pub(crate) fn is_active(&self) -> bool {
+        self.capture.is_active()
+    }
+    pub(crate) fn record(&self, direction: Direction, remote_addr: &SockAddr, parts: &[&[u8]]) {
+        self.capture
+            .record(direction, Some(self.connection_id), remote_addr, parts);
+    }
+}
+
+#[derive(Debug)]
+struct CaptureWriter {
+    config: Kcp2KCaptureConfig,
+    connections: Option<HashSet<u64>>,
+    local_addr: SocketAddr,
+    file: BufWriter<File>,
+    file_size: u64,
+    file_index: usize,
+}
+
+impl CaptureWriter {
+    fn open(config: Kcp2KCaptureConfig, local_addr: SocketAddr) -> Result<Self, Error> {
+        let mut file = BufWriter::new(File::create(&config.path)?);
+        let file_size = Self::write_header(&mut file)?;
+        Ok(Self {
+            connections: config
+                .connections
+                .as_ref()
+                .map(|connections| connections.iter().copied().collect()),
+            config,
+            local_addr,
+            file,
+            file_size,
+            file_index: 0,
+        })
+    }
+    // 轮转文件的路径：第 0 个是配置的路径，之后是 name.N.ext
+    fn rotated_path(path: &Path, index: usize) -> PathBuf {
+        if index == 0 {
+            return path.to_path_buf();
+        }
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let name = match path.extension() {
+            Some(extension) => format!("{}.{}.{}", stem, index, extension.to_string_lossy()),
+            None => format!("{}.{}", stem, index),
+        };
+        path.with_file_name(name)
+    }
+    fn rotate(&mut self) -> Result<(), Error> {
+        self.file.flush()?;
+        self.file_index += 1;
+        let path = Self::rotated_path(&self.config.path, self.file_index);
+        self.file = BufWriter::new(File::create(path)?);
+        self.file_size = Self::write_header(&mut self.file)?;
+        // 删除超出保留数量的旧文件
+        if self.config.max_files > 0 && self.file_index >= self.config.max_files {
+            let oldest = self.file_index - self.config.max_files;
+            let _ = std::fs::remove_file(Self::rotated_path(&self.config.path, oldest));
+        }
+        Ok(())
+    }
+    // Section Header Block + Interface Description Block
+    fn write_header(file: &mut impl Write) -> Result<u64, Error> {
+        let mut block = Vec::with_capacity(48);
+        // SHB
+        block.extend_from_slice(&BLOCK_SECTION_HEADER.to_le_bytes());
+        block.extend_from_slice(&28u32.to_le_bytes());
+        block.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        block.extend_from_slice(&1u16.to_le_bytes());
+        block.extend_from_slice(&0u16.to_le_bytes());
+        block.extend_from_slice(&(-1i64).to_le_bytes());
+        block.extend_from_slice(&28u32.to_le_bytes());
+        // IDB，时间戳精度使用默认的微秒
+        block.extend_from_slice(&BLOCK_INTERFACE_DESCRIPTION.to_le_bytes());
+        block.extend_from_slice(&20u32.to_le_bytes());
+        block.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        block.extend_from_slice(&0u16.to_le_bytes());
+        block.extend_from_slice(&SNAP_LEN.to_le_bytes());
+        block.extend_from_slice(&20u32.to_le_bytes());
+        file.write_all(&block)?;
+        Ok(block.len() as u64)
+    }
+    fn write_packet(
+        &mut self,
+        direction: Direction,
+        connection_id: Option<u64>,
+        remote_addr: SocketAddr,
+        parts: &[&[u8]],
+    ) -> Result<(), Error> {
+        if let Some(connections) = &self.connections {
+            match connection_id {
+                Some(connection_id) if connections.contains(&connection_id) => {}
+                _ => return Ok(()),
+            }
+        }
+        let (source, destination) = match direction {
+            Direction::Inbound => (remote_addr, self.local_addr),
+            Direction::Outbound => (self.local_addr, remote_addr),
+        };
+        let packet = synthesize_packet(source, destination, parts);
+        let block = enhanced_packet_block(direction, connection_id, &packet);
+        if self.config.max_file_size > 0
+            && self.file_size + block.len() as u64 > self.config.max_file_size
+        {
+            self.rotate()?;
+        }
+        self.file.write_all(&block)?;
+        self.file_size += block.len() as u64;
+        Ok(())
+    }
+}
+
+fn padding(len: usize) -> usize {
+    (4 - len % 4) % 4
+}
+
+fn put_option(block: &mut Vec<u8>, code: u16, value: &[u8]) {
+    block.extend_from_slice(&code.to_le_bytes());
+    block.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    block.extend_from_slice(value);
+    block.resize(block.len() + padding(value.len()), 0);
+}
+
+// Enhanced Packet Block，附带方向和连接 ID
+fn enhanced_packet_block(
+    direction: Direction,
+    connection_id: Option<u64>,
+    packet: &[u8],
+) -> Vec<u8> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64;
+    let captured_len = packet.len().min(SNAP_LEN as usize);
+    let mut block = Vec::with_capacity(64 + captured_len);
+    block.extend_from_slice(&BLOCK_ENHANCED_PACKET.to_le_bytes());
+    block.extend_from_slice(&0u32.to_le_bytes()); // 总长度，稍后填写
+    block.extend_from_slice(&0u32.to_le_bytes()); // interface id
+    block.extend_from_slice(&((timestamp >> 32) as u32).to_le_bytes());
+    block.extend_from_slice(&(timestamp as u32).to_le_bytes());
+    block.extend_from_slice(&(captured_len as u32).to_le_bytes());
+    block.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    block.extend_from_slice(&packet[..captured_len]);
+    block.resize(block.len() + padding(captured_len), 0);
+    put_option(&mut block, OPT_EPB_FLAGS, &(direction as u32).to_le_bytes());
+    if let Some(connection_id) = connection_id {
+        put_option(
+            &mut block,
+            OPT_COMMENT,
+            format!("kcp2k connection {}", connection_id).as_bytes(),
+        );
+    }
+    put_option(&mut block, OPT_END, &[]);
+    let total_len = (block.len() + 4) as u32;
+    block.extend_from_slice(&total_len.to_le_bytes());
+    block[4..8].copy_from_slice(&total_len.to_le_bytes());
+    block
+}
+
+// 合成 IP/UDP 头部。任一端是 IPv6 时使用 IPv6（IPv4 地址映射为 ::ffff:a.b.c.d）
+fn synthesize_packet(source: SocketAddr, destination: SocketAddr, parts: &[&[u8]]) -> Vec<u8> {
+    let payload_len: usize = parts.iter().map(|part| part.len()).sum();
+    let udp_len = UDP_HEADER_SIZE + payload_len;
+    let mut packet = Vec::with_capacity(IPV6_HEADER_SIZE + udp_len);
+    match (source.ip(), destination.ip()) {
+        (IpAddr::V4(source_ip), IpAddr::V4(destination_ip)) => {
+            let total_len = (IPV4_HEADER_SIZE + udp_len) as u16;
+            packet.extend_from_slice(&[0x45, 0]);
+            packet.extend_from_slice(&total_len.to_be_bytes());
+            packet.extend_from_slice(&[0, 0, 0x40, 0, 64, IP_PROTOCOL_UDP, 0, 0]);
+            packet.extend_from_slice(&source_ip.octets());
+            packet.extend_from_slice(&destination_ip.octets());
+            let checksum = ipv4_checksum(&packet);
+            packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+        }
+        (source_ip, destination_ip) => {
+            let to_v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            packet.extend_from_slice(&[0x60, 0, 0, 0]);
+            packet.extend_from_slice(&(udp_len as u16).to_be_bytes());
+            packet.extend_from_slice(&[IP_PROTOCOL_UDP, 64]);
+            packet.extend_from_slice(&to_v6(source_ip).octets());
+            packet.extend_from_slice(&to_v6(destination_ip).octets());
+        }
+    }
+    // UDP 头部，校验和为 0（不校验）
+    packet.extend_from_slice(&source.port().to_be_bytes());
+    packet.extend_from_slice(&destination.port().to_be_bytes());
+    packet.extend_from_slice(&(udp_len as u16).to_be_bytes());
+    packet.extend_from_slice(&[0, 0]);
+    for part in parts {
+        packet.extend_from_slice(part);
+    }
+    packet
+}
+
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = header
+        .chunks(2)
+        .map(|word| u16::from_be_bytes([word[0], word[1]]) as u32)
+        .sum();
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kcp2k_channel::Kcp2KChannel;
+    use crate::kcp2k_config::Kcp2KConfig;
+    use crate::kcp2k_testing::{addr, ignore, Kcp2KTestLink};
+    use bytes::Bytes;
+    use std::time::Duration;
+
+    // 每个测试使用自己的临时目录
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("kcp2k-capture-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn read_u32(data: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn read_u16(data: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+    }
+
+    // 按块拆分 pcapng 文件：块类型和完整的块
+    fn blocks(path: &Path) -> Vec<(u32, Vec<u8>)> {
+        let data = std::fs::read(path).unwrap();
+        let mut blocks = Vec::new();
+        let mut offset = 0;
+        while offset < data.len() {
+            let block_type = read_u32(&data, offset);
+            let total_len = read_u32(&data, offset + 4) as usize;
+            assert_eq!(total_len % 4, 0);
+            let block = data[offset..offset + total_len].to_vec();
+            // 块的首尾都记录总长度
+            assert_eq!(read_u32(&block, total_len - 4) as usize, total_len);
+            blocks.push((block_type, block));
+            offset += total_len;
+        }
+        blocks
+    }
+
+    #[derive(Debug)]
+    struct Packet {
+        flags: u32,
+        comment: Option<String>,
+        ip: Vec<u8>,
+    }
+
+    impl Packet {
+        fn udp_payload(&self) -> &[u8] {
+            &self.ip[IPV4_HEADER_SIZE + UDP_HEADER_SIZE..]
+        }
+    }
+
+    // 文件以 SHB 和 IDB 开始，之后都是 EPB
+    fn packets(path: &Path) -> Vec<Packet> {
+        let blocks = blocks(path);
+        assert!(blocks.len() >= 2);
+        assert_eq!(blocks[0].0, BLOCK_SECTION_HEADER);
+        assert_eq!(read_u32(&blocks[0].1, 8), BYTE_ORDER_MAGIC);
+        assert_eq!(blocks[1].0, BLOCK_INTERFACE_DESCRIPTION);
+        assert_eq!(read_u16(&blocks[1].1, 8), LINKTYPE_RAW);
+        blocks[2..]
+            .iter()
+            .map(|(block_type, block)| {
+                assert_eq!(*block_type, BLOCK_ENHANCED_PACKET);
+                let captured_len = read_u32(block, 20) as usize;
+                assert_eq!(read_u32(block, 24) as usize, captured_len);
+                let ip = block[28..28 + captured_len].to_vec();
+                let mut packet = Packet {
+                    flags: 0,
+                    comment: None,
+                    ip,
+                };
+                let mut offset = 28 + captured_len + padding(captured_len);
+                loop {
+                    let code = read_u16(block, offset);
+                    let len = read_u16(block, offset + 2) as usize;
+                    let value = &block[offset + 4..offset + 4 + len];
+                    match code {
+                        OPT_END => break,
+                        OPT_EPB_FLAGS => packet.flags = read_u32(value, 0),
+                        OPT_COMMENT => {
+                            packet.comment = Some(String::from_utf8(value.to_vec()).unwrap())
+                        }
+                        _ => panic!("unexpected option {}", code),
+                    }
+                    offset += 4 + len + padding(len);
+                }
+                assert_eq!(offset + 4 + 4, block.len());
+                packet
+            })
+            .collect()
+    }
+
+    fn send_hello(link: &mut Kcp2KTestLink) {
+        link.client
+            .send(
+                link.client_id,
+                Bytes::from_static(b"hello"),
+                Kcp2KChannel::Unreliable,
+            )
+            .unwrap();
+        link.server
+            .send(
+                link.server_id(),
+                Bytes::from_static(b"world"),
+                Kcp2KChannel::Unreliable,
+            )
+            .unwrap();
+        link.run(Duration::from_millis(50));
+    }
+
+    #[test]
+    fn session_is_captured_as_pcapng() {
+        let dir = temp_dir("session");
+        let path = dir.join("session.pcapng");
+        let mut link = Kcp2KTestLink::new(Kcp2KConfig::default(), ignore, ignore);
+        link.server
+            .start_capture(Kcp2KCaptureConfig::new(&path))
+            .unwrap();
+        link.run(Duration::from_millis(200));
+        send_hello(&mut link);
+        link.server.stop_capture().unwrap();
+        let packets = packets(&path);
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let comment = format!("kcp2k connection {}", link.server_id());
+        // 第一个 Hello 到达时连接还不存在，没有连接 ID
+        assert_eq!(packets[0].flags, Direction::Inbound as u32);
+        assert_eq!(packets[0].comment, None);
+        assert!(packets[1..]
+            .iter()
+            .all(|packet| packet.comment.as_deref() == Some(comment.as_str())));
+        let local_port = link.server.get_local_addr().unwrap().port();
+        for packet in &packets {
+            // 合成的 IPv4 头部校验和正确，UDP 端口与方向一致
+            assert_eq!(packet.ip[0], 0x45);
+            assert_eq!(ipv4_checksum(&packet.ip[..IPV4_HEADER_SIZE]), 0);
+            let source_port = u16::from_be_bytes([packet.ip[20], packet.ip[21]]);
+            let destination_port = u16::from_be_bytes([packet.ip[22], packet.ip[23]]);
+            match packet.flags {
+                1 => assert_eq!((source_port, destination_port), (50000, local_port)),
+                2 => assert_eq!((source_port, destination_port), (local_port, 50000)),
+                flags => panic!("unexpected direction {}", flags),
+            }
+        }
+        let contains = |flags: u32, data: &[u8]| {
+            packets
+                .iter()
+                .any(|packet| packet.flags == flags && packet.udp_payload().ends_with(data))
+        };
+        assert!(contains(Direction::Inbound as u32, b"hello"));
+        assert!(contains(Direction::Outbound as u32, b"world"));
+    }
+
+    #[test]
+    fn capture_is_filtered_by_connection() {
+        let dir = temp_dir("filter");
+        let mut link = Kcp2KTestLink::connected(Kcp2KConfig::default(), ignore, ignore);
+        let captures = [
+            ("all.pcapng", None),
+            ("own.pcapng", Some(vec![link.server_id()])),
+            ("other.pcapng", Some(vec![link.server_id() + 1])),
+        ];
+        let mut counts = Vec::new();
+        for (name, connections) in captures {
+            let path = dir.join(name);
+            let mut config = Kcp2KCaptureConfig::new(&path);
+            config.connections = connections;
+            link.server.start_capture(config).unwrap();
+            send_hello(&mut link);
+            // 不属于任何连接的数据报
+            link.server
+                .push_inbound(addr("192.0.2.1:9999"), Bytes::from_static(b"junk"));
+            link.run(Duration::from_millis(10));
+            link.server.stop_capture().unwrap();
+            let packets = packets(&path);
+            let unknown = packets
+                .iter()
+                .filter(|packet| packet.comment.is_none())
+                .count();
+            counts.push((packets.len() - unknown, unknown));
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert!(counts[0].0 > 0);
+        assert_eq!(counts[0].1, 1);
+        assert!(counts[1].0 > 0);
+        assert_eq!(counts[1].1, 0);
+        assert_eq!(counts[2], (0, 0));
+    }
+
+    #[test]
+    fn rotation_removes_oldest_files() {
+        let dir = temp_dir("rotation");
+        let path = dir.join("rotated.pcapng");
+        let capture = Kcp2KCapture::default();
+        let mut config = Kcp2KCaptureConfig::new(&path);
+        // 每个文件只能容纳一个数据包
+        config.max_file_size = 250;
+        config.max_files = 2;
+        capture
+            .start(config, "10.0.0.1:7777".parse().unwrap())
+            .unwrap();
+        let remote_addr = addr("10.0.0.2:50000");
+        for i in 0..5u8 {
+            capture.record(Direction::Inbound, Some(1), &remote_addr, &[&[i; 64]]);
+        }
+        capture.stop().unwrap();
+
+        let path_of = |index| CaptureWriter::rotated_path(&path, index);
+        assert_eq!(path_of(3), dir.join("rotated.3.pcapng"));
+        for index in 0..3 {
+            assert!(!path_of(index).exists(), "{:?}", path_of(index));
+        }
+        // 最新的两个文件各有一个数据包，都以 SHB 和 IDB 开始
+        for index in 3..5 {
+            let packets = packets(&path_of(index));
+            assert_eq!(packets.len(), 1);
+            assert_eq!(packets[0].udp_payload(), &[index as u8; 64]);
+        }
+        let files = std::fs::read_dir(&dir).unwrap().count();
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(files, 2);
+    }
+}
@@ -0,0 +1,467 @@
+use crate::kcp2k_channel::Kcp2KChannel;
+use crate::kcp2k_config::Kcp2KConfig;
+use crate::kcp2k_connection::Kcp2KConnection;
+use crate::kcp2k_header::{Kcp2KHeaderReliable, Kcp2KHeaderUnreliable};
+use crate::kcp2k_packet;
+use crate::kcp2k_packet::Kcp2KPacket;
+use crate::kcp2k_peer::Kcp2KPeer;
+use bytes::Bytes;
+use kcp::Kcp;
+use std::cell::RefCell;
+use std::io::{Error, ErrorKind, Write};
+use std::rc::Rc;
+
+// 本库线上格式的回归向量，以及 C# kcp2k 兼容模式（Kcp2KConfig::csharp_compat）
+//
+// 每个向量是一个完整的 UDP 数据报：
+//   可靠：通道(1) + cookie(4) + KCP 段头(24) + 头部(1) + 载荷
+//   不可靠：通道(1) + cookie(4) + 头部(1) + 载荷
+// 可靠消息是新建 KCP（conv=0、默认窗口 128）发出的第一个分段。
+//
+// 这些向量都不是从 C# kcp2k 抓包得到的：csharp 为 true 的向量是按 C# kcp2k 的源码手写的，
+// 其余是本库的扩展。它们只能防止本库的编码器和解码器偏离这些字节，不能证明与 C# kcp2k 互通。
+// 有了 C# kcp2k 对端的抓包后，用 kcp2k-dump --hex 导出对应的数据报替换 bytes，并在 source 中写明
+// 对端版本和抓包方式；编码校验使用向量自己的 cookie 和 KCP 时间戳，替换时不需要改动其他代码。
+// 在替换为抓包之前，兼容模式只保证本库不发送 C# kcp2k 没有的消息，与 C# 对端的互通没有经过测试。
+// TODO: 需要 C# kcp2k/Unity 对端的抓包。在此之前这里不是参考实现的黄金向量，不能当作 C# 兼容性的验证。
+// 修改线上格式的代码必须让本文件的测试继续通过
+
+// 手写向量使用的握手 cookie
+pub const VECTOR_COOKIE: [u8; 4] = [0x2a, 0x9c, 0x71, 0x05];
+// 手写的可靠向量中 KCP 段的时间戳，单位为毫秒
+pub const VECTOR_TIME: u32 = 1000;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Kcp2KWireVector {
+    pub name: &'static str,
+    pub channel: Kcp2KChannel,
+    pub header: u8,
+    pub payload: &'static [u8],
+    // C# kcp2k 是否有这种消息（不表示字节来自 C#），false 表示本库的扩展，兼容模式下不会发送
+    pub csharp: bool,
+    // 向量的来源：手写的依据，或者抓包的对端版本和抓包方式
+    pub source: &'static str,
+    pub bytes: &'static [u8],
+}
+
+pub const WIRE_VECTORS: [Kcp2KWireVector; 11] = [
+    Kcp2KWireVector {
+        name: "reliable hello",
+        channel: Kcp2KChannel::Reliable,
+        header: Kcp2KHeaderReliable::Hello as u8,
+        payload: b"",
+        csharp: true,
+        source: "手写，未与 C# 对端核对：按 C# kcp2k KcpPeer.SendHello 和 Kcp.Send 的源码推导",
+        bytes: &[
+            0x01, 0x2a, 0x9c, 0x71, 0x05, // 通道 + cookie
+            0x00, 0x00, 0x00, 0x00, // conv
+            0x51, 0x00, 0x80, 0x00, // cmd=PUSH frg=0 wnd=128
+            0xe8, 0x03, 0x00, 0x00, // ts=1000
+            0x00, 0x00, 0x00, 0x00, // sn=0
+            0x00, 0x00, 0x00, 0x00, // una=0
+            0x01, 0x00, 0x00, 0x00, // len=1
+            0x01, // Hello
+        ],
+    },
+    // 本库的 Hello 带有协议版本和功能（kcp2k_protocol），C# kcp2k 收到后忽略载荷
+    Kcp2KWireVector {
+        name: "reliable hello v1",
+        channel: Kcp2KChannel::Reliable,
+        header: Kcp2KHeaderReliable::Hello as u8,
+        payload: &[0x01, 0x00, 0x03, 0x00, 0x00, 0x00],
+        csharp: false,
+        source: "本库的扩展：Kcp2KHello::encode，见 kcp2k_protocol",
+        bytes: &[
+            0x01, 0x2a, 0x9c, 0x71, 0x05, // 通道 + cookie
+            0x00, 0x00, 0x00, 0x00, // conv
+            0x51, 0x00, 0x80, 0x00, // cmd=PUSH frg=0 wnd=128
+            0xe8, 0x03, 0x00, 0x00, // ts=1000
+            0x00, 0x00, 0x00, 0x00, // sn=0
+            0x00, 0x00, 0x00, 0x00, // una=0
+            0x07, 0x00, 0x00, 0x00, // len=7
+            0x01, // Hello
+            0x01, 0x00, 0x03, 0x00, 0x00, 0x00, // version=1 capabilities=0x3
+        ],
+    },
+    Kcp2KWireVector {
+        name: "reliable ping",
+        channel: Kcp2KChannel::Reliable,
+        header: Kcp2KHeaderReliable::Ping as u8,
+        payload: b"",
+        csharp: true,
+        source: "手写，未与 C# 对端核对：按 C# kcp2k KcpPeer.SendPing 和 Kcp.Send 的源码推导",
+        bytes: &[
+            0x01, 0x2a, 0x9c, 0x71, 0x05, // 通道 + cookie
+            0x00, 0x00, 0x00, 0x00, // conv
+            0x51, 0x00, 0x80, 0x00, // cmd=PUSH frg=0 wnd=128
+            0xe8, 0x03, 0x00, 0x00, // ts=1000
+            0x00, 0x00, 0x00, 0x00, // sn=0
+            0x00, 0x00, 0x00, 0x00, // una=0
+            0x01, 0x00, 0x00, 0x00, // len=1
+            0x02, // Ping
+        ],
+    },
+    Kcp2KWireVector {
+        name: "reliable data",
+        channel: Kcp2KChannel::Reliable,
+        header: Kcp2KHeaderReliable::Data as u8,
+        payload: b"hello",
+        csharp: true,
+        source:
+            "手写，未与 C# 对端核对：按 C# kcp2k KcpPeer.SendData(Reliable) 和 Kcp.Send 的源码推导",
+        bytes: &[
+            0x01, 0x2a, 0x9c, 0x71, 0x05, // 通道 + cookie
+            0x00, 0x00, 0x00, 0x00, // conv
+            0x51, 0x00, 0x80, 0x00, // cmd=PUSH frg=0 wnd=128
+            0xe8, 0x03, 0x00, 0x00, // ts=1000
+            0x00, 0x00, 0x00, 0x00, // sn=0
+            0x00, 0x00, 0x00, 0x00, // una=0
+            0x06, 0x00, 0x00, 0x00, // len=6
+            0x03, b'h', b'e', b'l', b'l', b'o', // Data + 载荷
+        ],
+    },
+    Kcp2KWireVector {
+        name: "unreliable data",
+        channel: Kcp2KChannel::Unreliable,
+        header: Kcp2KHeaderUnreliable::Data as u8,
+        payload: b"hello",
+        csharp: true,
+        source: "手写，未与 C# 对端核对：按 C# kcp2k KcpPeer.SendData(Unreliable) 的源码推导",
+        bytes: &[
+            0x02, 0x2a, 0x9c, 0x71, 0x05, // 通道 + cookie
+            0x04, b'h', b'e', b'l', b'l', b'o', // Data + 载荷
+        ],
+    },
+    // C# kcp2k 的 ping 总是走可靠通道，收到这个头部会断开连接
+    Kcp2KWireVector {
+        name: "unreliable ping",
+        channel: Kcp2KChannel::Unreliable,
+        header: Kcp2KHeaderUnreliable::Ping as u8,
+        payload: b"",
+        csharp: false,
+        source: "本库的扩展：Kcp2KCapabilities::UNRELIABLE_PING",
+        bytes: &[
+            0x02, 0x2a, 0x9c, 0x71, 0x05, // 通道 + cookie
+            0x06, // Ping
+        ],
+    },
+    // 带时间戳的 ping（kcp2k_rtt）：seq=1、发送时间 1s，C# kcp2k 收到后忽略载荷
+    Kcp2KWireVector {
+        name: "reliable ping timestamped",
+        channel: Kcp2KChannel::Reliable,
+        header: Kcp2KHeaderReliable::Ping as u8,
+        payload: &[
+            0x01, 0x00, 0x00, 0x00, 0x40, 0x42, 0x0f, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ],
+        csharp: false,
+        source: "本库的扩展：Kcp2KCapabilities::TIMESTAMPED_PING，见 kcp2k_rtt",
+        bytes: &[
+            0x01, 0x2a, 0x9c, 0x71, 0x05, // 通道 + cookie
+            0x00, 0x00, 0x00, 0x00, // conv
+            0x51, 0x00, 0x80, 0x00, // cmd=PUSH frg=0 wnd=128
+            0xe8, 0x03, 0x00, 0x00, // ts=1000
+            0x00, 0x00, 0x00, 0x00, // sn=0
+            0x00, 0x00, 0x00, 0x00, // una=0
+            0x0d, 0x00, 0x00, 0x00, // len=13
+            0x02, // Ping
+            0x01, 0x00, 0x00, 0x00, // seq=1
+            0x40, 0x42, 0x0f, 0x00, 0x00, 0x00, 0x00, 0x00, // 1000000 微秒
+        ],
+    },
+    // 回显上面的 ping，总是走不可靠通道
+    Kcp2KWireVector {
+        name: "unreliable pong",
+        channel: Kcp2KChannel::Unreliable,
+        header: Kcp2KHeaderUnreliable::Pong as u8,
+        payload: &[
+            0x01, 0x00, 0x00, 0x00, 0x40, 0x42, 0x0f, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ],
+        csharp: false,
+        source: "本库的扩展：Kcp2KCapabilities::TIMESTAMPED_PING，见 kcp2k_rtt",
+        bytes: &[
+            0x02, 0x2a, 0x9c, 0x71, 0x05, // 通道 + cookie
+            0x0a, // Pong
+            0x01, 0x00, 0x00, 0x00, // seq=1
+            0x40, 0x42, 0x0f, 0x00, 0x00, 0x00, 0x00, 0x00, // 1000000 微秒
+        ],
+    },
+    // 握手被拒绝：原因 VersionMismatch，没有说明
+    Kcp2KWireVector {
+        name: "unreliable reject",
+        channel: Kcp2KChannel::Unreliable,
+        header: Kcp2KHeaderUnreliable::Reject as u8,
+        payload: &[0x01],
+        csharp: false,
+        source: "本库的扩展：Kcp2KReject::encode，见 kcp2k_protocol",
+        bytes: &[
+            0x02, 0x2a, 0x9c, 0x71, 0x05, // 通道 + cookie
+            0x09, 0x01, // Reject + 原因
+        ],
+    },
+    Kcp2KWireVector {
+        name: "unreliable disconnect",
+        channel: Kcp2KChannel::Unreliable,
+        header: Kcp2KHeaderUnreliable::Disconnect as u8,
+        payload: b"",
+        csharp: true,
+        source: "手写，未与 C# 对端核对：按 C# kcp2k KcpPeer.SendDisconnect 的源码推导",
+        bytes: &[
+            0x02, 0x2a, 0x9c, 0x71, 0x05, // 通道 + cookie
+            0x05, // Disconnect
+        ],
+    },
+    // 带原因的断开：Kicked + "server full"，C# kcp2k 忽略载荷
+    Kcp2KWireVector {
+        name: "unreliable disconnect kicked",
+        channel: Kcp2KChannel::Unreliable,
+        header: Kcp2KHeaderUnreliable::Disconnect as u8,
+        payload: b"\x02server full",
+        csharp: false,
+        source: "本库的扩展：DisconnectReason::encode",
+        bytes: &[
+            0x02, 0x2a, 0x9c, 0x71, 0x05, // 通道 + cookie
+            0x05, 0x02, // Disconnect + 原因
+            0x73, 0x65, 0x72, 0x76, 0x65, 0x72, 0x20, 0x66, 0x75, 0x6c, 0x6c, // "server full"
+        ],
+    },
+];
+
+// 用本库的编码器和解码器校验所有向量，返回校验通过的向量数
+pub fn verify_wire_vectors() -> Result<usize, Error> {
+    for vector in WIRE_VECTORS.iter() {
+        verify_wire_vector(vector)?;
+    }
+    Ok(WIRE_VECTORS.len())
+}
+
+// 校验一个向量：本库编码出相同的字节，解码出相同的通道、头部和载荷
+pub fn verify_wire_vector(vector: &Kcp2KWireVector) -> Result<(), Error> {
+    let packet = Kcp2KPacket::decode(&Bytes::from_static(vector.bytes))
+        .map_err(|err| mismatch(vector, err.to_string()))?;
+    let encoded = encode(vector, &packet)?;
+    if encoded != vector.bytes {
+        return Err(mismatch(vector, format!("encoded {:02x?}", encoded)));
+    }
+    let (channel, header, payload) = decode(vector.bytes)?;
+    if channel != vector.channel || header != vector.header || payload != vector.payload {
+        return Err(mismatch(
+            vector,
+            format!("decoded {:?} {} {:02x?}", channel, header, payload),
+        ));
+    }
+    // 公开的数据报编解码必须原样还原
+    if packet.encode() != vector.bytes {
+        return Err(mismatch(vector, "packet round trip".to_string()));
+    }
+    // 客户端据此识别服务器的 Hello
+    let hello = vector.channel == Kcp2KChannel::Reliable
+        && vector.header == Kcp2KHeaderReliable::Hello.to_u8();
+    let datagram = kcp2k_packet::decode_datagram(&Bytes::from_static(vector.bytes))
+        .map_err(|err| mismatch(vector, err.to_string()))?;
+    if kcp2k_packet::is_handshake(&datagram) != hello {
+        return Err(mismatch(vector, "handshake detection".to_string()));
+    }
+    Ok(())
+}
+
+// 用向量自己的 cookie 和 KCP 时间戳编码，抓包得到的向量也能直接比较
+fn encode(vector: &Kcp2KWireVector, packet: &Kcp2KPacket) -> Result<Vec<u8>, Error> {
+    let message = Kcp2KConnection::build_message(vector.header, vector.payload);
+    let mut bytes = kcp2k_packet::encode_prefix(vector.channel, &packet.cookie()).to_vec();
+    match vector.channel {
+        Kcp2KChannel::Reliable => {
+            let ts = match packet.segments().next() {
+                Some(Ok(segment)) => segment.ts,
+                _ => return Err(mismatch(vector, "missing kcp segment".to_string())),
+            };
+            let output = VectorOutput::default();
+            let mut kcp = new_kcp(output.clone());
+            kcp.send(&message)?;
+            kcp.update(ts)?;
+            bytes.extend_from_slice(&output.0.borrow());
+        }
+        _ => bytes.extend_from_slice(&message),
+    }
+    Ok(bytes)
+}
+
+// 与 Kcp2KConnection::raw_input 相同的解析步骤，返回通道、头部和载荷
+fn decode(bytes: &'static [u8]) -> Result<(Kcp2KChannel, u8, Bytes), Error> {
+    let invalid = |message: String| Error::new(ErrorKind::InvalidData, message);
+    let datagram = kcp2k_packet::decode_datagram(&Bytes::from_static(bytes))
+        .map_err(|err| invalid(err.to_string()))?;
+    match datagram.channel {
+        Kcp2KChannel::Reliable => {
+            let mut kcp = new_kcp(VectorOutput::default());
+            kcp.input(&datagram.payload)?;
+            let mut message = vec![0u8; kcp.peeksize()?];
+            kcp.recv(&mut message)?;
+            let (header, payload) = kcp2k_packet::decode_reliable(&Bytes::from(message))
+                .map_err(|err| invalid(err.to_string()))?;
+            Ok((datagram.channel, header.to_u8(), payload))
+        }
+        _ => {
+            let (header, payload) = kcp2k_packet::decode_unreliable(&datagram.payload)
+                .map_err(|err| invalid(err.to_string()))?;
+            Ok((datagram.channel, header.to_u8(), payload))
+        }
+    }
+}
+
+fn new_kcp(output: VectorOutput) -> Kcp<VectorOutput> {
+    let mut kcp = Kcp::new(0, output);
+    Kcp2KPeer::configure_kcp(&mut kcp, &Kcp2KConfig::default());
+    kcp
+}
+
+fn mismatch(vector: &Kcp2KWireVector, detail: String) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("wire vector {:?} mismatch: {}", vector.name, detail),
+    )
+}
+
+// 收集 KCP 输出的分段
+#[derive(Debug, Default, Clone)]
+struct VectorOutput(Rc<RefCell<Vec<u8>>>);
+
+impl Write for VectorOutput {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kcp2k_disconnect_reason::DisconnectReason;
+    use crate::kcp2k_packet::KCP_CMD_PUSH;
+    use crate::kcp2k_testing::{ignore, Kcp2KTestLink};
+    use std::time::Duration;
+
+    fn vector(name: &str) -> &'static Kcp2KWireVector {
+        WIRE_VECTORS
+            .iter()
+            .find(|vector| vector.name == name)
+            .unwrap_or_else(|| panic!("no wire vector named {:?}", name))
+    }
+
+    // 每个向量一个测试
+    macro_rules! wire_vector_tests {
+        ($($test:ident: $name:literal,)*) => {
+            $(
+                #[test]
+                fn $test() {
+                    verify_wire_vector(vector($name)).unwrap();
+                }
+            )*
+
+            #[test]
+            fn every_vector_has_a_test() {
+                let tested = [$($name),*];
+                for vector in WIRE_VECTORS.iter() {
+                    assert!(tested.contains(&vector.name), "untested vector {:?}", vector.name);
+                }
+            }
+        };
+    }
+
+    wire_vector_tests! {
+        reliable_hello: "reliable hello",
+        reliable_hello_v1: "reliable hello v1",
+        reliable_ping: "reliable ping",
+        reliable_data: "reliable data",
+        unreliable_data: "unreliable data",
+        unreliable_ping: "unreliable ping",
+        reliable_ping_timestamped: "reliable ping timestamped",
+        unreliable_pong: "unreliable pong",
+        unreliable_reject: "unreliable reject",
+        unreliable_disconnect: "unreliable disconnect",
+        unreliable_disconnect_kicked: "unreliable disconnect kicked",
+    }
+
+    // 数据报中的 kcp2k 消息：通道、头部和载荷
+    fn messages(datagram: &[u8]) -> Vec<(Kcp2KChannel, u8, Vec<u8>)> {
+        let packet = Kcp2KPacket::decode(&Bytes::copy_from_slice(datagram)).unwrap();
+        match &packet {
+            Kcp2KPacket::Reliable { .. } => packet
+                .segments()
+                .map(|segment| segment.unwrap())
+                .filter(|segment| segment.cmd == KCP_CMD_PUSH && segment.frg == 0)
+                .filter_map(|segment| segment.data.split_first())
+                .map(|(&header, payload)| (Kcp2KChannel::Reliable, header, payload.to_vec()))
+                .collect(),
+            Kcp2KPacket::Unreliable {
+                header, payload, ..
+            } => vec![(Kcp2KChannel::Unreliable, header.to_u8(), payload.to_vec())],
+        }
+    }
+
+    // 与某个 csharp 向量形状相同：通道和头部相同，Data 的载荷任意，其他消息的载荷必须相同
+    fn is_csharp_shape(channel: Kcp2KChannel, header: u8, payload: &[u8]) -> bool {
+        let data = match channel {
+            Kcp2KChannel::Reliable => Kcp2KHeaderReliable::Data.to_u8(),
+            _ => Kcp2KHeaderUnreliable::Data.to_u8(),
+        };
+        WIRE_VECTORS.iter().any(|vector| {
+            vector.csharp
+                && vector.channel == channel
+                && vector.header == header
+                && (header == data || vector.payload == payload)
+        })
+    }
+
+    #[test]
+    fn csharp_compat_sends_only_csharp_shapes() {
+        // 即使要求不可靠 ping，兼容模式也只能发送可靠 ping
+        let config = Kcp2KConfig {
+            csharp_compat: true,
+            is_reliable_ping: false,
+            ping_interval: 50,
+            ..Default::default()
+        };
+        // 握手和几轮 ping
+        let mut link = Kcp2KTestLink::new(config, ignore, ignore);
+        link.run(Duration::from_millis(300));
+        let connection_id = link.server_id();
+        for channel in [Kcp2KChannel::Reliable, Kcp2KChannel::Unreliable] {
+            link.client
+                .send(link.client_id, Bytes::from_static(b"hello"), channel)
+                .unwrap();
+            link.server
+                .send(connection_id, Bytes::from_static(b"hello"), channel)
+                .unwrap();
+        }
+        link.run(Duration::from_millis(100));
+        // 带原因的断开在兼容模式下不带载荷
+        link.server
+            .disconnect(connection_id, DisconnectReason::Kicked, "server full");
+        link.run(Duration::from_millis(50));
+
+        let messages: Vec<_> = link.sent.iter().flat_map(|(_, _, d)| messages(d)).collect();
+        for (channel, header, payload) in &messages {
+            assert!(
+                is_csharp_shape(*channel, *header, payload),
+                "compat mode sent {:?} header {} payload {:02x?}",
+                channel,
+                header,
+                payload
+            );
+        }
+        // 每种 C# 消息都出现过
+        for vector in WIRE_VECTORS.iter().filter(|vector| vector.csharp) {
+            assert!(
+                messages.iter().any(|(channel, header, _)| {
+                    *channel == vector.channel && *header == vector.header
+                }),
+                "compat session never sent {:?}",
+                vector.name
+            );
+        }
+    }
+}
@@ -1,5 +1,9 @@
+use crate::kcp2k_protocol::Kcp2KCapabilities;
+use std::io::{Error, ErrorKind};
+use std::str::FromStr;
+
 // 定义 KcpConfig 结构体，用于配置 KCP 服务器
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Kcp2KConfig {
     // 使用 IPv6 和 IPv4 的双模式，不是所有平台都支持
     pub dual_mode: bool,
@@ -19,20 +23,75 @@ pub struct Kcp2KConfig {
     // 可修改的 KCP 窗口大小，以支持更高的负载
     pub send_window_size: u16,
     pub receive_window_size: u16,
-    // 超时设置，单位为毫秒
-    pub timeout: u64,
+    // 已通过验证的连接多久没有收到对方的消息后断开，单位为毫秒
+    pub idle_timeout: u64,
+    // 握手（包括异步认证）必须在连接建立后多久内完成，单位为毫秒
+    pub handshake_timeout: u64,
+    // 多久没有发送消息后发送 ping，单位为毫秒；可以用 Kcp2KConnection::set_ping_interval 按连接设置
+    pub ping_interval: u64,
+    // 即使一直在发送消息，也至少每隔多久发送一次带时间戳的 ping 测量 RTT，单位为毫秒；0 表示只在保活 ping 时测量
+    pub rtt_interval: u64,
     // 最大重传次数，直到连接被认为是断开的
     pub max_retransmits: u32,
     pub is_reliable_ping: bool,
+    // Linux 上使用 recvmmsg/sendmmsg 批量收发，其他平台自动回退到逐包收发
+    pub batch_io: bool,
+    // 每次 tick 每个连接最多处理的可靠消息数和字节数，防止单个连接占满整个 tick
+    pub connection_message_budget: usize,
+    pub connection_byte_budget: usize,
+    // 每次 tick 所有连接合计最多处理的可靠消息数和字节数
+    pub tick_message_budget: usize,
+    pub tick_byte_budget: usize,
+    // 与 C# kcp2k 兼容：只发送可靠 ping，不进行连接迁移，见 kcp2k_compat
+    pub csharp_compat: bool,
+    // 握手时通告的功能（Kcp2KCapabilities 位掩码）、要求对方必须支持的功能和最低协议版本，见 kcp2k_protocol
+    pub capabilities: u32,
+    pub required_capabilities: u32,
+    pub min_protocol_version: u16,
 }
 
 impl Kcp2KConfig {
-    pub const PING_INTERVAL: u64 = 1000;
+    // 所有字段名，与 set 接受的名字相同
+    pub const FIELDS: [&'static str; 25] = [
+        "dual_mode",
+        "recv_buffer_size",
+        "send_buffer_size",
+        "mtu",
+        "no_delay",
+        "interval",
+        "fast_resend",
+        "congestion_window",
+        "send_window_size",
+        "receive_window_size",
+        "idle_timeout",
+        "handshake_timeout",
+        "ping_interval",
+        "rtt_interval",
+        "max_retransmits",
+        "is_reliable_ping",
+        "batch_io",
+        "connection_message_budget",
+        "connection_byte_budget",
+        "tick_message_budget",
+        "tick_byte_budget",
+        "csharp_compat",
+        "capabilities",
+        "required_capabilities",
+        "min_protocol_version",
+    ];
+    // 连接迁移时每个连接发送路径挑战的最小间隔，单位为毫秒
+    pub const PATH_CHALLENGE_INTERVAL: u64 = 200;
+    // 新地址必须在多久内回应路径挑战，超时前其他地址不能发起迁移，单位为毫秒
+    pub const PATH_CHALLENGE_TIMEOUT: u64 = 1000;
+    // 多久没有收到 pong 时认为 ping 丢失，单位为毫秒
+    pub const PONG_TIMEOUT: u64 = 1000;
     pub const CHANNEL_HEADER_SIZE: usize = 1;
     pub const COOKIE_HEADER_SIZE: usize = 4;
     pub const METADATA_SIZE_RELIABLE: usize = Self::CHANNEL_HEADER_SIZE + Self::COOKIE_HEADER_SIZE;
     pub const METADATA_SIZE_UNRELIABLE: usize =
         Self::CHANNEL_HEADER_SIZE + Self::COOKIE_HEADER_SIZE;
+    // KCP 要求 MTU 至少为 50，再加上 kcp2k 的头部
+    pub const MIN_MTU: usize = 50 + Self::METADATA_SIZE_RELIABLE;
 }
 
 impl Default for Kcp2KConfig {
@@ -49,9 +108,249 @@ impl Default for Kcp2KConfig {
             congestion_window: false,
             send_window_size: 32,     // 假设这是发送窗口的默认大小
             receive_window_size: 128, // 假设这是接收窗口的默认大小
-            timeout: 2000,            // 假设这是默认的超时时间
-            max_retransmits: 20,      // 假设这是默认的最大重传次数
-            is_reliable_ping: true,   // 假设这是默认的可靠 ping
+            idle_timeout: 2000,
+            handshake_timeout: 5000,
+            ping_interval: 1000,
+            rtt_interval: 2000,
+            max_retransmits: 20,    // 假设这是默认的最大重传次数
+            is_reliable_ping: true, // 假设这是默认的可靠 ping
+            batch_io: false,
+            connection_message_budget: 1024,
+            connection_byte_budget: 1024 * 1024,
+            tick_message_budget: usize::MAX,
+            tick_byte_budget: usize::MAX,
+            csharp_compat: false,
+            capabilities: Kcp2KCapabilities::DEFAULT.bits(),
+            required_capabilities: 0,
+            min_protocol_version: 0, // 接受 C# kcp2k（版本 0）
+        }
+    }
+}
+
+impl Kcp2KConfig {
+    // 按字段名设置字段，值为字符串形式，用于命令行参数和配置文件
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), Error> {
+        fn parse<T: FromStr>(name: &str, value: &str) -> Result<T, Error> {
+            value.parse().map_err(|_| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("invalid value for {}: {}", name, value),
+                )
+            })
+        }
+        // 在副本上修改并检查，失败时不修改配置
+        let mut config = *self;
+        match name {
+            "dual_mode" => config.dual_mode = parse(name, value)?,
+            "recv_buffer_size" => config.recv_buffer_size = parse(name, value)?,
+            "send_buffer_size" => config.send_buffer_size = parse(name, value)?,
+            "mtu" => config.mtu = parse(name, value)?,
+            "no_delay" => config.no_delay = parse(name, value)?,
+            "interval" => config.interval = parse(name, value)?,
+            "fast_resend" => config.fast_resend = parse(name, value)?,
+            "congestion_window" => config.congestion_window = parse(name, value)?,
+            "send_window_size" => config.send_window_size = parse(name, value)?,
+            "receive_window_size" => config.receive_window_size = parse(name, value)?,
+            "idle_timeout" => config.idle_timeout = parse(name, value)?,
+            "handshake_timeout" => config.handshake_timeout = parse(name, value)?,
+            "ping_interval" => config.ping_interval = parse(name, value)?,
+            "rtt_interval" => config.rtt_interval = parse(name, value)?,
+            "max_retransmits" => config.max_retransmits = parse(name, value)?,
+            "is_reliable_ping" => config.is_reliable_ping = parse(name, value)?,
+            "batch_io" => config.batch_io = parse(name, value)?,
+            "connection_message_budget" => config.connection_message_budget = parse(name, value)?,
+            "connection_byte_budget" => config.connection_byte_budget = parse(name, value)?,
+            "tick_message_budget" => config.tick_message_budget = parse(name, value)?,
+            "tick_byte_budget" => config.tick_byte_budget = parse(name, value)?,
+            "csharp_compat" => config.csharp_compat = parse(name, value)?,
+            "capabilities" => config.capabilities = parse(name, value)?,
+            "required_capabilities" => config.required_capabilities = parse(name, value)?,
+            "min_protocol_version" => config.min_protocol_version = parse(name, value)?,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("unknown config field: {}", name),
+                ))
+            }
+        }
+        config.validate(name, value)?;
+        *self = config;
+        Ok(())
+    }
+
+    // 检查 KCP 无法使用的取值，name 和 value 是刚刚设置的字段，用于错误信息
+    fn validate(&self, name: &str, value: &str) -> Result<(), Error> {
+        let requirement = match name {
+            "mtu" if self.mtu < Self::MIN_MTU => format!("must be at least {}", Self::MIN_MTU),
+            "interval" if self.interval <= 0 => "must be greater than 0".to_string(),
+            "send_window_size" if self.send_window_size == 0 => {
+                "must be greater than 0".to_string()
+            }
+            // 可靠消息的最大长度按 receive_window_size - 1 个分片计算
+            "receive_window_size" if self.receive_window_size < 2 => {
+                "must be at least 2".to_string()
+            }
+            _ => return Ok(()),
+        };
+        Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid value for {}: {} ({})", name, value, requirement),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 每个字段一个与默认值不同的合法值，顺序与 FIELDS 相同
+    const VALUES: [(&str, &str); 25] = [
+        ("dual_mode", "true"),
+        ("recv_buffer_size", "7"),
+        ("send_buffer_size", "8"),
+        ("mtu", "1400"),
+        ("no_delay", "false"),
+        ("interval", "20"),
+        ("fast_resend", "2"),
+        ("congestion_window", "true"),
+        ("send_window_size", "64"),
+        ("receive_window_size", "256"),
+        ("idle_timeout", "3000"),
+        ("handshake_timeout", "4000"),
+        ("ping_interval", "500"),
+        ("rtt_interval", "600"),
+        ("max_retransmits", "30"),
+        ("is_reliable_ping", "false"),
+        ("batch_io", "true"),
+        ("connection_message_budget", "9"),
+        ("connection_byte_budget", "10"),
+        ("tick_message_budget", "11"),
+        ("tick_byte_budget", "12"),
+        ("csharp_compat", "true"),
+        ("capabilities", "13"),
+        ("required_capabilities", "14"),
+        ("min_protocol_version", "15"),
+    ];
+
+    #[test]
+    fn set_accepts_every_field() {
+        let names: Vec<&str> = VALUES.iter().map(|(name, _)| *name).collect();
+        assert_eq!(names, Kcp2KConfig::FIELDS);
+        for (name, value) in VALUES {
+            // 只修改同名字段
+            let mut config = Kcp2KConfig::default();
+            config.set(name, value).unwrap();
+            assert_ne!(config, Kcp2KConfig::default(), "{}", name);
+        }
+        let mut config = Kcp2KConfig::default();
+        for (name, value) in VALUES {
+            config.set(name, value).unwrap();
+        }
+        // 解构所有字段，新增字段时必须同时更新 FIELDS 和这里
+        let Kcp2KConfig {
+            dual_mode,
+            recv_buffer_size,
+            send_buffer_size,
+            mtu,
+            no_delay,
+            interval,
+            fast_resend,
+            congestion_window,
+            send_window_size,
+            receive_window_size,
+            idle_timeout,
+            handshake_timeout,
+            ping_interval,
+            rtt_interval,
+            max_retransmits,
+            is_reliable_ping,
+            batch_io,
+            connection_message_budget,
+            connection_byte_budget,
+            tick_message_budget,
+            tick_byte_budget,
+            csharp_compat,
+            capabilities,
+            required_capabilities,
+            min_protocol_version,
+        } = config;
+        assert!(dual_mode);
+        assert_eq!(recv_buffer_size, 7);
+        assert_eq!(send_buffer_size, 8);
+        assert_eq!(mtu, 1400);
+        assert!(!no_delay);
+        assert_eq!(interval, 20);
+        assert_eq!(fast_resend, 2);
+        assert!(congestion_window);
+        assert_eq!(send_window_size, 64);
+        assert_eq!(receive_window_size, 256);
+        assert_eq!(idle_timeout, 3000);
+        assert_eq!(handshake_timeout, 4000);
+        assert_eq!(ping_interval, 500);
+        assert_eq!(rtt_interval, 600);
+        assert_eq!(max_retransmits, 30);
+        assert!(!is_reliable_ping);
+        assert!(batch_io);
+        assert_eq!(connection_message_budget, 9);
+        assert_eq!(connection_byte_budget, 10);
+        assert_eq!(tick_message_budget, 11);
+        assert_eq!(tick_byte_budget, 12);
+        assert!(csharp_compat);
+        assert_eq!(capabilities, 13);
+        assert_eq!(required_capabilities, 14);
+        assert_eq!(min_protocol_version, 15);
+    }
+
+    #[test]
+    fn set_rejects_invalid_values_and_unknown_names() {
+        let mut config = Kcp2KConfig::default();
+        for name in Kcp2KConfig::FIELDS {
+            let err = config.set(name, "invalid").unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+            assert_eq!(
+                err.to_string(),
+                format!("invalid value for {}: invalid", name)
+            );
+        }
+        // 超出字段类型的范围
+        assert!(config.set("send_window_size", "65536").is_err());
+        assert!(config.set("mtu", "-1").is_err());
+        let err = config.set("no_such_field", "1").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert_eq!(err.to_string(), "unknown config field: no_such_field");
+        // 失败的 set 不修改配置
+        assert_eq!(config, Kcp2KConfig::default());
+    }
+
+    #[test]
+    fn set_rejects_values_kcp_cannot_use() {
+        let mut config = Kcp2KConfig::default();
+        for (name, value, requirement) in [
+            ("mtu", "0", "must be at least 55"),
+            ("mtu", "5", "must be at least 55"),
+            ("mtu", "54", "must be at least 55"),
+            ("interval", "0", "must be greater than 0"),
+            ("interval", "-10", "must be greater than 0"),
+            ("send_window_size", "0", "must be greater than 0"),
+            ("receive_window_size", "0", "must be at least 2"),
+            ("receive_window_size", "1", "must be at least 2"),
+        ] {
+            let err = config.set(name, value).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+            assert_eq!(
+                err.to_string(),
+                format!("invalid value for {}: {} ({})", name, value, requirement)
+            );
         }
+        assert_eq!(config, Kcp2KConfig::default());
+        // 边界值可以使用
+        config.set("mtu", "55").unwrap();
+        config.set("interval", "1").unwrap();
+        config.set("send_window_size", "1").unwrap();
+        config.set("receive_window_size", "2").unwrap();
+        assert_eq!(config.mtu, Kcp2KConfig::MIN_MTU);
+        assert_eq!(config.interval, 1);
+        assert_eq!(config.send_window_size, 1);
+        assert_eq!(config.receive_window_size, 2);
     }
 }
@@ -1,44 +1,93 @@
 use crate::common::Kcp2KMode;
 use crate::error_code::ErrorCode;
+use crate::kcp2k_auth::{Kcp2KAuth, Kcp2KAuthenticator};
+use crate::kcp2k_batch::SendQueue;
+use crate::kcp2k_budget::Kcp2KBudget;
 use crate::kcp2k_callback::{Callback, CallbackType};
+use crate::kcp2k_capture::{Direction, Kcp2KCaptureTap};
 use crate::kcp2k_channel::Kcp2KChannel;
 use crate::kcp2k_config::Kcp2KConfig;
+use crate::kcp2k_connection_handle::{ConnectionHandle, Kcp2KOutbox, Kcp2KOutgoing};
+use crate::kcp2k_context::Kcp2KContext;
+use crate::kcp2k_disconnect_reason::DisconnectReason;
 use crate::kcp2k_header::{Kcp2KHeaderReliable, Kcp2KHeaderUnreliable};
+use crate::kcp2k_packet;
 use crate::kcp2k_peer::Kcp2KPeer;
+use crate::kcp2k_protocol::{Kcp2KCapabilities, Kcp2KHello, Kcp2KProtocol, Kcp2KReject};
+use crate::kcp2k_rtt::{Kcp2KRtt, Kcp2KRttEstimator, PING_SIZE};
+use crate::kcp2k_socket::Kcp2KSocket;
 use crate::kcp2k_state::Kcp2KPeerState;
 use bytes::{BufMut, Bytes, BytesMut};
-use socket2::{SockAddr, Socket};
+use socket2::SockAddr;
 use std::collections::VecDeque;
-use std::sync::{Arc, Mutex};
+use std::io::IoSlice;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::Duration;
-use tklog::error;
+use tklog::{error, info};
+
+// 连接迁移：等待新地址回应的路径挑战
+#[derive(Debug)]
+struct PendingMigration {
+    sock_addr: SockAddr,  // 新地址
+    nonce: Bytes,         // 挑战随机数，重发时不变
+    start_time: Duration, // 第一次发送挑战的时间
+}
 
 // KcpServerConnection
 #[derive(Debug)]
 pub struct Kcp2KConnection {
-    socket: Arc<Socket>,
+    socket: Arc<Kcp2KSocket>,
     id: u64,
-    client_sock_addr: Arc<SockAddr>,
+    kcp2k_mode: Arc<Kcp2KMode>, // 连接的角色：Client 为主动发起，Server 为被动接受
+    client_sock_addr: Arc<RwLock<SockAddr>>,
     callback: fn(&Kcp2KConnection, Callback),
     rm_conn_ids: Arc<Mutex<VecDeque<u64>>>,
     kcp_peer: Kcp2KPeer,
     is_reliable_ping: bool,
+    receive_budget: Kcp2KBudget, // 每次 tick 的接收预算
+    pending_migration: Option<PendingMigration>,
+    last_path_challenge: Option<Duration>, // 最后发送路径挑战的时间，不论发往哪个地址
+    outbox: Arc<Kcp2KOutbox>,              // ConnectionHandle 写入的待发送消息
+    alive: Arc<AtomicBool>,                // 连接是否存活，与 ConnectionHandle 共享
+    capture: Kcp2KCaptureTap,              // 抓包
+    context: Arc<Kcp2KContext>,            // 时钟、随机数和会话录制
+    config: Arc<Kcp2KConfig>,
+    protocol: Arc<RwLock<Option<Kcp2KProtocol>>>, // 握手协商的协议版本和功能，与 ConnectionHandle 共享
+    hello_payload: Bytes,                         // 客户端：Hello 中附带的应用载荷
+    authenticator: Option<Kcp2KAuthenticator>,    // 服务器：认证钩子
+    authenticating: AtomicBool,                   // 等待异步认证完成
+    user_data: RwLock<Option<Bytes>>,             // 认证钩子接受连接时附带的用户数据
+    rejection: RwLock<Option<Kcp2KReject>>,       // 握手被拒绝的原因，本地拒绝或对方拒绝
+    ping_interval: AtomicU64,                     // 多久没有发送消息后发送 ping，单位为毫秒
+    connect_time: Duration,                       // 连接建立的时间，用于握手超时
+    rtt: Arc<Mutex<Kcp2KRttEstimator>>, // 带时间戳的 ping 测量的 RTT，与 ConnectionHandle 共享
 }
 
 impl Kcp2KConnection {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         config: Arc<Kcp2KConfig>,
         cookie: Arc<Bytes>,
-        socket: Arc<Socket>,
+        socket: Arc<Kcp2KSocket>,
+        send_queue: SendQueue,
         connection_id: u64,
         client_sock_addr: Arc<SockAddr>,
         kcp2k_mode: Arc<Kcp2KMode>,
         callback: fn(&Kcp2KConnection, Callback),
         rm_conn_ids: Arc<Mutex<VecDeque<u64>>>,
+        capture: Kcp2KCaptureTap,
+        context: Arc<Kcp2KContext>,
+        hello_payload: Bytes,
+        authenticator: Option<Kcp2KAuthenticator>,
     ) -> Self {
+        let client_sock_addr = Arc::new(RwLock::new((*client_sock_addr).clone()));
+        let ping_interval = AtomicU64::new(config.ping_interval);
+        let connect_time = context.now();
         let kcp_server_connection = Kcp2KConnection {
             socket: Arc::clone(&socket),
             id: connection_id,
+            kcp2k_mode: Arc::clone(&kcp2k_mode),
             client_sock_addr: Arc::clone(&client_sock_addr),
             callback,
             rm_conn_ids,
@@ -47,12 +96,36 @@ impl Kcp2KConnection {
                 Arc::clone(&config),
                 Arc::clone(&cookie),
                 Arc::clone(&socket),
+                send_queue,
                 Arc::clone(&client_sock_addr),
+                capture.clone(),
+                &context,
             ),
-            is_reliable_ping: config.is_reliable_ping,
+            // C# kcp2k 只有可靠 ping
+            is_reliable_ping: config.is_reliable_ping || config.csharp_compat,
+            receive_budget: Kcp2KBudget::new(
+                config.connection_message_budget,
+                config.connection_byte_budget,
+            ),
+            pending_migration: None,
+            last_path_challenge: None,
+            outbox: Arc::new(Kcp2KOutbox::new()),
+            alive: Arc::new(AtomicBool::new(true)),
+            capture,
+            context,
+            config,
+            protocol: Arc::new(RwLock::new(None)),
+            hello_payload,
+            authenticator,
+            authenticating: AtomicBool::new(false),
+            user_data: RwLock::new(None),
+            rejection: RwLock::new(None),
+            ping_interval,
+            connect_time,
+            rtt: Arc::new(Mutex::new(Kcp2KRttEstimator::default())),
         };
         if kcp2k_mode == Arc::from(Kcp2KMode::Client) {
-            let _ = kcp_server_connection.send_hello();
+            kcp_server_connection.send_hello();
         }
         kcp_server_connection
     }
@@ -65,15 +138,128 @@ impl Kcp2KConnection {
     pub fn set_connection_id(&mut self, connection_id: u64) {
         self.id = connection_id;
     }
+    // 获取可克隆到其他线程的连接句柄
+    pub fn get_handle(&self) -> ConnectionHandle {
+        ConnectionHandle::new(
+            self.id,
+            Arc::clone(&self.outbox),
+            Arc::clone(&self.alive),
+            Arc::clone(&self.client_sock_addr),
+            Arc::clone(&self.rtt),
+            Arc::clone(&self.protocol),
+            Arc::clone(&self.context),
+        )
+    }
+    // 获取连接的角色，P2P 模式下用于区分主动发起和被动接受的连接
+    pub fn get_mode(&self) -> Kcp2KMode {
+        *self.kcp2k_mode
+    }
+    // 获取会话 cookie
+    pub fn get_cookie(&self) -> Bytes {
+        self.kcp_peer.cookie.as_ref().clone()
+    }
+    // 是否未断开
+    fn is_connected(&self) -> bool {
+        match self.kcp_peer.state.try_read() {
+            Ok(state) => *state != Kcp2KPeerState::Disconnected,
+            Err(_) => false,
+        }
+    }
+    // 是否已通过验证
+    pub fn is_authenticated(&self) -> bool {
+        match self.kcp_peer.state.try_read() {
+            Ok(state) => *state == Kcp2KPeerState::Authenticated,
+            Err(_) => false,
+        }
+    }
     fn on_connected(&self) {
-        (self.callback)(
-            self,
-            Callback {
-                r#type: CallbackType::OnConnected,
-                conn_id: self.id,
-                ..Default::default()
-            },
+        self.emit(Callback {
+            r#type: CallbackType::OnConnected,
+            conn_id: self.id,
+            ..Default::default()
+        });
+    }
+    // 根据对方的 Hello 协商协议版本和功能，返回协商结果和 Hello 中的应用载荷
+    fn negotiate(&self, hello: &Bytes) -> Result<(Kcp2KProtocol, Bytes), Kcp2KReject> {
+        let (remote, payload) = Kcp2KHello::decode(hello)?;
+        let protocol =
+            Kcp2KProtocol::negotiate(&self.config, &Kcp2KHello::local(&self.config), &remote)?;
+        Ok((protocol, payload))
+    }
+    // 服务器调用认证钩子，没有钩子或主动发起的连接直接接受
+    fn authenticate(&self, payload: Bytes) -> Kcp2KAuth {
+        let authenticator = match self.authenticator {
+            Some(authenticator) if *self.kcp2k_mode == Kcp2KMode::Server => authenticator,
+            _ => return Kcp2KAuth::Accept(Bytes::new()),
+        };
+        let auth = Kcp2KContext::in_callback(|| authenticator(self, payload));
+        self.context.recorder.authenticate(self.id, &auth);
+        auth
+    }
+    // 完成认证：接受时进入 Authenticated 状态，拒绝时断开连接
+    fn complete_authentication(&self, auth: Kcp2KAuth) {
+        match auth {
+            Kcp2KAuth::Accept(user_data) => {
+                self.authenticating.store(false, Ordering::Release);
+                if let Ok(mut current) = self.user_data.write() {
+                    *current = Some(user_data);
+                }
+                self.on_authenticated();
+            }
+            Kcp2KAuth::Reject(reject) => {
+                self.authenticating.store(false, Ordering::Release);
+                self.reject(reject);
+            }
+            Kcp2KAuth::Pending => self.authenticating.store(true, Ordering::Release),
+        }
+    }
+    // 完成异步认证，连接不在等待认证时返回错误
+    pub(crate) fn resolve_authentication(&self, auth: Kcp2KAuth) -> Result<(), ErrorCode> {
+        if !self.is_connected() || !self.authenticating.load(Ordering::Acquire) {
+            return Err(ErrorCode::NotAuthenticating);
+        }
+        self.complete_authentication(auth);
+        Ok(())
+    }
+    // 是否在等待异步认证
+    pub fn is_authenticating(&self) -> bool {
+        self.authenticating.load(Ordering::Acquire)
+    }
+    // 认证钩子接受连接时附带的用户数据，认证完成前为 None
+    pub fn get_user_data(&self) -> Option<Bytes> {
+        match self.user_data.read() {
+            Ok(user_data) => user_data.clone(),
+            Err(err) => err.into_inner().clone(),
+        }
+    }
+    // 握手被拒绝的原因，可在 OnError 和 OnDisconnected 回调中读取
+    pub fn get_rejection(&self) -> Option<Kcp2KReject> {
+        match self.rejection.read() {
+            Ok(rejection) => rejection.clone(),
+            Err(err) => err.into_inner().clone(),
+        }
+    }
+    fn set_rejection(&self, reject: &Kcp2KReject) {
+        if let Ok(mut rejection) = self.rejection.write() {
+            *rejection = Some(reject.clone());
+        }
+    }
+    // 拒绝握手：把原因告诉对方，然后断开连接
+    fn reject(&self, reject: Kcp2KReject) {
+        self.set_rejection(&reject);
+        self.on_error(
+            ErrorCode::Rejected,
+            format!(
+                "{}: Rejected handshake: {}.",
+                std::any::type_name::<Self>(),
+                reject
+            ),
         );
+        let payload = reject.encode();
+        for _ in 0..5 {
+            let _ = self.send_unreliable(Kcp2KHeaderUnreliable::Reject, payload.clone());
+        }
+        self.on_disconnected(DisconnectReason::Rejected, reject.to_string());
     }
     fn on_authenticated(&self) {
         self.send_hello();
@@ -92,7 +278,7 @@ impl Kcp2KConnection {
         };
     }
     fn on_data(&self, data: Bytes, kcp2k_channel: Kcp2KChannel) {
-        (self.callback)(self,Callback {
+        self.emit(Callback {
             r#type: CallbackType::OnData,
             data,
             channel: kcp2k_channel,
@@ -100,7 +286,16 @@ impl Kcp2KConnection {
             ..Default::default()
         });
     }
-    fn on_disconnected(&self) {
+    fn on_rpc(&self, frame: Bytes) {
+        self.emit(Callback {
+            r#type: CallbackType::OnRpc,
+            data: frame,
+            channel: Kcp2KChannel::Reliable,
+            conn_id: self.id,
+            ..Default::default()
+        });
+    }
+    fn on_disconnected(&self, reason: DisconnectReason, message: String) {
         // 如果连接已经断开，则不执行任何操作
         match self.kcp_peer.state.try_read() {
             Ok(state) => {
@@ -116,12 +311,13 @@ impl Kcp2KConnection {
                 ));
             }
         }
-        // 发送断开消息
-        self.send_disconnect();
+        // 发送断开消息，把原因告诉对方
+        self.send_disconnect_with_reason(reason, &message);
         // 设置状态为断开
         match self.kcp_peer.state.try_write() {
             Ok(mut state) => {
                 *state = Kcp2KPeerState::Disconnected;
+                self.alive.store(false, Ordering::Release);
             }
             Err(err) => {
                 error!(format!(
@@ -132,14 +328,23 @@ impl Kcp2KConnection {
             }
         }
         // 回调
-        (self.callback)(self,Callback {
+        self.emit(Callback {
             r#type: CallbackType::OnDisconnected,
             conn_id: self.id,
+            disconnect_reason: reason,
+            disconnect_message: message,
+            ..Default::default()
+        });
+    }
+    fn on_address_changed(&self) {
+        self.emit(Callback {
+            r#type: CallbackType::OnAddressChanged,
+            conn_id: self.id,
             ..Default::default()
         });
     }
-    fn on_error(&self, error_code: ErrorCode, error_message: String) {
-        (self.callback)(self,Callback {
+    pub(crate) fn on_error(&self, error_code: ErrorCode, error_message: String) {
+        self.emit(Callback {
             r#type: CallbackType::OnError,
             conn_id: self.id,
             error_code,
@@ -147,47 +352,50 @@ impl Kcp2KConnection {
             ..Default::default()
         });
     }
-    fn raw_send(&self, data: &[u8]) -> Result<(), ErrorCode> {
-        match self.socket.send_to(&data, &self.client_sock_addr) {
+    // 执行回调，录制会话时同时记录回调
+    fn emit(&self, callback: Callback) {
+        self.context.recorder.callback(&callback);
+        Kcp2KContext::in_callback(|| (self.callback)(self, callback));
+    }
+    fn raw_send_to(&self, data: &[u8], sock_addr: &SockAddr) -> Result<(), ErrorCode> {
+        self.capture.record(Direction::Outbound, sock_addr, &[data]);
+        match self.socket.send_to(data, sock_addr) {
             Ok(_) => Ok(()),
             Err(_) => Err(ErrorCode::SendError),
         }
     }
     pub fn raw_input(&mut self, segment: Bytes) -> Result<(), ErrorCode> {
-        if segment.len() <= 5 {
-            self.on_error(
-                ErrorCode::InvalidReceive,
-                format!(
-                    "{}: Received invalid message with length={}. Disconnecting the connection.",
-                    std::any::type_name::<Self>(),
-                    segment.len()
-                ),
-            );
-            return Err(ErrorCode::InvalidReceive);
-        }
-
-        // cookie
-        let cookie = Arc::from(Bytes::copy_from_slice(&segment[1..5]));
+        let datagram = match kcp2k_packet::decode_datagram(&segment) {
+            Ok(datagram) => datagram,
+            Err(err) => {
+                self.on_error(
+                    ErrorCode::InvalidReceive,
+                    format!(
+                        "{}: Received invalid message: {}.",
+                        std::any::type_name::<Self>(),
+                        err
+                    ),
+                );
+                return Err(ErrorCode::InvalidReceive);
+            }
+        };
 
         // 如果连接已经通过验证，但是收到了带有不同 cookie 的消息，那么这可能是由于客户端的 Hello 消息被多次传输，或者攻击者尝试进行 UDP 欺骗。
         match self.kcp_peer.state.try_read() {
             Ok(state) => {
-                if *state == Kcp2KPeerState::Authenticated {
-                    if cookie != self.kcp_peer.cookie {
-                        self.on_error(
-                            ErrorCode::InvalidReceive,
-                            format!(
-                                "{}: Dropped message with invalid cookie: {:?} from {:?} expected: {:?} state: {:?}. This can happen if the client's Hello message was transmitted multiple times, or if an attacker attempted UDP spoofing.",
-                                std::any::type_name::<Self>(),
-                                cookie.to_vec(),
-                                self.client_sock_addr.clone(),
-                                self.kcp_peer.cookie.to_vec(),
-                                self.kcp_peer.state
-                            ),
-                        );
-                        self.send_disconnect();
-                        return Err(ErrorCode::InvalidReceive);
-                    }
+                if *state == Kcp2KPeerState::Authenticated
+                    && datagram.cookie != *self.kcp_peer.cookie
+                {
+                    // 与 C# kcp2k 一致只丢弃消息，不断开连接：重传的 Hello 在握手后仍可能到达
+                    info!(format!(
+                        "{}: Dropped message with invalid cookie: {:?} from {:?} expected: {:?} state: {:?}. This can happen if the client's Hello message was transmitted multiple times, or if an attacker attempted UDP spoofing.",
+                        std::any::type_name::<Self>(),
+                        datagram.cookie.to_vec(),
+                        self.get_sock_addr(),
+                        self.kcp_peer.cookie.to_vec(),
+                        self.kcp_peer.state
+                    ));
+                    return Err(ErrorCode::InvalidReceive);
                 }
             }
             Err(err) => {
@@ -199,21 +407,14 @@ impl Kcp2KConnection {
             }
         }
 
-        // 消息
-        let kcp_data = Bytes::copy_from_slice(&segment[5..]);
-
         if let Ok(mut last_recv_time) = self.kcp_peer.last_recv_time.write() {
             *last_recv_time = self.kcp_peer.watch.elapsed();
         }
 
         // 根据通道类型处理消息
-        match Kcp2KChannel::from(segment[0]) {
-            Kcp2KChannel::Reliable => self.raw_input_reliable(kcp_data),
-            Kcp2KChannel::Unreliable => self.raw_input_unreliable(kcp_data),
-            _ => {
-                self.on_error(ErrorCode::Unexpected, format!("{}: Received message with unexpected channel. Disconnecting the connection.", std::any::type_name::<Self>()));
-                Err(ErrorCode::Unexpected)
-            }
+        match datagram.channel {
+            Kcp2KChannel::Reliable => self.raw_input_reliable(datagram.payload),
+            _ => self.raw_input_unreliable(datagram.payload),
         }
     }
     fn receive_next_reliable(&self) -> Option<(Kcp2KHeaderReliable, Bytes)> {
@@ -230,45 +431,33 @@ impl Kcp2KConnection {
                 }
             }
         }
-        // 从 KCP 接收数据
-        if let Ok(mut kcp) = self.kcp_peer.kcp.write() {
-            match kcp.recv(&mut buffer) {
-                Ok(size) => {
-                    if size == 0 {
-                        self.on_error(
-                            ErrorCode::InvalidReceive,
-                            format!(
-                                "{}: Receive failed with error={}. closing connection.",
-                                std::any::type_name::<Self>(),
-                                size
-                            ),
-                        );
-                        self.send_disconnect();
-                        return None;
-                    }
-                    // 解析头部
-                    let header_byte = buffer[0];
-                    match Kcp2KHeaderReliable::parse(header_byte) {
-                        Some(header) => {
-                            // 从 buffer 中提取消息
-                            Some((header, Bytes::copy_from_slice(&buffer[1..size])))
-                        }
-                        None => {
-                            self.on_error(ErrorCode::InvalidReceive, format!("[KCP-2K] {}: Receive failed to parse header: {} is not defined in {}.", std::any::type_name::<Self>(), header_byte, std::any::type_name::<Kcp2KHeaderReliable>()));
-                            self.send_disconnect();
-                            None
-                        }
-                    }
-                }
-                Err(error) => {
-                    self.on_error(ErrorCode::InvalidReceive, format!("[KCP-2K] connection - {}: Receive failed with error={}. closing connection.", std::any::type_name::<Self>(), error));
-                    self.send_disconnect();
-                    None
+        // 从 KCP 接收数据，出错时在释放锁之后断开连接，回调中仍然可以使用连接
+        let received = match self.kcp_peer.kcp.write() {
+            Ok(mut kcp) => kcp.recv(&mut buffer),
+            Err(_) => return None,
+        };
+        let error = match received {
+            Ok(0) => "receive failed with error=0".to_string(),
+            Ok(size) => {
+                // 解析头部，从 buffer 中提取消息
+                buffer.truncate(size);
+                match kcp2k_packet::decode_reliable(&buffer.freeze()) {
+                    Ok(message) => return Some(message),
+                    Err(err) => format!("receive failed to parse header: {}", err),
                 }
             }
-        } else {
-            None
-        }
+            Err(err) => format!("receive failed with error={}", err),
+        };
+        self.on_error(
+            ErrorCode::InvalidReceive,
+            format!(
+                "{}: {}. closing connection.",
+                std::any::type_name::<Self>(),
+                error
+            ),
+        );
+        self.on_disconnected(DisconnectReason::InvalidData, error);
+        None
     }
     fn raw_input_reliable(&self, data: Bytes) -> Result<(), ErrorCode> {
         if let Ok(mut kcp) = self.kcp_peer.kcp.write() {
@@ -279,7 +468,7 @@ impl Kcp2KConnection {
                         "[KCP2K] {}: Input failed with error={:?} for buffer with length={}",
                         std::any::type_name::<Self>(),
                         e,
-                        data.len() - 1
+                        data.len()
                     ),
                 );
                 Err(ErrorCode::InvalidReceive)
@@ -291,34 +480,23 @@ impl Kcp2KConnection {
         }
     }
     fn raw_input_unreliable(&self, data: Bytes) -> Result<(), ErrorCode> {
-        // 至少需要一个字节用于 header
-        if data.len() < 1 {
-            return Err(ErrorCode::InvalidReceive);
-        }
         // 安全地提取标头。攻击者可能会发送超出枚举范围的值。
-        let header = data[0];
-
-        // 判断 header 类型
-        let header = match Kcp2KHeaderUnreliable::parse(header) {
-            Some(header) => header,
-            None => {
-                self.on_disconnected();
+        let (header, data) = match kcp2k_packet::decode_unreliable(&data) {
+            Ok(message) => message,
+            Err(err) => {
+                self.on_disconnected(DisconnectReason::InvalidData, err.to_string());
                 self.on_error(
                     ErrorCode::InvalidReceive,
                     format!(
-                        "{}: Receive failed to parse header: {} is not defined in {}.",
+                        "{}: Receive failed to parse header: {}.",
                         std::any::type_name::<Self>(),
-                        header,
-                        std::any::type_name::<Kcp2KHeaderUnreliable>()
+                        err
                     ),
                 );
                 return Err(ErrorCode::InvalidReceive);
             }
         };
 
-        // 提取数据
-        let data = Bytes::copy_from_slice(&data[1..]);
-
         // 根据头部类型处理消息
         match header {
             Kcp2KHeaderUnreliable::Data => match self.kcp_peer.state.try_read() {
@@ -344,32 +522,151 @@ impl Kcp2KConnection {
                     Err(ErrorCode::InvalidReceive)
                 }
             },
+            // 对方断开连接，载荷中是对方说明的原因
             Kcp2KHeaderUnreliable::Disconnect => {
-                self.on_disconnected();
+                let (reason, message) = DisconnectReason::decode(&data);
+                self.on_disconnected(reason, message);
+                Ok(())
+            }
+            Kcp2KHeaderUnreliable::Ping => {
+                self.send_pong(data);
+                Ok(())
+            }
+            Kcp2KHeaderUnreliable::Pong => {
+                self.handle_pong(&data);
+                Ok(())
+            }
+            // 服务器从新地址发来的路径挑战，原样回应随机数
+            Kcp2KHeaderUnreliable::PathChallenge => {
+                self.send_unreliable(Kcp2KHeaderUnreliable::PathResponse, data)
+            }
+            // 路径回应只在 raw_input_migration 中处理
+            Kcp2KHeaderUnreliable::PathResponse => Ok(()),
+            // 对方拒绝了握手，同一个拒绝会发送多次，只处理第一个
+            Kcp2KHeaderUnreliable::Reject => {
+                if !self.is_connected() {
+                    return Ok(());
+                }
+                let reject = Kcp2KReject::decode(&data);
+                self.set_rejection(&reject);
+                self.on_error(
+                    ErrorCode::Rejected,
+                    format!(
+                        "{}: Handshake rejected by remote: {}.",
+                        std::any::type_name::<Self>(),
+                        reject
+                    ),
+                );
+                self.on_disconnected(DisconnectReason::Rejected, reject.to_string());
                 Ok(())
             }
-            Kcp2KHeaderUnreliable::Ping => Ok(()),
         }
     }
+    // 处理来自新地址、但 cookie 属于本连接的消息。
+    // 新地址必须先回应路径挑战，才会把连接迁移过去，以防止 UDP 欺骗。
+    // 返回 true 表示连接已迁移到新地址。
+    pub fn raw_input_migration(&mut self, sock_addr: &SockAddr, segment: Bytes) -> bool {
+        // 只有已通过验证、并且握手时协商了连接迁移的连接才允许迁移
+        let migration = self
+            .get_protocol()
+            .is_some_and(|protocol| protocol.supports(Kcp2KCapabilities::MIGRATION));
+        if !self.is_authenticated() || !migration {
+            return false;
+        }
+        let datagram = match kcp2k_packet::decode_datagram(&segment) {
+            Ok(datagram) if datagram.cookie == *self.kcp_peer.cookie => datagram,
+            _ => return false,
+        };
+        let elapsed_time = self.kcp_peer.watch.elapsed();
+
+        // 新地址回应了路径挑战，完成迁移
+        let path_response = match datagram.channel {
+            Kcp2KChannel::Unreliable => match kcp2k_packet::decode_unreliable(&datagram.payload) {
+                Ok((Kcp2KHeaderUnreliable::PathResponse, nonce)) => Some(nonce),
+                _ => None,
+            },
+            _ => None,
+        };
+        if let Some(nonce) = path_response {
+            let validated = match &self.pending_migration {
+                Some(pending) => pending.sock_addr == *sock_addr && pending.nonce == nonce,
+                None => false,
+            };
+            if validated {
+                self.pending_migration = None;
+                self.set_sock_addr(sock_addr.clone());
+                if let Ok(mut last_recv_time) = self.kcp_peer.last_recv_time.write() {
+                    *last_recv_time = elapsed_time;
+                }
+                self.on_address_changed();
+            }
+            return validated;
+        }
+
+        // 等待中的挑战在验证成功或超时之前不会被其他地址替换，
+        // 否则看到明文 cookie 的攻击者可以不断覆盖随机数，使真正迁移的客户端永远无法通过验证
+        let timed_out = self.pending_migration.as_ref().is_some_and(|pending| {
+            elapsed_time
+                >= pending.start_time + Duration::from_millis(Kcp2KConfig::PATH_CHALLENGE_TIMEOUT)
+        });
+        if timed_out {
+            self.pending_migration = None;
+        }
+        if let Some(pending) = &self.pending_migration {
+            if pending.sock_addr != *sock_addr {
+                return false;
+            }
+        }
+        // 每个连接按间隔限速，不论地址，伪造源地址也不能让服务器向受害者发送大量挑战
+        if let Some(last_path_challenge) = self.last_path_challenge {
+            if elapsed_time
+                < last_path_challenge + Duration::from_millis(Kcp2KConfig::PATH_CHALLENGE_INTERVAL)
+            {
+                return false;
+            }
+        }
+        let (nonce, start_time) = match &self.pending_migration {
+            Some(pending) => (pending.nonce.clone(), pending.start_time),
+            None => (
+                Bytes::copy_from_slice(&self.context.random_bytes::<8>()),
+                elapsed_time,
+            ),
+        };
+        let mut buffer = vec![];
+        buffer.put_slice(&kcp2k_packet::encode_prefix(
+            Kcp2KChannel::Unreliable,
+            &self.kcp_peer.cookie,
+        ));
+        buffer.put_u8(Kcp2KHeaderUnreliable::PathChallenge.to_u8());
+        buffer.put_slice(&nonce);
+        let _ = self.raw_send_to(&buffer, sock_addr);
+        self.last_path_challenge = Some(elapsed_time);
+        self.pending_migration = Some(PendingMigration {
+            sock_addr: sock_addr.clone(),
+            nonce,
+            start_time,
+        });
+        false
+    }
+    // 构建消息：kcp2k 头部 + 数据。广播时只构建一次，所有连接共享
+    pub(crate) fn build_message(header: u8, data: &[u8]) -> Bytes {
+        let mut buffer = BytesMut::with_capacity(1 + data.len());
+        buffer.put_u8(header);
+        buffer.put_slice(data);
+        buffer.freeze()
+    }
     fn send_reliable(
         &self,
         kcp2k_header_reliable: Kcp2KHeaderReliable,
         data: Bytes,
     ) -> Result<(), ErrorCode> {
-        // 创建一个缓冲区，用于存储消息内容
-        let mut buffer = vec![];
-
-        // 写入通道头部
-        buffer.put_u8(kcp2k_header_reliable.to_u8());
-
-        // 写入数据
-        if !data.is_empty() {
-            buffer.put_slice(&data);
-        }
-
+        self.send_reliable_message(&Self::build_message(kcp2k_header_reliable.to_u8(), &data))
+    }
+    fn send_reliable_message(&self, message: &[u8]) -> Result<(), ErrorCode> {
+        self.update_last_send_time();
         // 通过 KCP 发送处理
         match self.kcp_peer.kcp.write() {
-            Ok(mut kcp) => match kcp.send(&buffer) {
+            Ok(mut kcp) => match kcp.send(message) {
                 Ok(_) => Ok(()),
                 Err(e) => {
                     self.on_error(
@@ -378,7 +675,7 @@ impl Kcp2KConnection {
                             "{}: 发送失败，错误码={}，内容长度={}",
                             "send_reliable",
                             e,
-                            data.len()
+                            message.len() - 1
                         ),
                     );
                     Err(ErrorCode::SendError)
@@ -398,26 +695,33 @@ impl Kcp2KConnection {
         kcp2k_header_unreliable: Kcp2KHeaderUnreliable,
         data: Bytes,
     ) -> Result<(), ErrorCode> {
-        // 创建一个缓冲区，用于存储消息内容
-        let mut buffer = vec![];
-
-        // 写入通道头部
-        buffer.put_u8(Kcp2KChannel::Unreliable.to_u8());
-
-        // 写入握手 cookie 以防止 UDP 欺骗
-        buffer.put_slice(&self.kcp_peer.cookie);
-
-        // 写入 kcp 头部
-        buffer.put_u8(kcp2k_header_unreliable.to_u8());
+        self.send_unreliable_message(&Self::build_message(kcp2k_header_unreliable.to_u8(), &data))
+    }
+    fn send_unreliable_message(&self, message: &[u8]) -> Result<(), ErrorCode> {
+        self.update_last_send_time();
+        let prefix = kcp2k_packet::encode_prefix(Kcp2KChannel::Unreliable, &self.kcp_peer.cookie);
 
-        // 写入数据
-        if !data.is_empty() {
-            buffer.put_slice(&data);
+        // 与消息一起分段发送，消息本身不需要复制
+        let client_sock_addr = match self.client_sock_addr.read() {
+            Ok(client_sock_addr) => client_sock_addr,
+            Err(_) => return Err(ErrorCode::SendError),
+        };
+        self.capture
+            .record(Direction::Outbound, &client_sock_addr, &[&prefix, message]);
+        match self.socket.send_to_vectored(
+            &[IoSlice::new(&prefix), IoSlice::new(message)],
+            &client_sock_addr,
+        ) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(ErrorCode::SendError),
         }
-        //  send it raw
-        self.raw_send(&buffer)
     }
     pub fn tick_incoming(&self) {
+        let mut budget = self.receive_budget;
+        self.tick_incoming_budget(&mut budget);
+    }
+    // 按预算处理接收：连接实际消耗的部分会从 budget 中扣除
+    pub fn tick_incoming_budget(&self, budget: &mut Kcp2KBudget) {
         // 获取经过的时间
         let elapsed_time = self.kcp_peer.watch.elapsed();
         // 根据状态处理不同的逻辑
@@ -427,16 +731,39 @@ impl Kcp2KConnection {
                 return;
             }
         };
+        // 连接预算不超过剩余的全局预算
+        let mut connection_budget = self.receive_budget.min(*budget);
+        let before = connection_budget;
         match state {
-            Kcp2KPeerState::Connected => self.tick_incoming_connected(elapsed_time),
-            Kcp2KPeerState::Authenticated => self.tick_incoming_authenticated(elapsed_time),
+            Kcp2KPeerState::Connected => {
+                self.tick_incoming_connected(elapsed_time, &mut connection_budget)
+            }
+            Kcp2KPeerState::Authenticated => {
+                self.tick_incoming_authenticated(elapsed_time, &mut connection_budget)
+            }
             Kcp2KPeerState::Disconnected => {}
         }
+        budget.spend(before, connection_budget);
     }
     pub fn tick_outgoing(&self) {
         match self.kcp_peer.state.try_read() {
             Ok(state) => match *state {
                 Kcp2KPeerState::Connected | Kcp2KPeerState::Authenticated => {
+                    // 握手完成后发送发件箱中的消息
+                    if *state == Kcp2KPeerState::Authenticated {
+                        while let Some(outgoing) = self.outbox.pop() {
+                            match outgoing {
+                                Kcp2KOutgoing::Data(data, channel) => {
+                                    self.context.recorder.outbox(self.id, channel, &data);
+                                    let _ = self.send_data_unrecorded(data, channel);
+                                }
+                                Kcp2KOutgoing::Rpc(frame) => {
+                                    self.context.recorder.outbox_rpc(self.id, &frame);
+                                    let _ = self.send_rpc(frame);
+                                }
+                            }
+                        }
+                    }
                     if let Ok(mut kcp) = self.kcp_peer.kcp.write() {
                         let _ = kcp.update(self.kcp_peer.watch.elapsed().as_millis() as u32);
                     }
@@ -452,71 +779,217 @@ impl Kcp2KConnection {
             }
         }
     }
+    // 按连接设置 ping 间隔，例如对延迟敏感的连接使用更短的间隔
+    pub fn set_ping_interval(&self, interval: Duration) {
+        self.ping_interval
+            .store(interval.as_millis() as u64, Ordering::Relaxed);
+    }
+    pub fn get_ping_interval(&self) -> Duration {
+        Duration::from_millis(self.ping_interval.load(Ordering::Relaxed))
+    }
+    // 带时间戳的 ping 测量的 RTT、抖动和丢包率，对方不支持时保持为零
+    pub fn get_rtt(&self) -> Kcp2KRtt {
+        match self.rtt.lock() {
+            Ok(rtt) => rtt.snapshot(),
+            Err(err) => err.into_inner().snapshot(),
+        }
+    }
+    // 握手完成并且双方都支持带时间戳的 ping
+    fn measures_rtt(&self) -> bool {
+        self.is_authenticated()
+            && self
+                .get_protocol()
+                .is_some_and(|protocol| protocol.supports(Kcp2KCapabilities::TIMESTAMPED_PING))
+    }
+    // 握手协商的协议版本和功能，握手完成前为 None
+    pub fn get_protocol(&self) -> Option<Kcp2KProtocol> {
+        match self.protocol.read() {
+            Ok(protocol) => *protocol,
+            Err(err) => *err.into_inner(),
+        }
+    }
     // 获取地址
     pub fn get_sock_addr(&self) -> Arc<SockAddr> {
-        Arc::clone(&self.client_sock_addr)
+        match self.client_sock_addr.read() {
+            Ok(client_sock_addr) => Arc::new(client_sock_addr.clone()),
+            Err(err) => Arc::new(err.into_inner().clone()),
+        }
+    }
+    // 更新地址（连接迁移）
+    fn set_sock_addr(&self, sock_addr: SockAddr) {
+        info!(format!(
+            "[KCP2K] Connection {} migrated from {:?} to {:?}",
+            self.id,
+            self.get_sock_addr().as_socket(),
+            sock_addr.as_socket()
+        ));
+        match self.client_sock_addr.write() {
+            Ok(mut client_sock_addr) => *client_sock_addr = sock_addr,
+            Err(err) => *err.into_inner() = sock_addr,
+        }
     }
     // 处理连接
-    fn tick_incoming_connected(&self, elapsed_time: Duration) {
+    fn tick_incoming_connected(&self, elapsed_time: Duration, budget: &mut Kcp2KBudget) {
         self.handle_ping(elapsed_time);
-        self.handle_timeout(elapsed_time);
+        self.handle_handshake_timeout();
         self.handle_dead_link();
+        // 等待异步认证时，Hello 之后的消息留在 KCP 中，认证完成后再处理
+        if self.is_authenticating() {
+            return;
+        }
 
-        if let Some((header, _)) = self.receive_next_reliable() {
+        while let Some((header, data)) = self.receive_next_reliable_budget(budget) {
             match header {
                 Kcp2KHeaderReliable::Hello => {
-                    self.on_authenticated();
+                    match self.negotiate(&data) {
+                        Ok((protocol, payload)) => {
+                            if let Ok(mut negotiated) = self.protocol.write() {
+                                *negotiated = Some(protocol);
+                            }
+                            self.complete_authentication(self.authenticate(payload));
+                            // 握手完成，同一 tick 内继续按已认证状态处理剩余消息
+                            if self.is_authenticated() {
+                                self.receive_authenticated(budget);
+                            }
+                        }
+                        Err(reject) => self.reject(reject),
+                    }
+                    return;
                 }
-                Kcp2KHeaderReliable::Data => {
+                Kcp2KHeaderReliable::Data | Kcp2KHeaderReliable::Rpc => {
                     self.on_error(
                         ErrorCode::InvalidReceive,
                         "Received invalid header while Connected. Disconnecting the connection."
                             .to_string(),
                     );
-                    self.on_disconnected();
+                    self.on_disconnected(
+                        DisconnectReason::InvalidData,
+                        "data before handshake".to_string(),
+                    );
+                    return;
                 }
                 Kcp2KHeaderReliable::Ping => {}
             }
         }
     }
     // 处理认证
-    fn tick_incoming_authenticated(&self, elapsed_time: Duration) {
+    fn tick_incoming_authenticated(&self, elapsed_time: Duration, budget: &mut Kcp2KBudget) {
         self.handle_ping(elapsed_time);
         self.handle_timeout(elapsed_time);
         self.handle_dead_link();
 
-        if let Some((header, data)) = self.receive_next_reliable() {
+        self.receive_authenticated(budget);
+    }
+    // 处理 KCP 接收队列中所有完整的消息，直到队列为空或预算用完
+    fn receive_authenticated(&self, budget: &mut Kcp2KBudget) {
+        while let Some((header, data)) = self.receive_next_reliable_budget(budget) {
             match header {
                 Kcp2KHeaderReliable::Hello => {
                     self.on_error(ErrorCode::InvalidReceive, "Received invalid header while Authenticated. Disconnecting the connection.".to_string());
-                    self.on_disconnected();
+                    self.on_disconnected(
+                        DisconnectReason::InvalidData,
+                        "hello after handshake".to_string(),
+                    );
+                    return;
                 }
                 Kcp2KHeaderReliable::Data => {
                     if data.is_empty() {
                         self.on_error(ErrorCode::InvalidReceive, "Received empty Data message while Authenticated. Disconnecting the connection.".to_string());
-                        self.on_disconnected();
+                        self.on_disconnected(
+                            DisconnectReason::InvalidData,
+                            "empty data message".to_string(),
+                        );
+                        return;
                     } else {
                         self.on_data(data, Kcp2KChannel::Reliable);
                     }
                 }
-                Kcp2KHeaderReliable::Ping => {}
+                // 只接受协商了 RPC 的连接上的 RPC 帧
+                Kcp2KHeaderReliable::Rpc => {
+                    if !self.supports_rpc() {
+                        self.on_error(ErrorCode::InvalidReceive, "Received Rpc message without negotiating rpc. Disconnecting the connection.".to_string());
+                        self.on_disconnected(
+                            DisconnectReason::InvalidData,
+                            "rpc not negotiated".to_string(),
+                        );
+                        return;
+                    }
+                    self.on_rpc(data);
+                }
+                Kcp2KHeaderReliable::Ping => self.send_pong(data),
             }
         }
     }
-    // 发送 hello
+    fn receive_next_reliable_budget(
+        &self,
+        budget: &mut Kcp2KBudget,
+    ) -> Option<(Kcp2KHeaderReliable, Bytes)> {
+        if budget.is_exhausted() || !self.is_connected() {
+            return None;
+        }
+        let (header, data) = self.receive_next_reliable()?;
+        budget.consume(data.len() + 1);
+        Some((header, data))
+    }
+    // 发送 hello：握手前通告本地的功能并附带应用载荷，握手后回复协商后的功能
     fn send_hello(&self) {
-        let _ = self.send_reliable(Kcp2KHeaderReliable::Hello, Default::default());
+        let hello = match self.get_protocol() {
+            // 服务器回复协商结果：较低的版本和共同的功能。与 C# kcp2k 协商的结果是 LEGACY，不带载荷
+            Some(protocol) if !self.config.csharp_compat => Kcp2KHello {
+                version: protocol.version,
+                capabilities: protocol.capabilities,
+            }
+            .encode(&[]),
+            Some(_) => Kcp2KHello::local(&self.config).encode(&[]),
+            None => Kcp2KHello::local(&self.config).encode(&self.hello_payload),
+        };
+        let _ = self.send_reliable(Kcp2KHeaderReliable::Hello, hello);
     }
-    // 发送 ping
+    // 发送 ping，对方不支持不可靠 ping 时使用可靠通道。双方支持时 ping 带有序号和时间戳
     fn send_ping(&self) {
-        if self.is_reliable_ping {
-            let _ = self.send_reliable(Kcp2KHeaderReliable::Ping, Default::default());
+        let unreliable_ping = self
+            .get_protocol()
+            .is_some_and(|protocol| protocol.supports(Kcp2KCapabilities::UNRELIABLE_PING));
+        let payload = match self.measures_rtt() {
+            true => match self.rtt.lock() {
+                Ok(mut rtt) => rtt.ping(self.context.now(), Self::pong_timeout()),
+                Err(_) => Bytes::new(),
+            },
+            false => Bytes::new(),
+        };
+        if self.is_reliable_ping || !unreliable_ping {
+            let _ = self.send_reliable(Kcp2KHeaderReliable::Ping, payload);
         } else {
-            let _ = self.send_unreliable(Kcp2KHeaderUnreliable::Ping, Default::default());
+            let _ = self.send_unreliable(Kcp2KHeaderUnreliable::Ping, payload);
         }
     }
+    // 回显带时间戳的 ping。无论 ping 走哪个通道，pong 都走不可靠通道
+    fn send_pong(&self, ping: Bytes) {
+        if ping.len() == PING_SIZE && self.measures_rtt() {
+            let _ = self.send_unreliable(Kcp2KHeaderUnreliable::Pong, ping);
+        }
+    }
+    // 收到 pong，更新 RTT 统计
+    fn handle_pong(&self, pong: &[u8]) {
+        if !self.measures_rtt() {
+            return;
+        }
+        if let Ok(mut rtt) = self.rtt.lock() {
+            rtt.pong(pong, self.context.now(), Self::pong_timeout());
+        }
+    }
+    fn pong_timeout() -> Duration {
+        Duration::from_millis(Kcp2KConfig::PONG_TIMEOUT)
+    }
     // 发送数据
     pub fn send_data(&self, data: Bytes, channel: Kcp2KChannel) -> Result<(), ErrorCode> {
+        // 回调中的发送在回放时由回调重现，不需要录制
+        if !Kcp2KContext::is_in_callback() {
+            self.context.recorder.send(self.id, channel, &data);
+        }
+        self.send_data_unrecorded(data, channel)
+    }
+    fn send_data_unrecorded(&self, data: Bytes, channel: Kcp2KChannel) -> Result<(), ErrorCode> {
         // 如果数据为空，则返回错误
         if data.is_empty() {
             self.on_error(
@@ -536,8 +1009,47 @@ impl Kcp2KConnection {
             }
         }
     }
-    // 发送断开连接
+    // 发送 RPC 帧，只在 Kcp2KRpc::handle 的回调中和取出发件箱时调用，回放时由回调或发件箱重现
+    pub(crate) fn send_rpc(&self, frame: Bytes) -> Result<(), ErrorCode> {
+        if !self.supports_rpc() {
+            self.on_error(
+                ErrorCode::InvalidSend,
+                "send_rpc: remote did not negotiate rpc.".to_string(),
+            );
+            return Err(ErrorCode::InvalidSend);
+        }
+        self.send_reliable(Kcp2KHeaderReliable::Rpc, frame)
+    }
+    fn supports_rpc(&self) -> bool {
+        self.get_protocol()
+            .is_some_and(|protocol| protocol.supports(Kcp2KCapabilities::RPC))
+    }
+    // 发送已构建好的数据消息（见 build_message），用于广播
+    pub(crate) fn send_message(
+        &self,
+        message: &Bytes,
+        channel: Kcp2KChannel,
+    ) -> Result<(), ErrorCode> {
+        if !Kcp2KContext::is_in_callback() && !message.is_empty() {
+            self.context.recorder.send(self.id, channel, &message[1..]);
+        }
+        match channel {
+            Kcp2KChannel::Reliable => self.send_reliable_message(message),
+            Kcp2KChannel::Unreliable => self.send_unreliable_message(message),
+            _ => Err(ErrorCode::InvalidSend),
+        }
+    }
+    // 断开连接
     pub fn send_disconnect(&self) {
+        self.disconnect(DisconnectReason::Closed, "");
+    }
+    // 断开连接并把原因告诉对方，双方都会收到带有原因和说明的 OnDisconnected 回调
+    pub fn disconnect(&self, reason: DisconnectReason, message: &str) {
+        self.on_disconnected(reason, message.to_string());
+    }
+    // 发送带原因的断开连接并移除连接，只由 on_disconnected 调用。兼容模式下与 C# kcp2k 一样不带载荷
+    fn send_disconnect_with_reason(&self, reason: DisconnectReason, message: &str) {
+        self.alive.store(false, Ordering::Release);
         // 将连接 ID 添加到删除列表
         match self.rm_conn_ids.try_lock() {
             Ok(mut rm_conn_ids) => {
@@ -551,30 +1063,62 @@ impl Kcp2KConnection {
                 ));
             }
         }
+        let payload = match self.config.csharp_compat {
+            true => Bytes::new(),
+            false => reason.encode(message),
+        };
         for _ in 0..5 {
-            let _ = self.send_unreliable(Kcp2KHeaderUnreliable::Disconnect, Default::default());
+            let _ = self.send_unreliable(Kcp2KHeaderUnreliable::Disconnect, payload.clone());
+        }
+    }
+    fn update_last_send_time(&self) {
+        if let Ok(mut last_send_time) = self.kcp_peer.last_send_time.write() {
+            *last_send_time = self.kcp_peer.watch.elapsed();
         }
     }
-    // 处理 ping
+    // 处理 ping：ping 间隔内发送过消息时，对方已经知道连接存活，不需要 ping；
+    // 但测量 RTT 时至少每 rtt_interval 发送一次
     fn handle_ping(&self, elapsed_time: Duration) {
-        if let Ok(mut last_send_ping_time) = self.kcp_peer.last_send_ping_time.write() {
-            if elapsed_time
-                >= *last_send_ping_time + Duration::from_millis(Kcp2KConfig::PING_INTERVAL)
-            {
-                *last_send_ping_time = elapsed_time;
-                self.send_ping();
-            }
+        let last_send_time = match self.kcp_peer.last_send_time.read() {
+            Ok(last_send_time) => *last_send_time,
+            Err(_) => return,
+        };
+        if elapsed_time >= last_send_time + self.get_ping_interval() || self.is_rtt_due() {
+            self.send_ping();
         }
     }
-    // 处理超时
+    fn is_rtt_due(&self) -> bool {
+        if self.config.rtt_interval == 0 || !self.measures_rtt() {
+            return false;
+        }
+        let last_ping_time = match self.rtt.lock() {
+            Ok(rtt) => rtt.last_ping_time(),
+            Err(_) => return false,
+        };
+        last_ping_time.is_none_or(|last_ping_time| {
+            self.context.now() >= last_ping_time + Duration::from_millis(self.config.rtt_interval)
+        })
+    }
+    // 处理空闲超时
     fn handle_timeout(&self, elapsed_time: Duration) {
         if let Ok(last_recv_time) = self.kcp_peer.last_recv_time.read() {
-            if elapsed_time > *last_recv_time + self.kcp_peer.timeout_duration {
+            if elapsed_time > *last_recv_time + self.kcp_peer.idle_timeout {
                 self.on_error(ErrorCode::Timeout, "timeout to disconnected.".to_string());
-                self.on_disconnected();
+                self.on_disconnected(DisconnectReason::Timeout, String::new());
             }
         }
     }
+    // 处理握手超时：握手（包括异步认证）没有在 handshake_timeout 内完成
+    fn handle_handshake_timeout(&self) {
+        let handshake_timeout = Duration::from_millis(self.config.handshake_timeout);
+        if self.context.now() > self.connect_time + handshake_timeout {
+            self.on_error(
+                ErrorCode::Timeout,
+                "handshake timeout to disconnected.".to_string(),
+            );
+            self.on_disconnected(DisconnectReason::Timeout, "handshake timeout".to_string());
+        }
+    }
     // 处理 dead_link
     fn handle_dead_link(&self) {
         if let Ok(kcp) = self.kcp_peer.kcp.read() {
@@ -583,8 +1127,512 @@ impl Kcp2KConnection {
                     ErrorCode::Timeout,
                     "dead link to disconnecting.".to_string(),
                 );
-                self.on_disconnected();
+                self.on_disconnected(DisconnectReason::DeadLink, String::new());
+            }
+        }
+    }
+}
+
+// 连接被移除时，句柄随之失效
+impl Drop for Kcp2KConnection {
+    fn drop(&mut self) {
+        self.alive.store(false, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kcp2k::Kcp2K;
+    use crate::kcp2k_testing::{addr, ignore, Kcp2KTestLink, STEP};
+    use std::cell::RefCell;
+
+    thread_local! {
+        // 收到的 OnDisconnected：连接的角色、原因和说明
+        static DISCONNECTED: RefCell<Vec<(Kcp2KMode, DisconnectReason, String)>> =
+            const { RefCell::new(Vec::new()) };
+    }
+
+    fn record_disconnected(conn: &Kcp2KConnection, cb: Callback) {
+        if let CallbackType::OnDisconnected = cb.r#type {
+            DISCONNECTED.with(|disconnected| {
+                disconnected.borrow_mut().push((
+                    conn.get_mode(),
+                    cb.disconnect_reason,
+                    cb.disconnect_message,
+                ))
+            });
+        }
+    }
+
+    fn take_disconnected() -> Vec<(Kcp2KMode, DisconnectReason, String)> {
+        DISCONNECTED.with(|disconnected| disconnected.take())
+    }
+
+    // 双方都记录 OnDisconnected 的链路
+    fn disconnect_link() -> Kcp2KTestLink {
+        Kcp2KTestLink::connected(
+            Kcp2KConfig::default(),
+            record_disconnected,
+            record_disconnected,
+        )
+    }
+
+    fn connected_link() -> Kcp2KTestLink {
+        Kcp2KTestLink::connected(Kcp2KConfig::default(), ignore, ignore)
+    }
+
+    fn server_addr_of_client(link: &Kcp2KTestLink) -> SockAddr {
+        (*link.server_conn().get_sock_addr()).clone()
+    }
+
+    // 攻击者看到明文 cookie 后伪造的数据报
+    fn spoofed(link: &Kcp2KTestLink) -> Bytes {
+        let cookie = link.server_conn().get_cookie();
+        let mut buffer = BytesMut::new();
+        buffer.put_slice(&kcp2k_packet::encode_prefix(
+            Kcp2KChannel::Unreliable,
+            &cookie,
+        ));
+        buffer.put_u8(Kcp2KHeaderUnreliable::Data.to_u8());
+        buffer.put_slice(b"spoofed");
+        buffer.freeze()
+    }
+
+    fn challenges_to(link: &Kcp2KTestLink, sock_addr: &SockAddr) -> usize {
+        link.stray
+            .iter()
+            .filter(|(to, data)| {
+                to == sock_addr
+                    && data.get(Kcp2KConfig::METADATA_SIZE_UNRELIABLE)
+                        == Some(&Kcp2KHeaderUnreliable::PathChallenge.to_u8())
+            })
+            .count()
+    }
+
+    // 客户端在新地址上持续发送，直到迁移完成或超时
+    fn migrate_client(
+        link: &mut Kcp2KTestLink,
+        to: &str,
+        attacker: Option<&str>,
+        limit: Duration,
+    ) -> bool {
+        link.client_addr = addr(to);
+        let start = link.now();
+        while link.now() < start + limit {
+            let _ = link.client.send(
+                link.client_id,
+                Bytes::from_static(b"move"),
+                Kcp2KChannel::Unreliable,
+            );
+            link.run(STEP);
+            // 伪造的数据报在客户端的数据报之后到达
+            if let Some(attacker) = attacker {
+                link.server.push_inbound(addr(attacker), spoofed(link));
+            }
+            if server_addr_of_client(link) == link.client_addr {
+                return true;
+            }
+        }
+        false
+    }
+
+    #[test]
+    fn migration_validates_new_address() {
+        let mut link = connected_link();
+        assert!(migrate_client(
+            &mut link,
+            "10.0.0.3:40000",
+            None,
+            Duration::from_millis(100)
+        ));
+        assert!(link.server_conn().is_authenticated());
+    }
+
+    #[test]
+    fn interleaved_spoofer_cannot_replace_pending_challenge() {
+        let mut link = connected_link();
+        // 真正的客户端先发起迁移，攻击者随后每一步都从另一个地址发送伪造的数据报
+        assert!(migrate_client(
+            &mut link,
+            "10.0.0.3:40000",
+            Some("192.0.2.1:9999"),
+            Duration::from_millis(100)
+        ));
+        assert_eq!(challenges_to(&link, &addr("192.0.2.1:9999")), 0);
+    }
+
+    #[test]
+    fn spoofed_pending_challenge_times_out() {
+        let mut link = connected_link();
+        // 攻击者抢先让服务器等待它的地址，真正的客户端要等挑战超时后才能迁移
+        link.server
+            .push_inbound(addr("192.0.2.1:9999"), spoofed(&link));
+        link.run(STEP);
+        assert_eq!(challenges_to(&link, &addr("192.0.2.1:9999")), 1);
+        let start = link.now();
+        assert!(migrate_client(
+            &mut link,
+            "10.0.0.3:40000",
+            None,
+            Duration::from_secs(2)
+        ));
+        let waited = link.now() - start;
+        assert!(waited >= Duration::from_millis(Kcp2KConfig::PATH_CHALLENGE_TIMEOUT) - STEP * 2);
+        assert!(waited < Duration::from_millis(Kcp2KConfig::PATH_CHALLENGE_TIMEOUT) + STEP * 10);
+    }
+
+    #[test]
+    fn spoofed_sources_are_rate_limited() {
+        let mut link = connected_link();
+        let original = server_addr_of_client(&link);
+        let victim = addr("192.0.2.1:9999");
+        let duration = Duration::from_secs(3);
+        let start = link.now();
+        let mut port = 10000;
+        while link.now() < start + duration {
+            // 每一步都伪造十个数据报：一个来自同一个受害者，其余来自不同的地址
+            link.server.push_inbound(victim.clone(), spoofed(&link));
+            for _ in 0..9 {
+                port += 1;
+                link.server
+                    .push_inbound(addr(&format!("198.51.100.1:{}", port)), spoofed(&link));
             }
+            link.run(STEP);
         }
+        let challenges = link
+            .stray
+            .iter()
+            .filter(|(_, data)| {
+                data.get(Kcp2KConfig::METADATA_SIZE_UNRELIABLE)
+                    == Some(&Kcp2KHeaderUnreliable::PathChallenge.to_u8())
+            })
+            .count();
+        let limit =
+            duration.as_millis() as usize / Kcp2KConfig::PATH_CHALLENGE_INTERVAL as usize + 1;
+        assert!(
+            challenges <= limit,
+            "{} challenges in {:?}",
+            challenges,
+            duration
+        );
+        // 受害者最先被挑战，之后的挑战只发给它，直到超时
+        assert!(challenges_to(&link, &victim) >= 1);
+        assert_eq!(link.stray[0].0, victim);
+        // 连接没有被迁移，真正的客户端不受影响
+        assert_eq!(server_addr_of_client(&link), original);
+        assert!(link.server_conn().is_authenticated());
+    }
+
+    #[test]
+    fn rpc_frame_without_negotiation_disconnects() {
+        let mut link = connected_link();
+        {
+            let client = link.client.get_connections().get(&link.client_id).unwrap();
+            assert!(!client.supports_rpc());
+            assert!(client.send_rpc(Bytes::from_static(b"rpc")).is_err());
+            // 绕过 send_rpc 的检查，直接发送 RPC 帧
+            client
+                .send_reliable(Kcp2KHeaderReliable::Rpc, Bytes::from_static(b"rpc"))
+                .unwrap();
+        }
+        link.run(Duration::from_millis(100));
+        assert!(link.server.get_connections().is_empty());
+    }
+
+    #[test]
+    fn local_disconnect_fires_on_disconnected_on_both_sides() {
+        let mut link = disconnect_link();
+        link.server
+            .disconnect(link.server_id(), DisconnectReason::Kicked, "server full");
+        let kicked = (DisconnectReason::Kicked, "server full".to_string());
+        assert_eq!(
+            take_disconnected(),
+            vec![(Kcp2KMode::Server, kicked.0, kicked.1.clone())]
+        );
+        link.run(Duration::from_millis(100));
+        assert_eq!(
+            take_disconnected(),
+            vec![(Kcp2KMode::Client, kicked.0, kicked.1)]
+        );
+        assert!(link.server.get_connections().is_empty());
+    }
+
+    #[test]
+    fn close_connection_fires_on_disconnected_locally() {
+        let mut link = disconnect_link();
+        link.client.close_connection(link.client_id);
+        assert_eq!(
+            take_disconnected(),
+            vec![(Kcp2KMode::Client, DisconnectReason::Closed, String::new())]
+        );
+        link.run(Duration::from_millis(100));
+        assert_eq!(
+            take_disconnected(),
+            vec![(Kcp2KMode::Server, DisconnectReason::Closed, String::new())]
+        );
+    }
+
+    #[test]
+    fn invalid_reliable_message_reports_reason() {
+        let mut link = disconnect_link();
+        link.client
+            .get_connections()
+            .get(&link.client_id)
+            .unwrap()
+            .send_reliable_message(&[0x77, 1, 2])
+            .unwrap();
+        link.run(Duration::from_millis(100));
+        let disconnected = take_disconnected();
+        assert_eq!(disconnected.len(), 2, "{:?}", disconnected);
+        let (mode, reason, message) = &disconnected[0];
+        assert_eq!(*mode, Kcp2KMode::Server);
+        assert_eq!(*reason, DisconnectReason::InvalidData);
+        assert!(
+            message.starts_with("receive failed to parse header"),
+            "{}",
+            message
+        );
+        // 对方收到相同的原因和说明
+        assert_eq!(
+            disconnected[1],
+            (Kcp2KMode::Client, *reason, message.clone())
+        );
+    }
+
+    // 不测量 RTT 的不可靠 ping：只有空闲时才会 ping，对方也不会回复 pong
+    fn keepalive_link() -> Kcp2KTestLink {
+        let config = Kcp2KConfig {
+            is_reliable_ping: false,
+            rtt_interval: 0,
+            capabilities: Kcp2KCapabilities::UNRELIABLE_PING.bits(),
+            ..Kcp2KConfig::default()
+        };
+        let mut link = Kcp2KTestLink::new(config, record_disconnected, record_disconnected);
+        link.run(Duration::from_millis(200));
+        assert!(link.server_conn().is_authenticated());
+        link
+    }
+
+    // 客户端在 since 之后发出 ping 的时间
+    fn client_pings(link: &Kcp2KTestLink, since: Duration) -> Vec<Duration> {
+        link.sent
+            .iter()
+            .filter(|(time, mode, data)| {
+                *time >= since
+                    && *mode == Kcp2KMode::Client
+                    && data.first() == Some(&Kcp2KChannel::Unreliable.to_u8())
+                    && data.get(Kcp2KConfig::METADATA_SIZE_UNRELIABLE)
+                        == Some(&Kcp2KHeaderUnreliable::Ping.to_u8())
+            })
+            .map(|(time, _, _)| *time)
+            .collect()
+    }
+
+    fn send_from_client(link: &Kcp2KTestLink) {
+        link.client
+            .send(
+                link.client_id,
+                Bytes::from_static(b"data"),
+                Kcp2KChannel::Unreliable,
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn no_ping_while_data_flows() {
+        let mut link = keepalive_link();
+        let start = link.now();
+        while link.now() < start + Duration::from_secs(3) {
+            send_from_client(&link);
+            link.run(STEP);
+        }
+        assert_eq!(client_pings(&link, start), Vec::<Duration>::new());
+        assert!(link.server_conn().is_authenticated());
+    }
+
+    #[test]
+    fn ping_is_sent_after_ping_interval_of_silence() {
+        let mut link = keepalive_link();
+        let ping_interval = Duration::from_millis(Kcp2KConfig::default().ping_interval);
+        send_from_client(&link);
+        let silent_since = link.now();
+        link.run(ping_interval * 2 + STEP);
+        let pings = client_pings(&link, silent_since);
+        assert_eq!(pings.len(), 2, "{:?}", pings);
+        assert!(pings[0] >= silent_since + ping_interval, "{:?}", pings);
+        assert!(
+            pings[0] <= silent_since + ping_interval + STEP,
+            "{:?}",
+            pings
+        );
+        // ping 本身也算发送，下一个 ping 在又一个 ping_interval 之后
+        assert_eq!(pings[1] - pings[0], ping_interval);
+    }
+
+    #[test]
+    fn idle_disconnect_fires_at_idle_timeout() {
+        let mut link = keepalive_link();
+        let idle_timeout = Duration::from_millis(Kcp2KConfig::default().idle_timeout);
+        // 双方持续发送，直到链路中断
+        for _ in 0..10 {
+            send_from_client(&link);
+            link.server
+                .send(
+                    link.server_id(),
+                    Bytes::from_static(b"data"),
+                    Kcp2KChannel::Unreliable,
+                )
+                .unwrap();
+            link.run(STEP);
+        }
+        take_disconnected();
+        link.blocked = true;
+        link.run(idle_timeout);
+        assert_eq!(take_disconnected(), Vec::new());
+        link.run(STEP * 3);
+        let mut disconnected = take_disconnected();
+        disconnected.sort_by_key(|(mode, _, _)| *mode == Kcp2KMode::Client);
+        assert_eq!(
+            disconnected,
+            vec![
+                (Kcp2KMode::Server, DisconnectReason::Timeout, String::new()),
+                (Kcp2KMode::Client, DisconnectReason::Timeout, String::new()),
+            ]
+        );
+    }
+
+    #[test]
+    fn handshake_disconnect_fires_at_handshake_timeout() {
+        let config = Kcp2KConfig {
+            handshake_timeout: 500,
+            ..Kcp2KConfig::default()
+        };
+        let mut link = Kcp2KTestLink::new(config, record_disconnected, record_disconnected);
+        // 服务器从不回复
+        link.blocked = true;
+        link.run(Duration::from_millis(config.handshake_timeout));
+        assert_eq!(take_disconnected(), Vec::new());
+        link.run(STEP * 2);
+        assert_eq!(
+            take_disconnected(),
+            vec![(
+                Kcp2KMode::Client,
+                DisconnectReason::Timeout,
+                "handshake timeout".to_string()
+            )]
+        );
+        assert!(link.client.get_connections().is_empty());
+    }
+
+    // 服务器和客户端使用不同配置的链路，客户端已经发起连接
+    fn version_link(server: Kcp2KConfig, client: Kcp2KConfig) -> Kcp2KTestLink {
+        let mut link = Kcp2KTestLink::unconnected(
+            server,
+            Kcp2KMode::Server,
+            Kcp2KMode::Client,
+            record_disconnected,
+            record_disconnected,
+        );
+        link.client = Kcp2K::new_replay(client, Kcp2KMode::Client, 2, record_disconnected).unwrap();
+        link.client_id = link.client.connect("10.0.0.1:7777".to_string()).unwrap();
+        link
+    }
+
+    // 服务器发出的 Hello 回复中 Hello 头部之后的字节
+    fn server_hello(link: &Kcp2KTestLink) -> Bytes {
+        link.sent
+            .iter()
+            .filter(|(_, mode, _)| *mode == Kcp2KMode::Server)
+            .filter_map(|(_, _, data)| kcp2k_packet::Kcp2KPacket::decode(data).ok())
+            .find_map(|packet| {
+                packet.segments().find_map(|segment| {
+                    let segment = segment.ok()?;
+                    match segment.data.split_first() {
+                        Some((&header, hello))
+                            if segment.cmd == kcp2k_packet::KCP_CMD_PUSH
+                                && header == Kcp2KHeaderReliable::Hello.to_u8() =>
+                        {
+                            Some(Bytes::copy_from_slice(hello))
+                        }
+                        _ => None,
+                    }
+                })
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn server_hello_advertises_negotiated_version() {
+        // C# kcp2k 客户端的 Hello 是 LEGACY，协商结果为版本 0，服务器也回复 LEGACY
+        let client = Kcp2KConfig {
+            csharp_compat: true,
+            ..Kcp2KConfig::default()
+        };
+        let mut link = version_link(Kcp2KConfig::default(), client);
+        link.run(Duration::from_millis(200));
+        assert!(link.server_conn().is_authenticated());
+        let protocol = link.server_conn().get_protocol().unwrap();
+        assert_eq!(protocol.version, 0);
+        let (hello, _) = Kcp2KHello::decode(&server_hello(&link)).unwrap();
+        assert_eq!(hello, Kcp2KHello::LEGACY);
+
+        let mut link = version_link(Kcp2KConfig::default(), Kcp2KConfig::default());
+        link.run(Duration::from_millis(200));
+        let (hello, _) = Kcp2KHello::decode(&server_hello(&link)).unwrap();
+        assert_eq!(
+            hello.version,
+            link.server_conn().get_protocol().unwrap().version
+        );
+        take_disconnected();
+    }
+
+    #[test]
+    fn server_rejects_client_below_min_protocol_version() {
+        let server = Kcp2KConfig {
+            min_protocol_version: 1,
+            ..Kcp2KConfig::default()
+        };
+        let client = Kcp2KConfig {
+            csharp_compat: true,
+            ..Kcp2KConfig::default()
+        };
+        let mut link = version_link(server, client);
+        link.run(Duration::from_millis(200));
+        assert!(link.server.get_connections().is_empty());
+        let disconnected = take_disconnected();
+        let (mode, reason, message) = &disconnected[0];
+        assert_eq!(
+            (*mode, *reason),
+            (Kcp2KMode::Server, DisconnectReason::Rejected)
+        );
+        assert!(
+            message.contains("version 0 is older than the minimum 1"),
+            "{}",
+            message
+        );
+    }
+
+    #[test]
+    fn client_rejects_server_reply_below_min_protocol_version() {
+        // 服务器回复的协商版本低于客户端要求的最低版本
+        let client = Kcp2KConfig {
+            min_protocol_version: 2,
+            ..Kcp2KConfig::default()
+        };
+        let mut link = version_link(Kcp2KConfig::default(), client);
+        link.run(Duration::from_millis(200));
+        assert!(link.client.get_connections().is_empty());
+        let disconnected = take_disconnected();
+        let client = disconnected
+            .iter()
+            .find(|(mode, _, _)| *mode == Kcp2KMode::Client)
+            .unwrap();
+        assert_eq!(client.1, DisconnectReason::Rejected);
+        assert!(
+            client.2.contains("version 1 is older than the minimum 2"),
+            "{}",
+            client.2
+        );
     }
 }
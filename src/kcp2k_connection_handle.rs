@@ -0,0 +1,194 @@
+use crate::error_code::ErrorCode;
+use crate::kcp2k_channel::Kcp2KChannel;
+use crate::kcp2k_context::Kcp2KContext;
+use crate::kcp2k_protocol::{Kcp2KCapabilities, Kcp2KProtocol};
+use crate::kcp2k_rtt::{Kcp2KRtt, Kcp2KRttEstimator};
+use bytes::Bytes;
+use crossbeam_queue::SegQueue;
+use socket2::SockAddr;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+// 发件箱中的消息：用户数据，或 Kcp2KRpc 的 RPC 帧
+#[derive(Debug)]
+pub(crate) enum Kcp2KOutgoing {
+    Data(Bytes, Kcp2KChannel),
+    Rpc(Bytes),
+}
+
+// 连接的发件箱：其他线程无锁写入，网络线程在 tick_outgoing 时取出发送
+pub(crate) type Kcp2KOutbox = SegQueue<Kcp2KOutgoing>;
+
+// ConnectionHandle: 可克隆、线程安全的连接句柄。
+// 发送的消息先进入连接的发件箱，由网络线程下一次 tick 时发出，因此不会因为连接正在 tick 而失败。
+#[derive(Debug, Clone)]
+pub struct ConnectionHandle {
+    id: u64,
+    outbox: Arc<Kcp2KOutbox>,
+    alive: Arc<AtomicBool>,
+    client_sock_addr: Arc<RwLock<SockAddr>>,
+    rtt: Arc<Mutex<Kcp2KRttEstimator>>,
+    protocol: Arc<RwLock<Option<Kcp2KProtocol>>>,
+    context: Arc<Kcp2KContext>,
+}
+
+impl ConnectionHandle {
+    pub(crate) fn new(
+        id: u64,
+        outbox: Arc<Kcp2KOutbox>,
+        alive: Arc<AtomicBool>,
+        client_sock_addr: Arc<RwLock<SockAddr>>,
+        rtt: Arc<Mutex<Kcp2KRttEstimator>>,
+        protocol: Arc<RwLock<Option<Kcp2KProtocol>>>,
+        context: Arc<Kcp2KContext>,
+    ) -> Self {
+        Self {
+            id,
+            outbox,
+            alive,
+            client_sock_addr,
+            rtt,
+            protocol,
+            context,
+        }
+    }
+    pub fn get_connection_id(&self) -> u64 {
+        self.id
+    }
+    // 连接是否仍然存活，断开或被移除后返回 false
+    pub fn is_alive(&self) -> bool {
+        self.alive.load(Ordering::Acquire)
+    }
+    // 远端地址，连接迁移后会随之更新
+    pub fn get_remote_addr(&self) -> Option<SocketAddr> {
+        match self.client_sock_addr.read() {
+            Ok(client_sock_addr) => client_sock_addr.as_socket(),
+            Err(err) => err.into_inner().as_socket(),
+        }
+    }
+    // 网络线程最近一次测量的 RTT，见 Kcp2KConnection::get_rtt
+    pub fn get_rtt(&self) -> Kcp2KRtt {
+        match self.rtt.lock() {
+            Ok(rtt) => rtt.snapshot(),
+            Err(err) => err.into_inner().snapshot(),
+        }
+    }
+    // 握手协商的协议版本和功能，握手完成前为 None
+    pub fn get_protocol(&self) -> Option<Kcp2KProtocol> {
+        match self.protocol.read() {
+            Ok(protocol) => *protocol,
+            Err(err) => *err.into_inner(),
+        }
+    }
+    // 连接所在 Kcp2K 的时钟最近一次 tick 的时间，回放时是虚拟时间
+    pub(crate) fn now(&self) -> Duration {
+        self.context.now()
+    }
+    // 把消息放入发件箱
+    pub fn send(&self, data: Bytes, channel: Kcp2KChannel) -> Result<(), ErrorCode> {
+        if !self.is_alive() {
+            return Err(ErrorCode::ConnectionClosed);
+        }
+        if data.is_empty() || channel == Kcp2KChannel::None {
+            return Err(ErrorCode::InvalidSend);
+        }
+        self.outbox.push(Kcp2KOutgoing::Data(data, channel));
+        Ok(())
+    }
+    // 把 RPC 帧放入发件箱。握手完成后对方没有协商 RPC 时返回错误，
+    // 握手完成前先放入发件箱，发送时再检查
+    pub(crate) fn send_rpc(&self, frame: Bytes) -> Result<(), ErrorCode> {
+        if !self.is_alive() {
+            return Err(ErrorCode::ConnectionClosed);
+        }
+        if let Some(protocol) = self.get_protocol() {
+            if !protocol.supports(Kcp2KCapabilities::RPC) {
+                return Err(ErrorCode::InvalidSend);
+            }
+        }
+        self.outbox.push(Kcp2KOutgoing::Rpc(frame));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kcp2k_callback::{Callback, CallbackType};
+    use crate::kcp2k_config::Kcp2KConfig;
+    use crate::kcp2k_connection::Kcp2KConnection;
+    use crate::kcp2k_disconnect_reason::DisconnectReason;
+    use crate::kcp2k_testing::{ignore, Kcp2KTestLink, STEP};
+    use std::cell::RefCell;
+    use std::thread;
+
+    thread_local! {
+        static CLIENT_DATA: RefCell<Vec<Bytes>> = const { RefCell::new(Vec::new()) };
+    }
+
+    fn record_data(_: &Kcp2KConnection, cb: Callback) {
+        if matches!(cb.r#type, CallbackType::OnData) {
+            CLIENT_DATA.with(|data| data.borrow_mut().push(cb.data));
+        }
+    }
+
+    // 在另一个线程上通过句柄发送
+    fn send_from_thread(handle: &ConnectionHandle, data: &'static [u8], channel: Kcp2KChannel) {
+        let handle = handle.clone();
+        thread::spawn(move || handle.send(Bytes::from_static(data), channel))
+            .join()
+            .unwrap()
+            .unwrap();
+    }
+
+    #[test]
+    fn send_from_another_thread_is_sent_on_next_tick_outgoing() {
+        let mut link = Kcp2KTestLink::connected(Kcp2KConfig::default(), ignore, record_data);
+        let handle = link.server_conn().get_handle();
+        assert_eq!(handle.get_connection_id(), link.server_id());
+        send_from_thread(&handle, b"from thread", Kcp2KChannel::Unreliable);
+        // 消息留在发件箱中，直到网络线程 tick_outgoing
+        assert!(link.server.take_outbound().is_empty());
+        link.server.tick_outgoing();
+        let outbound = link.server.take_outbound();
+        assert_eq!(outbound.len(), 1);
+        assert!(outbound[0].1.ends_with(b"from thread"));
+        for (_, data) in outbound {
+            link.client.push_inbound(link.server_addr.clone(), data);
+        }
+        link.run(STEP);
+        assert_eq!(
+            CLIENT_DATA.with(|data| data.take()),
+            vec![Bytes::from_static(b"from thread")]
+        );
+
+        send_from_thread(&handle, b"reliable", Kcp2KChannel::Reliable);
+        link.run(Duration::from_millis(100));
+        assert_eq!(
+            CLIENT_DATA.with(|data| data.take()),
+            vec![Bytes::from_static(b"reliable")]
+        );
+    }
+
+    #[test]
+    fn send_on_closed_connection_returns_error() {
+        let mut link = Kcp2KTestLink::connected(Kcp2KConfig::default(), ignore, record_data);
+        let handle = link.server_conn().get_handle();
+        assert!(matches!(
+            handle.send(Bytes::new(), Kcp2KChannel::Reliable),
+            Err(ErrorCode::InvalidSend)
+        ));
+        link.server
+            .disconnect(link.server_id(), DisconnectReason::Kicked, "");
+        link.run(Duration::from_millis(100));
+        assert!(link.server.get_connections().is_empty());
+        assert!(!handle.is_alive());
+        let result =
+            thread::spawn(move || handle.send(Bytes::from_static(b"late"), Kcp2KChannel::Reliable))
+                .join()
+                .unwrap();
+        assert!(matches!(result, Err(ErrorCode::ConnectionClosed)));
+    }
+}
@@ -0,0 +1,119 @@
+use crate::kcp2k_replay::Kcp2KRecorder;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cell::Cell;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+thread_local! {
+    // 当前线程正在执行的回调层数，回调中发起的发送在回放时由回调本身重现，不需要录制
+    static CALLBACK_DEPTH: Cell<u32> = const { Cell::new(0) };
+}
+
+#[derive(Debug)]
+enum Kcp2KClock {
+    Real(Instant),
+    Virtual, // 回放：时间由录制的 tick 时间设置
+}
+
+// Kcp2KContext: Kcp2K 和它的所有连接共享的时钟、随机数源和会话录制。
+// 时间在每次 tick 开始时锁定，同一次 tick 中的所有计时使用同一个时间，录制的会话因此可以确定地回放
+#[derive(Debug)]
+pub struct Kcp2KContext {
+    clock: Kcp2KClock,
+    now: AtomicU64, // 锁定的时间，单位为微秒
+    rng: Mutex<StdRng>,
+    pub(crate) recorder: Kcp2KRecorder,
+}
+
+impl Kcp2KContext {
+    pub(crate) fn new() -> Self {
+        Self {
+            clock: Kcp2KClock::Real(Instant::now()),
+            now: AtomicU64::new(0),
+            rng: Mutex::new(StdRng::seed_from_u64(rand::random())),
+            recorder: Kcp2KRecorder::default(),
+        }
+    }
+    // 回放使用的虚拟时钟和固定的随机数种子
+    pub(crate) fn new_virtual(seed: u64) -> Self {
+        Self {
+            clock: Kcp2KClock::Virtual,
+            now: AtomicU64::new(0),
+            rng: Mutex::new(StdRng::seed_from_u64(seed)),
+            recorder: Kcp2KRecorder::default(),
+        }
+    }
+    // tick 开始时锁定当前时间并返回
+    pub(crate) fn latch(&self) -> Duration {
+        if let Kcp2KClock::Real(start) = self.clock {
+            self.now
+                .store(start.elapsed().as_micros() as u64, Ordering::Release);
+        }
+        self.now()
+    }
+    pub(crate) fn now(&self) -> Duration {
+        Duration::from_micros(self.now.load(Ordering::Acquire))
+    }
+    // 虚拟时钟：设置当前时间
+    pub(crate) fn set_time(&self, now: Duration) {
+        if let Kcp2KClock::Virtual = self.clock {
+            self.now.store(now.as_micros() as u64, Ordering::Release);
+        }
+    }
+    pub(crate) fn reseed(&self, seed: u64) {
+        if let Ok(mut rng) = self.rng.lock() {
+            *rng = StdRng::seed_from_u64(seed);
+        }
+    }
+    pub(crate) fn random_u64(&self) -> u64 {
+        match self.rng.lock() {
+            Ok(mut rng) => rng.random(),
+            Err(err) => err.into_inner().random(),
+        }
+    }
+    pub(crate) fn random_bytes<const N: usize>(&self) -> [u8; N] {
+        let mut buffer = [0u8; N];
+        match self.rng.lock() {
+            Ok(mut rng) => rng.fill(&mut buffer),
+            Err(err) => err.into_inner().fill(&mut buffer),
+        }
+        buffer
+    }
+    // 执行回调，期间 is_in_callback 返回 true
+    pub(crate) fn in_callback<R>(f: impl FnOnce() -> R) -> R {
+        // 回调 panic 时也要恢复层数
+        struct Guard;
+        impl Drop for Guard {
+            fn drop(&mut self) {
+                CALLBACK_DEPTH.with(|depth| depth.set(depth.get() - 1));
+            }
+        }
+        CALLBACK_DEPTH.with(|depth| depth.set(depth.get() + 1));
+        let _guard = Guard;
+        f()
+    }
+    pub(crate) fn is_in_callback() -> bool {
+        CALLBACK_DEPTH.with(|depth| depth.get() > 0)
+    }
+}
+
+// Kcp2KWatch: 从创建时开始计时的秒表，读取所属 Kcp2KContext 锁定的时间
+#[derive(Debug, Clone)]
+pub struct Kcp2KWatch {
+    context: Arc<Kcp2KContext>,
+    start: Duration,
+}
+
+impl Kcp2KWatch {
+    pub(crate) fn new(context: &Arc<Kcp2KContext>) -> Self {
+        Self {
+            context: Arc::clone(context),
+            start: context.now(),
+        }
+    }
+    pub fn elapsed(&self) -> Duration {
+        self.context.now().saturating_sub(self.start)
+    }
+}
@@ -0,0 +1,134 @@
+use crate::kcp2k_reason::{Kcp2KReasonByte, Kcp2KReasonCode};
+use bytes::{BufMut, Bytes, BytesMut};
+
+// 断开消息中说明的最大字节数
+pub const MAX_DISCONNECT_MESSAGE: usize = 255;
+
+// 连接断开的原因，随 OnDisconnected 回调传递。
+// 不可靠通道的 Disconnect 消息载荷：原因(1) + 说明（UTF-8），C# kcp2k 的断开消息没有载荷。
+// 原因字节的编码见 kcp2k_reason
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DisconnectReason {
+    #[default]
+    None, // 未断开
+    Closed,                       // 主动关闭，或对方关闭时没有说明原因
+    Kicked,                       // 被服务器踢出
+    Timeout,                      // 超时未收到对方的消息
+    DeadLink,                     // 可靠消息的重传次数超过上限
+    InvalidData,                  // 收到无效的数据
+    Rejected,                     // 握手被拒绝，见 Kcp2KRejectReason
+    Shutdown,                     // 网络线程或服务器停止
+    Application(Kcp2KReasonCode), // 应用定义的原因，见 application
+    Unknown(Kcp2KReasonCode),     // 更新的版本定义的原因
+}
+
+impl DisconnectReason {
+    // 应用定义的原因，code 超过 127 时返回 None
+    pub fn application(code: u8) -> Option<Self> {
+        Kcp2KReasonCode::new(code).map(Self::Application)
+    }
+
+    pub fn from(value: u8) -> Self {
+        let code = match Kcp2KReasonByte::from(value) {
+            Kcp2KReasonByte::Application(code) => return Self::Application(code),
+            Kcp2KReasonByte::Library(code) => code,
+        };
+        match code.get() {
+            0 => Self::None,
+            1 => Self::Closed,
+            2 => Self::Kicked,
+            3 => Self::Timeout,
+            4 => Self::DeadLink,
+            5 => Self::InvalidData,
+            6 => Self::Rejected,
+            7 => Self::Shutdown,
+            _ => Self::Unknown(code),
+        }
+    }
+
+    pub fn to_u8(self) -> u8 {
+        match self {
+            Self::None => 0,
+            Self::Closed => 1,
+            Self::Kicked => 2,
+            Self::Timeout => 3,
+            Self::DeadLink => 4,
+            Self::InvalidData => 5,
+            Self::Rejected => 6,
+            Self::Shutdown => 7,
+            Self::Application(code) => Kcp2KReasonByte::Application(code).to_u8(),
+            Self::Unknown(code) => Kcp2KReasonByte::Library(code).to_u8(),
+        }
+    }
+
+    // Disconnect 消息的载荷，说明超过 MAX_DISCONNECT_MESSAGE 时在字符边界截断
+    pub fn encode(self, message: &str) -> Bytes {
+        let mut end = message.len().min(MAX_DISCONNECT_MESSAGE);
+        while !message.is_char_boundary(end) {
+            end -= 1;
+        }
+        let mut buffer = BytesMut::with_capacity(1 + end);
+        buffer.put_u8(self.to_u8());
+        buffer.put_slice(&message.as_bytes()[..end]);
+        buffer.freeze()
+    }
+
+    // 解码 Disconnect 消息的载荷，没有载荷时视为对方关闭
+    pub fn decode(payload: &[u8]) -> (Self, String) {
+        match payload.split_first() {
+            Some((&reason, message)) => (
+                Self::from(reason),
+                String::from_utf8_lossy(message).into_owned(),
+            ),
+            None => (Self::Closed, String::new()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_byte_round_trips() {
+        for value in 0..=u8::MAX {
+            assert_eq!(DisconnectReason::from(value).to_u8(), value);
+        }
+    }
+
+    #[test]
+    fn application_reasons_round_trip() {
+        for code in 0..=Kcp2KReasonCode::MAX {
+            let reason = DisconnectReason::application(code).unwrap();
+            assert_eq!(DisconnectReason::from(reason.to_u8()), reason);
+        }
+        assert_eq!(DisconnectReason::application(128), None);
+        assert_eq!(DisconnectReason::application(200), None);
+    }
+
+    #[test]
+    fn unknown_reasons_stay_unknown() {
+        let reason = DisconnectReason::from(100);
+        assert_eq!(
+            reason,
+            DisconnectReason::Unknown(Kcp2KReasonCode::new(100).unwrap())
+        );
+        assert_eq!(DisconnectReason::from(reason.to_u8()), reason);
+    }
+
+    #[test]
+    fn message_round_trips_and_is_truncated_on_char_boundary() {
+        let reason = DisconnectReason::application(42).unwrap();
+        assert_eq!(
+            DisconnectReason::decode(&reason.encode("server full")),
+            (reason, "server full".to_string())
+        );
+        let long = "é".repeat(MAX_DISCONNECT_MESSAGE);
+        let (_, message) = DisconnectReason::decode(&DisconnectReason::Kicked.encode(&long));
+        assert_eq!(message, "é".repeat(MAX_DISCONNECT_MESSAGE / 2));
+        assert_eq!(
+            DisconnectReason::decode(&[]),
+            (DisconnectReason::Closed, String::new())
+        );
+    }
+}
@@ -0,0 +1,195 @@
+use dashmap::DashMap;
+use std::collections::HashSet;
+
+// Kcp2KGroups: 命名分组，分组名 -> 连接 ID 集合
+// 分组在第一个连接加入时创建，最后一个连接离开时删除
+#[derive(Debug, Default)]
+pub(crate) struct Kcp2KGroups {
+    groups: DashMap<String, HashSet<u64>>,
+}
+
+impl Kcp2KGroups {
+    // 加入分组，已在分组中时返回 false
+    pub(crate) fn join(&self, group: &str, connection_id: u64) -> bool {
+        self.groups
+            .entry(group.to_string())
+            .or_default()
+            .insert(connection_id)
+    }
+    // 离开分组，不在分组中时返回 false
+    pub(crate) fn leave(&self, group: &str, connection_id: u64) -> bool {
+        let removed = match self.groups.get_mut(group) {
+            Some(mut members) => members.remove(&connection_id),
+            None => false,
+        };
+        self.groups
+            .remove_if(group, |_, members| members.is_empty());
+        removed
+    }
+    // 连接断开时，从所有分组中移除
+    pub(crate) fn remove_connection(&self, connection_id: u64) {
+        self.groups.retain(|_, members| {
+            members.remove(&connection_id);
+            !members.is_empty()
+        });
+    }
+    pub(crate) fn remove_group(&self, group: &str) {
+        self.groups.remove(group);
+    }
+    pub(crate) fn contains(&self, group: &str, connection_id: u64) -> bool {
+        match self.groups.get(group) {
+            Some(members) => members.contains(&connection_id),
+            None => false,
+        }
+    }
+    pub(crate) fn get_members(&self, group: &str) -> Vec<u64> {
+        match self.groups.get(group) {
+            Some(members) => members.iter().copied().collect(),
+            None => Vec::new(),
+        }
+    }
+    pub(crate) fn get_names(&self) -> Vec<String> {
+        self.groups
+            .iter()
+            .map(|group| group.key().clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error_code::ErrorCode;
+    use crate::kcp2k_callback::{Callback, CallbackType};
+    use crate::kcp2k_channel::Kcp2KChannel;
+    use crate::kcp2k_config::Kcp2KConfig;
+    use crate::kcp2k_connection::Kcp2KConnection;
+    use crate::kcp2k_disconnect_reason::DisconnectReason;
+    use crate::kcp2k_testing::{ignore, Kcp2KTestClients};
+    use bytes::Bytes;
+    use std::cell::RefCell;
+    use std::time::Duration;
+
+    thread_local! {
+        // 客户端收到的数据：连接的 cookie 和数据
+        static CLIENT_DATA: RefCell<Vec<(Bytes, Bytes)>> = const { RefCell::new(Vec::new()) };
+    }
+
+    fn record_data(conn: &Kcp2KConnection, cb: Callback) {
+        if matches!(cb.r#type, CallbackType::OnData) {
+            CLIENT_DATA.with(|data| data.borrow_mut().push((conn.get_cookie(), cb.data)));
+        }
+    }
+
+    fn take_data() -> Vec<(Bytes, Bytes)> {
+        CLIENT_DATA.with(|data| data.take())
+    }
+
+    fn connected(count: usize) -> Kcp2KTestClients {
+        let mut link = Kcp2KTestClients::new(Kcp2KConfig::default(), count, ignore, record_data);
+        link.run(Duration::from_millis(200));
+        assert_eq!(link.server.get_connections().len(), count);
+        link
+    }
+
+    // 广播后收到数据的客户端
+    fn receivers(link: &mut Kcp2KTestClients) -> Vec<usize> {
+        link.run(Duration::from_millis(100));
+        let data = take_data();
+        (0..link.clients.len())
+            .filter(|i| {
+                data.iter()
+                    .any(|(cookie, _)| *cookie == cookie_of(link, *i))
+            })
+            .collect()
+    }
+
+    fn cookie_of(link: &Kcp2KTestClients, i: usize) -> Bytes {
+        link.server
+            .get_connections()
+            .get(&link.server_id(i))
+            .unwrap()
+            .get_cookie()
+    }
+
+    fn sorted(mut ids: Vec<u64>) -> Vec<u64> {
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn join_and_leave() {
+        let link = connected(2);
+        let (a, b) = (link.server_id(0), link.server_id(1));
+        link.server.join_group("red", a).unwrap();
+        link.server.join_group("red", b).unwrap();
+        link.server.join_group("blue", a).unwrap();
+        assert!(link.server.is_in_group("red", a));
+        assert_eq!(
+            sorted(link.server.get_group_members("red")),
+            sorted(vec![a, b])
+        );
+        let mut names = link.server.get_group_names();
+        names.sort();
+        assert_eq!(names, vec!["blue", "red"]);
+        assert!(matches!(
+            link.server.join_group("red", a.wrapping_add(b)),
+            Err(ErrorCode::ConnectionNotFound)
+        ));
+
+        assert!(link.server.leave_group("red", a));
+        assert!(!link.server.leave_group("red", a));
+        assert!(!link.server.is_in_group("red", a));
+        assert_eq!(link.server.get_group_members("red"), vec![b]);
+        // 最后一个连接离开后分组被删除
+        assert!(link.server.leave_group("blue", a));
+        assert_eq!(link.server.get_group_names(), vec!["red"]);
+        link.server.remove_group("red");
+        assert!(link.server.get_group_names().is_empty());
+    }
+
+    #[test]
+    fn disconnected_connection_leaves_all_groups() {
+        let mut link = connected(2);
+        let (a, b) = (link.server_id(0), link.server_id(1));
+        link.server.join_group("red", a).unwrap();
+        link.server.join_group("red", b).unwrap();
+        link.server.join_group("blue", a).unwrap();
+        link.server.disconnect(a, DisconnectReason::Kicked, "");
+        link.run(Duration::from_millis(100));
+        assert_eq!(link.server.get_connections().len(), 1);
+        assert_eq!(link.server.get_group_members("red"), vec![b]);
+        assert_eq!(link.server.get_group_names(), vec!["red"]);
+    }
+
+    #[test]
+    fn broadcast_skips_excluded_connections() {
+        let mut link = connected(3);
+        let ids: Vec<u64> = (0..3).map(|i| link.server_id(i)).collect();
+        link.server.join_group("red", ids[0]).unwrap();
+        link.server.join_group("red", ids[1]).unwrap();
+        take_data();
+
+        let broadcast = |link: &Kcp2KTestClients, group, except: &[u64]| {
+            link.server
+                .broadcast(
+                    group,
+                    Bytes::from_static(b"hi"),
+                    Kcp2KChannel::Reliable,
+                    except,
+                )
+                .unwrap()
+        };
+        assert_eq!(broadcast(&link, None, &[]), 3);
+        assert_eq!(receivers(&mut link), vec![0, 1, 2]);
+        assert_eq!(broadcast(&link, None, &[ids[1]]), 2);
+        assert_eq!(receivers(&mut link), vec![0, 2]);
+        // 分组广播只发给成员，排除列表同样生效
+        assert_eq!(broadcast(&link, Some("red"), &[]), 2);
+        assert_eq!(receivers(&mut link), vec![0, 1]);
+        assert_eq!(broadcast(&link, Some("red"), &[ids[0]]), 1);
+        assert_eq!(receivers(&mut link), vec![1]);
+        assert_eq!(broadcast(&link, Some("red"), &ids), 0);
+        assert_eq!(receivers(&mut link), Vec::<usize>::new());
+        assert_eq!(broadcast(&link, Some("green"), &[]), 0);
+    }
+}
@@ -0,0 +1,408 @@
+use crate::common::Kcp2KMode;
+use crate::error_code::ErrorCode;
+use crate::kcp2k::Kcp2K;
+use crate::kcp2k_callback::Callback;
+use crate::kcp2k_channel::Kcp2KChannel;
+use crate::kcp2k_config::Kcp2KConfig;
+use crate::kcp2k_connection::Kcp2KConnection;
+use crate::kcp2k_disconnect_reason::DisconnectReason;
+use bytes::Bytes;
+use crossbeam_channel::{bounded, Receiver as EventReceiver, Sender as EventSender};
+use std::cell::RefCell;
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use tklog::{error, info};
+
+// 发往网络线程的命令
+enum Kcp2KCommand {
+    Send(u64, Bytes, Kcp2KChannel),
+    Broadcast(Option<String>, Bytes, Kcp2KChannel, Vec<u64>),
+    JoinGroup(String, u64),
+    LeaveGroup(String, u64),
+    Close(u64, DisconnectReason, String),
+    Connect(String, Sender<Result<u64, Error>>),
+    Shutdown,
+}
+
+// 事件队列的容量。队列满时网络线程阻塞，直到有句柄取走事件，
+// 因此处理事件过慢会拖慢 tick，但内存不会无限增长
+pub const EVENT_CAPACITY: usize = 4096;
+// connect 等待网络线程回复的最长时间
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+thread_local! {
+    // 网络线程的事件发送端，回调是函数指针，通过线程局部变量把事件转发到 channel
+    static EVENT_SENDER: RefCell<Option<EventSender<Callback>>> = const { RefCell::new(None) };
+}
+
+fn forward_callback(_: &Kcp2KConnection, callback: Callback) {
+    EVENT_SENDER.with(|sender| {
+        if let Some(sender) = sender.borrow().as_ref() {
+            let _ = sender.send(callback);
+        }
+    });
+}
+
+struct Kcp2KHandleInner {
+    commands: Sender<Kcp2KCommand>,
+    events: EventReceiver<Callback>,
+    local_addr: SocketAddr,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+// 最后一个句柄被释放时，停止网络线程并等待其退出。
+// 网络线程可能正阻塞在已满的事件队列上，等待期间丢弃剩余事件
+impl Drop for Kcp2KHandleInner {
+    fn drop(&mut self) {
+        let _ = self.commands.send(Kcp2KCommand::Shutdown);
+        if let Ok(mut thread) = self.thread.lock() {
+            if let Some(thread) = thread.take() {
+                while !thread.is_finished() {
+                    let _ = self.events.recv_timeout(Duration::from_millis(1));
+                }
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+// Kcp2KHandle: 后台网络线程的句柄，可以克隆并发送到其他线程。
+// 通过 send 发送消息，通过 recv/try_recv 接收 OnConnected、OnData 等事件。
+// 多个克隆可以同时接收，每个事件只会交给其中一个。
+#[derive(Clone)]
+pub struct Kcp2KHandle {
+    inner: Arc<Kcp2KHandleInner>,
+}
+
+impl Kcp2KHandle {
+    pub(crate) fn spawn(config: Kcp2KConfig, addr: String, mode: Kcp2KMode) -> Result<Self, Error> {
+        let kcp2k = match mode {
+            Kcp2KMode::Server => Kcp2K::new_server(config, addr, forward_callback)?,
+            Kcp2KMode::Client => Kcp2K::new_client(config, addr, forward_callback)?,
+            Kcp2KMode::Peer => Kcp2K::new_peer(config, addr, forward_callback)?,
+        };
+        let local_addr = kcp2k.get_local_addr()?;
+        let (command_sender, command_receiver) = channel();
+        let (event_sender, event_receiver) = bounded(EVENT_CAPACITY);
+        let interval = Duration::from_millis(config.interval.max(1) as u64);
+        let thread = std::thread::Builder::new()
+            .name(format!("kcp2k-{:?}", mode).to_lowercase())
+            .spawn(move || {
+                EVENT_SENDER.with(|sender| *sender.borrow_mut() = Some(event_sender));
+                Self::run(kcp2k, command_receiver, interval);
+            })?;
+        Ok(Self {
+            inner: Arc::new(Kcp2KHandleInner {
+                commands: command_sender,
+                events: event_receiver,
+                local_addr,
+                thread: Mutex::new(Some(thread)),
+            }),
+        })
+    }
+    // 网络线程：按固定间隔 tick，间隔内等待并处理命令
+    fn run(kcp2k: Kcp2K, commands: Receiver<Kcp2KCommand>, interval: Duration) {
+        let mut next_tick = Instant::now();
+        'running: loop {
+            let now = Instant::now();
+            if now >= next_tick {
+                kcp2k.tick();
+                next_tick = Self::next_tick(next_tick, now, interval);
+                continue;
+            }
+            match commands.recv_timeout(next_tick - now) {
+                Ok(Kcp2KCommand::Send(connection_id, data, channel)) => {
+                    if let Err(err) = kcp2k.send(connection_id, data, channel) {
+                        error!(format!(
+                            "[KCP2K] Handle send to {} failed: {:?}",
+                            connection_id, err
+                        ));
+                    }
+                }
+                Ok(Kcp2KCommand::Broadcast(group, data, channel, except)) => {
+                    if let Err(err) = kcp2k.broadcast(group.as_deref(), data, channel, &except) {
+                        error!(format!("[KCP2K] Handle broadcast failed: {:?}", err));
+                    }
+                }
+                Ok(Kcp2KCommand::JoinGroup(group, connection_id)) => {
+                    let _ = kcp2k.join_group(&group, connection_id);
+                }
+                Ok(Kcp2KCommand::LeaveGroup(group, connection_id)) => {
+                    kcp2k.leave_group(&group, connection_id);
+                }
+                Ok(Kcp2KCommand::Close(connection_id, reason, message)) => {
+                    kcp2k.disconnect(connection_id, reason, &message)
+                }
+                Ok(Kcp2KCommand::Connect(addr, reply)) => {
+                    let _ = reply.send(kcp2k.connect(addr));
+                }
+                Ok(Kcp2KCommand::Shutdown) | Err(RecvTimeoutError::Disconnected) => break 'running,
+                Err(RecvTimeoutError::Timeout) => {}
+            }
+        }
+        // 通知所有连接断开，并把断开消息发出去
+        for connection in kcp2k.get_connections().iter() {
+            connection.disconnect(DisconnectReason::Shutdown, "");
+        }
+        kcp2k.tick_outgoing();
+        let _ = kcp2k.stop();
+        info!("[KCP2K] Network thread stopped");
+    }
+    // 下一次 tick 的时间：按固定间隔推进，落后太多时（例如线程被挂起）不再追赶
+    fn next_tick(last_tick: Instant, now: Instant, interval: Duration) -> Instant {
+        let next_tick = last_tick + interval;
+        if next_tick < now {
+            now + interval
+        } else {
+            next_tick
+        }
+    }
+    // 网络线程绑定的本地地址
+    pub fn get_local_addr(&self) -> SocketAddr {
+        self.inner.local_addr
+    }
+    // 发送消息，由网络线程在下一次 tick 前写入连接
+    pub fn send(
+        &self,
+        connection_id: u64,
+        data: Bytes,
+        channel: Kcp2KChannel,
+    ) -> Result<(), ErrorCode> {
+        match self
+            .inner
+            .commands
+            .send(Kcp2KCommand::Send(connection_id, data, channel))
+        {
+            Ok(_) => Ok(()),
+            Err(_) => Err(ErrorCode::ConnectionClosed),
+        }
+    }
+    // 广播，group 为 None 时发给所有连接
+    pub fn broadcast(
+        &self,
+        group: Option<&str>,
+        data: Bytes,
+        channel: Kcp2KChannel,
+        except: &[u64],
+    ) -> Result<(), ErrorCode> {
+        match self.inner.commands.send(Kcp2KCommand::Broadcast(
+            group.map(str::to_string),
+            data,
+            channel,
+            except.to_vec(),
+        )) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(ErrorCode::ConnectionClosed),
+        }
+    }
+    pub fn join_group(&self, group: &str, connection_id: u64) {
+        let _ = self
+            .inner
+            .commands
+            .send(Kcp2KCommand::JoinGroup(group.to_string(), connection_id));
+    }
+    pub fn leave_group(&self, group: &str, connection_id: u64) {
+        let _ = self
+            .inner
+            .commands
+            .send(Kcp2KCommand::LeaveGroup(group.to_string(), connection_id));
+    }
+    pub fn close_connection(&self, connection_id: u64) {
+        self.disconnect(connection_id, DisconnectReason::Closed, "");
+    }
+    // 断开连接并把原因告诉对方
+    pub fn disconnect(&self, connection_id: u64, reason: DisconnectReason, message: &str) {
+        let _ = self.inner.commands.send(Kcp2KCommand::Close(
+            connection_id,
+            reason,
+            message.to_string(),
+        ));
+    }
+    // P2P 节点主动连接其他节点，返回连接 ID。
+    // 会阻塞到网络线程处理完命令；网络线程阻塞在已满的事件队列上时（例如调用者自己就是取事件的线程），
+    // 最多等待 CONNECT_TIMEOUT 后返回 TimedOut，命令仍留在队列中，之后可能照常发起连接
+    pub fn connect(&self, addr: String) -> Result<u64, Error> {
+        let (reply_sender, reply_receiver) = channel();
+        if self
+            .inner
+            .commands
+            .send(Kcp2KCommand::Connect(addr, reply_sender))
+            .is_err()
+        {
+            return Err(Error::new(
+                ErrorKind::NotConnected,
+                "network thread stopped",
+            ));
+        }
+        match reply_receiver.recv_timeout(CONNECT_TIMEOUT) {
+            Ok(result) => result,
+            Err(RecvTimeoutError::Timeout) => Err(Error::new(
+                ErrorKind::TimedOut,
+                "network thread did not reply, the event queue may be full",
+            )),
+            Err(RecvTimeoutError::Disconnected) => Err(Error::new(
+                ErrorKind::NotConnected,
+                "network thread stopped",
+            )),
+        }
+    }
+    // 阻塞等待下一个事件，网络线程退出后返回 None
+    pub fn recv(&self) -> Option<Callback> {
+        self.inner.events.recv().ok()
+    }
+    // 等待下一个事件，最多等待 timeout
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<Callback> {
+        self.inner.events.recv_timeout(timeout).ok()
+    }
+    // 非阻塞地获取下一个事件
+    pub fn try_recv(&self) -> Option<Callback> {
+        self.inner.events.try_recv().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kcp2k_callback::CallbackType;
+    use std::thread;
+
+    // 等待第一个满足条件的事件，之前的事件丢弃
+    fn wait_for(handle: &Kcp2KHandle, found: impl Fn(&Callback) -> bool) -> Callback {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+            match handle.recv_timeout(remaining) {
+                Some(callback) if found(&callback) => return callback,
+                Some(_) => {}
+                None => break,
+            }
+        }
+        panic!("event not received in time");
+    }
+
+    // 连接好的服务器、客户端和客户端上的连接 ID
+    fn connected() -> (Kcp2KHandle, Kcp2KHandle, u64) {
+        let config = Kcp2KConfig::default();
+        let server = Kcp2K::spawn(config, "127.0.0.1:0".to_string()).unwrap();
+        let client = Kcp2K::spawn_client(config, server.get_local_addr().to_string()).unwrap();
+        wait_for(&server, |cb| matches!(cb.r#type, CallbackType::OnConnected));
+        let conn_id =
+            wait_for(&client, |cb| matches!(cb.r#type, CallbackType::OnConnected)).conn_id;
+        (server, client, conn_id)
+    }
+
+    #[test]
+    fn next_tick_keeps_the_interval_and_skips_missed_ticks() {
+        let start = Instant::now();
+        let interval = Duration::from_millis(10);
+        // 按时 tick 时在上一次的计划时间上推进，不累积误差
+        let late = start + Duration::from_millis(3);
+        assert_eq!(
+            Kcp2KHandle::next_tick(start, late, interval),
+            start + interval
+        );
+        // 落后超过一个间隔时从现在重新开始
+        let suspended = start + Duration::from_millis(55);
+        assert_eq!(
+            Kcp2KHandle::next_tick(start, suspended, interval),
+            suspended + interval
+        );
+    }
+
+    #[test]
+    fn spawned_threads_tick_without_the_caller() {
+        // 没有任何 tick 调用，握手和数据都由网络线程完成
+        let (server, client, conn_id) = connected();
+        client
+            .send(conn_id, Bytes::from_static(b"ping"), Kcp2KChannel::Reliable)
+            .unwrap();
+        let data = wait_for(&server, |cb| matches!(cb.r#type, CallbackType::OnData));
+        assert_eq!(data.data, Bytes::from_static(b"ping"));
+        assert_eq!(data.channel, Kcp2KChannel::Reliable);
+    }
+
+    #[test]
+    fn events_are_delivered_to_other_threads() {
+        let (server, client, conn_id) = connected();
+        let server_events = server.clone();
+        let receiver = thread::spawn(move || {
+            wait_for(&server_events, |cb| {
+                matches!(cb.r#type, CallbackType::OnData)
+            })
+            .data
+        });
+        client
+            .send(
+                conn_id,
+                Bytes::from_static(b"hello"),
+                Kcp2KChannel::Reliable,
+            )
+            .unwrap();
+        assert_eq!(receiver.join().unwrap(), Bytes::from_static(b"hello"));
+    }
+
+    #[test]
+    fn try_recv_does_not_wait_for_a_blocked_recv() {
+        let (server, client, conn_id) = connected();
+        let blocked = server.clone();
+        // 这个克隆阻塞在 recv 上，直到客户端发来数据
+        let receiver = thread::spawn(move || blocked.recv().unwrap().data);
+        thread::sleep(Duration::from_millis(50));
+        let start = Instant::now();
+        assert!(server.try_recv().is_none());
+        assert!(server.recv_timeout(Duration::from_millis(10)).is_none());
+        assert!(start.elapsed() < Duration::from_secs(1));
+        client
+            .send(conn_id, Bytes::from_static(b"wake"), Kcp2KChannel::Reliable)
+            .unwrap();
+        assert_eq!(receiver.join().unwrap(), Bytes::from_static(b"wake"));
+    }
+
+    #[test]
+    fn dropping_the_last_handle_disconnects_with_shutdown() {
+        let (server, client, conn_id) = connected();
+        let client_clone = client.clone();
+        drop(client);
+        // 仍有克隆时网络线程继续运行
+        client_clone
+            .send(
+                conn_id,
+                Bytes::from_static(b"alive"),
+                Kcp2KChannel::Reliable,
+            )
+            .unwrap();
+        wait_for(&server, |cb| matches!(cb.r#type, CallbackType::OnData));
+        drop(client_clone);
+        let disconnected = wait_for(&server, |cb| {
+            matches!(cb.r#type, CallbackType::OnDisconnected)
+        });
+        assert_eq!(disconnected.disconnect_reason, DisconnectReason::Shutdown);
+    }
+
+    #[test]
+    fn connect_times_out_while_the_event_queue_is_full() {
+        let (server, client, conn_id) = connected();
+        // 服务器的事件没有人取，队列满后网络线程阻塞
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !server.inner.events.is_full() {
+            assert!(Instant::now() < deadline, "event queue did not fill up");
+            for _ in 0..64 {
+                let _ = client.send(conn_id, Bytes::from_static(b"x"), Kcp2KChannel::Unreliable);
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        // 队列满了以后网络线程仍可能在两次 tick 之间处理命令，再发一条消息，等它阻塞在转发上
+        client
+            .send(conn_id, Bytes::from_static(b"x"), Kcp2KChannel::Reliable)
+            .unwrap();
+        thread::sleep(Duration::from_millis(100));
+        let start = Instant::now();
+        let err = server.connect("127.0.0.1:9".to_string()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert!(start.elapsed() >= CONNECT_TIMEOUT);
+        assert!(start.elapsed() < CONNECT_TIMEOUT * 3);
+    }
+}
@@ -4,6 +4,7 @@ pub enum Kcp2KHeaderReliable {
     Hello = 1,
     Ping = 2,
     Data = 3,
+    Rpc = 11, // RPC 帧，只在双方协商了 Kcp2KCapabilities::RPC 后发送，见 kcp2k_rpc。与不可靠通道的头部不重叠
 }
 
 #[derive(Debug, PartialEq, Clone, Copy)]
@@ -12,6 +13,10 @@ pub enum Kcp2KHeaderUnreliable {
     Data = 4,
     Disconnect = 5,
     Ping = 6,
+    PathChallenge = 7,
+    PathResponse = 8,
+    Reject = 9, // 握手被拒绝，载荷为原因和说明
+    Pong = 10,  // 回显带时间戳的 ping，见 kcp2k_rtt
 }
 
 impl Kcp2KHeaderReliable {
@@ -20,6 +25,7 @@ impl Kcp2KHeaderReliable {
             1 => Some(Self::Hello),
             2 => Some(Self::Ping),
             3 => Some(Self::Data),
+            11 => Some(Self::Rpc),
             _ => None,
         }
     }
@@ -35,6 +41,10 @@ impl Kcp2KHeaderUnreliable {
             4 => Some(Self::Data),
             5 => Some(Self::Disconnect),
             6 => Some(Self::Ping),
+            7 => Some(Self::PathChallenge),
+            8 => Some(Self::PathResponse),
+            9 => Some(Self::Reject),
+            10 => Some(Self::Pong),
             _ => None,
         }
     }
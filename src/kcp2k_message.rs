@@ -0,0 +1,288 @@
+use crate::error_code::ErrorCode;
+use crate::kcp2k_channel::Kcp2KChannel;
+use crate::kcp2k_connection::Kcp2KConnection;
+use bytes::{BufMut, Bytes, BytesMut};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::HashMap;
+
+// 消息 ID 的长度（u16，小端序）
+const MESSAGE_ID_SIZE: usize = 2;
+
+// Kcp2KMessage: 类型化消息，ID 在通信双方之间必须稳定且唯一
+pub trait Kcp2KMessage: Serialize + DeserializeOwned {
+    const ID: u16;
+}
+
+type Handler = Box<dyn Fn(&Kcp2KConnection, &[u8]) -> Result<(), postcard::Error> + Send + Sync>;
+
+// Kcp2KMessageRegistry: 消息 ID -> 类型化处理函数
+// 在 OnData 回调中调用 dispatch，未注册的 ID 和解码失败通过 OnError 回调报告
+#[derive(Default)]
+pub struct Kcp2KMessageRegistry {
+    handlers: HashMap<u16, (&'static str, Handler)>,
+}
+
+impl Kcp2KMessageRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    // 注册消息处理函数，ID 已被注册时返回错误
+    pub fn register<T: Kcp2KMessage + 'static>(
+        &mut self,
+        handler: fn(&Kcp2KConnection, T),
+    ) -> Result<(), ErrorCode> {
+        if self.handlers.contains_key(&T::ID) {
+            return Err(ErrorCode::InvalidMessage);
+        }
+        let handler: Handler = Box::new(move |conn, payload| {
+            handler(conn, postcard::from_bytes::<T>(payload)?);
+            Ok(())
+        });
+        self.handlers
+            .insert(T::ID, (std::any::type_name::<T>(), handler));
+        Ok(())
+    }
+    pub fn is_registered(&self, message_id: u16) -> bool {
+        self.handlers.contains_key(&message_id)
+    }
+    // 解析消息 ID 并交给对应的处理函数
+    pub fn dispatch(&self, conn: &Kcp2KConnection, data: &[u8]) -> Result<(), ErrorCode> {
+        let message_id = match read_message_id(data) {
+            Some(message_id) => message_id,
+            None => {
+                conn.on_error(
+                    ErrorCode::InvalidMessage,
+                    format!(
+                        "{}: message too short: {} bytes",
+                        std::any::type_name::<Self>(),
+                        data.len()
+                    ),
+                );
+                return Err(ErrorCode::InvalidMessage);
+            }
+        };
+        let (type_name, handler) = match self.handlers.get(&message_id) {
+            Some(entry) => entry,
+            None => {
+                conn.on_error(
+                    ErrorCode::UnknownMessage,
+                    format!(
+                        "{}: unknown message id: {}",
+                        std::any::type_name::<Self>(),
+                        message_id
+                    ),
+                );
+                return Err(ErrorCode::UnknownMessage);
+            }
+        };
+        match handler(conn, &data[MESSAGE_ID_SIZE..]) {
+            Ok(_) => Ok(()),
+            Err(err) => {
+                conn.on_error(
+                    ErrorCode::InvalidMessage,
+                    format!(
+                        "{}: failed to decode message id {} as {}: {}",
+                        std::any::type_name::<Self>(),
+                        message_id,
+                        type_name,
+                        err
+                    ),
+                );
+                Err(ErrorCode::InvalidMessage)
+            }
+        }
+    }
+}
+
+// 读取消息 ID，数据不足时返回 None
+pub fn read_message_id(data: &[u8]) -> Option<u16> {
+    data.get(..MESSAGE_ID_SIZE)
+        .map(|id| u16::from_le_bytes([id[0], id[1]]))
+}
+
+// 编码消息：消息 ID + postcard 序列化的内容。
+// 编码结果可以用于 Kcp2K::send、broadcast 或 ConnectionHandle::send
+pub fn encode_message<T: Kcp2KMessage>(message: &T) -> Result<Bytes, ErrorCode> {
+    let payload = match postcard::to_allocvec(message) {
+        Ok(payload) => payload,
+        Err(_) => return Err(ErrorCode::InvalidMessage),
+    };
+    let mut buffer = BytesMut::with_capacity(MESSAGE_ID_SIZE + payload.len());
+    buffer.put_u16_le(T::ID);
+    buffer.put_slice(&payload);
+    Ok(buffer.freeze())
+}
+
+// 解码指定类型的消息，消息 ID 不匹配时返回 UnknownMessage
+pub fn decode_message<T: Kcp2KMessage>(data: &[u8]) -> Result<T, ErrorCode> {
+    match read_message_id(data) {
+        Some(message_id) if message_id == T::ID => {
+            match postcard::from_bytes(&data[MESSAGE_ID_SIZE..]) {
+                Ok(message) => Ok(message),
+                Err(_) => Err(ErrorCode::InvalidMessage),
+            }
+        }
+        Some(_) => Err(ErrorCode::UnknownMessage),
+        None => Err(ErrorCode::InvalidMessage),
+    }
+}
+
+// 在回调中发送类型化消息，回调之外使用 Kcp2K::send_message
+pub fn send_message<T: Kcp2KMessage>(
+    conn: &Kcp2KConnection,
+    message: &T,
+    channel: Kcp2KChannel,
+) -> Result<(), ErrorCode> {
+    conn.send_data(encode_message(message)?, channel)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kcp2k_callback::{Callback, CallbackType};
+    use crate::kcp2k_config::Kcp2KConfig;
+    use crate::kcp2k_testing::{ignore, Kcp2KTestLink};
+    use serde::Deserialize;
+    use std::cell::RefCell;
+    use std::time::Duration;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Login {
+        name: String,
+        level: u32,
+    }
+    impl Kcp2KMessage for Login {
+        const ID: u16 = 1;
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Logout;
+    impl Kcp2KMessage for Logout {
+        const ID: u16 = 2;
+    }
+
+    // 与 Login 使用相同的 ID
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Duplicate;
+    impl Kcp2KMessage for Duplicate {
+        const ID: u16 = 1;
+    }
+
+    thread_local! {
+        static REGISTRY: Kcp2KMessageRegistry = {
+            let mut registry = Kcp2KMessageRegistry::new();
+            registry.register(on_login).unwrap();
+            registry
+        };
+        static LOGINS: RefCell<Vec<Login>> = const { RefCell::new(Vec::new()) };
+        static ERRORS: RefCell<Vec<(ErrorCode, String)>> = const { RefCell::new(Vec::new()) };
+    }
+
+    fn on_login(_: &Kcp2KConnection, login: Login) {
+        LOGINS.with(|logins| logins.borrow_mut().push(login));
+    }
+
+    fn server(conn: &Kcp2KConnection, cb: Callback) {
+        match cb.r#type {
+            CallbackType::OnData => {
+                let _ = REGISTRY.with(|registry| registry.dispatch(conn, &cb.data));
+            }
+            CallbackType::OnError => {
+                ERRORS.with(|errors| errors.borrow_mut().push((cb.error_code, cb.error_message)))
+            }
+            _ => {}
+        }
+    }
+
+    fn connected_link() -> Kcp2KTestLink {
+        LOGINS.with(|logins| logins.take());
+        ERRORS.with(|errors| errors.take());
+        Kcp2KTestLink::connected(Kcp2KConfig::default(), server, ignore)
+    }
+
+    // 客户端发送原始数据，返回服务器报告的错误
+    fn errors_for(data: &'static [u8]) -> Vec<(ErrorCode, String)> {
+        let mut link = connected_link();
+        link.client
+            .send(
+                link.client_id,
+                Bytes::from_static(data),
+                Kcp2KChannel::Reliable,
+            )
+            .unwrap();
+        link.run(Duration::from_millis(100));
+        assert!(LOGINS.with(|logins| logins.borrow().is_empty()));
+        ERRORS.with(|errors| errors.take())
+    }
+
+    #[test]
+    fn encoded_message_is_dispatched_to_its_handler() {
+        let mut link = connected_link();
+        let login = Login {
+            name: "rust".to_string(),
+            level: 7,
+        };
+        let encoded = encode_message(&login).unwrap();
+        assert_eq!(read_message_id(&encoded), Some(Login::ID));
+        assert_eq!(decode_message::<Login>(&encoded).unwrap(), login);
+        assert!(matches!(
+            decode_message::<Logout>(&encoded),
+            Err(ErrorCode::UnknownMessage)
+        ));
+
+        link.client
+            .send_message(link.client_id, &login, Kcp2KChannel::Reliable)
+            .unwrap();
+        link.run(Duration::from_millis(100));
+        assert_eq!(LOGINS.with(|logins| logins.take()), vec![login]);
+        assert!(ERRORS.with(|errors| errors.take()).is_empty());
+    }
+
+    #[test]
+    fn send_message_to_unknown_connection_fails() {
+        let link = connected_link();
+        let result = link
+            .client
+            .send_message(link.client_id + 1, &Logout, Kcp2KChannel::Reliable);
+        assert!(matches!(result, Err(ErrorCode::ConnectionNotFound)));
+    }
+
+    #[test]
+    fn unknown_message_id_reaches_on_error() {
+        let errors = errors_for(&[2, 0]);
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(errors[0].0, ErrorCode::UnknownMessage));
+        assert!(errors[0].1.contains("unknown message id: 2"));
+    }
+
+    #[test]
+    fn truncated_payload_reaches_on_error() {
+        // Login 的 name 声明了 10 个字节，实际只有 2 个
+        let errors = errors_for(&[1, 0, 10, b'r', b'u']);
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(errors[0].0, ErrorCode::InvalidMessage));
+        assert!(errors[0].1.contains("failed to decode message id 1"));
+    }
+
+    #[test]
+    fn message_shorter_than_its_id_reaches_on_error() {
+        let errors = errors_for(&[1]);
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(errors[0].0, ErrorCode::InvalidMessage));
+        assert!(errors[0].1.contains("message too short: 1 bytes"));
+    }
+
+    #[test]
+    fn duplicate_id_is_rejected() {
+        fn on_duplicate(_: &Kcp2KConnection, _: Duplicate) {}
+        let mut registry = Kcp2KMessageRegistry::new();
+        registry.register(on_login).unwrap();
+        assert!(matches!(
+            registry.register(on_duplicate),
+            Err(ErrorCode::InvalidMessage)
+        ));
+        assert!(registry.is_registered(Login::ID));
+        assert!(!registry.is_registered(Logout::ID));
+    }
+}
@@ -0,0 +1,543 @@
+use crate::kcp2k_channel::Kcp2KChannel;
+use crate::kcp2k_config::Kcp2KConfig;
+use crate::kcp2k_header::{Kcp2KHeaderReliable, Kcp2KHeaderUnreliable};
+use bytes::{BufMut, Bytes, BytesMut};
+use kcp::KCP_OVERHEAD;
+use std::fmt;
+
+// kcp2k 数据报的编码和解码：通道(1) + cookie(4) + 载荷
+//   可靠通道的载荷是一个或多个 KCP 段，段头为 conv(4) cmd(1) frg(1) wnd(2) ts(4) sn(4) una(4) len(4)
+//   不可靠通道的载荷是 Kcp2KHeaderUnreliable(1) + 数据
+// 解码的所有读取都经过边界检查，任何输入都只会返回错误，不会 panic
+
+// KCP 段命令
+pub const KCP_CMD_PUSH: u8 = 81;
+pub const KCP_CMD_ACK: u8 = 82;
+pub const KCP_CMD_WASK: u8 = 83;
+pub const KCP_CMD_WINS: u8 = 84;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kcp2KDecodeError {
+    TooShort(usize),    // 数据报长度不足以容纳通道、cookie 和消息
+    InvalidChannel(u8), // 未定义的通道
+    InvalidHeader(u8),  // 未定义的 kcp2k 头部
+    Empty,              // 消息缺少头部
+    TruncatedSegment { needed: usize, available: usize }, // KCP 段被截断
+}
+
+impl fmt::Display for Kcp2KDecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Kcp2KDecodeError::TooShort(len) => write!(f, "datagram too short: length={}", len),
+            Kcp2KDecodeError::InvalidChannel(channel) => write!(f, "invalid channel: {}", channel),
+            Kcp2KDecodeError::InvalidHeader(header) => write!(f, "invalid header: {}", header),
+            Kcp2KDecodeError::Empty => write!(f, "empty message"),
+            Kcp2KDecodeError::TruncatedSegment { needed, available } => write!(
+                f,
+                "truncated kcp segment: needs {} bytes, {} available",
+                needed, available
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Kcp2KDecodeError {}
+
+// 一个完整的 kcp2k 数据报
+#[derive(Debug, Clone, PartialEq)]
+pub enum Kcp2KPacket {
+    // 可靠通道：KCP 段，解码时不检查，用 segments 逐个解析
+    Reliable {
+        cookie: [u8; 4],
+        segments: Bytes,
+    },
+    // 不可靠通道：头部 + 数据
+    Unreliable {
+        cookie: [u8; 4],
+        header: Kcp2KHeaderUnreliable,
+        payload: Bytes,
+    },
+}
+
+impl Kcp2KPacket {
+    pub fn decode(data: &Bytes) -> Result<Self, Kcp2KDecodeError> {
+        let datagram = decode_datagram(data)?;
+        let mut cookie = [0u8; 4];
+        cookie.copy_from_slice(&datagram.cookie);
+        match datagram.channel {
+            Kcp2KChannel::Reliable => Ok(Kcp2KPacket::Reliable {
+                cookie,
+                segments: datagram.payload,
+            }),
+            _ => {
+                let (header, payload) = decode_unreliable(&datagram.payload)?;
+                Ok(Kcp2KPacket::Unreliable {
+                    cookie,
+                    header,
+                    payload,
+                })
+            }
+        }
+    }
+
+    pub fn encode(&self) -> Bytes {
+        let mut buffer = BytesMut::with_capacity(self.encoded_len());
+        buffer.put_slice(&encode_prefix(self.channel(), &self.cookie()));
+        match self {
+            Kcp2KPacket::Reliable { segments, .. } => buffer.put_slice(segments),
+            Kcp2KPacket::Unreliable {
+                header, payload, ..
+            } => {
+                buffer.put_u8(header.to_u8());
+                buffer.put_slice(payload);
+            }
+        }
+        buffer.freeze()
+    }
+
+    pub fn encoded_len(&self) -> usize {
+        Kcp2KConfig::METADATA_SIZE_RELIABLE
+            + match self {
+                Kcp2KPacket::Reliable { segments, .. } => segments.len(),
+                Kcp2KPacket::Unreliable { payload, .. } => 1 + payload.len(),
+            }
+    }
+
+    pub fn channel(&self) -> Kcp2KChannel {
+        match self {
+            Kcp2KPacket::Reliable { .. } => Kcp2KChannel::Reliable,
+            Kcp2KPacket::Unreliable { .. } => Kcp2KChannel::Unreliable,
+        }
+    }
+
+    pub fn cookie(&self) -> [u8; 4] {
+        match self {
+            Kcp2KPacket::Reliable { cookie, .. } | Kcp2KPacket::Unreliable { cookie, .. } => {
+                *cookie
+            }
+        }
+    }
+
+    // 可靠数据报中的 KCP 段，遇到截断的段时返回错误并停止；不可靠数据报没有 KCP 段
+    pub fn segments(&self) -> impl Iterator<Item = Result<KcpSegment<'_>, Kcp2KDecodeError>> {
+        let mut rest: &[u8] = match self {
+            Kcp2KPacket::Reliable { segments, .. } => segments,
+            Kcp2KPacket::Unreliable { .. } => &[],
+        };
+        std::iter::from_fn(move || {
+            if rest.is_empty() {
+                return None;
+            }
+            match KcpSegment::decode(rest) {
+                Ok((segment, next)) => {
+                    rest = next;
+                    Some(Ok(segment))
+                }
+                Err(err) => {
+                    rest = &[];
+                    Some(Err(err))
+                }
+            }
+        })
+    }
+}
+
+// KCP 段头和数据
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KcpSegment<'a> {
+    pub conv: u32,
+    pub cmd: u8,
+    pub frg: u8,
+    pub wnd: u16,
+    pub ts: u32,
+    pub sn: u32,
+    pub una: u32,
+    pub data: &'a [u8],
+}
+
+impl<'a> KcpSegment<'a> {
+    // 解码第一个 KCP 段，返回段和剩余的字节
+    pub fn decode(data: &'a [u8]) -> Result<(Self, &'a [u8]), Kcp2KDecodeError> {
+        let truncated = |needed: usize| Kcp2KDecodeError::TruncatedSegment {
+            needed,
+            available: data.len(),
+        };
+        let (header, body) = data
+            .split_at_checked(KCP_OVERHEAD)
+            .ok_or_else(|| truncated(KCP_OVERHEAD))?;
+        let u32_at = |offset: usize| {
+            u32::from_le_bytes([
+                header[offset],
+                header[offset + 1],
+                header[offset + 2],
+                header[offset + 3],
+            ])
+        };
+        let len = u32_at(20) as usize;
+        let (segment_data, rest) = body
+            .split_at_checked(len)
+            .ok_or_else(|| truncated(KCP_OVERHEAD.saturating_add(len)))?;
+        let segment = KcpSegment {
+            conv: u32_at(0),
+            cmd: header[4],
+            frg: header[5],
+            wnd: u16::from_le_bytes([header[6], header[7]]),
+            ts: u32_at(8),
+            sn: u32_at(12),
+            una: u32_at(16),
+            data: segment_data,
+        };
+        Ok((segment, rest))
+    }
+
+    pub fn encode(&self, buffer: &mut BytesMut) {
+        buffer.put_u32_le(self.conv);
+        buffer.put_u8(self.cmd);
+        buffer.put_u8(self.frg);
+        buffer.put_u16_le(self.wnd);
+        buffer.put_u32_le(self.ts);
+        buffer.put_u32_le(self.sn);
+        buffer.put_u32_le(self.una);
+        buffer.put_u32_le(self.data.len() as u32);
+        buffer.put_slice(self.data);
+    }
+}
+
+// 通道头部 + 握手 cookie（防止 UDP 欺骗），发送时与消息一起分段写出，避免复制消息
+pub(crate) fn encode_prefix(
+    channel: Kcp2KChannel,
+    cookie: &[u8],
+) -> [u8; Kcp2KConfig::METADATA_SIZE_RELIABLE] {
+    let mut prefix = [0u8; Kcp2KConfig::METADATA_SIZE_RELIABLE];
+    prefix[0] = channel.to_u8();
+    prefix[Kcp2KConfig::CHANNEL_HEADER_SIZE..].copy_from_slice(cookie);
+    prefix
+}
+
+// 数据报：通道 + cookie + 载荷，cookie 与接收缓冲区共享内存
+#[derive(Debug, Clone)]
+pub(crate) struct Kcp2KDatagram {
+    pub channel: Kcp2KChannel,
+    pub cookie: Bytes,
+    pub payload: Bytes,
+}
+
+pub(crate) fn decode_datagram(data: &Bytes) -> Result<Kcp2KDatagram, Kcp2KDecodeError> {
+    let metadata_size = Kcp2KConfig::METADATA_SIZE_RELIABLE;
+    if data.len() <= metadata_size {
+        return Err(Kcp2KDecodeError::TooShort(data.len()));
+    }
+    let channel = match Kcp2KChannel::from(data[0]) {
+        Kcp2KChannel::None => return Err(Kcp2KDecodeError::InvalidChannel(data[0])),
+        channel => channel,
+    };
+    Ok(Kcp2KDatagram {
+        channel,
+        cookie: data.slice(Kcp2KConfig::CHANNEL_HEADER_SIZE..metadata_size),
+        payload: data.slice(metadata_size..),
+    })
+}
+
+// 从 KCP 收到的完整消息：头部 + 数据
+pub(crate) fn decode_reliable(
+    message: &Bytes,
+) -> Result<(Kcp2KHeaderReliable, Bytes), Kcp2KDecodeError> {
+    let header = *message.first().ok_or(Kcp2KDecodeError::Empty)?;
+    match Kcp2KHeaderReliable::parse(header) {
+        Some(kcp2k_header) => Ok((kcp2k_header, message.slice(1..))),
+        None => Err(Kcp2KDecodeError::InvalidHeader(header)),
+    }
+}
+
+// 不可靠通道的载荷：头部 + 数据
+pub(crate) fn decode_unreliable(
+    payload: &Bytes,
+) -> Result<(Kcp2KHeaderUnreliable, Bytes), Kcp2KDecodeError> {
+    let header = *payload.first().ok_or(Kcp2KDecodeError::Empty)?;
+    match Kcp2KHeaderUnreliable::parse(header) {
+        Some(kcp2k_header) => Ok((kcp2k_header, payload.slice(1..))),
+        None => Err(Kcp2KDecodeError::InvalidHeader(header)),
+    }
+}
+
+// 数据报是否带有对方的 Hello：可靠通道中某个单分片的 KCP 数据段以 Hello 头部开始。
+// 同一个数据报中 Hello 之前可能还有 ACK 段，因此不能只看固定偏移
+pub(crate) fn is_handshake(datagram: &Kcp2KDatagram) -> bool {
+    hello_segment(datagram).is_some()
+}
+
+// 数据报中的 Hello 是否是对我们 Hello 的回复：对方总是在收到我们的 Hello（sn 0）之后才回复，
+// 回复的 una 大于 0；对方主动发起连接时的 Hello 还没有收到任何消息，una 为 0
+pub(crate) fn is_handshake_reply(datagram: &Kcp2KDatagram) -> bool {
+    hello_segment(datagram).is_some_and(|segment| segment.una > 0)
+}
+
+fn hello_segment(datagram: &Kcp2KDatagram) -> Option<KcpSegment<'_>> {
+    if datagram.channel != Kcp2KChannel::Reliable {
+        return None;
+    }
+    let mut rest: &[u8] = &datagram.payload;
+    while let Ok((segment, next)) = KcpSegment::decode(rest) {
+        if segment.cmd == KCP_CMD_PUSH
+            && segment.frg == 0
+            && segment.data.first() == Some(&Kcp2KHeaderReliable::Hello.to_u8())
+        {
+            return Some(segment);
+        }
+        rest = next;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::Kcp2KMode;
+    use crate::kcp2k::Kcp2K;
+    use crate::kcp2k_testing::ignore;
+
+    const COOKIE: [u8; 4] = [1, 2, 3, 4];
+
+    fn segment(len: u32, data: &[u8]) -> BytesMut {
+        let mut buffer = BytesMut::new();
+        KcpSegment {
+            conv: 0,
+            cmd: KCP_CMD_PUSH,
+            frg: 0,
+            wnd: 128,
+            ts: 0,
+            sn: 0,
+            una: 0,
+            data,
+        }
+        .encode(&mut buffer);
+        // 改写长度字段，模拟对方声明的长度
+        buffer[20..KCP_OVERHEAD].copy_from_slice(&len.to_le_bytes());
+        buffer
+    }
+
+    fn reliable(segments: &[u8]) -> Bytes {
+        let mut buffer = BytesMut::new();
+        buffer.put_slice(&encode_prefix(Kcp2KChannel::Reliable, &COOKIE));
+        buffer.put_slice(segments);
+        buffer.freeze()
+    }
+
+    #[test]
+    fn reliable_round_trip() {
+        // 一条消息分成多个 KCP 段，后面跟一个 ACK
+        let mut segments = BytesMut::new();
+        let chunks: [&[u8]; 3] = [b"first", b"second", b"third"];
+        for (sn, chunk) in chunks.iter().enumerate() {
+            KcpSegment {
+                conv: 0,
+                cmd: KCP_CMD_PUSH,
+                frg: (chunks.len() - 1 - sn) as u8,
+                wnd: 128,
+                ts: 1000,
+                sn: 7 + sn as u32,
+                una: 3,
+                data: chunk,
+            }
+            .encode(&mut segments);
+        }
+        let ack = KcpSegment {
+            conv: 0,
+            cmd: KCP_CMD_ACK,
+            frg: 0,
+            wnd: 128,
+            ts: 990,
+            sn: 2,
+            una: 3,
+            data: &[],
+        };
+        ack.encode(&mut segments);
+        let packet = Kcp2KPacket::Reliable {
+            cookie: COOKIE,
+            segments: segments.freeze(),
+        };
+
+        let data = packet.encode();
+        assert_eq!(data.len(), packet.encoded_len());
+        assert_eq!(data[0], Kcp2KChannel::Reliable.to_u8());
+        assert_eq!(data[1..5], COOKIE);
+        let decoded = Kcp2KPacket::decode(&data).unwrap();
+        assert_eq!(decoded, packet);
+        assert_eq!(decoded.channel(), Kcp2KChannel::Reliable);
+        assert_eq!(decoded.cookie(), COOKIE);
+        let decoded_segments: Vec<KcpSegment> =
+            decoded.segments().map(|segment| segment.unwrap()).collect();
+        assert_eq!(decoded_segments.len(), 4);
+        for (sn, (segment, chunk)) in decoded_segments.iter().zip(chunks).enumerate() {
+            assert_eq!(segment.cmd, KCP_CMD_PUSH);
+            assert_eq!(segment.frg as usize, chunks.len() - 1 - sn);
+            assert_eq!(segment.sn, 7 + sn as u32);
+            assert_eq!(segment.una, 3);
+            assert_eq!(segment.ts, 1000);
+            assert_eq!(segment.data, chunk);
+        }
+        assert_eq!(decoded_segments[3], ack);
+        // 再次编码得到相同的字节
+        assert_eq!(decoded.encode(), data);
+    }
+
+    #[test]
+    fn unreliable_round_trip() {
+        for header in [
+            Kcp2KHeaderUnreliable::Data,
+            Kcp2KHeaderUnreliable::Disconnect,
+            Kcp2KHeaderUnreliable::Ping,
+            Kcp2KHeaderUnreliable::PathChallenge,
+            Kcp2KHeaderUnreliable::PathResponse,
+            Kcp2KHeaderUnreliable::Reject,
+            Kcp2KHeaderUnreliable::Pong,
+        ] {
+            for payload in [Bytes::new(), Bytes::from_static(b"payload")] {
+                let packet = Kcp2KPacket::Unreliable {
+                    cookie: COOKIE,
+                    header,
+                    payload: payload.clone(),
+                };
+                let data = packet.encode();
+                assert_eq!(data.len(), packet.encoded_len());
+                assert_eq!(data[0], Kcp2KChannel::Unreliable.to_u8());
+                assert_eq!(data[1..5], COOKIE);
+                assert_eq!(data[5], header.to_u8());
+                assert_eq!(data[6..], payload);
+                let decoded = Kcp2KPacket::decode(&data).unwrap();
+                assert_eq!(decoded, packet);
+                assert_eq!(decoded.channel(), Kcp2KChannel::Unreliable);
+                assert_eq!(decoded.segments().count(), 0);
+                assert_eq!(decoded.encode(), data);
+            }
+        }
+    }
+
+    #[test]
+    fn too_short() {
+        for len in 0..=Kcp2KConfig::METADATA_SIZE_RELIABLE {
+            let data = Bytes::from(vec![Kcp2KChannel::Reliable.to_u8(); len]);
+            assert_eq!(
+                decode_datagram(&data).unwrap_err(),
+                Kcp2KDecodeError::TooShort(len)
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_channel() {
+        for channel in [0, 3, 0xFF] {
+            let data = Bytes::from(vec![channel, 1, 2, 3, 4, 5]);
+            assert_eq!(
+                decode_datagram(&data).unwrap_err(),
+                Kcp2KDecodeError::InvalidChannel(channel)
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_header() {
+        assert_eq!(
+            decode_unreliable(&Bytes::from_static(&[0, 1])).unwrap_err(),
+            Kcp2KDecodeError::InvalidHeader(0)
+        );
+        assert_eq!(
+            decode_unreliable(&Bytes::from_static(&[0xFF])).unwrap_err(),
+            Kcp2KDecodeError::InvalidHeader(0xFF)
+        );
+        // 可靠通道的头部不能出现在不可靠通道，反之亦然
+        assert_eq!(
+            decode_unreliable(&Bytes::from_static(&[Kcp2KHeaderReliable::Data as u8])).unwrap_err(),
+            Kcp2KDecodeError::InvalidHeader(Kcp2KHeaderReliable::Data as u8)
+        );
+        assert_eq!(
+            decode_reliable(&Bytes::from_static(&[Kcp2KHeaderUnreliable::Data as u8])).unwrap_err(),
+            Kcp2KDecodeError::InvalidHeader(Kcp2KHeaderUnreliable::Data as u8)
+        );
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(
+            decode_reliable(&Bytes::new()).unwrap_err(),
+            Kcp2KDecodeError::Empty
+        );
+        assert_eq!(
+            decode_unreliable(&Bytes::new()).unwrap_err(),
+            Kcp2KDecodeError::Empty
+        );
+    }
+
+    #[test]
+    fn truncated_segment_header() {
+        let header = segment(0, &[]);
+        for len in 1..KCP_OVERHEAD {
+            assert_eq!(
+                KcpSegment::decode(&header[..len]).unwrap_err(),
+                Kcp2KDecodeError::TruncatedSegment {
+                    needed: KCP_OVERHEAD,
+                    available: len
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn truncated_segment_data() {
+        let data = segment(10, b"hello");
+        assert_eq!(
+            KcpSegment::decode(&data).unwrap_err(),
+            Kcp2KDecodeError::TruncatedSegment {
+                needed: KCP_OVERHEAD + 10,
+                available: KCP_OVERHEAD + 5
+            }
+        );
+        // 截断的段之后不再解析，也不会被当作 Hello
+        let packet = Kcp2KPacket::decode(&reliable(&data)).unwrap();
+        let segments: Vec<_> = packet.segments().collect();
+        assert_eq!(segments.len(), 1);
+        assert!(segments[0].is_err());
+        assert!(!is_handshake(&decode_datagram(&reliable(&data)).unwrap()));
+    }
+
+    #[test]
+    fn segment_length_near_u32_max() {
+        for len in [u32::MAX, u32::MAX - 1, u32::MAX - KCP_OVERHEAD as u32] {
+            let data = segment(len, b"hello");
+            assert_eq!(
+                KcpSegment::decode(&data).unwrap_err(),
+                Kcp2KDecodeError::TruncatedSegment {
+                    needed: KCP_OVERHEAD.saturating_add(len as usize),
+                    available: data.len()
+                }
+            );
+            let datagram = decode_datagram(&reliable(&data)).unwrap();
+            assert!(!is_handshake(&datagram));
+        }
+    }
+
+    // 通道 + cookie + 没有数据的 KCP 段头，正好 29 个字节：读取第一个数据字节曾经越界
+    #[test]
+    fn twenty_nine_byte_datagram() {
+        let data = reliable(&segment(0, &[]));
+        assert_eq!(data.len(), 29);
+        let datagram = decode_datagram(&data).unwrap();
+        assert!(!is_handshake(&datagram));
+        let packet = Kcp2KPacket::decode(&data).unwrap();
+        let segments: Vec<_> = packet.segments().collect();
+        assert_eq!(segments.len(), 1);
+        assert!(segments[0].unwrap().data.is_empty());
+
+        // 客户端和服务器收到时都不会 panic
+        let server_addr = crate::kcp2k_testing::addr("10.0.0.1:7777");
+        let client =
+            Kcp2K::new_replay(Kcp2KConfig::default(), Kcp2KMode::Client, 0, ignore).unwrap();
+        client.connect("10.0.0.1:7777".to_string()).unwrap();
+        client.push_inbound(server_addr.clone(), data.clone());
+        client.tick();
+        let server =
+            Kcp2K::new_replay(Kcp2KConfig::default(), Kcp2KMode::Server, 0, ignore).unwrap();
+        server.push_inbound(server_addr, data);
+        server.tick();
+    }
+}
@@ -1,14 +1,20 @@
 use crate::common::Kcp2KMode;
+use crate::kcp2k_batch;
+use crate::kcp2k_batch::SendQueue;
+use crate::kcp2k_capture::{Direction, Kcp2KCaptureTap};
 use crate::kcp2k_channel::Kcp2KChannel;
 use crate::kcp2k_config::Kcp2KConfig;
+use crate::kcp2k_context::{Kcp2KContext, Kcp2KWatch};
+use crate::kcp2k_packet;
+use crate::kcp2k_socket::Kcp2KSocket;
 use crate::kcp2k_state::Kcp2KPeerState;
 use bytes::{BufMut, Bytes, BytesMut};
 use kcp::{Kcp, KCP_OVERHEAD};
-use socket2::{SockAddr, Socket};
+use socket2::SockAddr;
 use std::io;
 use std::io::Write;
 use std::sync::{Arc, RwLock};
-use std::time::{Duration, Instant};
+use std::time::Duration;
 use tklog::error;
 
 #[derive(Debug)]
@@ -16,33 +22,54 @@ pub struct Kcp2KPeer {
     pub cookie: Arc<Bytes>,            // cookie
     pub state: RwLock<Kcp2KPeerState>, // 状态
     pub kcp: RwLock<Kcp<UdpOutput>>,   // kcp
-    pub watch: Instant,
-    pub timeout_duration: Duration,            // 超时时间
-    pub last_recv_time: RwLock<Duration>,      // 最后接收时间
-    pub last_send_ping_time: RwLock<Duration>, // 最后发送 ping 的时间
+    pub watch: Kcp2KWatch,
+    pub idle_timeout: Duration,           // 空闲超时时间
+    pub last_recv_time: RwLock<Duration>, // 最后接收时间
+    pub last_send_time: RwLock<Duration>, // 最后发送消息（包括 ping）的时间
 }
 
 impl Kcp2KPeer {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         kcp2k_mode: Arc<Kcp2KMode>,
         config: Arc<Kcp2KConfig>,
         cookie: Arc<Bytes>,
-        socket: Arc<Socket>,
-        client_sock_addr: Arc<SockAddr>,
+        socket: Arc<Kcp2KSocket>,
+        send_queue: SendQueue,
+        client_sock_addr: Arc<RwLock<SockAddr>>,
+        capture: Kcp2KCaptureTap,
+        context: &Arc<Kcp2KContext>,
     ) -> Self {
         // set up kcp over a reliable channel (that's what kcp is for)
         let udp_output = UdpOutput::new(
             kcp2k_mode,
             Arc::clone(&cookie),
             Arc::clone(&socket),
+            (config.batch_io && kcp2k_batch::SUPPORTED).then_some(send_queue),
             Arc::clone(&client_sock_addr),
+            capture,
         );
         // kcp
         let mut kcp = Kcp::new(0, udp_output);
+        Self::configure_kcp(&mut kcp, &config);
+
+        Self {
+            kcp: RwLock::new(kcp),
+            cookie,
+            state: RwLock::new(Kcp2KPeerState::Connected),
+            idle_timeout: Duration::from_millis(config.idle_timeout),
+            watch: Kcp2KWatch::new(context),
+            last_recv_time: RwLock::new(Duration::from_secs(0)),
+            last_send_time: RwLock::new(Duration::from_secs(0)),
+        }
+    }
+
+    // 按配置设置 KCP 参数，线上格式向量的编码器也使用相同的设置
+    pub(crate) fn configure_kcp<W: Write>(kcp: &mut Kcp<W>, config: &Kcp2KConfig) {
         // set nodelay.
         // note that kcp uses 'nocwnd' internally so we negate the parameter
         kcp.set_nodelay(
-            if config.no_delay { true } else { false },
+            config.no_delay,
             config.interval,
             config.fast_resend,
             !config.congestion_window,
@@ -58,16 +85,6 @@ impl Kcp2KPeer {
 
         // set maximum retransmits (aka dead_link)
         kcp.set_maximum_resend_times(config.max_retransmits);
-
-        Self {
-            kcp: RwLock::new(kcp),
-            cookie,
-            state: RwLock::new(Kcp2KPeerState::Connected),
-            timeout_duration: Duration::from_millis(config.timeout),
-            watch: Instant::now(),
-            last_recv_time: RwLock::new(Duration::from_secs(0)),
-            last_send_ping_time: RwLock::new(Duration::from_secs(0)),
-        }
     }
 
     pub fn reliable_max_message_size_unconstrained(mtu: u32, rcv_wnd: u32) -> usize {
@@ -83,10 +100,13 @@ impl Kcp2KPeer {
 
 #[derive(Debug)]
 pub struct UdpOutput {
-    kcp2k_mode: Arc<Kcp2KMode>,      // kcp2k_mode
-    cookie: Arc<Bytes>,              // cookie
-    socket: Arc<Socket>,             // socket
-    client_sock_addr: Arc<SockAddr>, // client_sock_addr
+    kcp2k_mode: Arc<Kcp2KMode>,              // kcp2k_mode
+    cookie: Arc<Bytes>,                      // cookie
+    socket: Arc<Kcp2KSocket>,                // socket
+    connected: bool,                         // socket 是否已 connect 到固定地址
+    send_queue: Option<SendQueue>, // 批量发送队列，启用 batch_io 时由 Kcp2K 在 tick_outgoing 后统一发送
+    client_sock_addr: Arc<RwLock<SockAddr>>, // client_sock_addr，连接迁移时会被更新
+    capture: Kcp2KCaptureTap,      // 抓包
 }
 
 impl UdpOutput {
@@ -94,14 +114,19 @@ impl UdpOutput {
     pub fn new(
         kcp2k_mode: Arc<Kcp2KMode>,
         cookie: Arc<Bytes>,
-        socket: Arc<Socket>,
-        client_sock_addr: Arc<SockAddr>,
+        socket: Arc<Kcp2KSocket>,
+        send_queue: Option<SendQueue>,
+        client_sock_addr: Arc<RwLock<SockAddr>>,
+        capture: Kcp2KCaptureTap,
     ) -> UdpOutput {
         UdpOutput {
             kcp2k_mode,
             cookie,
+            connected: socket.peer_addr().is_ok(),
+            send_queue,
             socket,
             client_sock_addr,
+            capture,
         }
     }
 }
@@ -111,27 +136,61 @@ impl Write for UdpOutput {
         // 创建一个缓冲区，用于存储消息内容
         let mut buffer = BytesMut::new();
 
-        // 写入通道头部
-        buffer.put_u8(Kcp2KChannel::Reliable.to_u8());
-
-        // 写入握手 cookie 以防止 UDP 欺骗
-        buffer.put_slice(&self.cookie);
+        // 写入通道头部和握手 cookie 以防止 UDP 欺骗
+        buffer.put_slice(&kcp2k_packet::encode_prefix(
+            Kcp2KChannel::Reliable,
+            &self.cookie,
+        ));
 
         // 写入 data
         buffer.put_slice(buf);
 
+        // 记录到抓包文件
+        if self.capture.is_active() {
+            match self.client_sock_addr.read() {
+                Ok(client_sock_addr) => {
+                    self.capture
+                        .record(Direction::Outbound, &client_sock_addr, &[&buffer])
+                }
+                Err(err) => self
+                    .capture
+                    .record(Direction::Outbound, &err.into_inner(), &[&buffer]),
+            }
+        }
+
+        // 批量发送：放入队列，等待 sendmmsg
+        if let Some(send_queue) = &self.send_queue {
+            let sock_addr = match self.connected {
+                true => None,
+                false => match self.client_sock_addr.read() {
+                    Ok(client_sock_addr) => Some(client_sock_addr.clone()),
+                    Err(err) => Some(err.into_inner().clone()),
+                },
+            };
+            if let Ok(mut send_queue) = send_queue.lock() {
+                send_queue.push((sock_addr, buffer.freeze()));
+                return Ok(buf.len());
+            }
+        }
+
         // 发送数据
-        match match *self.kcp2k_mode {
-            // 客户端
-            Kcp2KMode::Client => self.socket.send(&buffer),
-            // 服务器
-            Kcp2KMode::Server => self.socket.send_to(&buffer, &self.client_sock_addr),
+        match match self.connected {
+            // 已连接的 socket（单连接客户端）
+            true => self.socket.send(&buffer),
+            // 未连接的 socket（服务器、多连接客户端）
+            false => match self.client_sock_addr.read() {
+                Ok(client_sock_addr) => self.socket.send_to(&buffer, &client_sock_addr),
+                Err(err) => self.socket.send_to(&buffer, &err.into_inner()),
+            },
         } {
             // 发送成功
             Ok(_) => Ok(buf.len()),
             // 发送失败
             Err(err) => {
-                error!(format!("UdpOutput write error: {:?}", err));
+                error!(format!(
+                    "{:?} UdpOutput write error: {:?}",
+                    self.kcp2k_mode, err
+                ));
                 Err(err)
             }
         }
@@ -0,0 +1,87 @@
+use bytes::BytesMut;
+use std::sync::Mutex;
+
+// Kcp2KBufferPool: 接收缓冲区的内存池。
+// 每次从 arena 中切出一块缓冲区，freeze 后作为 Bytes 交给 KCP 和回调，不再逐包拷贝；
+// 当切出去的 Bytes 全部释放后，arena 的内存会被下一次 take 复用。
+// 代价：切出的 Bytes 共享 arena 的整块内存（capacity 字节），只要还有一个数据包被持有，整块内存都不会释放，
+// 内存池只能分配新的 arena。因此 arena 只在批量接收时容纳一批数据包，逐包接收时只容纳一个。
+// 需要长期保存数据时应拷贝出来（Bytes::copy_from_slice）。
+#[derive(Debug)]
+pub struct Kcp2KBufferPool {
+    arena: Mutex<BytesMut>,
+    capacity: usize, // arena 每次分配的大小
+}
+
+impl Kcp2KBufferPool {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            arena: Mutex::new(BytesMut::with_capacity(capacity)),
+            capacity,
+        }
+    }
+
+    // 取出一块容量为 size 的空缓冲区，内存没有初始化，写入后再 set_len
+    pub fn take(&self, size: usize) -> BytesMut {
+        match self.arena.lock() {
+            Ok(mut arena) => {
+                if arena.capacity() < size {
+                    // 如果之前切出的 Bytes 都已释放，reserve 会直接复用原来的内存
+                    arena.reserve(size.max(self.capacity));
+                }
+                let rest = arena.split_off(size);
+                std::mem::replace(&mut *arena, rest)
+            }
+            Err(_) => BytesMut::with_capacity(size),
+        }
+    }
+
+    // 归还 take 取出但没有用到的缓冲区，比 arena 剩余的容量大时代替 arena 供下一次 take 使用
+    pub fn give_back(&self, mut unused: BytesMut) {
+        if let Ok(mut arena) = self.arena.lock() {
+            if unused.capacity() > arena.capacity() {
+                unused.clear();
+                *arena = unused;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn take_does_not_initialize() {
+        let pool = Kcp2KBufferPool::new(1024);
+        let buffer = pool.take(100);
+        assert_eq!(buffer.len(), 0);
+        assert_eq!(buffer.capacity(), 100);
+    }
+
+    #[test]
+    fn unused_buffer_is_reused() {
+        let pool = Kcp2KBufferPool::new(1024);
+        let mut buffer = pool.take(1024);
+        let ptr = buffer.as_ptr();
+        let rest = buffer.split_off(100);
+        pool.give_back(rest);
+        // arena 已经用完，下一次从归还的部分中切出
+        let next = pool.take(200);
+        assert_eq!(next.as_ptr(), ptr.wrapping_add(100));
+        assert_eq!(next.capacity(), 200);
+    }
+
+    #[test]
+    fn held_packet_keeps_arena_and_pool_allocates_new_one() {
+        let pool = Kcp2KBufferPool::new(1024);
+        let mut packet = pool.take(1024);
+        packet.extend_from_slice(b"held");
+        let held = packet.freeze();
+        // arena 仍被 held 引用，只能分配新的内存
+        let next = pool.take(1024);
+        assert_ne!(next.as_ptr(), held.as_ptr());
+        drop(next);
+        assert_eq!(&held[..], b"held");
+    }
+}
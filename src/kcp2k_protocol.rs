@@ -0,0 +1,355 @@
+use crate::kcp2k_config::Kcp2KConfig;
+use crate::kcp2k_reason::{Kcp2KReasonByte, Kcp2KReasonCode};
+use bytes::{BufMut, Bytes, BytesMut};
+use std::fmt;
+use std::ops::BitAnd;
+
+// 握手时协商的协议版本和功能
+//
+// Hello 消息的载荷：version(u16 LE) + capabilities(u32 LE) + 应用载荷（见 kcp2k_auth）。
+// C# kcp2k 的 Hello 没有载荷，视为版本 0、没有任何功能
+
+// 本库的协议版本
+pub const PROTOCOL_VERSION: u16 = 1;
+
+// 功能位掩码
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Kcp2KCapabilities(u32);
+
+impl Kcp2KCapabilities {
+    // 不可靠通道的 ping
+    pub const UNRELIABLE_PING: Self = Self(1 << 0);
+    // 客户端地址变化后的连接迁移
+    pub const MIGRATION: Self = Self(1 << 1);
+    // 带时间戳的 ping 和 pong，用于测量 RTT，见 kcp2k_rtt
+    pub const TIMESTAMPED_PING: Self = Self(1 << 2);
+    // 可靠通道上的 RPC 帧，见 kcp2k_rpc。需要应用在 Kcp2KConfig::capabilities 中开启
+    pub const RPC: Self = Self(1 << 3);
+    // 默认通告的功能
+    pub const DEFAULT: Self =
+        Self(Self::UNRELIABLE_PING.0 | Self::MIGRATION.0 | Self::TIMESTAMPED_PING.0);
+    // 本库支持的所有功能
+    pub const ALL: Self = Self(Self::DEFAULT.0 | Self::RPC.0);
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+    pub const fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitAnd for Kcp2KCapabilities {
+    type Output = Self;
+
+    fn bitand(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
+// Hello 消息中的协议信息
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Kcp2KHello {
+    pub version: u16,
+    pub capabilities: Kcp2KCapabilities,
+}
+
+impl Kcp2KHello {
+    pub const SIZE: usize = 6;
+    // C# kcp2k 的 Hello
+    pub const LEGACY: Self = Self {
+        version: 0,
+        capabilities: Kcp2KCapabilities::empty(),
+    };
+
+    // 按配置发送的 Hello，兼容模式下与 C# kcp2k 一样不带载荷
+    pub fn local(config: &Kcp2KConfig) -> Self {
+        match config.csharp_compat {
+            true => Self::LEGACY,
+            false => Self {
+                version: PROTOCOL_VERSION,
+                capabilities: Kcp2KCapabilities::from_bits(config.capabilities)
+                    & Kcp2KCapabilities::ALL,
+            },
+        }
+    }
+
+    // 编码 Hello 和应用载荷。LEGACY 与 C# kcp2k 一样不带载荷，应用载荷也不会发送
+    pub fn encode(&self, payload: &[u8]) -> Bytes {
+        if *self == Self::LEGACY {
+            return Bytes::new();
+        }
+        let mut buffer = BytesMut::with_capacity(Self::SIZE + payload.len());
+        buffer.put_u16_le(self.version);
+        buffer.put_u32_le(self.capabilities.bits());
+        buffer.put_slice(payload);
+        buffer.freeze()
+    }
+
+    // 解码 Hello，返回 Hello 和其后的应用载荷
+    pub fn decode(data: &Bytes) -> Result<(Self, Bytes), Kcp2KReject> {
+        if data.is_empty() {
+            return Ok((Self::LEGACY, Bytes::new()));
+        }
+        match data.get(..Self::SIZE) {
+            Some(hello) => Ok((
+                Self {
+                    version: u16::from_le_bytes([hello[0], hello[1]]),
+                    capabilities: Kcp2KCapabilities::from_bits(u32::from_le_bytes([
+                        hello[2], hello[3], hello[4], hello[5],
+                    ])),
+                },
+                data.slice(Self::SIZE..),
+            )),
+            None => Err(Kcp2KReject::new(
+                Kcp2KRejectReason::InvalidHello,
+                format!("hello payload has {} bytes", data.len()),
+            )),
+        }
+    }
+}
+
+// 协商结果：双方都支持的版本和功能
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Kcp2KProtocol {
+    pub version: u16,
+    pub capabilities: Kcp2KCapabilities,
+}
+
+impl Kcp2KProtocol {
+    // 取较低的版本和共同的功能；对方版本过低或缺少必需的功能时拒绝
+    pub fn negotiate(
+        config: &Kcp2KConfig,
+        local: &Kcp2KHello,
+        remote: &Kcp2KHello,
+    ) -> Result<Self, Kcp2KReject> {
+        if remote.version < config.min_protocol_version {
+            return Err(Kcp2KReject::new(
+                Kcp2KRejectReason::VersionMismatch,
+                format!(
+                    "version {} is older than the minimum {}",
+                    remote.version, config.min_protocol_version
+                ),
+            ));
+        }
+        let protocol = Self {
+            version: local.version.min(remote.version),
+            capabilities: local.capabilities & remote.capabilities,
+        };
+        let required = Kcp2KCapabilities::from_bits(config.required_capabilities);
+        if !protocol.capabilities.contains(required) {
+            return Err(Kcp2KReject::new(
+                Kcp2KRejectReason::MissingCapabilities,
+                format!(
+                    "required capabilities {:#x}, common {:#x}",
+                    required.bits(),
+                    protocol.capabilities.bits()
+                ),
+            ));
+        }
+        Ok(protocol)
+    }
+
+    pub fn supports(&self, capabilities: Kcp2KCapabilities) -> bool {
+        self.capabilities.contains(capabilities)
+    }
+}
+
+// 握手被拒绝的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kcp2KRejectReason {
+    VersionMismatch,              // 对方的协议版本低于 min_protocol_version
+    MissingCapabilities,          // 缺少 required_capabilities 中的功能
+    InvalidHello,                 // Hello 载荷无法解析
+    Application(Kcp2KReasonCode), // 认证钩子返回的应用定义的原因，见 application
+    Unknown(Kcp2KReasonCode),     // 更新的版本定义的原因
+}
+
+// 原因字节的编码见 kcp2k_reason
+impl Kcp2KRejectReason {
+    // 应用定义的原因，code 超过 127 时返回 None
+    pub fn application(code: u8) -> Option<Self> {
+        Kcp2KReasonCode::new(code).map(Self::Application)
+    }
+
+    pub fn from(value: u8) -> Self {
+        let code = match Kcp2KReasonByte::from(value) {
+            Kcp2KReasonByte::Application(code) => return Self::Application(code),
+            Kcp2KReasonByte::Library(code) => code,
+        };
+        match code.get() {
+            1 => Self::VersionMismatch,
+            2 => Self::MissingCapabilities,
+            3 => Self::InvalidHello,
+            _ => Self::Unknown(code),
+        }
+    }
+
+    pub fn to_u8(self) -> u8 {
+        match self {
+            Self::VersionMismatch => 1,
+            Self::MissingCapabilities => 2,
+            Self::InvalidHello => 3,
+            Self::Application(code) => Kcp2KReasonByte::Application(code).to_u8(),
+            Self::Unknown(code) => Kcp2KReasonByte::Library(code).to_u8(),
+        }
+    }
+}
+
+impl fmt::Display for Kcp2KRejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::VersionMismatch => write!(f, "protocol version mismatch"),
+            Self::MissingCapabilities => write!(f, "missing required capabilities"),
+            Self::InvalidHello => write!(f, "invalid hello"),
+            Self::Application(code) => write!(f, "application reason {}", code),
+            Self::Unknown(value) => write!(f, "reason {}", value),
+        }
+    }
+}
+
+// 拒绝握手的原因和说明，随不可靠通道的 Reject 消息发送给对方
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Kcp2KReject {
+    pub reason: Kcp2KRejectReason,
+    pub message: String,
+}
+
+impl Kcp2KReject {
+    pub fn new(reason: Kcp2KRejectReason, message: String) -> Self {
+        Self { reason, message }
+    }
+
+    // Reject 消息的载荷：原因(1) + 说明（UTF-8）
+    pub fn encode(&self) -> Bytes {
+        let mut buffer = BytesMut::with_capacity(1 + self.message.len());
+        buffer.put_u8(self.reason.to_u8());
+        buffer.put_slice(self.message.as_bytes());
+        buffer.freeze()
+    }
+
+    pub fn decode(payload: &[u8]) -> Self {
+        match payload.split_first() {
+            Some((&reason, message)) => Self::new(
+                Kcp2KRejectReason::from(reason),
+                String::from_utf8_lossy(message).into_owned(),
+            ),
+            None => Self::new(Kcp2KRejectReason::from(0), String::new()),
+        }
+    }
+}
+
+impl fmt::Display for Kcp2KReject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.message.is_empty() {
+            true => write!(f, "{}", self.reason),
+            false => write!(f, "{}: {}", self.reason, self.message),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hello(version: u16, capabilities: Kcp2KCapabilities) -> Kcp2KHello {
+        Kcp2KHello {
+            version,
+            capabilities,
+        }
+    }
+
+    #[test]
+    fn reject_reasons_round_trip() {
+        for value in 0..=u8::MAX {
+            assert_eq!(Kcp2KRejectReason::from(value).to_u8(), value);
+        }
+        for code in 0..=Kcp2KReasonCode::MAX {
+            let reason = Kcp2KRejectReason::application(code).unwrap();
+            assert_eq!(Kcp2KRejectReason::from(reason.to_u8()), reason);
+        }
+        assert_eq!(Kcp2KRejectReason::application(200), None);
+        let reject = Kcp2KReject::new(Kcp2KRejectReason::MissingCapabilities, "rpc".to_string());
+        assert_eq!(Kcp2KReject::decode(&reject.encode()), reject);
+    }
+
+    #[test]
+    fn legacy_empty_hello_is_version_zero() {
+        let (hello, payload) = Kcp2KHello::decode(&Bytes::new()).unwrap();
+        assert_eq!(hello, Kcp2KHello::LEGACY);
+        assert_eq!(hello.version, 0);
+        assert!(payload.is_empty());
+        assert!(Kcp2KHello::LEGACY.encode(b"token").is_empty());
+    }
+
+    #[test]
+    fn hello_round_trips_with_payload() {
+        let local = hello(PROTOCOL_VERSION, Kcp2KCapabilities::ALL);
+        let (decoded, payload) = Kcp2KHello::decode(&local.encode(b"token")).unwrap();
+        assert_eq!(decoded, local);
+        assert_eq!(payload, Bytes::from_static(b"token"));
+    }
+
+    #[test]
+    fn truncated_hello_is_rejected() {
+        let encoded = hello(PROTOCOL_VERSION, Kcp2KCapabilities::ALL).encode(&[]);
+        for len in 1..Kcp2KHello::SIZE {
+            let reject = Kcp2KHello::decode(&encoded.slice(..len)).unwrap_err();
+            assert_eq!(reject.reason, Kcp2KRejectReason::InvalidHello);
+        }
+    }
+
+    #[test]
+    fn negotiate_takes_lower_version_and_common_capabilities() {
+        let config = Kcp2KConfig::default();
+        let local = hello(2, Kcp2KCapabilities::ALL);
+        let remote = hello(
+            1,
+            Kcp2KCapabilities::from_bits(
+                Kcp2KCapabilities::MIGRATION.bits() | Kcp2KCapabilities::RPC.bits(),
+            ),
+        );
+        let protocol = Kcp2KProtocol::negotiate(&config, &local, &remote).unwrap();
+        assert_eq!(protocol.version, 1);
+        assert_eq!(protocol.capabilities, remote.capabilities);
+    }
+
+    #[test]
+    fn negotiate_rejects_versions_below_minimum() {
+        let config = Kcp2KConfig {
+            min_protocol_version: PROTOCOL_VERSION,
+            ..Default::default()
+        };
+        let local = Kcp2KHello::local(&config);
+        let reject = Kcp2KProtocol::negotiate(&config, &local, &Kcp2KHello::LEGACY).unwrap_err();
+        assert_eq!(reject.reason, Kcp2KRejectReason::VersionMismatch);
+        assert!(Kcp2KProtocol::negotiate(&config, &local, &local).is_ok());
+    }
+
+    #[test]
+    fn negotiate_rejects_missing_required_capabilities() {
+        let config = Kcp2KConfig {
+            required_capabilities: Kcp2KCapabilities::MIGRATION.bits(),
+            ..Default::default()
+        };
+        let local = Kcp2KHello::local(&config);
+        let remote = hello(PROTOCOL_VERSION, Kcp2KCapabilities::UNRELIABLE_PING);
+        let reject = Kcp2KProtocol::negotiate(&config, &local, &remote).unwrap_err();
+        assert_eq!(reject.reason, Kcp2KRejectReason::MissingCapabilities);
+        // 对方支持，但本地没有通告的功能也不算共同功能
+        let config = Kcp2KConfig {
+            capabilities: Kcp2KCapabilities::UNRELIABLE_PING.bits(),
+            ..config
+        };
+        let local = Kcp2KHello::local(&config);
+        let remote = hello(PROTOCOL_VERSION, Kcp2KCapabilities::ALL);
+        let reject = Kcp2KProtocol::negotiate(&config, &local, &remote).unwrap_err();
+        assert_eq!(reject.reason, Kcp2KRejectReason::MissingCapabilities);
+    }
+}
@@ -0,0 +1,78 @@
+use std::fmt;
+
+// 断开和拒绝原因在线上的编码，DisconnectReason 和 Kcp2KRejectReason 共用：
+// 原因字节的最高位为 0 时是本库定义的原因，为 1 时是应用定义的原因，低 7 位为原因码
+
+// 原因码，只能是 0..=127，编码后再解码不会改变
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Kcp2KReasonCode(u8);
+
+impl Kcp2KReasonCode {
+    pub const MAX: u8 = 0x7F;
+
+    // 超过 MAX 时返回 None
+    pub const fn new(code: u8) -> Option<Self> {
+        match code <= Self::MAX {
+            true => Some(Self(code)),
+            false => None,
+        }
+    }
+
+    pub const fn get(self) -> u8 {
+        self.0
+    }
+}
+
+impl fmt::Display for Kcp2KReasonCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+// 解码后的原因字节
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Kcp2KReasonByte {
+    Library(Kcp2KReasonCode),
+    Application(Kcp2KReasonCode),
+}
+
+impl Kcp2KReasonByte {
+    const APPLICATION: u8 = 0x80;
+
+    pub(crate) fn from(value: u8) -> Self {
+        let code = Kcp2KReasonCode(value & Kcp2KReasonCode::MAX);
+        match value & Self::APPLICATION {
+            0 => Self::Library(code),
+            _ => Self::Application(code),
+        }
+    }
+
+    pub(crate) fn to_u8(self) -> u8 {
+        match self {
+            Self::Library(code) => code.0,
+            Self::Application(code) => Self::APPLICATION | code.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_byte_round_trips() {
+        for value in 0..=u8::MAX {
+            assert_eq!(Kcp2KReasonByte::from(value).to_u8(), value);
+        }
+    }
+
+    #[test]
+    fn codes_above_max_are_rejected() {
+        assert_eq!(
+            Kcp2KReasonCode::new(127).map(Kcp2KReasonCode::get),
+            Some(127)
+        );
+        assert_eq!(Kcp2KReasonCode::new(128), None);
+        assert_eq!(Kcp2KReasonCode::new(200), None);
+    }
+}
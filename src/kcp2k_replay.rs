@@ -0,0 +1,713 @@
+use crate::common::Kcp2KMode;
+use crate::kcp2k::Kcp2K;
+use crate::kcp2k_auth::Kcp2KAuth;
+use crate::kcp2k_callback::Callback;
+use crate::kcp2k_channel::Kcp2KChannel;
+use crate::kcp2k_config::Kcp2KConfig;
+use crate::kcp2k_connection::Kcp2KConnection;
+use crate::kcp2k_disconnect_reason::DisconnectReason;
+use crate::kcp2k_protocol::Kcp2KReject;
+use bytes::Bytes;
+use socket2::SockAddr;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+use tklog::error;
+
+// 录制文件格式：魔数 + 版本 + 模式 + 随机数种子，之后是事件序列
+const RECORDING_MAGIC: &[u8; 8] = b"KCP2KREC";
+const RECORDING_VERSION: u8 = 1;
+
+// 录制的事件
+#[derive(Debug, Clone)]
+enum Kcp2KEvent {
+    TickIncoming(Duration),                    // tick_incoming 开始，锁定的时间
+    TickOutgoing(Duration),                    // tick_outgoing 开始，锁定的时间
+    Receive(SocketAddr, Bytes),                // 收到的数据报
+    Outbox(u64, Kcp2KChannel, Bytes), // tick_outgoing 时从 ConnectionHandle 发件箱取出的消息
+    Send(u64, Kcp2KChannel, Bytes),   // 回调之外发起的发送
+    Connect(SocketAddr, Bytes),       // 回调之外主动发起的连接和 Hello 的应用载荷
+    Callback(String),                 // 回调，用于回放时比较
+    Authenticate(u64, Kcp2KAuth),     // 认证钩子的结果
+    ResolveAuthentication(u64, Kcp2KAuth), // 回调之外完成的异步认证
+    Disconnect(u64, DisconnectReason, String), // 回调之外断开的连接、原因和说明
+    OutboxRpc(u64, Bytes),            // tick_outgoing 时从发件箱取出的 RPC 帧
+}
+
+impl Kcp2KEvent {
+    fn write(&self, writer: &mut impl Write) -> Result<(), Error> {
+        match self {
+            Kcp2KEvent::TickIncoming(now) => {
+                writer.write_all(&[1])?;
+                writer.write_all(&(now.as_micros() as u64).to_le_bytes())
+            }
+            Kcp2KEvent::TickOutgoing(now) => {
+                writer.write_all(&[2])?;
+                writer.write_all(&(now.as_micros() as u64).to_le_bytes())
+            }
+            Kcp2KEvent::Receive(addr, data) => {
+                writer.write_all(&[3])?;
+                write_bytes(writer, addr.to_string().as_bytes())?;
+                write_bytes(writer, data)
+            }
+            Kcp2KEvent::Outbox(connection_id, channel, data) => {
+                writer.write_all(&[4])?;
+                writer.write_all(&connection_id.to_le_bytes())?;
+                writer.write_all(&[channel.to_u8()])?;
+                write_bytes(writer, data)
+            }
+            Kcp2KEvent::Send(connection_id, channel, data) => {
+                writer.write_all(&[5])?;
+                writer.write_all(&connection_id.to_le_bytes())?;
+                writer.write_all(&[channel.to_u8()])?;
+                write_bytes(writer, data)
+            }
+            Kcp2KEvent::Connect(addr, hello_payload) => {
+                writer.write_all(&[6])?;
+                write_bytes(writer, addr.to_string().as_bytes())?;
+                write_bytes(writer, hello_payload)
+            }
+            Kcp2KEvent::Callback(callback) => {
+                writer.write_all(&[7])?;
+                write_bytes(writer, callback.as_bytes())
+            }
+            Kcp2KEvent::Authenticate(connection_id, auth) => {
+                writer.write_all(&[8])?;
+                writer.write_all(&connection_id.to_le_bytes())?;
+                write_auth(writer, auth)
+            }
+            Kcp2KEvent::ResolveAuthentication(connection_id, auth) => {
+                writer.write_all(&[9])?;
+                writer.write_all(&connection_id.to_le_bytes())?;
+                write_auth(writer, auth)
+            }
+            Kcp2KEvent::Disconnect(connection_id, reason, message) => {
+                writer.write_all(&[10])?;
+                writer.write_all(&connection_id.to_le_bytes())?;
+                write_bytes(writer, &reason.encode(message))
+            }
+            Kcp2KEvent::OutboxRpc(connection_id, frame) => {
+                writer.write_all(&[11])?;
+                writer.write_all(&connection_id.to_le_bytes())?;
+                write_bytes(writer, frame)
+            }
+        }
+    }
+    // 读取下一个事件，文件结束时返回 None
+    fn read(reader: &mut impl Read) -> Result<Option<Self>, Error> {
+        let mut tag = [0u8; 1];
+        if reader.read(&mut tag)? == 0 {
+            return Ok(None);
+        }
+        let event = match tag[0] {
+            1 => Kcp2KEvent::TickIncoming(Duration::from_micros(read_u64(reader)?)),
+            2 => Kcp2KEvent::TickOutgoing(Duration::from_micros(read_u64(reader)?)),
+            3 => Kcp2KEvent::Receive(read_addr(reader)?, read_bytes(reader)?),
+            4 => Kcp2KEvent::Outbox(
+                read_u64(reader)?,
+                read_channel(reader)?,
+                read_bytes(reader)?,
+            ),
+            5 => Kcp2KEvent::Send(
+                read_u64(reader)?,
+                read_channel(reader)?,
+                read_bytes(reader)?,
+            ),
+            6 => Kcp2KEvent::Connect(read_addr(reader)?, read_bytes(reader)?),
+            7 => Kcp2KEvent::Callback(read_string(reader)?),
+            8 => Kcp2KEvent::Authenticate(read_u64(reader)?, read_auth(reader)?),
+            9 => Kcp2KEvent::ResolveAuthentication(read_u64(reader)?, read_auth(reader)?),
+            10 => {
+                let connection_id = read_u64(reader)?;
+                let (reason, message) = DisconnectReason::decode(&read_bytes(reader)?);
+                Kcp2KEvent::Disconnect(connection_id, reason, message)
+            }
+            11 => Kcp2KEvent::OutboxRpc(read_u64(reader)?, read_bytes(reader)?),
+            tag => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("invalid recording event: {}", tag),
+                ))
+            }
+        };
+        Ok(Some(event))
+    }
+}
+
+fn write_bytes(writer: &mut impl Write, data: &[u8]) -> Result<(), Error> {
+    writer.write_all(&(data.len() as u32).to_le_bytes())?;
+    writer.write_all(data)
+}
+
+// 认证结果：类型(1) + 用户数据或拒绝消息的载荷
+fn write_auth(writer: &mut impl Write, auth: &Kcp2KAuth) -> Result<(), Error> {
+    match auth {
+        Kcp2KAuth::Pending => writer.write_all(&[0]),
+        Kcp2KAuth::Accept(user_data) => {
+            writer.write_all(&[1])?;
+            write_bytes(writer, user_data)
+        }
+        Kcp2KAuth::Reject(reject) => {
+            writer.write_all(&[2])?;
+            write_bytes(writer, &reject.encode())
+        }
+    }
+}
+
+fn read_auth(reader: &mut impl Read) -> Result<Kcp2KAuth, Error> {
+    let mut kind = [0u8; 1];
+    reader.read_exact(&mut kind)?;
+    match kind[0] {
+        0 => Ok(Kcp2KAuth::Pending),
+        1 => Ok(Kcp2KAuth::Accept(read_bytes(reader)?)),
+        2 => Ok(Kcp2KAuth::Reject(Kcp2KReject::decode(&read_bytes(reader)?))),
+        kind => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("invalid recording authentication: {}", kind),
+        )),
+    }
+}
+
+fn read_u64(reader: &mut impl Read) -> Result<u64, Error> {
+    let mut buffer = [0u8; 8];
+    reader.read_exact(&mut buffer)?;
+    Ok(u64::from_le_bytes(buffer))
+}
+
+fn read_channel(reader: &mut impl Read) -> Result<Kcp2KChannel, Error> {
+    let mut buffer = [0u8; 1];
+    reader.read_exact(&mut buffer)?;
+    Ok(Kcp2KChannel::from(buffer[0]))
+}
+
+fn read_bytes(reader: &mut impl Read) -> Result<Bytes, Error> {
+    let mut len = [0u8; 4];
+    reader.read_exact(&mut len)?;
+    let mut buffer = vec![0u8; u32::from_le_bytes(len) as usize];
+    reader.read_exact(&mut buffer)?;
+    Ok(Bytes::from(buffer))
+}
+
+fn read_string(reader: &mut impl Read) -> Result<String, Error> {
+    String::from_utf8(read_bytes(reader)?.to_vec())
+        .map_err(|err| Error::new(ErrorKind::InvalidData, err))
+}
+
+fn read_addr(reader: &mut impl Read) -> Result<SocketAddr, Error> {
+    read_string(reader)?
+        .parse()
+        .map_err(|err| Error::new(ErrorKind::InvalidData, err))
+}
+
+fn mode_to_u8(mode: Kcp2KMode) -> u8 {
+    match mode {
+        Kcp2KMode::Client => 0,
+        Kcp2KMode::Server => 1,
+        Kcp2KMode::Peer => 2,
+    }
+}
+
+fn mode_from_u8(mode: u8) -> Result<Kcp2KMode, Error> {
+    match mode {
+        0 => Ok(Kcp2KMode::Client),
+        1 => Ok(Kcp2KMode::Server),
+        2 => Ok(Kcp2KMode::Peer),
+        mode => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("invalid recording mode: {}", mode),
+        )),
+    }
+}
+
+// Kcp2KRecorder: 把影响 Kcp2K 状态的所有输入写入录制文件，未启动时只有一次原子读取的开销
+#[derive(Debug, Default)]
+pub(crate) struct Kcp2KRecorder {
+    active: AtomicBool,
+    writer: Mutex<Option<BufWriter<File>>>,
+}
+
+impl Kcp2KRecorder {
+    pub(crate) fn start(&self, path: &Path, mode: Kcp2KMode, seed: u64) -> Result<(), Error> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(RECORDING_MAGIC)?;
+        writer.write_all(&[RECORDING_VERSION, mode_to_u8(mode)])?;
+        writer.write_all(&seed.to_le_bytes())?;
+        *self.lock_writer() = Some(writer);
+        self.active.store(true, Ordering::Release);
+        Ok(())
+    }
+    pub(crate) fn stop(&self) -> Result<(), Error> {
+        self.active.store(false, Ordering::Release);
+        match self.lock_writer().take() {
+            Some(mut writer) => writer.flush(),
+            None => Ok(()),
+        }
+    }
+    pub(crate) fn is_active(&self) -> bool {
+        self.active.load(Ordering::Acquire)
+    }
+    pub(crate) fn flush(&self) {
+        if !self.is_active() {
+            return;
+        }
+        if let Some(writer) = self.lock_writer().as_mut() {
+            let _ = writer.flush();
+        }
+    }
+    pub(crate) fn tick_incoming(&self, now: Duration) {
+        self.record(|| Kcp2KEvent::TickIncoming(now));
+    }
+    pub(crate) fn tick_outgoing(&self, now: Duration) {
+        self.record(|| Kcp2KEvent::TickOutgoing(now));
+    }
+    pub(crate) fn receive(&self, sock_addr: &SockAddr, data: &Bytes) {
+        if let Some(addr) = sock_addr.as_socket() {
+            self.record(|| Kcp2KEvent::Receive(addr, data.clone()));
+        }
+    }
+    pub(crate) fn outbox(&self, connection_id: u64, channel: Kcp2KChannel, data: &Bytes) {
+        self.record(|| Kcp2KEvent::Outbox(connection_id, channel, data.clone()));
+    }
+    pub(crate) fn outbox_rpc(&self, connection_id: u64, frame: &Bytes) {
+        self.record(|| Kcp2KEvent::OutboxRpc(connection_id, frame.clone()));
+    }
+    pub(crate) fn send(&self, connection_id: u64, channel: Kcp2KChannel, data: &[u8]) {
+        self.record(|| Kcp2KEvent::Send(connection_id, channel, Bytes::copy_from_slice(data)));
+    }
+    pub(crate) fn disconnect(&self, connection_id: u64, reason: DisconnectReason, message: &str) {
+        self.record(|| Kcp2KEvent::Disconnect(connection_id, reason, message.to_string()));
+    }
+    pub(crate) fn connect(&self, sock_addr: &SockAddr, hello_payload: &Bytes) {
+        if let Some(addr) = sock_addr.as_socket() {
+            self.record(|| Kcp2KEvent::Connect(addr, hello_payload.clone()));
+        }
+    }
+    pub(crate) fn authenticate(&self, connection_id: u64, auth: &Kcp2KAuth) {
+        self.record(|| Kcp2KEvent::Authenticate(connection_id, auth.clone()));
+    }
+    pub(crate) fn resolve_authentication(&self, connection_id: u64, auth: &Kcp2KAuth) {
+        self.record(|| Kcp2KEvent::ResolveAuthentication(connection_id, auth.clone()));
+    }
+    pub(crate) fn callback(&self, callback: &Callback) {
+        self.record(|| Kcp2KEvent::Callback(format!("{:?}", callback)));
+    }
+    // 只有在录制时才构建事件
+    fn record(&self, event: impl FnOnce() -> Kcp2KEvent) {
+        if !self.is_active() {
+            return;
+        }
+        if let Some(writer) = self.lock_writer().as_mut() {
+            if let Err(err) = event().write(writer) {
+                error!(format!("[KCP2K] Recording write failed: {:?}", err));
+            }
+        }
+    }
+    fn lock_writer(&self) -> MutexGuard<'_, Option<BufWriter<File>>> {
+        match self.writer.lock() {
+            Ok(writer) => writer,
+            Err(err) => err.into_inner(),
+        }
+    }
+}
+
+// Kcp2KReplayReport: 回放结果
+#[derive(Debug, Clone)]
+pub struct Kcp2KReplayReport {
+    pub events: usize,              // 回放的事件数
+    pub callbacks: usize,           // 回放产生的回调数
+    pub expected_callbacks: usize,  // 录制时的回调数
+    pub divergence: Option<String>, // 第一个与录制不一致的回调
+}
+
+impl Kcp2KReplayReport {
+    // 回放产生的回调序列与录制时完全一致
+    pub fn is_exact(&self) -> bool {
+        self.divergence.is_none()
+    }
+}
+
+struct ReplayState {
+    expected: Vec<String>,
+    index: usize,
+    divergence: Option<String>,
+    callback: fn(&Kcp2KConnection, Callback),
+    authentications: VecDeque<(u64, Kcp2KAuth)>, // 录制的认证钩子结果，按调用顺序
+}
+
+thread_local! {
+    static REPLAY: RefCell<Option<ReplayState>> = const { RefCell::new(None) };
+}
+
+// 回放时的回调：与录制的回调比较，然后交给用户的回调
+fn replay_callback(conn: &Kcp2KConnection, callback: Callback) {
+    let actual = format!("{:?}", callback);
+    let user_callback = REPLAY.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut()?;
+        if state.divergence.is_none() {
+            match state.expected.get(state.index) {
+                Some(expected) if *expected == actual => {}
+                Some(expected) => {
+                    state.divergence = Some(format!(
+                        "callback #{}: expected `{}`, got `{}`",
+                        state.index, expected, actual
+                    ))
+                }
+                None => {
+                    state.divergence = Some(format!(
+                        "callback #{}: unexpected `{}`",
+                        state.index, actual
+                    ))
+                }
+            }
+        }
+        state.index += 1;
+        Some(state.callback)
+    });
+    if let Some(user_callback) = user_callback {
+        user_callback(conn, callback);
+    }
+}
+
+// 回放时的认证钩子：返回录制时钩子的结果。没有录制结果时与没有钩子一样接受连接
+fn replay_authenticate(conn: &Kcp2KConnection, _: Bytes) -> Kcp2KAuth {
+    REPLAY
+        .with(|state| {
+            let mut state = state.borrow_mut();
+            let state = state.as_mut()?;
+            match state.authentications.front() {
+                Some((connection_id, _)) if *connection_id == conn.get_connection_id() => {
+                    state.authentications.pop_front().map(|(_, auth)| auth)
+                }
+                _ => None,
+            }
+        })
+        .unwrap_or(Kcp2KAuth::Accept(Bytes::new()))
+}
+
+// Kcp2KReplay: 读取录制的会话，用虚拟时钟在新的 Kcp2K 中回放。
+// 回放不会向网络发送数据；回调按录制时的顺序重现，包括 KCP 重传和超时产生的回调
+pub struct Kcp2KReplay {
+    mode: Kcp2KMode,
+    seed: u64,
+    events: Vec<Kcp2KEvent>,
+}
+
+impl Kcp2KReplay {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != RECORDING_MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, "not a kcp2k recording"));
+        }
+        let mut header = [0u8; 2];
+        reader.read_exact(&mut header)?;
+        if header[0] != RECORDING_VERSION {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("unsupported recording version: {}", header[0]),
+            ));
+        }
+        let mode = mode_from_u8(header[1])?;
+        let seed = read_u64(&mut reader)?;
+        let mut events = Vec::new();
+        while let Some(event) = Kcp2KEvent::read(&mut reader)? {
+            events.push(event);
+        }
+        Ok(Self { mode, seed, events })
+    }
+    // 第 index 个事件（tick 开始）之后、下一次 tick 开始之前的事件
+    fn tick_events(&self, index: usize) -> impl Iterator<Item = &Kcp2KEvent> {
+        self.events[index + 1..].iter().take_while(|event| {
+            !matches!(
+                event,
+                Kcp2KEvent::TickIncoming(_) | Kcp2KEvent::TickOutgoing(_)
+            )
+        })
+    }
+    pub fn get_mode(&self) -> Kcp2KMode {
+        self.mode
+    }
+    pub fn get_event_count(&self) -> usize {
+        self.events.len()
+    }
+    // 回放会话。config 必须与录制时相同；callback 与录制时的回调逻辑相同才能重现回调中发起的发送
+    pub fn run(
+        &self,
+        config: Kcp2KConfig,
+        callback: fn(&Kcp2KConnection, Callback),
+    ) -> Result<Kcp2KReplayReport, Error> {
+        let expected: Vec<String> = self
+            .events
+            .iter()
+            .filter_map(|event| match event {
+                Kcp2KEvent::Callback(callback) => Some(callback.clone()),
+                _ => None,
+            })
+            .collect();
+        let expected_callbacks = expected.len();
+        let authentications = self
+            .events
+            .iter()
+            .filter_map(|event| match event {
+                Kcp2KEvent::Authenticate(connection_id, auth) => {
+                    Some((*connection_id, auth.clone()))
+                }
+                _ => None,
+            })
+            .collect();
+        REPLAY.with(|state| {
+            *state.borrow_mut() = Some(ReplayState {
+                expected,
+                index: 0,
+                divergence: None,
+                callback,
+                authentications,
+            })
+        });
+
+        let mut kcp2k = Kcp2K::new_replay(config, self.mode, self.seed, replay_callback)?;
+        kcp2k.set_authenticator(replay_authenticate);
+        for (index, event) in self.events.iter().enumerate() {
+            match event {
+                Kcp2KEvent::TickIncoming(now) => {
+                    kcp2k.get_context().set_time(*now);
+                    // 这次 tick 中收到的数据报，中间可能穿插着回调
+                    for event in self.tick_events(index) {
+                        if let Kcp2KEvent::Receive(addr, data) = event {
+                            kcp2k.push_inbound(SockAddr::from(*addr), data.clone());
+                        }
+                    }
+                    kcp2k.tick_incoming();
+                }
+                Kcp2KEvent::TickOutgoing(now) => {
+                    kcp2k.get_context().set_time(*now);
+                    // 这次 tick 中从发件箱取出的消息
+                    for event in self.tick_events(index) {
+                        let _ = match event {
+                            Kcp2KEvent::Outbox(connection_id, channel, data) => kcp2k
+                                .get_connection_handle(*connection_id)
+                                .map(|handle| handle.send(data.clone(), *channel)),
+                            Kcp2KEvent::OutboxRpc(connection_id, frame) => kcp2k
+                                .get_connection_handle(*connection_id)
+                                .map(|handle| handle.send_rpc(frame.clone())),
+                            _ => None,
+                        };
+                    }
+                    kcp2k.tick_outgoing();
+                }
+                // 已在所属的 tick 中处理
+                Kcp2KEvent::Receive(..)
+                | Kcp2KEvent::Outbox(..)
+                | Kcp2KEvent::OutboxRpc(..)
+                | Kcp2KEvent::Callback(_)
+                | Kcp2KEvent::Authenticate(..) => {}
+                Kcp2KEvent::Send(connection_id, channel, data) => {
+                    let _ = kcp2k.send(*connection_id, data.clone(), *channel);
+                }
+                Kcp2KEvent::Disconnect(connection_id, reason, message) => {
+                    kcp2k.disconnect(*connection_id, *reason, message)
+                }
+                Kcp2KEvent::Connect(addr, hello_payload) => {
+                    let _ = kcp2k.connect_with_hello(addr.to_string(), hello_payload.clone());
+                }
+                Kcp2KEvent::ResolveAuthentication(connection_id, auth) => {
+                    let _ = match auth.clone() {
+                        Kcp2KAuth::Accept(user_data) => kcp2k.accept(*connection_id, user_data),
+                        Kcp2KAuth::Reject(reject) => kcp2k.reject(*connection_id, reject),
+                        Kcp2KAuth::Pending => Ok(()),
+                    };
+                }
+            }
+        }
+
+        let state = REPLAY.with(|state| state.borrow_mut().take());
+        let (callbacks, mut divergence) = match state {
+            Some(state) => (state.index, state.divergence),
+            None => (0, None),
+        };
+        if divergence.is_none() && callbacks < expected_callbacks {
+            divergence = Some(format!(
+                "callback #{}: expected {} callbacks, replay produced {}",
+                callbacks, expected_callbacks, callbacks
+            ));
+        }
+        Ok(Kcp2KReplayReport {
+            events: self.events.len(),
+            callbacks,
+            expected_callbacks,
+            divergence,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kcp2k_callback::CallbackType;
+    use crate::kcp2k_testing::{ignore, Kcp2KTestLink};
+    use std::path::PathBuf;
+
+    const LOSSY: &[u8] = b"sent while the link is down";
+
+    thread_local! {
+        // 服务器收到的回调
+        static CALLBACKS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+    }
+
+    // 记录回调，并原样返回收到的数据
+    fn echo_server(conn: &Kcp2KConnection, cb: Callback) {
+        CALLBACKS.with(|callbacks| callbacks.borrow_mut().push(format!("{:?}", cb)));
+        if let CallbackType::OnData = cb.r#type {
+            let _ = conn.send_data(cb.data, cb.channel);
+        }
+    }
+
+    fn take_callbacks() -> Vec<String> {
+        CALLBACKS.with(|callbacks| callbacks.take())
+    }
+
+    fn recording_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("kcp2k-{}-{}.kcp2krec", name, std::process::id()))
+    }
+
+    // 录制服务器会话：握手、丢包时的重传、链路恢复，最后链路中断直到空闲超时。
+    // 返回录制时服务器的回调
+    fn record_lossy_server_session(config: Kcp2KConfig, path: &Path) -> Vec<String> {
+        take_callbacks();
+        let mut link = Kcp2KTestLink::unconnected(
+            config,
+            Kcp2KMode::Server,
+            Kcp2KMode::Client,
+            echo_server,
+            ignore,
+        );
+        link.server.start_recording(path).unwrap();
+        link.client_id = link.client.connect("10.0.0.1:7777".to_string()).unwrap();
+        link.run(Duration::from_millis(200));
+        let server_id = link.server_id();
+
+        // 链路中断时双方都发送可靠消息，KCP 反复重传
+        link.blocked = true;
+        let blocked_at = link.now();
+        link.server
+            .send(server_id, Bytes::from_static(LOSSY), Kcp2KChannel::Reliable)
+            .unwrap();
+        link.client
+            .send(
+                link.client_id,
+                Bytes::from_static(b"retransmitted"),
+                Kcp2KChannel::Reliable,
+            )
+            .unwrap();
+        link.run(Duration::from_millis(600));
+        let retransmits = link
+            .sent
+            .iter()
+            .filter(|(time, mode, data)| {
+                *time > blocked_at
+                    && *mode == Kcp2KMode::Server
+                    && data.windows(LOSSY.len()).any(|window| window == LOSSY)
+            })
+            .count();
+        assert!(
+            retransmits > 1,
+            "{} datagrams carried the payload",
+            retransmits
+        );
+        link.blocked = false;
+        link.run(Duration::from_millis(300));
+
+        // 链路再次中断，服务器在空闲超时后断开连接
+        link.blocked = true;
+        link.run(Duration::from_millis(config.idle_timeout + 500));
+        assert!(link.server.get_connections().is_empty());
+        link.server.stop_recording().unwrap();
+        take_callbacks()
+    }
+
+    fn client_callback(conn: &Kcp2KConnection, cb: Callback) {
+        if let CallbackType::OnConnected = cb.r#type {
+            let _ = conn.send_data(Bytes::from_static(b"hello"), Kcp2KChannel::Reliable);
+        }
+    }
+
+    #[test]
+    fn client_records_from_construction() {
+        let config = Kcp2KConfig::default();
+        let path = recording_path("client");
+        let mut link = Kcp2KTestLink::unconnected(
+            config,
+            Kcp2KMode::Server,
+            Kcp2KMode::Client,
+            ignore,
+            client_callback,
+        );
+        // 与 new_client_recording 相同：先开始录制，再发起连接
+        link.client
+            .connect_default(link.server_addr.clone(), Bytes::new(), Some(&path))
+            .unwrap();
+        assert!(link.client.is_recording());
+        link.run(Duration::from_millis(200));
+        link.client.stop_recording().unwrap();
+        assert!(link.server_conn().is_authenticated());
+
+        let replay = Kcp2KReplay::open(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(replay.get_mode(), Kcp2KMode::Client);
+        let report = replay.run(config, client_callback).unwrap();
+        assert!(report.is_exact(), "{:?}", report);
+        assert!(report.callbacks > 0);
+    }
+
+    #[test]
+    fn server_replay_reproduces_retransmits_and_idle_timeout() {
+        let config = Kcp2KConfig::default();
+        let path = recording_path("server");
+        let recorded = record_lossy_server_session(config, &path);
+        let replay = Kcp2KReplay::open(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(replay.get_mode(), Kcp2KMode::Server);
+
+        let report = replay.run(config, echo_server).unwrap();
+        let replayed = take_callbacks();
+        assert!(report.is_exact(), "{:?}", report);
+        assert_eq!(replayed, recorded);
+        assert_eq!(report.callbacks, recorded.len());
+        // 握手、丢包期间发送的消息在链路恢复后到达、最后空闲超时
+        assert!(recorded[0].starts_with("OnConnected"));
+        assert!(recorded
+            .iter()
+            .any(|callback| callback.starts_with("OnData") && callback.contains("retransmitted")));
+        let disconnected = recorded.last().unwrap();
+        assert!(disconnected.starts_with("OnDisconnected"));
+        assert!(disconnected.contains("Timeout"));
+    }
+
+    #[test]
+    fn replay_with_a_different_config_diverges() {
+        let config = Kcp2KConfig::default();
+        let path = recording_path("diverge");
+        let recorded = record_lossy_server_session(config, &path);
+        let replay = Kcp2KReplay::open(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        // 空闲超时比中断的时间短，回放时连接在丢包期间就断开了
+        let config = Kcp2KConfig {
+            idle_timeout: 300,
+            ..config
+        };
+        let report = replay.run(config, echo_server).unwrap();
+        let replayed = take_callbacks();
+        assert!(!report.is_exact());
+        assert_ne!(replayed, recorded);
+        assert!(report.divergence.unwrap().contains("expected"));
+    }
+}
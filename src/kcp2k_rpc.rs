@@ -0,0 +1,514 @@
+use crate::error_code::ErrorCode;
+use crate::kcp2k_callback::{Callback, CallbackType};
+use crate::kcp2k_connection::Kcp2KConnection;
+use crate::kcp2k_connection_handle::ConnectionHandle;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+use tklog::error;
+
+// RPC 帧使用单独的可靠消息头部 Kcp2KHeaderReliable::Rpc，不会与应用的数据消息混淆。
+// 只有双方都在 Kcp2KConfig::capabilities 中开启了 Kcp2KCapabilities::RPC 的连接才能发送和接收 RPC 帧
+
+// RPC 帧格式：类型(1) + 调用 ID(4, 小端序) [+ 方法 ID(2, 小端序)，仅请求] + 内容
+const RPC_HEADER_SIZE: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RpcKind {
+    Request = 1,
+    Response = 2,
+    Error = 3,
+    UnknownMethod = 4,
+}
+
+impl RpcKind {
+    fn parse(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(RpcKind::Request),
+            2 => Some(RpcKind::Response),
+            3 => Some(RpcKind::Error),
+            4 => Some(RpcKind::UnknownMethod),
+            _ => None,
+        }
+    }
+}
+
+// Kcp2KRpcError: 调用失败的原因。Remote 是对端处理函数返回的错误响应
+#[derive(Debug, Clone)]
+pub enum Kcp2KRpcError {
+    Remote(Bytes),         // 对端返回的错误响应
+    UnknownMethod(u16),    // 对端没有注册该方法
+    Timeout,               // 超时未收到响应
+    Disconnected,          // 连接在收到响应前断开
+    SendFailed(ErrorCode), // 请求发送失败
+}
+
+pub type Kcp2KRpcResult = Result<Bytes, Kcp2KRpcError>;
+
+// 方法处理函数：Ok 作为正常响应返回，Err 作为错误响应返回
+pub type Kcp2KRpcHandler = fn(&Kcp2KConnection, Bytes) -> Result<Bytes, Bytes>;
+
+type Completion = Box<dyn FnOnce(Kcp2KRpcResult) + Send>;
+
+struct PendingCall {
+    connection: ConnectionHandle,
+    deadline: Duration, // 连接所在 Kcp2K 的时钟，回放和测试中是虚拟时间
+    completion: Completion,
+}
+
+// Kcp2KRpc: 基于可靠通道的请求/响应。
+// 在回调中调用 handle 处理 OnRpc 回调中的 RPC 帧，在 tick 循环中调用 tick 处理超时和断开的连接
+#[derive(Default)]
+pub struct Kcp2KRpc {
+    handlers: HashMap<u16, Kcp2KRpcHandler>,
+    pending: Mutex<HashMap<(u64, u32), PendingCall>>,
+    next_call_id: AtomicU32,
+}
+
+impl Kcp2KRpc {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    // 注册方法处理函数，方法 ID 已被注册时返回错误
+    pub fn register(&mut self, method: u16, handler: Kcp2KRpcHandler) -> Result<(), ErrorCode> {
+        if self.handlers.contains_key(&method) {
+            return Err(ErrorCode::InvalidMessage);
+        }
+        self.handlers.insert(method, handler);
+        Ok(())
+    }
+    // 发起调用，结果交给 completion。可以在任意线程调用
+    pub fn call(
+        &self,
+        connection: &ConnectionHandle,
+        method: u16,
+        payload: Bytes,
+        timeout: Duration,
+        completion: impl FnOnce(Kcp2KRpcResult) + Send + 'static,
+    ) {
+        let call_id = self.next_call_id.fetch_add(1, Ordering::Relaxed);
+        let mut frame = BytesMut::with_capacity(RPC_HEADER_SIZE + 2 + payload.len());
+        frame.put_u8(RpcKind::Request as u8);
+        frame.put_u32_le(call_id);
+        frame.put_u16_le(method);
+        frame.put_slice(&payload);
+
+        // 先登记再发送，保证响应到达时能找到调用
+        let key = (connection.get_connection_id(), call_id);
+        self.lock_pending().insert(
+            key,
+            PendingCall {
+                connection: connection.clone(),
+                deadline: connection.now() + timeout,
+                completion: Box::new(completion),
+            },
+        );
+        if let Err(err) = connection.send_rpc(frame.freeze()) {
+            self.complete(key, Err(Kcp2KRpcError::SendFailed(err)));
+        }
+    }
+    // 发起调用，返回可以 await 的 future
+    pub fn call_future(
+        &self,
+        connection: &ConnectionHandle,
+        method: u16,
+        payload: Bytes,
+        timeout: Duration,
+    ) -> Kcp2KRpcFuture {
+        let state = Arc::new(Mutex::new(FutureState::default()));
+        let completion_state = Arc::clone(&state);
+        self.call(connection, method, payload, timeout, move |result| {
+            if let Ok(mut state) = completion_state.lock() {
+                state.result = Some(result);
+                if let Some(waker) = state.waker.take() {
+                    waker.wake();
+                }
+            }
+        });
+        Kcp2KRpcFuture { state }
+    }
+    // 处理 OnRpc 回调，是 RPC 帧时返回 true
+    pub fn handle(&self, conn: &Kcp2KConnection, cb: &Callback) -> bool {
+        if !matches!(cb.r#type, CallbackType::OnRpc) {
+            return false;
+        }
+        if cb.data.len() < RPC_HEADER_SIZE {
+            conn.on_error(
+                ErrorCode::InvalidMessage,
+                format!(
+                    "{}: rpc frame too short: {} bytes",
+                    std::any::type_name::<Self>(),
+                    cb.data.len()
+                ),
+            );
+            return true;
+        }
+        let mut frame = cb.data.clone();
+        let kind = frame.get_u8();
+        let call_id = frame.get_u32_le();
+        match RpcKind::parse(kind) {
+            Some(RpcKind::Request) => self.handle_request(conn, call_id, frame),
+            Some(RpcKind::Response) => {
+                self.complete((conn.get_connection_id(), call_id), Ok(frame))
+            }
+            Some(RpcKind::Error) => self.complete(
+                (conn.get_connection_id(), call_id),
+                Err(Kcp2KRpcError::Remote(frame)),
+            ),
+            Some(RpcKind::UnknownMethod) => {
+                let method = if frame.len() >= 2 {
+                    frame.get_u16_le()
+                } else {
+                    0
+                };
+                self.complete(
+                    (conn.get_connection_id(), call_id),
+                    Err(Kcp2KRpcError::UnknownMethod(method)),
+                )
+            }
+            None => conn.on_error(
+                ErrorCode::InvalidMessage,
+                format!(
+                    "{}: invalid rpc frame kind: {}",
+                    std::any::type_name::<Self>(),
+                    kind
+                ),
+            ),
+        }
+        true
+    }
+    fn handle_request(&self, conn: &Kcp2KConnection, call_id: u32, mut frame: Bytes) {
+        if frame.len() < 2 {
+            conn.on_error(
+                ErrorCode::InvalidMessage,
+                format!(
+                    "{}: rpc request without method",
+                    std::any::type_name::<Self>()
+                ),
+            );
+            return;
+        }
+        let method = frame.get_u16_le();
+        let (kind, payload) = match self.handlers.get(&method) {
+            Some(handler) => match handler(conn, frame) {
+                Ok(payload) => (RpcKind::Response, payload),
+                Err(payload) => (RpcKind::Error, payload),
+            },
+            None => (
+                RpcKind::UnknownMethod,
+                Bytes::copy_from_slice(&method.to_le_bytes()),
+            ),
+        };
+        let mut response = BytesMut::with_capacity(RPC_HEADER_SIZE + payload.len());
+        response.put_u8(kind as u8);
+        response.put_u32_le(call_id);
+        response.put_slice(&payload);
+        if let Err(err) = conn.send_rpc(response.freeze()) {
+            error!(format!(
+                "[KCP2K] Failed to send rpc response {}: {:?}",
+                call_id, err
+            ));
+        }
+    }
+    // 处理超时的调用和连接已断开的调用
+    pub fn tick(&self) {
+        let expired: Vec<(PendingCall, Kcp2KRpcError)> = {
+            let mut pending = self.lock_pending();
+            let keys: Vec<(u64, u32)> = pending
+                .iter()
+                .filter(|(_, call)| {
+                    !call.connection.is_alive() || call.deadline <= call.connection.now()
+                })
+                .map(|(key, _)| *key)
+                .collect();
+            keys.into_iter()
+                .filter_map(|key| pending.remove(&key))
+                .map(|call| {
+                    let err = if call.connection.is_alive() {
+                        Kcp2KRpcError::Timeout
+                    } else {
+                        Kcp2KRpcError::Disconnected
+                    };
+                    (call, err)
+                })
+                .collect()
+        };
+        // 在锁外执行回调，回调中可以再次发起调用
+        for (call, err) in expired {
+            (call.completion)(Err(err));
+        }
+    }
+    // 连接断开时立即取消该连接上的所有调用
+    pub fn cancel_connection(&self, connection_id: u64) {
+        let cancelled: Vec<PendingCall> = {
+            let mut pending = self.lock_pending();
+            let keys: Vec<(u64, u32)> = pending
+                .keys()
+                .filter(|(id, _)| *id == connection_id)
+                .copied()
+                .collect();
+            keys.into_iter()
+                .filter_map(|key| pending.remove(&key))
+                .collect()
+        };
+        for call in cancelled {
+            (call.completion)(Err(Kcp2KRpcError::Disconnected));
+        }
+    }
+    // 等待响应的调用数
+    pub fn get_pending_count(&self) -> usize {
+        self.lock_pending().len()
+    }
+    fn complete(&self, key: (u64, u32), result: Kcp2KRpcResult) {
+        // 已超时或未知的调用 ID，丢弃迟到的响应
+        let call = self.lock_pending().remove(&key);
+        if let Some(call) = call {
+            (call.completion)(result);
+        }
+    }
+    fn lock_pending(&self) -> std::sync::MutexGuard<'_, HashMap<(u64, u32), PendingCall>> {
+        match self.pending.lock() {
+            Ok(pending) => pending,
+            Err(err) => err.into_inner(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct FutureState {
+    result: Option<Kcp2KRpcResult>,
+    waker: Option<Waker>,
+}
+
+// Kcp2KRpcFuture: 调用结果的 future，由 Kcp2KRpc::handle 或 tick 完成
+pub struct Kcp2KRpcFuture {
+    state: Arc<Mutex<FutureState>>,
+}
+
+impl Future for Kcp2KRpcFuture {
+    type Output = Kcp2KRpcResult;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(err) => err.into_inner(),
+        };
+        match state.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kcp2k_channel::Kcp2KChannel;
+    use crate::kcp2k_config::Kcp2KConfig;
+    use crate::kcp2k_protocol::Kcp2KCapabilities;
+    use crate::kcp2k_testing::Kcp2KTestLink;
+    use std::cell::RefCell;
+
+    const ECHO: u16 = 1;
+    const FAIL: u16 = 2;
+    const UNREGISTERED: u16 = 99;
+
+    thread_local! {
+        static SERVER_RPC: Kcp2KRpc = {
+            let mut rpc = Kcp2KRpc::new();
+            rpc.register(ECHO, echo).unwrap();
+            rpc.register(FAIL, fail).unwrap();
+            rpc
+        };
+        static CLIENT_RPC: Kcp2KRpc = Kcp2KRpc::new();
+        static SERVER_DATA: RefCell<Vec<Bytes>> = const { RefCell::new(Vec::new()) };
+    }
+
+    fn echo(_: &Kcp2KConnection, payload: Bytes) -> Result<Bytes, Bytes> {
+        Ok(payload)
+    }
+
+    fn fail(_: &Kcp2KConnection, _: Bytes) -> Result<Bytes, Bytes> {
+        Err(Bytes::from_static(b"denied"))
+    }
+
+    fn server(conn: &Kcp2KConnection, cb: Callback) {
+        if SERVER_RPC.with(|rpc| rpc.handle(conn, &cb)) {
+            return;
+        }
+        if let CallbackType::OnData = cb.r#type {
+            SERVER_DATA.with(|data| data.borrow_mut().push(cb.data));
+        }
+    }
+
+    fn client(conn: &Kcp2KConnection, cb: Callback) {
+        CLIENT_RPC.with(|rpc| rpc.handle(conn, &cb));
+    }
+
+    // 不处理 RPC 帧的服务器，调用永远不会得到响应
+    fn silent(_: &Kcp2KConnection, _: Callback) {}
+
+    fn connected_link(capabilities: Kcp2KCapabilities) -> Kcp2KTestLink {
+        connected_link_with(capabilities, server)
+    }
+
+    fn connected_link_with(
+        capabilities: Kcp2KCapabilities,
+        server: fn(&Kcp2KConnection, Callback),
+    ) -> Kcp2KTestLink {
+        let config = Kcp2KConfig {
+            capabilities: capabilities.bits(),
+            ..Default::default()
+        };
+        Kcp2KTestLink::connected(config, server, client)
+    }
+
+    // 在客户端发起调用，返回保存结果的位置
+    fn call(
+        link: &Kcp2KTestLink,
+        method: u16,
+        payload: &'static [u8],
+        timeout: Duration,
+    ) -> Arc<Mutex<Option<Kcp2KRpcResult>>> {
+        let result = Arc::new(Mutex::new(None));
+        let completion_result = Arc::clone(&result);
+        let handle = link.client.get_connection_handle(link.client_id).unwrap();
+        CLIENT_RPC.with(|rpc| {
+            rpc.call(
+                &handle,
+                method,
+                Bytes::from_static(payload),
+                timeout,
+                move |r| *completion_result.lock().unwrap() = Some(r),
+            )
+        });
+        result
+    }
+
+    #[test]
+    fn data_header_payload_is_never_dispatched_as_rpc() {
+        let mut link = connected_link(Kcp2KCapabilities::ALL);
+        // 载荷是一个完整的 RPC 请求帧，但走 Data 头部，仍按普通数据交付
+        let mut frame = BytesMut::new();
+        frame.put_u8(RpcKind::Request as u8);
+        frame.put_u32_le(1);
+        frame.put_u16_le(ECHO);
+        frame.put_slice(b"data");
+        let data = frame.freeze();
+        link.client
+            .send(link.client_id, data.clone(), Kcp2KChannel::Reliable)
+            .unwrap();
+        let result = call(&link, ECHO, b"ping", Duration::from_secs(5));
+        link.run(Duration::from_millis(200));
+
+        assert_eq!(
+            SERVER_DATA.with(|received| received.borrow().clone()),
+            vec![data]
+        );
+        let result = result.lock().unwrap().take();
+        match result {
+            Some(Ok(payload)) => assert_eq!(payload, Bytes::from_static(b"ping")),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn call_fails_without_negotiated_rpc() {
+        let link = connected_link(Kcp2KCapabilities::DEFAULT);
+        let result = call(&link, ECHO, b"ping", Duration::from_secs(5));
+        assert!(matches!(
+            result.lock().unwrap().take(),
+            Some(Err(Kcp2KRpcError::SendFailed(ErrorCode::InvalidSend)))
+        ));
+        assert_eq!(CLIENT_RPC.with(|rpc| rpc.get_pending_count()), 0);
+    }
+
+    #[test]
+    fn call_times_out_on_the_virtual_clock() {
+        let mut link = connected_link_with(Kcp2KCapabilities::ALL, silent);
+        let result = call(&link, ECHO, b"ping", Duration::from_millis(500));
+        // 测试只用了几毫秒的真实时间，超时只能来自虚拟时钟
+        link.run(Duration::from_millis(400));
+        CLIENT_RPC.with(|rpc| rpc.tick());
+        assert!(result.lock().unwrap().is_none());
+        assert_eq!(CLIENT_RPC.with(|rpc| rpc.get_pending_count()), 1);
+        link.run(Duration::from_millis(110));
+        CLIENT_RPC.with(|rpc| rpc.tick());
+        assert!(matches!(
+            result.lock().unwrap().take(),
+            Some(Err(Kcp2KRpcError::Timeout))
+        ));
+        assert_eq!(CLIENT_RPC.with(|rpc| rpc.get_pending_count()), 0);
+    }
+
+    #[test]
+    fn handler_error_reaches_caller_as_remote() {
+        let mut link = connected_link(Kcp2KCapabilities::ALL);
+        let result = call(&link, FAIL, b"ping", Duration::from_secs(5));
+        link.run(Duration::from_millis(200));
+        match result.lock().unwrap().take() {
+            Some(Err(Kcp2KRpcError::Remote(payload))) => {
+                assert_eq!(payload, Bytes::from_static(b"denied"))
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(CLIENT_RPC.with(|rpc| rpc.get_pending_count()), 0);
+    }
+
+    #[test]
+    fn unregistered_method_returns_unknown_method() {
+        let mut link = connected_link(Kcp2KCapabilities::ALL);
+        let result = call(&link, UNREGISTERED, b"ping", Duration::from_secs(5));
+        link.run(Duration::from_millis(200));
+        assert!(matches!(
+            result.lock().unwrap().take(),
+            Some(Err(Kcp2KRpcError::UnknownMethod(UNREGISTERED)))
+        ));
+        assert_eq!(CLIENT_RPC.with(|rpc| rpc.get_pending_count()), 0);
+    }
+
+    #[test]
+    fn tick_fails_calls_on_disconnected_connections() {
+        let mut link = connected_link_with(Kcp2KCapabilities::ALL, silent);
+        let result = call(&link, ECHO, b"ping", Duration::from_secs(60));
+        link.client.close_connection(link.client_id);
+        link.run(Duration::from_millis(20));
+        CLIENT_RPC.with(|rpc| rpc.tick());
+        // 不必等到超时
+        assert!(matches!(
+            result.lock().unwrap().take(),
+            Some(Err(Kcp2KRpcError::Disconnected))
+        ));
+        assert_eq!(CLIENT_RPC.with(|rpc| rpc.get_pending_count()), 0);
+    }
+
+    #[test]
+    fn cancel_connection_fails_its_calls_immediately() {
+        let mut link = connected_link(Kcp2KCapabilities::ALL);
+        let cancelled = call(&link, ECHO, b"ping", Duration::from_secs(60));
+        CLIENT_RPC.with(|rpc| rpc.cancel_connection(link.client_id + 1));
+        assert_eq!(CLIENT_RPC.with(|rpc| rpc.get_pending_count()), 1);
+        CLIENT_RPC.with(|rpc| rpc.cancel_connection(link.client_id));
+        assert!(matches!(
+            cancelled.lock().unwrap().take(),
+            Some(Err(Kcp2KRpcError::Disconnected))
+        ));
+        assert_eq!(CLIENT_RPC.with(|rpc| rpc.get_pending_count()), 0);
+        // 迟到的响应被丢弃，之后的调用不受影响
+        let result = call(&link, ECHO, b"pong", Duration::from_secs(5));
+        link.run(Duration::from_millis(200));
+        assert!(cancelled.lock().unwrap().is_none());
+        assert!(matches!(
+            result.lock().unwrap().take(),
+            Some(Ok(payload)) if payload == Bytes::from_static(b"pong")
+        ));
+    }
+}
@@ -0,0 +1,224 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::collections::VecDeque;
+use std::time::Duration;
+
+// 应用层 RTT 测量：双方协商了 Kcp2KCapabilities::TIMESTAMPED_PING 后，ping 的载荷为
+// seq(u32 LE) + 发送时间(u64 LE，微秒)，对方在不可靠通道的 Pong 中原样回显。
+// 可靠 ping 的 RTT 包含 KCP 的排队和重传，丢包率只反映 pong 的丢失
+
+// ping 载荷的长度
+pub const PING_SIZE: usize = 12;
+// 最近多少个 ping 用于估计丢包率
+const LOSS_WINDOW: usize = 64;
+
+// Kcp2KRtt: 连接的 RTT 统计快照
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Kcp2KRtt {
+    pub rtt: Duration,      // 平滑 RTT，新样本权重 1/8
+    pub min_rtt: Duration,  // 最小 RTT
+    pub last_rtt: Duration, // 最近一个样本
+    pub jitter: Duration,   // 相邻样本差值的平滑平均，新样本权重 1/16（RFC 3550）
+    pub loss: f64,          // 最近 64 个 ping 中超时没有收到 pong 的比例
+    pub pings: u64,         // 发送的带时间戳的 ping 数
+    pub pongs: u64,         // 收到的有效 pong 数
+}
+
+// 已发送、等待 pong 的 ping
+#[derive(Debug)]
+struct PingRecord {
+    seq: u32,
+    send_time: Duration,
+    answered: bool,
+}
+
+// 根据 ping/pong 计算 Kcp2KRtt
+#[derive(Debug, Default)]
+pub(crate) struct Kcp2KRttEstimator {
+    stats: Kcp2KRtt,
+    next_seq: u32,
+    last_ping_time: Option<Duration>,
+    records: VecDeque<PingRecord>,
+}
+
+impl Kcp2KRttEstimator {
+    // 记录一个新的 ping，返回它的载荷
+    pub(crate) fn ping(&mut self, now: Duration, pong_timeout: Duration) -> Bytes {
+        let seq = self.next_seq;
+        self.next_seq = self.next_seq.wrapping_add(1);
+        self.last_ping_time = Some(now);
+        self.stats.pings += 1;
+        if self.records.len() == LOSS_WINDOW {
+            self.records.pop_front();
+        }
+        self.records.push_back(PingRecord {
+            seq,
+            send_time: now,
+            answered: false,
+        });
+        self.update_loss(now, pong_timeout);
+
+        let mut buffer = BytesMut::with_capacity(PING_SIZE);
+        buffer.put_u32_le(seq);
+        buffer.put_u64_le(now.as_micros() as u64);
+        buffer.freeze()
+    }
+
+    // 处理 pong，只接受窗口内尚未回应、也没有超时的 seq。返回是否得到了一个新样本
+    pub(crate) fn pong(
+        &mut self,
+        mut payload: &[u8],
+        now: Duration,
+        pong_timeout: Duration,
+    ) -> bool {
+        if payload.len() != PING_SIZE {
+            return false;
+        }
+        let seq = payload.get_u32_le();
+        let send_time = Duration::from_micros(payload.get_u64_le());
+        let record = match self.records.iter_mut().find(|record| record.seq == seq) {
+            Some(record)
+                if !record.answered
+                    && record.send_time == send_time
+                    && now < send_time + pong_timeout =>
+            {
+                record
+            }
+            _ => return false,
+        };
+        record.answered = true;
+
+        let rtt = now.saturating_sub(send_time);
+        let stats = &mut self.stats;
+        if stats.pongs == 0 {
+            stats.rtt = rtt;
+            stats.min_rtt = rtt;
+        } else {
+            stats.rtt = (stats.rtt * 7 + rtt) / 8;
+            stats.min_rtt = stats.min_rtt.min(rtt);
+            let delta = rtt.abs_diff(stats.last_rtt);
+            stats.jitter = match delta > stats.jitter {
+                true => stats.jitter + (delta - stats.jitter) / 16,
+                false => stats.jitter - (stats.jitter - delta) / 16,
+            };
+        }
+        stats.last_rtt = rtt;
+        stats.pongs += 1;
+        self.update_loss(now, pong_timeout);
+        true
+    }
+
+    // 丢包率：已经收到 pong 或已超时的 ping 中，超时的比例
+    fn update_loss(&mut self, now: Duration, pong_timeout: Duration) {
+        let (mut resolved, mut lost) = (0usize, 0usize);
+        for record in &self.records {
+            if record.answered {
+                resolved += 1;
+            } else if now >= record.send_time + pong_timeout {
+                resolved += 1;
+                lost += 1;
+            }
+        }
+        if resolved > 0 {
+            self.stats.loss = lost as f64 / resolved as f64;
+        }
+    }
+
+    pub(crate) fn last_ping_time(&self) -> Option<Duration> {
+        self.last_ping_time
+    }
+
+    pub(crate) fn snapshot(&self) -> Kcp2KRtt {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_millis(1000);
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn pong_updates_rtt_and_jitter() {
+        let mut estimator = Kcp2KRttEstimator::default();
+        let first = estimator.ping(ms(0), TIMEOUT);
+        let second = estimator.ping(ms(100), TIMEOUT);
+        assert!(estimator.pong(&first, ms(10), TIMEOUT));
+        assert!(estimator.pong(&second, ms(130), TIMEOUT));
+        let rtt = estimator.snapshot();
+        assert_eq!(rtt.last_rtt, ms(30));
+        assert_eq!(rtt.min_rtt, ms(10));
+        assert_eq!(rtt.rtt, (ms(10) * 7 + ms(30)) / 8);
+        assert_eq!(rtt.jitter, ms(20) / 16);
+        assert_eq!((rtt.pings, rtt.pongs), (2, 2));
+        assert_eq!(rtt.loss, 0.0);
+    }
+
+    #[test]
+    fn duplicate_pong_is_ignored() {
+        let mut estimator = Kcp2KRttEstimator::default();
+        let ping = estimator.ping(ms(0), TIMEOUT);
+        assert!(estimator.pong(&ping, ms(10), TIMEOUT));
+        let before = estimator.snapshot();
+        assert!(!estimator.pong(&ping, ms(500), TIMEOUT));
+        assert_eq!(estimator.snapshot(), before);
+    }
+
+    #[test]
+    fn stale_seq_is_ignored() {
+        let mut estimator = Kcp2KRttEstimator::default();
+        let stale = estimator.ping(ms(0), TIMEOUT);
+        for i in 1..=LOSS_WINDOW as u64 {
+            estimator.ping(ms(i), TIMEOUT);
+        }
+        // 第一个 ping 已经移出窗口
+        assert!(!estimator.pong(&stale, ms(100), TIMEOUT));
+        // 从未发送过的 seq
+        let mut unknown = BytesMut::new();
+        unknown.put_u32_le(LOSS_WINDOW as u32 + 1);
+        unknown.put_u64_le(0);
+        assert!(!estimator.pong(&unknown, ms(100), TIMEOUT));
+        assert_eq!(estimator.snapshot().pongs, 0);
+    }
+
+    #[test]
+    fn wrong_send_time_is_ignored() {
+        let mut estimator = Kcp2KRttEstimator::default();
+        let ping = estimator.ping(ms(50), TIMEOUT);
+        // seq 正确，但回显的发送时间被改成更早，否则会得到偏大的 RTT
+        let mut forged = BytesMut::from(&ping[..4]);
+        forged.put_u64_le(0);
+        assert!(!estimator.pong(&forged, ms(60), TIMEOUT));
+        // 长度不对
+        assert!(!estimator.pong(&ping[..PING_SIZE - 1], ms(60), TIMEOUT));
+        assert_eq!(estimator.snapshot().pongs, 0);
+        // 原样回显的 pong 仍然有效
+        assert!(estimator.pong(&ping, ms(60), TIMEOUT));
+        assert_eq!(estimator.snapshot().last_rtt, ms(10));
+    }
+
+    #[test]
+    fn loss_counts_pings_after_pong_timeout() {
+        let mut estimator = Kcp2KRttEstimator::default();
+        estimator.ping(ms(0), TIMEOUT);
+        let answered = estimator.ping(ms(100), TIMEOUT);
+        assert!(estimator.pong(&answered, ms(150), TIMEOUT));
+        // 第一个 ping 还没有超时，不算丢失
+        estimator.ping(ms(999), TIMEOUT);
+        assert_eq!(estimator.snapshot().loss, 0.0);
+        // 超时后算作丢失；最后一个 ping 还在等待，不计入
+        estimator.ping(ms(1000), TIMEOUT);
+        assert_eq!(estimator.snapshot().loss, 0.5);
+        // 超时之后才到达的 pong 不再计入，该 ping 仍算作丢失
+        let mut late = BytesMut::new();
+        late.put_u32_le(0);
+        late.put_u64_le(0);
+        assert!(!estimator.pong(&late, ms(1200), TIMEOUT));
+        assert_eq!(estimator.snapshot().loss, 0.5);
+        assert_eq!(estimator.snapshot().pongs, 1);
+    }
+}
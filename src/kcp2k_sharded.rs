@@ -0,0 +1,374 @@
+use crate::error_code::ErrorCode;
+use crate::kcp2k::Kcp2K;
+use crate::kcp2k_callback::Callback;
+use crate::kcp2k_channel::Kcp2KChannel;
+use crate::kcp2k_config::Kcp2KConfig;
+use crate::kcp2k_connection::Kcp2KConnection;
+use crate::kcp2k_disconnect_reason::DisconnectReason;
+use crate::kcp2k_stats::Kcp2KStats;
+use bytes::Bytes;
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use tklog::{debug, info};
+
+// 发往分片线程的命令
+enum ShardCommand {
+    Send(u64, Bytes, Kcp2KChannel),
+    Broadcast(Bytes, Kcp2KChannel),
+    Close(u64, DisconnectReason, String),
+}
+
+// Kcp2KShardedServer: 多线程分片服务器
+// 每个分片一个 socket（SO_REUSEPORT 绑定同一地址）和一个工作线程，内核按客户端地址把数据包分配到固定分片。
+// 回调在分片线程中执行。所有分片共用会话 cookie 表：客户端地址变化后，收到数据报的分片把它转发给连接所在的分片，
+// 之后新地址的数据报都经过一次转发。
+pub struct Kcp2KShardedServer {
+    handle: Kcp2KShardedHandle,
+    workers: Vec<JoinHandle<()>>,
+}
+
+// Kcp2KShardedHandle: 线程安全的句柄，可以克隆到其他线程进行跨分片发送、广播和统计
+#[derive(Clone)]
+pub struct Kcp2KShardedHandle {
+    shards: Arc<Vec<Arc<Kcp2K>>>,
+    senders: Arc<Vec<Sender<ShardCommand>>>,
+    running: Arc<AtomicBool>,
+}
+
+impl Kcp2KShardedServer {
+    pub fn new(
+        config: Kcp2KConfig,
+        addr: String,
+        shard_count: usize,
+        callback: fn(&Kcp2KConnection, Callback),
+    ) -> Result<Self, Error> {
+        if shard_count == 0 || shard_count > u16::MAX as usize {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid shard count: {}", shard_count),
+            ));
+        }
+        let mut socket_addr: SocketAddr = addr
+            .parse()
+            .map_err(|err| Error::new(ErrorKind::InvalidInput, err))?;
+        let mut shards = Vec::with_capacity(shard_count);
+        for shard_index in 0..shard_count {
+            let shard = Kcp2K::new_server_shard(
+                config,
+                socket_addr,
+                callback,
+                shard_index as u64,
+                shard_count as u64,
+            )?;
+            // 端口为 0 时，其余分片绑定第一个分片分配到的端口
+            socket_addr = shard.get_local_addr()?;
+            shards.push(shard);
+        }
+        Kcp2K::link_shards(&mut shards);
+        let shards: Vec<Arc<Kcp2K>> = shards.into_iter().map(Arc::new).collect();
+
+        let running = Arc::new(AtomicBool::new(true));
+        let mut senders = Vec::with_capacity(shard_count);
+        let mut workers = Vec::with_capacity(shard_count);
+        for (shard_index, shard) in shards.iter().enumerate() {
+            let (sender, receiver) = channel();
+            senders.push(sender);
+            let shard = Arc::clone(shard);
+            let running = Arc::clone(&running);
+            let interval = Duration::from_millis(config.interval.max(1) as u64);
+            workers.push(
+                std::thread::Builder::new()
+                    .name(format!("kcp2k-shard-{}", shard_index))
+                    .spawn(move || Self::run_shard(shard, receiver, running, interval))?,
+            );
+        }
+        info!(format!(
+            "[KCP2K] Sharded server running {} shards on: {:?}",
+            shard_count, socket_addr
+        ));
+
+        Ok(Self {
+            handle: Kcp2KShardedHandle {
+                shards: Arc::new(shards),
+                senders: Arc::new(senders),
+                running,
+            },
+            workers,
+        })
+    }
+    // 分片工作线程：处理命令，然后 tick
+    fn run_shard(
+        shard: Arc<Kcp2K>,
+        receiver: Receiver<ShardCommand>,
+        running: Arc<AtomicBool>,
+        interval: Duration,
+    ) {
+        let mut deadline = Instant::now();
+        while running.load(Ordering::SeqCst) {
+            deadline = next_deadline(deadline, interval, Instant::now());
+            while let Ok(command) = receiver.try_recv() {
+                match command {
+                    ShardCommand::Send(connection_id, data, channel) => {
+                        if let Err(err) = shard.send(connection_id, data, channel) {
+                            debug!(format!(
+                                "[KCP2K] Shard send to {} failed: {:?}",
+                                connection_id, err
+                            ));
+                        }
+                    }
+                    ShardCommand::Broadcast(data, channel) => {
+                        let _ = shard.broadcast(None, data, channel, &[]);
+                    }
+                    ShardCommand::Close(connection_id, reason, message) => {
+                        shard.disconnect(connection_id, reason, &message)
+                    }
+                }
+            }
+            shard.tick();
+            // 睡到下一个 tick 的开始时间，tick 本身的耗时不会让频率漂移
+            std::thread::sleep(deadline.saturating_duration_since(Instant::now()));
+        }
+    }
+    pub fn get_handle(&self) -> Kcp2KShardedHandle {
+        self.handle.clone()
+    }
+    // 停止所有分片并等待工作线程退出
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+    fn shutdown(&mut self) {
+        self.handle.running.store(false, Ordering::SeqCst);
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+        for shard in self.handle.shards.iter() {
+            let _ = shard.stop();
+        }
+    }
+}
+
+impl Drop for Kcp2KShardedServer {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+// 本次 tick 开始后的下一个 tick 时间：上一次的时间加上 interval。
+// 如果 tick 超时已经错过了这个时间，从现在重新计时，不连续补 tick
+fn next_deadline(deadline: Instant, interval: Duration, now: Instant) -> Instant {
+    match deadline + interval > now {
+        true => deadline + interval,
+        false => now + interval,
+    }
+}
+
+impl Kcp2KShardedHandle {
+    // 连接所在的分片
+    fn shard_of(&self, connection_id: u64) -> usize {
+        (connection_id % self.senders.len() as u64) as usize
+    }
+    // 跨分片发送：消息交给连接所在的分片线程发送
+    pub fn send(
+        &self,
+        connection_id: u64,
+        data: Bytes,
+        channel: Kcp2KChannel,
+    ) -> Result<(), ErrorCode> {
+        match self.senders[self.shard_of(connection_id)].send(ShardCommand::Send(
+            connection_id,
+            data,
+            channel,
+        )) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(ErrorCode::ConnectionClosed),
+        }
+    }
+    // 广播给所有分片上已通过验证的连接
+    pub fn broadcast(&self, data: Bytes, channel: Kcp2KChannel) -> Result<(), ErrorCode> {
+        for sender in self.senders.iter() {
+            if sender
+                .send(ShardCommand::Broadcast(data.clone(), channel))
+                .is_err()
+            {
+                return Err(ErrorCode::ConnectionClosed);
+            }
+        }
+        Ok(())
+    }
+    pub fn close_connection(&self, connection_id: u64) {
+        self.disconnect(connection_id, DisconnectReason::Closed, "");
+    }
+    // 断开连接并把原因告诉对方
+    pub fn disconnect(&self, connection_id: u64, reason: DisconnectReason, message: &str) {
+        let _ = self.senders[self.shard_of(connection_id)].send(ShardCommand::Close(
+            connection_id,
+            reason,
+            message.to_string(),
+        ));
+    }
+    pub fn get_connection_address(&self, connection_id: u64) -> String {
+        self.shards[self.shard_of(connection_id)].get_connection_address(connection_id)
+    }
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+    pub fn get_shard_count(&self) -> usize {
+        self.shards.len()
+    }
+    // 每个分片的统计信息
+    pub fn get_shard_stats(&self) -> Vec<Kcp2KStats> {
+        self.shards.iter().map(|shard| shard.get_stats()).collect()
+    }
+    // 所有分片的汇总统计信息
+    pub fn get_stats(&self) -> Kcp2KStats {
+        self.get_shard_stats()
+            .into_iter()
+            .fold(Kcp2KStats::default(), |total, stats| total + stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::Kcp2KMode;
+    use crate::kcp2k_callback::CallbackType;
+    use crate::kcp2k_testing::{addr, ignore, run_network};
+    use socket2::SockAddr;
+    use std::cell::RefCell;
+
+    thread_local! {
+        static SERVER_DATA: RefCell<Vec<(u64, Bytes)>> = const { RefCell::new(Vec::new()) };
+    }
+
+    fn record_data(conn: &Kcp2KConnection, cb: Callback) {
+        if matches!(cb.r#type, CallbackType::OnData) {
+            SERVER_DATA.with(|data| data.borrow_mut().push((conn.get_connection_id(), cb.data)));
+        }
+    }
+
+    // 两个离线分片和一个客户端，client_shard 模拟内核按客户端地址选择分片
+    struct ShardedLink {
+        shards: Vec<Kcp2K>,
+        client: Kcp2K,
+        client_id: u64,
+        client_addr: SockAddr,
+        client_shard: usize,
+        now: Duration,
+    }
+
+    impl ShardedLink {
+        fn new() -> Self {
+            let config = Kcp2KConfig::default();
+            let mut shards: Vec<Kcp2K> = (0..2)
+                .map(|seed| {
+                    Kcp2K::new_replay(config, Kcp2KMode::Server, seed, record_data).unwrap()
+                })
+                .collect();
+            Kcp2K::link_shards(&mut shards);
+            let client = Kcp2K::new_replay(config, Kcp2KMode::Client, 9, ignore).unwrap();
+            let client_id = client.connect("10.0.0.1:7777".to_string()).unwrap();
+            Self {
+                shards,
+                client,
+                client_id,
+                client_addr: addr("10.0.0.2:50000"),
+                client_shard: 0,
+                now: Duration::ZERO,
+            }
+        }
+
+        fn run(&mut self, duration: Duration) {
+            // 两个分片共享服务器地址，内核按客户端地址选择 client_shard
+            let server_addr = addr("10.0.0.1:7777");
+            let nodes: Vec<(&Kcp2K, &SockAddr)> = self
+                .shards
+                .iter()
+                .map(|shard| (shard, &server_addr))
+                .chain(std::iter::once((&self.client, &self.client_addr)))
+                .collect();
+            let steer = [(&self.client_addr, self.client_shard)];
+            run_network(&nodes, &steer, &mut self.now, duration);
+        }
+
+        fn send(&mut self, data: &'static [u8]) {
+            let _ = self.client.send(
+                self.client_id,
+                Bytes::from_static(data),
+                Kcp2KChannel::Reliable,
+            );
+            self.run(Duration::from_millis(500));
+        }
+    }
+
+    #[test]
+    fn migration_is_forwarded_to_the_owning_shard() {
+        let mut link = ShardedLink::new();
+        link.run(Duration::from_millis(200));
+        link.send(b"before");
+        let connection_id = *link.shards[0]
+            .get_connections()
+            .iter()
+            .next()
+            .unwrap()
+            .key();
+
+        // NAT 重绑定后，内核把新地址的数据报交给另一个分片
+        link.client_addr = addr("10.0.0.3:40000");
+        link.client_shard = 1;
+        link.send(b"after");
+
+        // 新地址的数据报确实先到了另一个分片
+        assert!(link.shards[1].get_stats().received_packets > 0);
+        assert!(link.shards[1].get_connections().is_empty());
+        let conn = link.shards[0]
+            .get_connections()
+            .get(&connection_id)
+            .map(|conn| ((*conn.get_sock_addr()).clone(), conn.is_authenticated()));
+        assert_eq!(conn, Some((link.client_addr.clone(), true)));
+        let received = SERVER_DATA.with(|data| data.take());
+        assert_eq!(
+            received,
+            vec![
+                (connection_id, Bytes::from_static(b"before")),
+                (connection_id, Bytes::from_static(b"after"))
+            ]
+        );
+    }
+
+    #[test]
+    fn new_rejects_invalid_arguments() {
+        let config = Kcp2KConfig::default();
+        for (addr, shard_count) in [("not an address", 2), ("127.0.0.1:0", 0)] {
+            let err = Kcp2KShardedServer::new(config, addr.to_string(), shard_count, ignore)
+                .err()
+                .unwrap();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        }
+    }
+
+    const INTERVAL: Duration = Duration::from_millis(10);
+
+    #[test]
+    fn deadline_advances_by_interval_regardless_of_tick_time() {
+        let start = Instant::now();
+        // 第一个 tick 耗时 3ms，第二个耗时 9ms，tick 仍然每 10ms 开始一次
+        let first = next_deadline(start, INTERVAL, start);
+        assert_eq!(first, start + INTERVAL);
+        let second = next_deadline(first, INTERVAL, first + Duration::from_millis(3));
+        assert_eq!(second, start + INTERVAL * 2);
+        let third = next_deadline(second, INTERVAL, second + Duration::from_millis(9));
+        assert_eq!(third, start + INTERVAL * 3);
+    }
+
+    #[test]
+    fn overrun_tick_restarts_from_now() {
+        let start = Instant::now();
+        let late = start + Duration::from_millis(35);
+        assert_eq!(next_deadline(start, INTERVAL, late), late + INTERVAL);
+    }
+}
@@ -0,0 +1,134 @@
+use bytes::Bytes;
+use socket2::{SockAddr, Socket};
+use std::collections::VecDeque;
+use std::io;
+use std::io::IoSlice;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+// Kcp2KSocket: UDP socket 的封装。
+// 离线模式（会话回放等）下不会向网络发送任何数据，接收的数据报来自内存队列
+#[derive(Debug)]
+pub struct Kcp2KSocket {
+    socket: Socket,
+    inbound: Option<Mutex<VecDeque<(SockAddr, Bytes)>>>,
+    sent_packets: AtomicU64, // 发送的数据包数
+    sent_bytes: AtomicU64,   // 发送的字节数
+    #[cfg(test)]
+    outbound: Mutex<Vec<(SockAddr, Bytes)>>, // 测试：离线模式下发送的数据报
+}
+
+impl Kcp2KSocket {
+    pub(crate) fn new(socket: Socket) -> Self {
+        Self {
+            socket,
+            inbound: None,
+            sent_packets: AtomicU64::new(0),
+            sent_bytes: AtomicU64::new(0),
+            #[cfg(test)]
+            outbound: Mutex::new(Vec::new()),
+        }
+    }
+    pub(crate) fn offline(socket: Socket) -> Self {
+        Self {
+            socket,
+            inbound: Some(Mutex::new(VecDeque::new())),
+            sent_packets: AtomicU64::new(0),
+            sent_bytes: AtomicU64::new(0),
+            #[cfg(test)]
+            outbound: Mutex::new(Vec::new()),
+        }
+    }
+    pub fn is_offline(&self) -> bool {
+        self.inbound.is_some()
+    }
+    pub fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        let result = match self.inbound {
+            Some(_) => Ok(buf.len()),
+            None => self.socket.send(buf),
+        };
+        self.count_result(result)
+    }
+    pub fn send_to(&self, buf: &[u8], addr: &SockAddr) -> io::Result<usize> {
+        let result = match self.inbound {
+            Some(_) => {
+                #[cfg(test)]
+                self.record_outbound(addr, &[buf]);
+                Ok(buf.len())
+            }
+            None => self.socket.send_to(buf, addr),
+        };
+        self.count_result(result)
+    }
+    pub fn send_to_vectored(&self, bufs: &[IoSlice<'_>], addr: &SockAddr) -> io::Result<usize> {
+        let result = match self.inbound {
+            Some(_) => {
+                #[cfg(test)]
+                self.record_outbound(addr, &bufs.iter().map(|buf| &buf[..]).collect::<Vec<_>>());
+                Ok(bufs.iter().map(|buf| buf.len()).sum())
+            }
+            None => self.socket.send_to_vectored(bufs, addr),
+        };
+        self.count_result(result)
+    }
+    fn count_result(&self, result: io::Result<usize>) -> io::Result<usize> {
+        if let Ok(size) = result {
+            self.count_sent(1, size as u64);
+        }
+        result
+    }
+    // 统计发送的数据包，批量发送（sendmmsg）绕过了 send_to，由调用方统计
+    pub(crate) fn count_sent(&self, packets: u64, bytes: u64) {
+        self.sent_packets.fetch_add(packets, Ordering::Relaxed);
+        self.sent_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+    // 发送的数据包数和字节数
+    pub fn get_sent(&self) -> (u64, u64) {
+        (
+            self.sent_packets.load(Ordering::Relaxed),
+            self.sent_bytes.load(Ordering::Relaxed),
+        )
+    }
+    // 离线模式：放入一个待接收的数据报
+    pub(crate) fn push_inbound(&self, sock_addr: SockAddr, data: Bytes) {
+        if let Some(inbound) = &self.inbound {
+            if let Ok(mut inbound) = inbound.lock() {
+                inbound.push_back((sock_addr, data));
+            }
+        }
+    }
+    #[cfg(test)]
+    fn record_outbound(&self, addr: &SockAddr, bufs: &[&[u8]]) {
+        if let Ok(mut outbound) = self.outbound.lock() {
+            outbound.push((addr.clone(), Bytes::from(bufs.concat())));
+        }
+    }
+    // 测试：取出离线模式下发送的数据报
+    #[cfg(test)]
+    pub(crate) fn take_outbound(&self) -> Vec<(SockAddr, Bytes)> {
+        match self.outbound.lock() {
+            Ok(mut outbound) => std::mem::take(&mut *outbound),
+            Err(_) => Vec::new(),
+        }
+    }
+    // 离线模式：取出下一个待接收的数据报
+    pub(crate) fn pop_inbound(&self) -> Option<(SockAddr, Bytes)> {
+        match &self.inbound {
+            Some(inbound) => match inbound.lock() {
+                Ok(mut inbound) => inbound.pop_front(),
+                Err(_) => None,
+            },
+            None => None,
+        }
+    }
+}
+
+// 其余操作（接收、本地地址、关闭等）直接使用底层 socket
+impl Deref for Kcp2KSocket {
+    type Target = Socket;
+
+    fn deref(&self) -> &Self::Target {
+        &self.socket
+    }
+}
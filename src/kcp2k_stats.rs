@@ -0,0 +1,30 @@
+use std::ops::Add;
+
+// Kcp2KStats: 统计信息快照
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Kcp2KStats {
+    pub connections: usize,     // 当前连接数
+    pub received_packets: u64,  // 收到的数据包数
+    pub received_bytes: u64,    // 收到的字节数
+    pub oversized_packets: u64, // 超过 MTU 被丢弃的数据包数
+    pub sent_packets: u64,      // 发送的数据包数，包括 KCP 重传、ACK 和 ping
+    pub sent_bytes: u64,        // 发送的字节数
+    pub dropped_packets: u64,   // 批量发送时无法发送或等待重试过多而丢弃的数据包数
+}
+
+// 多个分片的统计信息相加得到汇总
+impl Add for Kcp2KStats {
+    type Output = Kcp2KStats;
+
+    fn add(self, other: Kcp2KStats) -> Kcp2KStats {
+        Kcp2KStats {
+            connections: self.connections + other.connections,
+            received_packets: self.received_packets + other.received_packets,
+            received_bytes: self.received_bytes + other.received_bytes,
+            oversized_packets: self.oversized_packets + other.oversized_packets,
+            sent_packets: self.sent_packets + other.sent_packets,
+            sent_bytes: self.sent_bytes + other.sent_bytes,
+            dropped_packets: self.dropped_packets + other.dropped_packets,
+        }
+    }
+}
@@ -0,0 +1,245 @@
+use crate::common::Kcp2KMode;
+use crate::kcp2k::Kcp2K;
+use crate::kcp2k_callback::Callback;
+use crate::kcp2k_config::Kcp2KConfig;
+use crate::kcp2k_connection::Kcp2KConnection;
+use bytes::Bytes;
+use socket2::SockAddr;
+use std::net::SocketAddr;
+use std::ops::Deref;
+use std::time::Duration;
+
+// 测试用的离线服务器和客户端：数据报在内存中转发，时间由测试推进。
+// client_addr 是服务器看到的客户端地址，修改它可以模拟 NAT 重绑定；
+// 服务器发往其他地址的数据报放入 stray，不会到达客户端。
+// sent 记录双方发出的所有数据报；blocked 为 true 时链路中断，数据报全部丢弃
+pub(crate) struct Kcp2KTestLink {
+    pub server: Kcp2K,
+    pub client: Kcp2K,
+    pub server_addr: SockAddr,
+    pub client_addr: SockAddr,
+    pub client_id: u64,
+    pub stray: Vec<(SockAddr, Bytes)>,
+    pub sent: Vec<(Duration, Kcp2KMode, Bytes)>,
+    pub blocked: bool,
+    now: Duration,
+}
+
+// 每一步推进的时间
+pub(crate) const STEP: Duration = Duration::from_millis(10);
+
+pub(crate) fn addr(addr: &str) -> SockAddr {
+    addr.parse::<SocketAddr>().unwrap().into()
+}
+
+// 不关心回调的一端
+pub(crate) fn ignore(_: &Kcp2KConnection, _: Callback) {}
+
+impl Kcp2KTestLink {
+    pub(crate) fn new(
+        config: Kcp2KConfig,
+        server_callback: fn(&Kcp2KConnection, Callback),
+        client_callback: fn(&Kcp2KConnection, Callback),
+    ) -> Self {
+        Self::with_hello(config, server_callback, client_callback, Bytes::new())
+    }
+
+    // 完成握手的链路
+    pub(crate) fn connected(
+        config: Kcp2KConfig,
+        server_callback: fn(&Kcp2KConnection, Callback),
+        client_callback: fn(&Kcp2KConnection, Callback),
+    ) -> Self {
+        let mut link = Self::new(config, server_callback, client_callback);
+        link.run(Duration::from_millis(200));
+        assert!(link.server_conn().is_authenticated());
+        link
+    }
+
+    // 客户端的 Hello 附带应用载荷，服务器的认证钩子需要在第一次 run 之前设置
+    pub(crate) fn with_hello(
+        config: Kcp2KConfig,
+        server_callback: fn(&Kcp2KConnection, Callback),
+        client_callback: fn(&Kcp2KConnection, Callback),
+        hello_payload: Bytes,
+    ) -> Self {
+        let mut link = Self::unconnected(
+            config,
+            Kcp2KMode::Server,
+            Kcp2KMode::Client,
+            server_callback,
+            client_callback,
+        );
+        link.client_id = link
+            .client
+            .connect_with_hello("10.0.0.1:7777".to_string(), hello_payload)
+            .unwrap();
+        link
+    }
+
+    // 指定双方模式、还没有连接的链路，例如两个 P2P 节点。client_id 为 0，由测试调用 connect
+    pub(crate) fn unconnected(
+        config: Kcp2KConfig,
+        server_mode: Kcp2KMode,
+        client_mode: Kcp2KMode,
+        server_callback: fn(&Kcp2KConnection, Callback),
+        client_callback: fn(&Kcp2KConnection, Callback),
+    ) -> Self {
+        Self {
+            server: Kcp2K::new_replay(config, server_mode, 1, server_callback).unwrap(),
+            client: Kcp2K::new_replay(config, client_mode, 2, client_callback).unwrap(),
+            server_addr: addr("10.0.0.1:7777"),
+            client_addr: addr("10.0.0.2:50000"),
+            client_id: 0,
+            stray: Vec::new(),
+            sent: Vec::new(),
+            blocked: false,
+            now: Duration::ZERO,
+        }
+    }
+
+    // 服务器上唯一的连接
+    pub(crate) fn server_id(&self) -> u64 {
+        *self.server.get_connections().iter().next().unwrap().key()
+    }
+
+    pub(crate) fn server_conn(&self) -> impl Deref<Target = Kcp2KConnection> + '_ {
+        self.server
+            .get_connections()
+            .get(&self.server_id())
+            .unwrap()
+    }
+
+    pub(crate) fn now(&self) -> Duration {
+        self.now
+    }
+
+    // 推进 duration，每 STEP tick 一次并转发数据报
+    pub(crate) fn run(&mut self, duration: Duration) {
+        let end = self.now + duration;
+        while self.now < end {
+            self.now += STEP;
+            self.server.get_context().set_time(self.now);
+            self.client.get_context().set_time(self.now);
+            self.server.tick();
+            self.client.tick();
+            self.route();
+        }
+    }
+
+    fn route(&mut self) {
+        for (_, data) in self.client.take_outbound() {
+            self.sent.push((self.now, Kcp2KMode::Client, data.clone()));
+            if !self.blocked {
+                self.server.push_inbound(self.client_addr.clone(), data);
+            }
+        }
+        for (sock_addr, data) in self.server.take_outbound() {
+            self.sent.push((self.now, Kcp2KMode::Server, data.clone()));
+            if self.blocked {
+                continue;
+            }
+            match sock_addr == self.client_addr {
+                true => self.client.push_inbound(self.server_addr.clone(), data),
+                false => self.stray.push((sock_addr, data)),
+            }
+        }
+    }
+}
+
+// 一个离线服务器和多个客户端，每个客户端有自己的地址 10.0.0.2、10.0.0.3 ...
+pub(crate) struct Kcp2KTestClients {
+    pub server: Kcp2K,
+    pub clients: Vec<(Kcp2K, SockAddr)>,
+    now: Duration,
+}
+
+impl Kcp2KTestClients {
+    pub(crate) fn new(
+        config: Kcp2KConfig,
+        count: usize,
+        server_callback: fn(&Kcp2KConnection, Callback),
+        client_callback: fn(&Kcp2KConnection, Callback),
+    ) -> Self {
+        let server = Kcp2K::new_replay(config, Kcp2KMode::Server, 1, server_callback).unwrap();
+        let clients = (0..count)
+            .map(|i| {
+                let client =
+                    Kcp2K::new_replay(config, Kcp2KMode::Client, 2 + i as u64, client_callback)
+                        .unwrap();
+                client.connect("10.0.0.1:7777".to_string()).unwrap();
+                (client, addr(&format!("10.0.0.{}:50000", 2 + i)))
+            })
+            .collect();
+        Self {
+            server,
+            clients,
+            now: Duration::ZERO,
+        }
+    }
+
+    // 第 i 个客户端在服务器上的连接 ID
+    pub(crate) fn server_id(&self, i: usize) -> u64 {
+        *self
+            .server
+            .get_connections()
+            .iter()
+            .find(|conn| *conn.get_sock_addr() == self.clients[i].1)
+            .unwrap()
+            .key()
+    }
+
+    // 第 i 个客户端上唯一的连接
+    pub(crate) fn client_id(&self, i: usize) -> u64 {
+        *self.clients[i]
+            .0
+            .get_connections()
+            .iter()
+            .next()
+            .unwrap()
+            .key()
+    }
+
+    pub(crate) fn run(&mut self, duration: Duration) {
+        let server_addr = addr("10.0.0.1:7777");
+        let nodes: Vec<(&Kcp2K, &SockAddr)> = std::iter::once((&self.server, &server_addr))
+            .chain(
+                self.clients
+                    .iter()
+                    .map(|(client, client_addr)| (client, client_addr)),
+            )
+            .collect();
+        run_network(&nodes, &[], &mut self.now, duration);
+    }
+}
+
+// 按地址转发的离线网络：每 STEP 推进所有节点的时钟并 tick，然后把每个节点发出的数据报交给地址等于
+// 目的地址的节点，源地址为发送节点的地址，没有节点的地址上的数据报被丢弃。
+// 多个节点共享一个地址时（例如 SO_REUSEPORT 分片），steer 按源地址选择节点，没有指定时交给第一个
+pub(crate) fn run_network(
+    nodes: &[(&Kcp2K, &SockAddr)],
+    steer: &[(&SockAddr, usize)],
+    now: &mut Duration,
+    duration: Duration,
+) {
+    let end = *now + duration;
+    while *now < end {
+        *now += STEP;
+        for (kcp2k, _) in nodes {
+            kcp2k.get_context().set_time(*now);
+            kcp2k.tick();
+        }
+        for (kcp2k, from) in nodes {
+            for (to, data) in kcp2k.take_outbound() {
+                let mut receivers = (0..nodes.len()).filter(|&i| *nodes[i].1 == to);
+                let steered = steer
+                    .iter()
+                    .find(|(source, i)| source == from && *nodes[*i].1 == to)
+                    .map(|(_, i)| *i);
+                if let Some(i) = steered.or_else(|| receivers.next()) {
+                    nodes[i].0.push_inbound((*from).clone(), data);
+                }
+            }
+        }
+    }
+}
@@ -1,10 +1,34 @@
+pub mod common;
+pub mod error_code;
 pub mod kcp2k;
+pub mod kcp2k_auth;
+mod kcp2k_batch;
+pub mod kcp2k_budget;
 pub mod kcp2k_callback;
+pub mod kcp2k_capture;
 pub mod kcp2k_channel;
+pub mod kcp2k_compat;
 pub mod kcp2k_config;
 pub mod kcp2k_connection;
-pub mod error_code;
+pub mod kcp2k_connection_handle;
+pub mod kcp2k_context;
+pub mod kcp2k_disconnect_reason;
+mod kcp2k_group;
+pub mod kcp2k_handle;
+pub mod kcp2k_header;
+#[cfg(feature = "message")]
+pub mod kcp2k_message;
+pub mod kcp2k_packet;
 pub mod kcp2k_peer;
-pub mod common;
-mod kcp2k_header;
-mod kcp2k_state;
+mod kcp2k_pool;
+pub mod kcp2k_protocol;
+pub mod kcp2k_reason;
+pub mod kcp2k_replay;
+pub mod kcp2k_rpc;
+pub mod kcp2k_rtt;
+pub mod kcp2k_sharded;
+pub mod kcp2k_socket;
+pub mod kcp2k_state;
+pub mod kcp2k_stats;
+#[cfg(test)]
+mod kcp2k_testing;